@@ -0,0 +1,108 @@
+//! Throughput of the aggregation of the packets into the report
+//!
+//! The packets are read from the pcap file in NETWORK_ANALYZER_BENCH_PCAP, e.g. a recording of a
+//! busy link, or generated when it is not set. They are decoded once, then added to the report by
+//! as many threads as CPUs, like the workers of a capture:
+//! * single_lock: a report with a single shard, as when every worker locked the whole report
+//! * sharded: a report with 4 shards for each CPU, as used by the control block
+//!
+//! cargo bench --bench aggregation
+//! NETWORK_ANALYZER_BENCH_PCAP=trace.pcap cargo bench --bench aggregation
+use std::time::Duration;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use etherparse::InternetSlice::{Ipv4, Ipv6};
+use etherparse::SlicedPacket;
+use etherparse::TransportSlice::{Tcp, Udp};
+use network_analyzer::packet::Packet;
+use network_analyzer::ShardedReport;
+
+/// Packets generated when no pcap file is given
+const GENERATED_PACKETS: usize = 1_000_000;
+/// Communications of the generated packets
+const GENERATED_FLOWS: usize = 10_000;
+/// Packets read at most from the pcap file
+const MAX_PACKETS: usize = 2_000_000;
+
+fn load_packets() -> Vec<Packet> {
+    match std::env::var("NETWORK_ANALYZER_BENCH_PCAP") {
+        Ok(path) => read_pcap(&path),
+        Err(_) => generate_packets(),
+    }
+}
+
+fn read_pcap(path: &str) -> Vec<Packet> {
+    let mut capture = pcap::Capture::from_file(path).expect("cannot open the pcap file");
+    let mut packets = Vec::new();
+    while packets.len() < MAX_PACKETS {
+        let packet = match capture.next_packet() {
+            Ok(p) => p,
+            Err(_) => break,
+        };
+        let sliced = match SlicedPacket::from_ethernet(packet.data) {
+            Ok(s) => s,
+            Err(_) => continue,
+        };
+        let (source, destination) = match &sliced.ip {
+            Some(Ipv4(header, ..)) => (header.source_addr().to_string(), header.destination_addr().to_string()),
+            Some(Ipv6(header, ..)) => (header.source_addr().to_string(), header.destination_addr().to_string()),
+            None => continue,
+        };
+        let (protocol, source_port, destination_port) = match &sliced.transport {
+            Some(Tcp(header)) => ("TCP", Some(header.source_port().to_string()), Some(header.destination_port().to_string())),
+            Some(Udp(header)) => ("UDP", Some(header.source_port().to_string()), Some(header.destination_port().to_string())),
+            _ => ("IP", None, None),
+        };
+        let mut p = Packet::new(String::new(), source, destination, source_port, destination_port, String::from(protocol), packet.header.len, String::new());
+        p.set_timestamp(&packet.header.ts.tv_sec, &packet.header.ts.tv_usec);
+        packets.push(p);
+    }
+    packets
+}
+
+fn generate_packets() -> Vec<Packet> {
+    (0..GENERATED_PACKETS).map(|i| {
+        let flow = i % GENERATED_FLOWS;
+        let (source, destination, source_port, destination_port) = match i % 2 {
+            0 => (format!("10.0.{}.{}", flow / 250, flow % 250 + 1), String::from("192.168.1.10"), 40000 + flow % 20000, 443),
+            _ => (String::from("192.168.1.10"), format!("10.0.{}.{}", flow / 250, flow % 250 + 1), 443, 40000 + flow % 20000),
+        };
+        let mut p = Packet::new(String::new(), source, destination, Some(source_port.to_string()), Some(destination_port.to_string()), String::from("TCP"), 64 + (i % 1400) as u32, String::new());
+        p.set_timestamp(&(1_700_000_000 + (i / 1_000_000) as libc::c_long), &((i % 1_000_000) as libc::c_long));
+        p
+    }).collect()
+}
+
+/// Adds the packets to the report from one thread for each CPU, then merges the shards.
+fn aggregate(packets: &[Packet], shards: usize) -> usize {
+    let report = ShardedReport::new(shards, 0);
+    let threads = num_cpus::get();
+    let chunk = packets.len().div_ceil(threads);
+    std::thread::scope(|scope| {
+        for part in packets.chunks(chunk) {
+            let report = &report;
+            scope.spawn(move || {
+                for packet in part {
+                    report.add_packet(packet, |_, _, _| ());
+                }
+            });
+        }
+    });
+    report.merge().report_lines.len()
+}
+
+fn aggregation(c: &mut Criterion) {
+    let packets = load_packets();
+    let mut group = c.benchmark_group("aggregation");
+    group.throughput(Throughput::Elements(packets.len() as u64));
+    group.sample_size(10);
+    group.measurement_time(Duration::from_secs(20));
+    for (name, shards) in [("single_lock", 1), ("sharded", num_cpus::get() * 4)] {
+        group.bench_with_input(BenchmarkId::new(name, packets.len()), &shards, |b, shards| {
+            b.iter(|| aggregate(&packets, *shards));
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, aggregation);
+criterion_main!(benches);
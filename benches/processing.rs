@@ -0,0 +1,137 @@
+//! Throughput of the workers of a capture, in packets per second
+//!
+//! The packets of a pcap file are given to a PacketProcessor, which runs the job of the workers
+//! for every captured packet: decoding, dissectors, metrics, DoS and DNS detectors, display
+//! filter and report. The file is benches/fixtures/mixed.pcap, a recording of TCP sessions,
+//! HTTP requests, DNS queries and ICMP echoes over a few hundred hosts, or the one in
+//! NETWORK_ANALYZER_BENCH_PCAP. It is replayed, shifting the timestamps by its duration every
+//! time, until REPLAYED_PACKETS packets, shared by as many threads as CPUs.
+//!
+//! The file is read without libpcap, so only the classic pcap format of Ethernet frames is
+//! supported, e.g. `tcpdump -w` or `editcap -F pcap`.
+//!
+//! The second case loads the rules of benches/fixtures/mixed.rules, which match a part of the
+//! traffic, and subscribes to the flows and the packets, so that the workers also raise alerts
+//! and send events.
+//!
+//! cargo bench --bench processing
+//! NETWORK_ANALYZER_BENCH_PCAP=trace.pcap cargo bench --bench processing
+use std::sync::Arc;
+use std::time::Duration;
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use libc::{suseconds_t, time_t, timeval};
+use network_analyzer::parameters::Parameters;
+use network_analyzer::subscription::Topic;
+use network_analyzer::{ControlBlockBuilder, PacketProcessor};
+use pcap::PacketHeader;
+
+/// The recording used when NETWORK_ANALYZER_BENCH_PCAP is not set
+const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/benches/fixtures/mixed.pcap");
+/// The rules of the case with alerts
+const RULES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/benches/fixtures/mixed.rules");
+/// Packets given to the workers in every iteration
+const REPLAYED_PACKETS: usize = 1_000_000;
+/// Link type of the Ethernet frames
+const LINKTYPE_ETHERNET: u32 = 1;
+
+/// A packet of the recording
+struct Frame {
+    header: PacketHeader,
+    data: Vec<u8>,
+}
+
+/// Reads the frames of a classic pcap file, in microseconds or nanoseconds.
+fn read_pcap(path: &str) -> Vec<Frame> {
+    let file = std::fs::read(path).unwrap_or_else(|e| panic!("cannot read {}: {}", path, e));
+    let magic = u32::from_le_bytes(file[0..4].try_into().unwrap());
+    let (little_endian, nanoseconds) = match magic {
+        0xa1b2c3d4 => (true, false),
+        0xa1b23c4d => (true, true),
+        0xd4c3b2a1 => (false, false),
+        0x4d3cb2a1 => (false, true),
+        _ => panic!("{} is not a pcap file", path),
+    };
+    let read_u32 = |offset: usize| {
+        let bytes = file[offset..offset + 4].try_into().unwrap();
+        match little_endian {
+            true => u32::from_le_bytes(bytes),
+            false => u32::from_be_bytes(bytes),
+        }
+    };
+    assert_eq!(read_u32(20), LINKTYPE_ETHERNET, "{} does not contain Ethernet frames", path);
+    let mut frames = Vec::new();
+    let mut offset = 24;
+    while offset + 16 <= file.len() {
+        let caplen = read_u32(offset + 8);
+        let start = offset + 16;
+        let end = start + caplen as usize;
+        if end > file.len() {
+            break;
+        }
+        let fraction = read_u32(offset + 4);
+        let header = PacketHeader {
+            ts: timeval {
+                tv_sec: read_u32(offset) as time_t,
+                tv_usec: (if nanoseconds { fraction / 1000 } else { fraction }) as suseconds_t,
+            },
+            caplen,
+            len: read_u32(offset + 12),
+        };
+        frames.push(Frame { header, data: file[start..end].to_vec() });
+        offset = end;
+    }
+    assert!(!frames.is_empty(), "{} contains no packets", path);
+    frames
+}
+
+/// Gives the frames to a new processor from one thread for each CPU, replaying the recording
+/// until REPLAYED_PACKETS packets. Returns the communications of the report.
+fn process(frames: &[Frame], processor: &PacketProcessor) -> usize {
+    // Every replay starts one second after the end of the previous one
+    let duration = frames[frames.len() - 1].header.ts.tv_sec - frames[0].header.ts.tv_sec + 1;
+    let threads = num_cpus::get();
+    let chunk = REPLAYED_PACKETS.div_ceil(threads);
+    std::thread::scope(|scope| {
+        for first in (0..REPLAYED_PACKETS).step_by(chunk) {
+            scope.spawn(move || {
+                for i in first..(first + chunk).min(REPLAYED_PACKETS) {
+                    let frame = &frames[i % frames.len()];
+                    let mut header = frame.header;
+                    header.ts.tv_sec += (i / frames.len()) as time_t * duration;
+                    processor.process("bench0", &header, &frame.data, i as u64 + 1);
+                }
+            });
+        }
+    });
+    processor.get_control_block().snapshot().get_totals().flows
+}
+
+fn processing(c: &mut Criterion) {
+    let path = std::env::var("NETWORK_ANALYZER_BENCH_PCAP").unwrap_or_else(|_| String::from(FIXTURE));
+    let frames = read_pcap(&path);
+    let mut group = c.benchmark_group("processing");
+    group.throughput(Throughput::Elements(REPLAYED_PACKETS as u64));
+    group.sample_size(10);
+    group.measurement_time(Duration::from_secs(30));
+    group.bench_with_input(BenchmarkId::new("workers", num_cpus::get()), &frames, |b, frames| {
+        // Every iteration starts from an empty report and new detectors
+        b.iter_batched(|| {
+            let control_block = ControlBlockBuilder::new().build_offline().expect("cannot build the control block");
+            PacketProcessor::new(Arc::new(control_block))
+        }, |processor| process(frames, &processor), BatchSize::PerIteration);
+    });
+    group.bench_with_input(BenchmarkId::new("workers_with_rules_and_subscriber", num_cpus::get()), &frames, |b, frames| {
+        b.iter_batched(|| {
+            let parameters = Parameters { rules_file: Some(String::from(RULES)), ..Parameters::default() };
+            let control_block = ControlBlockBuilder::new().parameters(parameters).build_offline().expect("cannot build the control block");
+            // The subscriber keeps up with the events, its thread ends with the control block
+            let events = control_block.subscribe(&[Topic::Flows, Topic::Packets]);
+            std::thread::spawn(move || events.iter().count());
+            PacketProcessor::new(Arc::new(control_block))
+        }, |processor| process(frames, &processor), BatchSize::PerIteration);
+    });
+    group.finish();
+}
+
+criterion_group!(benches, processing);
+criterion_main!(benches);
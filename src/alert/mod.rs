@@ -0,0 +1,62 @@
+use std::fmt;
+use crate::packet::format_timestamp_us;
+
+/// Number of targets printed before the list is truncated
+const MAX_PRINTED_TARGETS: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// The detector that raised an alert
+pub enum AlertCategory {
+    Scan,
+    Dos,
+    Dns,
+    Signature,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Represents a suspicious activity found by one of the detectors
+pub struct Alert {
+    /// The detector that raised the alert
+    pub category: AlertCategory,
+    /// What was detected, e.g. "Vertical SYN scan"
+    pub kind: String,
+    /// The host responsible for the activity
+    pub source: String,
+    /// The hosts or services targeted
+    pub targets: Vec<String>,
+    /// The observations that led to the alert
+    pub evidence: Vec<String>,
+    /// When the activity started, in microseconds since the epoch
+    pub start_us: i64,
+    /// When the activity was last seen, in microseconds since the epoch
+    pub end_us: i64,
+}
+
+impl fmt::Display for AlertCategory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AlertCategory::Scan => write!(f, "Scan"),
+            AlertCategory::Dos => write!(f, "DoS"),
+            AlertCategory::Dns => write!(f, "DNS"),
+            AlertCategory::Signature => write!(f, "Signature"),
+        }
+    }
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}] {} - {} from {}", self.category, format_timestamp_us(self.start_us), format_timestamp_us(self.end_us), self.source)?;
+        write!(f, ": {}", self.kind)?;
+        if !self.targets.is_empty() {
+            let printed = self.targets.iter().take(MAX_PRINTED_TARGETS).cloned().collect::<Vec<String>>();
+            write!(f, " targeting {}", printed.join(", "))?;
+            if self.targets.len() > MAX_PRINTED_TARGETS {
+                write!(f, " and {} more", self.targets.len() - MAX_PRINTED_TARGETS)?;
+            }
+        }
+        if !self.evidence.is_empty() {
+            write!(f, " ({})", self.evidence.join("; "))?;
+        }
+        Ok(())
+    }
+}
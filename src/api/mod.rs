@@ -0,0 +1,279 @@
+//! HTTP/JSON control API
+//!
+//! Maps the methods of the control block onto a local REST API, served over TCP
+//! ("127.0.0.1:8080" or "tcp://127.0.0.1:8080") or over a unix socket ("unix:///run/na.sock").
+//! Bodies are JSON objects, errors are answered as {"error": "..."}.
+//!
+//! | Method     | Path     | Body                        | Action                               |
+//! |------------|----------|-----------------------------|--------------------------------------|
+//! | GET        | /state   |                             | {"state": "capturing", "interfaces": ["eth0"]} |
+//! | POST       | /pause   |                             | pauses the capture                   |
+//! | POST       | /resume  |                             | resumes the capture                  |
+//! | POST       | /stop    |                             | stops the capture                    |
+//! | GET, PUT   | /timeout | {"timeout": 10}             | report generation interval (seconds) |
+//! | GET, PUT   | /output  | {"path": "report.txt"}      | output file                          |
+//! | PUT        | /device  | {"id": 2}                   | device, starting from 1              |
+//! | PUT        | /device  | {"device": "name:eth*"}     | device, see the device module        |
+//! | PUT        | /filter  | {"filter": "tcp port 443"}  | BPF filter                           |
+//! | GET        | /errors  |                             | errors waiting to be read            |
+//! | DELETE     | /errors  | {"count": 3} (optional)     | removes the oldest errors, or all    |
+//! | GET        | /alerts  |                             | alerts waiting to be read            |
+//! | DELETE     | /alerts  | {"count": 3} (optional)     | removes the oldest alerts, or all    |
+//! | GET        | /report  |                             | the current report                   |
+//! | GET        | /snapshot |                            | the flows and totals of the report   |
+//! | POST       | /snapshot |                            | the snapshot, then resets the report |
+//! | GET        | /metrics |                             | the metrics in the Prometheus format |
+use std::sync::Arc;
+use serde_json::{json, Value};
+use crate::device::DeviceSelector;
+use crate::http::{self, Handler, Request, Response, Server};
+use crate::sink::Event;
+use crate::{CaptureState, ConfigError, ControlBlock, SnifferError};
+
+/// Serves the API on the address until the server returned is stopped or dropped.
+pub(crate) fn serve(control_block: &Arc<ControlBlock>, address: &str) -> Result<Server, SnifferError> {
+    let weak = Arc::downgrade(control_block);
+    let handler: Handler = Arc::new(move |request: &Request| {
+        match weak.upgrade() {
+            Some(control_block) => handle(&control_block, request),
+            None => error(409, "The capture has ended"),
+        }
+    });
+    let result = match address.split_once("://") {
+        Some(("tcp", a)) => http::serve_tcp(a, handler),
+        #[cfg(unix)]
+        Some(("unix", path)) => http::serve_unix(path, handler),
+        Some((scheme, _)) => return Err(SnifferError::ConfigError(ConfigError::InvalidAddress(format!("Unsupported scheme {}", scheme)))),
+        None => http::serve_tcp(address, handler),
+    };
+    match result {
+        Ok(server) => Ok(server),
+        Err(e) => Err(SnifferError::ConfigError(ConfigError::InvalidAddress(format!("{}: {}", address, e))))
+    }
+}
+
+/// Answers a request of the API.
+pub(crate) fn handle(control_block: &ControlBlock, request: &Request) -> Response {
+    let body = if request.body.is_empty() {
+        Value::Null
+    } else {
+        match serde_json::from_slice::<Value>(&request.body) {
+            Ok(v) => v,
+            Err(e) => return error(400, &format!("Invalid JSON: {}", e)),
+        }
+    };
+    let method = match request.method.as_str() {
+        // Setters accept POST as well as PUT
+        "POST" if matches!(request.path.as_str(), "/timeout" | "/output" | "/device" | "/filter") => "PUT",
+        m => m,
+    };
+    match (method, request.path.as_str()) {
+        ("GET", "/state") => Response::json(200, &json!({ "state": state_name(&control_block.get_state()), "interfaces": control_block.get_interfaces() })),
+        ("POST", "/pause") => {
+            control_block.pause();
+            ok()
+        }
+        ("POST", "/resume") => {
+            control_block.resume();
+            ok()
+        }
+        ("POST", "/stop") => {
+            control_block.stop();
+            ok()
+        }
+        ("GET", "/timeout") => Response::json(200, &json!({ "timeout": control_block.get_timeout() })),
+        ("PUT", "/timeout") => match body["timeout"].as_u64().and_then(|t| u32::try_from(t).ok()) {
+            Some(timeout) if timeout > 0 => {
+                control_block.set_timeout(timeout);
+                ok()
+            }
+            _ => error(400, "Expected a positive \"timeout\""),
+        },
+        ("GET", "/output") => Response::json(200, &json!({ "path": control_block.get_output_file() })),
+        ("PUT", "/output") => match body["path"].as_str() {
+            Some(path) => result(control_block.set_output_file(path.to_string())),
+            None => error(400, "Expected a \"path\""),
+        },
+        ("PUT", "/device") => match (body["id"].as_u64(), body["device"].as_str()) {
+            (Some(id), _) if id > 0 => result(control_block.set_device(id as usize)),
+            (None, Some(device)) => result(control_block.select_device(&DeviceSelector::parse(device))),
+            _ => error(400, "Expected an \"id\" starting from 1 or a \"device\""),
+        },
+        ("PUT", "/filter") => match body["filter"].as_str() {
+            Some(filter) => match control_block.apply_filter(filter) {
+                Ok(_) => ok(),
+                Err(e) => error(400, &e.to_string()),
+            },
+            None => error(400, "Expected a \"filter\""),
+        },
+        ("GET", "/errors") => {
+            let errors = control_block.get_errors().iter().map(|e| e.to_string()).collect::<Vec<String>>();
+            Response::json(200, &json!({ "errors": errors }))
+        }
+        ("DELETE", "/errors") => {
+            let count = body["count"].as_u64().map_or(control_block.get_errors().len(), |c| c as usize);
+            control_block.clear_errors(count);
+            ok()
+        }
+        ("GET", "/alerts") => {
+            let alerts = control_block.get_alerts().iter().map(|a| Event::Alert(a.clone()).to_json()).collect::<Vec<Value>>();
+            Response::json(200, &json!({ "alerts": alerts }))
+        }
+        ("DELETE", "/alerts") => {
+            let count = body["count"].as_u64().map_or(control_block.get_alerts().len(), |c| c as usize);
+            control_block.clear_alerts(count);
+            ok()
+        }
+        ("GET", "/report") => Response::json(200, &control_block.get_report_json()),
+        ("GET", "/snapshot") => Response::json(200, &control_block.snapshot().to_json()),
+        ("POST", "/snapshot") => Response::json(200, &control_block.snapshot_and_reset().to_json()),
+        ("GET", "/metrics") => Response::new(200, "text/plain; version=0.0.4", control_block.get_metrics().into_bytes()),
+        (_, "/state" | "/pause" | "/resume" | "/stop" | "/timeout" | "/output" | "/device" | "/filter" | "/errors" | "/alerts" | "/report" | "/snapshot" | "/metrics") =>
+            error(405, "Method not allowed"),
+        _ => error(404, "Not found"),
+    }
+}
+
+/// Gets the name of a state as used by the API.
+pub fn state_name(state: &CaptureState) -> &'static str {
+    match state {
+        CaptureState::Idle() => "idle",
+        CaptureState::Capturing() => "capturing",
+        CaptureState::Paused() => "paused",
+        CaptureState::Stopped() => "stopped",
+    }
+}
+
+fn ok() -> Response {
+    Response::json(200, &json!({ "result": "ok" }))
+}
+
+fn error(status: u16, message: &str) -> Response {
+    Response::json(status, &json!({ "error": message }))
+}
+
+fn result(result: Result<(), SnifferError>) -> Response {
+    match result {
+        Ok(_) => ok(),
+        Err(e) => error(400, &e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::time::Duration;
+    use crate::alert::{Alert, AlertCategory};
+    use crate::ControlBlockBuilder;
+    use super::*;
+
+    /// Serves the API of an offline control block on a local port.
+    fn start() -> (Arc<ControlBlock>, Server) {
+        let control_block = Arc::new(ControlBlockBuilder::new().build_offline().unwrap());
+        let server = serve(&control_block, "127.0.0.1:0").unwrap();
+        (control_block, server)
+    }
+
+    /// Sends a request and returns the status and the JSON body of the response.
+    fn request(address: &SocketAddr, method: &str, path: &str, body: &str) -> (u16, Value) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        write!(stream, "{} {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", method, path, body.len(), body).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse::<u16>().unwrap();
+        (status, serde_json::from_str(body).unwrap_or(Value::String(body.to_string())))
+    }
+
+    fn alert(source: &str) -> Alert {
+        Alert {
+            category: AlertCategory::Scan,
+            kind: String::from("Vertical SYN scan"),
+            source: source.to_string(),
+            targets: vec![String::from("10.0.0.2")],
+            evidence: Vec::new(),
+            start_us: 0,
+            end_us: 1_000_000,
+        }
+    }
+
+    #[test]
+    fn gets_the_state() {
+        let (_control_block, server) = start();
+        let address = server.get_address().unwrap();
+        assert_eq!(request(&address, "GET", "/state", ""), (200, json!({ "state": "idle", "interfaces": [] })));
+        // An idle capture cannot be paused
+        assert_eq!(request(&address, "POST", "/pause", "").0, 200);
+        assert_eq!(request(&address, "GET", "/state", "").1["state"], json!("idle"));
+    }
+
+    #[test]
+    fn sets_the_timeout() {
+        let (control_block, server) = start();
+        let address = server.get_address().unwrap();
+        assert_eq!(request(&address, "PUT", "/timeout", "{\"timeout\": 7}"), (200, json!({ "result": "ok" })));
+        assert_eq!(control_block.get_timeout(), 7);
+        assert_eq!(request(&address, "POST", "/timeout", "{\"timeout\": 3}").0, 200);
+        assert_eq!(request(&address, "GET", "/timeout", ""), (200, json!({ "timeout": 3 })));
+        assert_eq!(request(&address, "PUT", "/timeout", "{\"timeout\": 0}").0, 400);
+        assert_eq!(request(&address, "PUT", "/timeout", "{\"timeout\": -1}").0, 400);
+        assert_eq!(control_block.get_timeout(), 3);
+    }
+
+    #[test]
+    fn rejects_invalid_requests() {
+        let (_control_block, server) = start();
+        let address = server.get_address().unwrap();
+        let (status, body) = request(&address, "PUT", "/timeout", "{timeout");
+        assert_eq!(status, 400);
+        assert!(body["error"].as_str().unwrap().starts_with("Invalid JSON"));
+        assert_eq!(request(&address, "GET", "/flows", "").0, 404);
+        assert_eq!(request(&address, "DELETE", "/state", "").0, 405);
+        assert_eq!(request(&address, "PUT", "/device", "{\"id\": 0}").0, 400);
+        assert_eq!(request(&address, "PUT", "/filter", "{}").0, 400);
+        // No device to apply the filter to
+        assert_eq!(request(&address, "PUT", "/filter", "{\"filter\": \"tcp port 443\"}").0, 200);
+    }
+
+    #[test]
+    fn reads_and_clears_the_alerts() {
+        let (control_block, server) = start();
+        let address = server.get_address().unwrap();
+        for source in ["10.0.0.1", "10.0.0.3", "10.0.0.4"] {
+            control_block.push_alert(alert(source));
+        }
+        let (status, body) = request(&address, "GET", "/alerts", "");
+        assert_eq!(status, 200);
+        let sources = body["alerts"].as_array().unwrap().iter().map(|a| a["source"].clone()).collect::<Vec<Value>>();
+        assert_eq!(sources, vec![json!("10.0.0.1"), json!("10.0.0.3"), json!("10.0.0.4")]);
+
+        assert_eq!(request(&address, "DELETE", "/alerts", "{\"count\": 2}").0, 200);
+        assert_eq!(control_block.get_alerts().len(), 1);
+        assert_eq!(control_block.get_alerts()[0].source, "10.0.0.4");
+        assert_eq!(request(&address, "DELETE", "/alerts", "").0, 200);
+        assert_eq!(request(&address, "GET", "/alerts", ""), (200, json!({ "alerts": [] })));
+    }
+
+    #[test]
+    fn answers_once_the_capture_has_ended() {
+        let (control_block, server) = start();
+        let address = server.get_address().unwrap();
+        drop(control_block);
+        assert_eq!(request(&address, "GET", "/state", "").0, 409);
+    }
+
+    #[test]
+    fn gets_the_snapshot_and_the_metrics() {
+        let (_control_block, server) = start();
+        let address = server.get_address().unwrap();
+        let (status, body) = request(&address, "GET", "/snapshot", "");
+        assert_eq!(status, 200);
+        assert_eq!(body["totals"], json!({ "flows": 0, "packets": 0, "bytes": 0 }));
+        assert_eq!(request(&address, "POST", "/snapshot", "").0, 200);
+        let (status, body) = request(&address, "GET", "/metrics", "");
+        assert_eq!(status, 200);
+        assert!(body.as_str().unwrap().contains("capture_state{state=\"idle\"} 1"));
+    }
+}
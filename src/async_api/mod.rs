@@ -0,0 +1,196 @@
+//! Async front-end for tokio users, enabled by the `async` feature
+//!
+//! The capture keeps running on its own threads: the calls that can block, like opening the
+//! devices or waiting for the last report, run on the blocking pool of tokio, and the events of
+//! the subscriptions are forwarded to tokio channels, so that the runtime is never blocked.
+//!
+//! # Usage
+//! let capture = analyze_network_async(parameters).await?;
+//! let mut flows = capture.flow_events();
+//! while let Some(event) = flows.next().await {
+//!     if let CaptureEvent::FlowEnd(flow) = event {
+//!         println!("{} <-> {} {} bytes", flow.address_1, flow.address_2, flow.bytes_total);
+//!     }
+//! }
+//! capture.stop().await;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::time::Duration;
+use futures::stream::{self, Stream, StreamExt};
+use tokio::sync::mpsc;
+use tokio::task;
+use crate::packet::Packet;
+use crate::parameters::Parameters;
+use crate::snapshot::ReportSnapshot;
+use crate::subscription::{CaptureEvent, Topic, SUBSCRIPTION_CAPACITY};
+use crate::{CaptureError, CaptureState, ControlBlock, ControlBlockBuilder, SnifferError};
+
+/// Longest time the thread of a stream of events takes to notice that the stream was dropped
+const CLOSED_CHECK: Duration = Duration::from_millis(500);
+
+/// Starts a capture like analyze_network, opening the devices on the blocking pool.
+pub async fn analyze_network_async(parameters: Parameters) -> Result<AsyncCapture, SnifferError> {
+    let control_block = task::spawn_blocking(move || -> Result<Arc<ControlBlock>, SnifferError> {
+        let control_block = Arc::new(ControlBlockBuilder::new().parameters(parameters).build()?);
+        control_block.start()?;
+        Ok(control_block)
+    }).await.map_err(|e| {
+        SnifferError::CaptureError(CaptureError::CaptureError(pcap::Error::PcapError(format!("The capture could not be started: {}", e))))
+    })??;
+    Ok(AsyncCapture { control_block })
+}
+
+#[derive(Clone)]
+/// A running capture, controlled from async code
+pub struct AsyncCapture {
+    control_block: Arc<ControlBlock>,
+}
+
+impl AsyncCapture {
+    /// Controls a control block built elsewhere, e.g. an offline one fed by a PacketProcessor.
+    pub fn new(control_block: Arc<ControlBlock>) -> AsyncCapture {
+        AsyncCapture { control_block }
+    }
+
+    /// Gets the control block, for the calls that do not block.
+    pub fn get_control_block(&self) -> &Arc<ControlBlock> {
+        &self.control_block
+    }
+
+    pub fn get_state(&self) -> CaptureState {
+        self.control_block.get_state()
+    }
+
+    pub async fn pause(&self) {
+        let control_block = self.control_block.clone();
+        let _ = task::spawn_blocking(move || control_block.pause()).await;
+    }
+
+    pub async fn resume(&self) {
+        let control_block = self.control_block.clone();
+        let _ = task::spawn_blocking(move || control_block.resume()).await;
+    }
+
+    /// Stops the capture and waits until the packets already captured are in the report.
+    pub async fn stop(&self) {
+        let control_block = self.control_block.clone();
+        let _ = task::spawn_blocking(move || {
+            control_block.stop();
+            control_block.wait_finished();
+        }).await;
+    }
+
+    /// Waits until the capture is stopped, e.g. by the packet count, and its last report is written.
+    pub async fn wait_finished(&self) {
+        let control_block = self.control_block.clone();
+        let _ = task::spawn_blocking(move || control_block.wait_finished()).await;
+    }
+
+    /// Gets the events of the topics, see the subscription module.
+    pub fn events(&self, topics: &[Topic]) -> impl Stream<Item = CaptureEvent> {
+        let events = self.control_block.subscribe(topics);
+        let (sender, receiver) = mpsc::channel(SUBSCRIPTION_CAPACITY);
+        // The subscription is a blocking channel, read by a thread of its own. The thread stops
+        // soon after the stream is dropped, even on a quiet topic, or when the control block is dropped.
+        std::thread::spawn(move || {
+            while !sender.is_closed() {
+                match events.recv_timeout(CLOSED_CHECK) {
+                    Ok(event) => {
+                        if sender.blocking_send(event).is_err() {
+                            break;
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+        });
+        stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.map(|event| (event, receiver))
+        })
+    }
+
+    /// Gets the decoded packets that pass the display filter.
+    pub fn packets(&self) -> impl Stream<Item = Packet> {
+        self.events(&[Topic::Packets]).filter_map(|event| async move {
+            match event {
+                CaptureEvent::Packet(packet) => Some(*packet),
+                _ => None,
+            }
+        })
+    }
+
+    /// Gets the start, update and end events of the flows.
+    pub fn flow_events(&self) -> impl Stream<Item = CaptureEvent> {
+        self.events(&[Topic::Flows])
+    }
+
+    /// Gets a snapshot of the report every interval, the stream ends with the last report once
+    /// the capture is stopped.
+    pub fn report_snapshots(&self, interval: Duration) -> impl Stream<Item = ReportSnapshot> {
+        let control_block = self.control_block.clone();
+        stream::unfold(Some(control_block), move |state| async move {
+            let control_block = state?;
+            tokio::time::sleep(interval).await;
+            let cb = control_block.clone();
+            let (snapshot, finished) = task::spawn_blocking(move || {
+                let stopped = cb.get_state() == CaptureState::Stopped();
+                if stopped {
+                    cb.wait_finished();
+                }
+                (cb.snapshot(), stopped)
+            }).await.ok()?;
+            match finished {
+                true => Some((snapshot, None)),
+                false => Some((snapshot, Some(control_block))),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offline() -> AsyncCapture {
+        AsyncCapture::new(Arc::new(ControlBlockBuilder::new().build_offline().unwrap()))
+    }
+
+    #[tokio::test]
+    async fn ends_the_streams_once_stopped() {
+        let capture = offline();
+        let mut states = Box::pin(capture.events(&[Topic::States]));
+        let mut snapshots = Box::pin(capture.report_snapshots(Duration::from_millis(10)));
+        assert!(snapshots.next().await.is_some());
+        capture.stop().await;
+        assert_eq!(capture.get_state(), CaptureState::Stopped());
+        // The last snapshot, then the end of the stream
+        assert!(snapshots.next().await.is_some());
+        assert!(snapshots.next().await.is_none());
+
+        match states.next().await {
+            Some(CaptureEvent::StateChanged { from, to }) => assert_eq!((from, to), (CaptureState::Idle(), CaptureState::Stopped())),
+            e => panic!("unexpected event {:?}", e),
+        }
+        // The events end with the control block
+        drop(snapshots);
+        drop(capture);
+        assert!(states.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn stops_reading_the_events_of_a_dropped_stream() {
+        let capture = offline();
+        let control_block = capture.get_control_block().clone();
+        let errors = capture.events(&[Topic::Errors]);
+        assert!(control_block.subscriptions.wants(Topic::Errors));
+        drop(errors);
+        // The thread notices within CLOSED_CHECK, and the subscription is removed by the next error
+        let start = std::time::Instant::now();
+        while control_block.subscriptions.wants(Topic::Errors) {
+            assert!(start.elapsed() < CLOSED_CHECK * 4);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            control_block.push_error(SnifferError::CaptureError(CaptureError::InvalidTransition(String::from("test"))));
+        }
+    }
+}
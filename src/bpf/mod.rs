@@ -0,0 +1,254 @@
+//! Typed builder for BPF filters
+//!
+//! Builds the filters accepted by `analyze_network` and `ControlBlock::set_filter` without
+//! writing the BPF syntax by hand, and compiles them offline to check them before use.
+//!
+//! # Usage
+//! let filter = BpfFilter::host("192.168.1.10".parse().unwrap())
+//!     .and(BpfFilter::tcp().and(BpfFilter::dst_port(443)).or(BpfFilter::udp().and(BpfFilter::port(53))))
+//!     .and(BpfFilter::net("10.0.0.0".parse().unwrap(), 8).not());
+//! // host 192.168.1.10 and ((tcp and dst port 443) or (udp and port 53)) and not net 10.0.0.0/8
+//! let program = compile_filter(&filter.to_string(), Linktype::ETHERNET)?;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use pcap::{BpfProgram, Capture, Linktype};
+use crate::ConfigError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The direction a host, net or port primitive applies to
+pub enum Direction {
+    /// Either the source or the destination
+    Any,
+    Src,
+    Dst,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The protocols that can be matched by a filter
+pub enum Protocol {
+    Arp,
+    Ip,
+    Ip6,
+    Tcp,
+    Udp,
+    Icmp,
+    Icmp6,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A BPF filter expression
+pub enum BpfFilter {
+    Host(IpAddr, Direction),
+    Net(IpAddr, u8, Direction),
+    Port(u16, Direction),
+    PortRange(u16, u16, Direction),
+    Proto(Protocol),
+    And(Box<BpfFilter>, Box<BpfFilter>),
+    Or(Box<BpfFilter>, Box<BpfFilter>),
+    Not(Box<BpfFilter>),
+}
+
+impl BpfFilter {
+    /// Matches packets from or to the host.
+    pub fn host(address: IpAddr) -> Self {
+        BpfFilter::Host(address, Direction::Any)
+    }
+
+    pub fn src_host(address: IpAddr) -> Self {
+        BpfFilter::Host(address, Direction::Src)
+    }
+
+    pub fn dst_host(address: IpAddr) -> Self {
+        BpfFilter::Host(address, Direction::Dst)
+    }
+
+    /// Matches packets from or to the network with the given prefix length. The host bits of
+    /// the address are ignored.
+    pub fn net(address: IpAddr, prefix: u8) -> Self {
+        BpfFilter::Net(address, prefix, Direction::Any)
+    }
+
+    pub fn src_net(address: IpAddr, prefix: u8) -> Self {
+        BpfFilter::Net(address, prefix, Direction::Src)
+    }
+
+    pub fn dst_net(address: IpAddr, prefix: u8) -> Self {
+        BpfFilter::Net(address, prefix, Direction::Dst)
+    }
+
+    /// Matches TCP and UDP packets from or to the port.
+    pub fn port(port: u16) -> Self {
+        BpfFilter::Port(port, Direction::Any)
+    }
+
+    pub fn src_port(port: u16) -> Self {
+        BpfFilter::Port(port, Direction::Src)
+    }
+
+    pub fn dst_port(port: u16) -> Self {
+        BpfFilter::Port(port, Direction::Dst)
+    }
+
+    /// Matches TCP and UDP packets from or to any port between first and last, included.
+    pub fn port_range(first: u16, last: u16) -> Self {
+        BpfFilter::PortRange(first, last, Direction::Any)
+    }
+
+    pub fn proto(protocol: Protocol) -> Self {
+        BpfFilter::Proto(protocol)
+    }
+
+    pub fn tcp() -> Self {
+        BpfFilter::Proto(Protocol::Tcp)
+    }
+
+    pub fn udp() -> Self {
+        BpfFilter::Proto(Protocol::Udp)
+    }
+
+    pub fn icmp() -> Self {
+        BpfFilter::Proto(Protocol::Icmp)
+    }
+
+    pub fn and(self, other: BpfFilter) -> Self {
+        BpfFilter::And(Box::new(self), Box::new(other))
+    }
+
+    pub fn or(self, other: BpfFilter) -> Self {
+        BpfFilter::Or(Box::new(self), Box::new(other))
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn not(self) -> Self {
+        BpfFilter::Not(Box::new(self))
+    }
+
+    /// Formats an operand, adding parentheses when its operator differs from the parent one.
+    fn fmt_operand(&self, f: &mut fmt::Formatter, parent: &BpfFilter) -> fmt::Result {
+        let same = matches!((self, parent), (BpfFilter::And(..), BpfFilter::And(..)) | (BpfFilter::Or(..), BpfFilter::Or(..)));
+        match self {
+            BpfFilter::And(..) | BpfFilter::Or(..) if !same => write!(f, "({})", self),
+            _ => write!(f, "{}", self),
+        }
+    }
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Direction::Any => Ok(()),
+            Direction::Src => write!(f, "src "),
+            Direction::Dst => write!(f, "dst "),
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Protocol::Arp => "arp",
+            Protocol::Ip => "ip",
+            Protocol::Ip6 => "ip6",
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+            Protocol::Icmp => "icmp",
+            Protocol::Icmp6 => "icmp6",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl fmt::Display for BpfFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BpfFilter::Host(address, direction) => write!(f, "{}host {}", direction, address),
+            BpfFilter::Net(address, prefix, direction) => {
+                let (network, prefix) = mask(address, *prefix);
+                write!(f, "{}net {}/{}", direction, network, prefix)
+            }
+            BpfFilter::Port(port, direction) => write!(f, "{}port {}", direction, port),
+            BpfFilter::PortRange(first, last, direction) => write!(f, "{}portrange {}-{}", direction, first, last),
+            BpfFilter::Proto(protocol) => write!(f, "{}", protocol),
+            BpfFilter::And(l, r) | BpfFilter::Or(l, r) => {
+                l.fmt_operand(f, self)?;
+                write!(f, " {} ", if matches!(self, BpfFilter::And(..)) { "and" } else { "or" })?;
+                r.fmt_operand(f, self)
+            }
+            BpfFilter::Not(e) => match e.as_ref() {
+                BpfFilter::And(..) | BpfFilter::Or(..) => write!(f, "not ({})", e),
+                _ => write!(f, "not {}", e),
+            },
+        }
+    }
+}
+
+/// Clears the host bits of the address, which pcap rejects in a net primitive, and limits the
+/// prefix length to the size of the address.
+fn mask(address: &IpAddr, prefix: u8) -> (IpAddr, u8) {
+    match address {
+        IpAddr::V4(a) => {
+            let prefix = prefix.min(32);
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            (IpAddr::V4(Ipv4Addr::from(u32::from(*a) & mask)), prefix)
+        }
+        IpAddr::V6(a) => {
+            let prefix = prefix.min(128);
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            (IpAddr::V6(Ipv6Addr::from(u128::from(*a) & mask)), prefix)
+        }
+    }
+}
+
+impl From<BpfFilter> for String {
+    fn from(filter: BpfFilter) -> Self {
+        filter.to_string()
+    }
+}
+
+/// Parses a datalink type given by name (e.g. "EN10MB") or by number (e.g. "1").
+pub fn parse_linktype(linktype: &str) -> Result<Linktype, ConfigError> {
+    match linktype.parse::<i32>() {
+        Ok(n) => Ok(Linktype(n)),
+        Err(_) => Linktype::from_name(linktype).map_err(ConfigError::InvalidLinktype),
+    }
+}
+
+/// Compiles a BPF filter offline for the given datalink type, without opening any device.
+pub fn compile_filter(filter: &str, linktype: Linktype) -> Result<BpfProgram, ConfigError> {
+    let capture = match Capture::dead(linktype) {
+        Ok(c) => c,
+        Err(e) => return Err(ConfigError::InvalidLinktype(e)),
+    };
+    capture.compile(filter, true).map_err(ConfigError::InvalidFilter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_the_primitives() {
+        assert_eq!(BpfFilter::src_host("10.0.0.1".parse().unwrap()).to_string(), "src host 10.0.0.1");
+        assert_eq!(BpfFilter::dst_port(443).to_string(), "dst port 443");
+        assert_eq!(BpfFilter::port_range(6000, 6010).to_string(), "portrange 6000-6010");
+        assert_eq!(BpfFilter::proto(Protocol::Icmp6).to_string(), "icmp6");
+    }
+
+    #[test]
+    fn masks_the_host_bits_of_networks() {
+        assert_eq!(BpfFilter::net("10.0.0.5".parse().unwrap(), 8).to_string(), "net 10.0.0.0/8");
+        assert_eq!(BpfFilter::dst_net("192.168.1.77".parse().unwrap(), 32).to_string(), "dst net 192.168.1.77/32");
+        assert_eq!(BpfFilter::net("10.1.2.3".parse().unwrap(), 0).to_string(), "net 0.0.0.0/0");
+        assert_eq!(BpfFilter::net("10.1.2.3".parse().unwrap(), 40).to_string(), "net 10.1.2.3/32");
+        assert_eq!(BpfFilter::src_net("2001:db8::1".parse().unwrap(), 32).to_string(), "src net 2001:db8::/32");
+    }
+
+    #[test]
+    fn adds_parentheses_between_different_operators() {
+        let filter = BpfFilter::host("192.168.1.10".parse().unwrap())
+            .and(BpfFilter::tcp().and(BpfFilter::dst_port(443)).or(BpfFilter::udp().and(BpfFilter::port(53))))
+            .and(BpfFilter::net("10.0.0.0".parse().unwrap(), 8).not());
+        assert_eq!(filter.to_string(), "host 192.168.1.10 and ((tcp and dst port 443) or (udp and port 53)) and not net 10.0.0.0/8");
+        assert_eq!(BpfFilter::tcp().or(BpfFilter::udp()).not().to_string(), "not (tcp or udp)");
+    }
+}
@@ -0,0 +1,314 @@
+//! Configuration file with named capture profiles
+//!
+//! The file is in the TOML format, every table under `profiles` is a profile that sets the
+//! parameters of a capture:
+//! ```toml
+//! [profiles.office]
+//! device = "name:eth0"            # see the device module
+//! devices = ["name:eth0", "name:wlan0"]  # captured at the same time, instead of device
+//! group_by_interface = true       # a line of the report for each device
+//! filter = "not port 22"          # BPF filter
+//! display_filter = "dns.rcode != 0"
+//! snaplen = 65535
+//! promisc = true
+//! buffer_size = 4194304           # bytes
+//! immediate_mode = true
+//! read_timeout = 1000             # milliseconds
+//! interval = 10                   # seconds between two reports
+//! output = "office.txt"
+//! formats = ["text", "json"]      # the first to output, the others to output.<format>
+//! export = "office.pcap"
+//! rules = "local.rules"
+//! metrics = "127.0.0.1:9100"
+//! api = "127.0.0.1:8080"
+//! packet_count = 100000
+//!
+//! [[profiles.office.sinks]]
+//! type = "syslog"
+//! address = "udp://10.0.0.1:514"
+//!
+//! [[profiles.office.sinks]]
+//! type = "webhook"
+//! url = "http://10.0.0.2:8000/alerts"
+//! retries = 3
+//! backoff = 1                     # seconds
+//! ```
+//! Every mistake, like an unknown key or a value of the wrong type, is reported as
+//! `ConfigError::InvalidProfile`.
+use std::fs;
+use std::time::Duration;
+use toml::{Table, Value};
+use crate::device::DeviceSelector;
+use crate::parameters::{OutputFormat, Parameters};
+use crate::sink::{SinkConfig, SyslogTransport};
+use crate::ConfigError;
+
+/// Largest snaplen accepted, the one used by tcpdump
+const MAX_SNAPLEN: i64 = 262144;
+
+/// Reads a profile from a configuration file.
+pub fn load_profile(path: &str, name: &str) -> Result<Parameters, ConfigError> {
+    match fs::read_to_string(path) {
+        Ok(text) => parse_profile(&text, name),
+        Err(e) => Err(ConfigError::InvalidProfile(format!("{}: {}", path, e))),
+    }
+}
+
+/// Gets the names of the profiles of a configuration.
+pub fn get_profile_names(text: &str) -> Result<Vec<String>, ConfigError> {
+    Ok(get_profiles(text)?.keys().cloned().collect())
+}
+
+/// Converts a profile of a configuration to the parameters of a capture.
+pub fn parse_profile(text: &str, name: &str) -> Result<Parameters, ConfigError> {
+    let profiles = get_profiles(text)?;
+    let profile = match profiles.get(name) {
+        Some(Value::Table(t)) => t,
+        Some(_) => return Err(invalid(name, "the profile is not a table")),
+        None => {
+            let names = profiles.keys().cloned().collect::<Vec<String>>().join(", ");
+            return Err(ConfigError::InvalidProfile(format!("No profile named {}, the profiles are: {}", name, names)));
+        }
+    };
+    parse_table(profile, name)
+}
+
+/// Converts a file holding the keys of a single profile, without the `profiles` table, to parameters.
+/// The errors name the profile "settings".
+pub fn parse_settings(text: &str) -> Result<Parameters, ConfigError> {
+    match text.parse::<Table>() {
+        Ok(t) => parse_table(&t, "settings"),
+        Err(e) => Err(ConfigError::InvalidProfile(e.to_string())),
+    }
+}
+
+fn parse_table(profile: &Table, name: &str) -> Result<Parameters, ConfigError> {
+    let mut parameters = Parameters::default();
+    for (key, value) in profile.iter() {
+        let error = |message: &str| invalid(name, &format!("{}: {}", key, message));
+        match key.as_str() {
+            "device" => parameters.device = DeviceSelector::parse(&get_string(value).ok_or(error("expected a string"))?),
+            "devices" => {
+                let devices = value.as_array().ok_or(error("expected an array of strings"))?;
+                for d in devices {
+                    parameters.add_device(DeviceSelector::parse(&get_string(d).ok_or(error("expected an array of strings"))?));
+                }
+            }
+            "group_by_interface" => parameters.group_by_interface = value.as_bool().ok_or(error("expected a boolean"))?,
+            "filter" => parameters.filter = Some(get_string(value).ok_or(error("expected a string"))?),
+            "display_filter" => parameters.display_filter = Some(get_string(value).ok_or(error("expected a string"))?),
+            "snaplen" => parameters.capture_options.snaplen = get_integer(value, 1, MAX_SNAPLEN).ok_or(error(&format!("expected an integer from 1 to {}", MAX_SNAPLEN)))? as i32,
+            "promisc" => parameters.capture_options.promisc = value.as_bool().ok_or(error("expected a boolean"))?,
+            "buffer_size" => parameters.capture_options.buffer_size = Some(get_integer(value, 1, i64::from(i32::MAX)).ok_or(error("expected a positive integer"))? as i32),
+            "immediate_mode" => parameters.capture_options.immediate_mode = value.as_bool().ok_or(error("expected a boolean"))?,
+            "read_timeout" => parameters.capture_options.read_timeout = get_integer(value, 1, i64::from(i32::MAX)).ok_or(error("expected a positive integer"))? as i32,
+            "interval" => parameters.timeout = get_integer(value, 1, i64::from(u32::MAX)).ok_or(error("expected a positive integer"))? as u32,
+            "output" => parameters.file_path = get_string(value).ok_or(error("expected a string"))?,
+            "formats" => {
+                let formats = value.as_array().ok_or(error("expected an array"))?;
+                for f in formats {
+                    match f.as_str().and_then(OutputFormat::parse) {
+                        Some(format) if !parameters.output_formats.contains(&format) => parameters.add_output_format(format),
+                        Some(_) => return Err(error("repeated format")),
+                        None => return Err(error("expected \"text\" or \"json\"")),
+                    }
+                }
+            }
+            "export" => parameters.export_file = Some(get_string(value).ok_or(error("expected a string"))?),
+            "rules" => parameters.rules_file = Some(get_string(value).ok_or(error("expected a string"))?),
+            "metrics" => parameters.metrics_address = Some(get_string(value).ok_or(error("expected a string"))?),
+            "api" => parameters.api_address = Some(get_string(value).ok_or(error("expected a string"))?),
+            "packet_count" => parameters.packet_count = Some(get_integer(value, 1, i64::MAX).ok_or(error("expected a positive integer"))? as u64),
+            "sinks" => {
+                let sinks = value.as_array().ok_or(error("expected an array of tables"))?;
+                for (i, sink) in sinks.iter().enumerate() {
+                    match sink.as_table() {
+                        Some(t) => parameters.add_sink(parse_sink(t).map_err(|e| error(&format!("sink {}: {}", i + 1, e)))?),
+                        None => return Err(error("expected an array of tables")),
+                    }
+                }
+            }
+            _ => return Err(error("unknown key")),
+        }
+    }
+    Ok(parameters)
+}
+
+fn get_profiles(text: &str) -> Result<Table, ConfigError> {
+    let mut config = match text.parse::<Table>() {
+        Ok(t) => t,
+        Err(e) => return Err(ConfigError::InvalidProfile(e.to_string())),
+    };
+    match config.remove("profiles") {
+        Some(Value::Table(t)) => Ok(t),
+        Some(_) => Err(ConfigError::InvalidProfile(String::from("profiles is not a table"))),
+        None => Err(ConfigError::InvalidProfile(String::from("No profiles in the configuration"))),
+    }
+}
+
+fn parse_sink(sink: &Table) -> Result<SinkConfig, String> {
+    let string = |key: &str| match sink.get(key) {
+        Some(v) => get_string(v).ok_or(format!("{} is not a string", key)),
+        None => Err(format!("{} is missing", key)),
+    };
+    let allowed: &[&str] = match sink.get("type").and_then(Value::as_str) {
+        Some("syslog") => &["type", "address"],
+        Some("ndjson") => &["type", "path"],
+        Some("webhook") => &["type", "url", "retries", "backoff"],
+        _ => return Err(String::from("type must be \"syslog\", \"ndjson\" or \"webhook\"")),
+    };
+    if let Some(key) = sink.keys().find(|k| !allowed.contains(&k.as_str())) {
+        return Err(format!("unknown key {}", key));
+    }
+    match sink.get("type").and_then(Value::as_str) {
+        Some("syslog") => SyslogTransport::parse(&string("address")?).map(SinkConfig::Syslog).map_err(|e| e.to_string()),
+        Some("ndjson") => Ok(SinkConfig::Ndjson(string("path")?)),
+        _ => {
+            let retries = match sink.get("retries") {
+                Some(v) => get_integer(v, 0, i64::from(u32::MAX)).ok_or("retries is not a positive integer")? as u32,
+                None => 3,
+            };
+            let backoff = match sink.get("backoff") {
+                Some(v) => get_integer(v, 0, i64::from(u32::MAX)).ok_or("backoff is not a positive integer")? as u64,
+                None => 1,
+            };
+            Ok(SinkConfig::Webhook { url: string("url")?, retries, backoff: Duration::from_secs(backoff) })
+        }
+    }
+}
+
+fn get_string(value: &Value) -> Option<String> {
+    value.as_str().map(|s| s.to_string())
+}
+
+/// Gets an integer between min and max, both included.
+fn get_integer(value: &Value, min: i64, max: i64) -> Option<i64> {
+    value.as_integer().filter(|i| *i >= min && *i <= max)
+}
+
+fn invalid(profile: &str, message: &str) -> ConfigError {
+    ConfigError::InvalidProfile(format!("{}: {}", profile, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses a profile that must be rejected and returns the message of the error.
+    fn error(text: &str, name: &str) -> String {
+        match parse_profile(text, name) {
+            Err(ConfigError::InvalidProfile(message)) => message,
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(p) => panic!("profile accepted: {:?}", p),
+        }
+    }
+
+    #[test]
+    fn parses_every_key_of_a_profile() {
+        let text = r#"
+            [profiles.office]
+            devices = ["name:eth0", "2"]
+            group_by_interface = true
+            filter = "not port 22"
+            display_filter = "dns.rcode != 0"
+            snaplen = 1500
+            promisc = false
+            buffer_size = 4194304
+            immediate_mode = true
+            read_timeout = 100
+            interval = 10
+            output = "office.txt"
+            formats = ["json", "text"]
+            export = "office.pcap"
+            rules = "local.rules"
+            metrics = "127.0.0.1:9100"
+            api = "unix:///run/na.sock"
+            packet_count = 1000
+
+            [[profiles.office.sinks]]
+            type = "syslog"
+            address = "tcp://10.0.0.1:601"
+
+            [[profiles.office.sinks]]
+            type = "webhook"
+            url = "http://10.0.0.2:8000/alerts"
+            backoff = 2
+
+            [profiles.home]
+            device = "desc:*Wi-Fi*"
+        "#;
+        let p = parse_profile(text, "office").unwrap();
+        assert_eq!(p.get_devices(), vec![DeviceSelector::Name(String::from("eth0")), DeviceSelector::Index(2)]);
+        assert!(p.group_by_interface);
+        assert_eq!(p.filter.as_deref(), Some("not port 22"));
+        assert_eq!(p.display_filter.as_deref(), Some("dns.rcode != 0"));
+        assert_eq!((p.capture_options.snaplen, p.capture_options.promisc), (1500, false));
+        assert_eq!(p.capture_options.buffer_size, Some(4194304));
+        assert_eq!((p.capture_options.immediate_mode, p.capture_options.read_timeout), (true, 100));
+        assert_eq!((p.timeout, p.file_path.as_str()), (10, "office.txt"));
+        assert_eq!(p.output_formats, vec![OutputFormat::Json, OutputFormat::Text]);
+        assert_eq!(p.export_file.as_deref(), Some("office.pcap"));
+        assert_eq!(p.rules_file.as_deref(), Some("local.rules"));
+        assert_eq!(p.metrics_address.as_deref(), Some("127.0.0.1:9100"));
+        assert_eq!(p.api_address.as_deref(), Some("unix:///run/na.sock"));
+        assert_eq!(p.packet_count, Some(1000));
+        assert_eq!(p.sinks, vec![
+            SinkConfig::Syslog(SyslogTransport::Tcp(String::from("10.0.0.1:601"))),
+            SinkConfig::Webhook { url: String::from("http://10.0.0.2:8000/alerts"), retries: 3, backoff: Duration::from_secs(2) },
+        ]);
+
+        // What the profile does not set keeps the defaults
+        let p = parse_profile(text, "home").unwrap();
+        assert_eq!(p.get_devices(), vec![DeviceSelector::Description(String::from("*Wi-Fi*"))]);
+        assert_eq!(p.timeout, 0);
+        assert_eq!(p.capture_options, crate::parameters::CaptureOptions::default());
+        assert!(p.sinks.is_empty());
+        assert_eq!(get_profile_names(text).unwrap(), vec![String::from("home"), String::from("office")]);
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert_eq!(error("[profiles.a]\ninterval = 5\ncolour = \"red\"", "a"), "a: colour: unknown key");
+        assert_eq!(error("[profiles.a]\n[[profiles.a.sinks]]\ntype = \"ndjson\"\npath = \"a.json\"\nurl = \"http://x\"", "a"),
+                   "a: sinks: sink 1: unknown key url");
+    }
+
+    #[test]
+    fn rejects_values_of_the_wrong_type_or_out_of_range() {
+        assert_eq!(error("[profiles.a]\ninterval = \"10\"", "a"), "a: interval: expected a positive integer");
+        assert_eq!(error("[profiles.a]\ninterval = 0", "a"), "a: interval: expected a positive integer");
+        assert_eq!(error("[profiles.a]\nsnaplen = 300000", "a"), "a: snaplen: expected an integer from 1 to 262144");
+        assert_eq!(error("[profiles.a]\npromisc = 1", "a"), "a: promisc: expected a boolean");
+        assert_eq!(error("[profiles.a]\ndevices = [\"eth0\", 2]", "a"), "a: devices: expected an array of strings");
+        assert_eq!(error("[profiles.a]\nformats = [\"text\", \"text\"]", "a"), "a: formats: repeated format");
+        assert_eq!(error("[profiles.a]\nformats = [\"xml\"]", "a"), "a: formats: expected \"text\" or \"json\"");
+        assert_eq!(error("[profiles.a]\n[[profiles.a.sinks]]\ntype = \"mail\"", "a"),
+                   "a: sinks: sink 1: type must be \"syslog\", \"ndjson\" or \"webhook\"");
+        assert_eq!(error("[profiles.a]\n[[profiles.a.sinks]]\ntype = \"webhook\"\nretries = -1\nurl = \"http://x\"", "a"),
+                   "a: sinks: sink 1: retries is not a positive integer");
+        assert_eq!(error("[profiles.a]\n[[profiles.a.sinks]]\ntype = \"webhook\"", "a"), "a: sinks: sink 1: url is missing");
+    }
+
+    #[test]
+    fn reports_missing_profiles() {
+        let text = "[profiles.office]\n[profiles.home]";
+        assert_eq!(error(text, "lab"), "No profile named lab, the profiles are: home, office");
+        assert_eq!(error("[profiles]\nlab = 1", "lab"), "lab: the profile is not a table");
+        assert_eq!(error("[capture]\ninterval = 1", "lab"), "No profiles in the configuration");
+        assert!(!error("[profiles.a", "a").is_empty());
+        match load_profile("/nonexistent/profiles.toml", "a") {
+            Err(ConfigError::InvalidProfile(message)) => assert!(message.starts_with("/nonexistent/profiles.toml: ")),
+            _ => panic!("missing file accepted"),
+        }
+    }
+
+    #[test]
+    fn parses_settings_without_profiles() {
+        let p = parse_settings("filter = \"tcp port 443\"\ninterval = 30").unwrap();
+        assert_eq!((p.filter.as_deref(), p.timeout), (Some("tcp port 443"), 30));
+        match parse_settings("interval = 0") {
+            Err(ConfigError::InvalidProfile(message)) => assert_eq!(message, "settings: interval: expected a positive integer"),
+            _ => panic!("invalid settings accepted"),
+        }
+    }
+}
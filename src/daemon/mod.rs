@@ -0,0 +1,215 @@
+//! Daemon mode of the sample app
+//!
+//! The process detaches from the terminal, writes its pid to the pidfile and appends its output
+//! to the log file. The signals are handled as follows:
+//! * SIGHUP: reloads the settings file into the control block
+//! * SIGUSR1: writes the report immediately
+//! * SIGTERM, SIGINT: stops the capture, waits for the packets being decoded and writes the final report
+//!
+//! The settings file holds the keys of a profile of the config module at the top level. Only
+//! the ones that can be changed while capturing are applied, the others are ignored:
+//! ```text
+//! filter = "tcp port 443"
+//! display_filter = "tls.sni contains \"example\""  # "" removes the display filter
+//! output = "/var/lib/network_analyzer/report.txt"
+//! interval = 30
+//! ```
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use network_analyzer::{CaptureState, ControlBlock, SnifferError};
+use network_analyzer::config::parse_settings;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR1};
+use signal_hook::iterator::Signals;
+
+/// Time between two checks of the signals and of the errors
+const POLL: Duration = Duration::from_millis(500);
+
+/// Detaches the process from the terminal and redirects its output to the log file, or discards it.
+/// Must be called before any thread is spawned, the threads do not survive the fork.
+pub fn detach(log_file: Option<&str>) -> io::Result<()> {
+    // The log file is opened first so that a wrong path is still reported on the terminal
+    let log = CString::new(log_file.unwrap_or("/dev/null")).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let null = CString::new("/dev/null").unwrap();
+    unsafe {
+        let out = libc::open(log.as_ptr(), libc::O_WRONLY | libc::O_CREAT | libc::O_APPEND, 0o644);
+        let input = libc::open(null.as_ptr(), libc::O_RDONLY);
+        if out < 0 || input < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        match libc::fork() {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => (),
+            _ => libc::_exit(0),
+        }
+        if libc::setsid() < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::dup2(input, libc::STDIN_FILENO);
+        libc::dup2(out, libc::STDOUT_FILENO);
+        libc::dup2(out, libc::STDERR_FILENO);
+        libc::close(input);
+        libc::close(out);
+    }
+    Ok(())
+}
+
+/// The pidfile of the process, removed when dropped
+pub struct PidFile {
+    path: String,
+}
+
+impl PidFile {
+    /// Writes the pid of the process to the pidfile.
+    pub fn create(path: &str) -> io::Result<PidFile> {
+        fs::write(path, format!("{}\n", std::process::id()))?;
+        Ok(PidFile { path: path.to_string() })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Handles the signals until the capture is stopped, then returns the exit code of the process.
+pub fn run(cb: Arc<ControlBlock>, config_file: Option<String>, pid_file: Option<PidFile>) -> i32 {
+    let mut signals = match Signals::new([SIGHUP, SIGUSR1, SIGTERM, SIGINT]) {
+        Ok(s) => s,
+        Err(e) => {
+            log(&format!("Error in registering the signals: {}", e));
+            cb.stop();
+            return 1;
+        }
+    };
+    if let Some(path) = &config_file {
+        reload(&cb, path);
+    }
+    log(&format!("Capture started, pid {}", std::process::id()));
+    let mut failed = false;
+    while cb.get_state() != CaptureState::Stopped() {
+        for signal in signals.pending() {
+            match signal {
+                SIGHUP => match &config_file {
+                    Some(path) => reload(&cb, path),
+                    None => log("SIGHUP ignored, no settings file"),
+                },
+                SIGUSR1 => match cb.write_report() {
+                    Ok(_) => log(&format!("Report written to {}", cb.get_output_file())),
+                    Err(e) => log(&e.to_string()),
+                },
+                _ => {
+                    log("Stopping the capture");
+                    cb.stop();
+                }
+            }
+        }
+        failed |= log_errors(&cb);
+        std::thread::sleep(POLL);
+    }
+    cb.wait_finished();
+    failed |= log_errors(&cb);
+    let code = if failed { 1 } else { 0 };
+    // The process exits without running the destructors
+    drop(pid_file);
+    log(&format!("Capture stopped, exit code {}", code));
+    code
+}
+
+/// Applies the settings file to the control block, logging every change.
+fn reload(cb: &ControlBlock, path: &str) {
+    let settings = match fs::read_to_string(path) {
+        Ok(text) => match parse_settings(&text) {
+            Ok(p) => p,
+            Err(e) => return log(&format!("Settings file {} not valid: {}", path, e)),
+        },
+        Err(e) => return log(&format!("Error in reading the settings file {}: {}", path, e)),
+    };
+    if let Some(filter) = &settings.filter {
+        log_change("filter", filter, cb.apply_filter(filter).map_err(|e| e.to_string()));
+    }
+    if let Some(filter) = &settings.display_filter {
+        let result = cb.set_display_filter(if filter.is_empty() { None } else { Some(filter.clone()) });
+        log_change("display_filter", filter, result.map_err(|e| e.to_string()));
+    }
+    if !settings.file_path.is_empty() {
+        log_change("output", &settings.file_path, cb.set_output_file(settings.file_path.clone()).map_err(|e| e.to_string()));
+    }
+    if settings.timeout != 0 {
+        cb.set_timeout(settings.timeout);
+        log_change("interval", &settings.timeout.to_string(), Ok(()));
+    }
+}
+
+fn log_change(key: &str, value: &str, result: Result<(), String>) {
+    match result {
+        Ok(_) => log(&format!("{} set to {}", key, value)),
+        Err(e) => log(&format!("{} not changed: {}", key, e)),
+    }
+}
+
+/// Logs and clears the errors of the capture, returns true if there were capture errors.
+fn log_errors(cb: &ControlBlock) -> bool {
+    let errors = cb.get_errors();
+    for e in errors.iter() {
+        log(&e.to_string());
+    }
+    let count = errors.len();
+    let failed = errors.iter().any(|e| matches!(e, SnifferError::CaptureError(_)));
+    drop(errors);
+    cb.clear_errors(count);
+    failed
+}
+
+fn log(message: &str) {
+    println!("{} {}", chrono::Local::now().format("%Y-%m-%d %H:%M:%S"), message);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use network_analyzer::ControlBlockBuilder;
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("network_analyzer-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn writes_and_removes_the_pid_file() {
+        let path = temp_path("daemon.pid");
+        let pid_file = PidFile::create(path.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", std::process::id()));
+        drop(pid_file);
+        assert!(!path.exists());
+        assert!(PidFile::create("/nonexistent/daemon.pid").is_err());
+    }
+
+    #[test]
+    fn applies_the_settings_file() {
+        let cb = ControlBlockBuilder::new().build_offline().unwrap();
+        let path = temp_path("settings.toml");
+        let output = temp_path("report.txt");
+        fs::write(&path, format!("filter = \"tcp port 443\"\ndisplay_filter = \"dns.rcode != 0\"\noutput = {:?}\ninterval = 30\nsnaplen = 1500", output)).unwrap();
+        reload(&cb, path.to_str().unwrap());
+        assert_eq!(cb.get_timeout(), 30);
+        assert_eq!(cb.get_output_file(), output.to_str().unwrap());
+        assert_eq!(cb.get_display_filter().as_deref(), Some("dns.rcode != 0"));
+
+        // An empty display filter removes it
+        fs::write(&path, "display_filter = \"\"").unwrap();
+        reload(&cb, path.to_str().unwrap());
+        assert_eq!(cb.get_display_filter(), None);
+        assert_eq!(cb.get_timeout(), 30);
+
+        // An invalid file changes nothing
+        fs::write(&path, "interval = 5\ncolour = \"red\"").unwrap();
+        reload(&cb, path.to_str().unwrap());
+        assert_eq!(cb.get_timeout(), 30);
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&output);
+    }
+}
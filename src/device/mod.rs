@@ -0,0 +1,461 @@
+//! Selection of the capture device
+//!
+//! A device can be selected by its position in the list of the devices, starting from 1, or by
+//! matching a glob (`*` any text, `?` any character, ignoring the case) against its name, its
+//! description, its IP addresses or its MAC address:
+//! * `2`: the second device of the list
+//! * `name:eth*`: the first device whose name starts with eth
+//! * `desc:*Wi-Fi*`: a device whose description contains Wi-Fi
+//! * `ip:192.168.1.*`: a device with an address in 192.168.1.0/24
+//! * `mac:00:1a:2b:*`: a device whose MAC address starts with 00:1a:2b
+//! * `eth0`: a device whose name, description, IP or MAC address matches
+//!
+//! When nothing matches, the error suggests the devices with a similar name.
+//!
+//! The devices are described by `DeviceInfo`, returned by `get_devices`, or by
+//! `get_devices_with_datalinks` with the datalink types too.
+use std::fmt::{Display, Formatter};
+use pcap::{Address, Capture, Device};
+use serde_json::{json, Value};
+use crate::ConfigError;
+
+/// Largest edit distance of a name suggested when nothing matches
+const MAX_SUGGESTION_DISTANCE: usize = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+/// Selects a capture device
+pub enum DeviceSelector {
+    /// Position in the list of the devices, starting from 1
+    Index(usize),
+    /// Glob matched against the name of the interface
+    Name(String),
+    /// Glob matched against the description of the interface
+    Description(String),
+    /// Glob matched against the IP addresses of the interface
+    Ip(String),
+    /// Glob matched against the MAC address of the interface
+    Mac(String),
+    /// Glob matched against the name, the description, the IP and the MAC addresses
+    Any(String),
+}
+
+impl Default for DeviceSelector {
+    fn default() -> Self {
+        DeviceSelector::Index(1)
+    }
+}
+
+impl Display for DeviceSelector {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceSelector::Index(i) => write!(f, "{}", i),
+            DeviceSelector::Name(p) => write!(f, "name:{}", p),
+            DeviceSelector::Description(p) => write!(f, "desc:{}", p),
+            DeviceSelector::Ip(p) => write!(f, "ip:{}", p),
+            DeviceSelector::Mac(p) => write!(f, "mac:{}", p),
+            DeviceSelector::Any(p) => write!(f, "{}", p),
+        }
+    }
+}
+
+impl DeviceSelector {
+    /// Parses a selector: a number, a glob prefixed by name:, desc:, ip: or mac:, or a bare glob.
+    pub fn parse(selector: &str) -> DeviceSelector {
+        let selector = selector.trim();
+        if let Ok(i) = selector.parse::<usize>() {
+            return DeviceSelector::Index(i);
+        }
+        match selector.split_once(':') {
+            Some(("name", p)) => DeviceSelector::Name(p.to_string()),
+            Some(("desc", p)) => DeviceSelector::Description(p.to_string()),
+            Some(("ip", p)) => DeviceSelector::Ip(p.to_string()),
+            Some(("mac", p)) => DeviceSelector::Mac(p.to_string()),
+            _ => DeviceSelector::Any(selector.to_string()),
+        }
+    }
+
+    /// Checks whether the device is selected; the index is not taken into account.
+    pub fn matches(&self, device: &Device) -> bool {
+        let name = |p: &str| glob_match(p, &device.name);
+        let description = |p: &str| device.desc.as_ref().is_some_and(|d| glob_match(p, d));
+        let ip = |p: &str| device.addresses.iter().any(|a| glob_match(p, &a.addr.to_string()));
+        let mac = |p: &str| get_mac_address(&device.name).is_some_and(|m| glob_match(p, &m));
+        match self {
+            DeviceSelector::Index(_) => false,
+            DeviceSelector::Name(p) => name(p),
+            DeviceSelector::Description(p) => description(p),
+            DeviceSelector::Ip(p) => ip(p),
+            DeviceSelector::Mac(p) => mac(p),
+            DeviceSelector::Any(p) => name(p) || description(p) || ip(p) || mac(p),
+        }
+    }
+
+    /// Selects a device of the list.
+    pub fn select(&self, devices: &[Device]) -> Result<Device, ConfigError> {
+        let selected = match self {
+            DeviceSelector::Index(i) => match i.checked_sub(1).and_then(|i| devices.get(i)) {
+                Some(d) => Some(d),
+                None => return Err(invalid_device(format!("Device {} does not exist, there are {} devices", i, devices.len()))),
+            },
+            _ => devices.iter().find(|d| self.matches(d)),
+        };
+        match selected {
+            Some(d) => Ok(d.clone()),
+            None => Err(invalid_device(format!("No device matches {}{}", self, suggest(self, devices)))),
+        }
+    }
+
+    /// Selects a device of the list of the system.
+    pub fn select_device(&self) -> Result<Device, ConfigError> {
+        match Device::list() {
+            Ok(devices) => self.select(&devices),
+            Err(e) => Err(ConfigError::InvalidDeviceId(e)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+/// Describes a network device
+pub struct DeviceInfo {
+    pub name: String,
+    pub description: Option<String>,
+    pub addresses: Vec<Address>,
+    pub flags: InterfaceFlags,
+    /// The names of the datalink types supported, empty unless read with read_datalinks or
+    /// when the device cannot be opened
+    pub datalinks: Vec<String>,
+    pub mtu: Option<u32>,
+    pub mac_address: Option<String>,
+    /// The counters of the interface, when the system provides them
+    pub statistics: Option<InterfaceStatistics>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+/// The state of a network interface
+pub struct InterfaceFlags {
+    pub up: bool,
+    pub running: bool,
+    pub loopback: bool,
+    pub wireless: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+/// The counters of a network interface since it was brought up
+pub struct InterfaceStatistics {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    pub rx_dropped: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    pub tx_dropped: u64,
+}
+
+impl DeviceInfo {
+    /// Collects the information about a device, without opening it.
+    pub fn from_device(device: &Device) -> DeviceInfo {
+        DeviceInfo {
+            name: device.name.clone(),
+            description: device.desc.clone(),
+            addresses: device.addresses.clone(),
+            flags: InterfaceFlags {
+                up: device.flags.is_up(),
+                running: device.flags.is_running(),
+                loopback: device.flags.is_loopback(),
+                wireless: device.flags.is_wireless(),
+            },
+            datalinks: Vec::new(),
+            mtu: get_mtu(&device.name),
+            mac_address: get_mac_address(&device.name),
+            statistics: get_statistics(&device.name),
+        }
+    }
+
+    /// Reads the datalink types supported by opening the device, which usually requires the
+    /// capture privileges and can take a while.
+    pub fn read_datalinks(&mut self) {
+        self.datalinks = match Capture::from_device(self.name.as_str()).and_then(|c| c.snaplen(64).timeout(1).open()) {
+            Ok(capture) => match capture.list_datalinks() {
+                Ok(linktypes) => linktypes.iter().map(|l| l.get_name().unwrap_or(l.0.to_string())).collect(),
+                Err(_) => Vec::new(),
+            },
+            Err(_) => Vec::new(),
+        };
+    }
+
+    /// Converts the description of the device to a JSON object.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "name": self.name,
+            "description": self.description,
+            "addresses": self.addresses.iter().map(|a| json!({
+                "address": a.addr.to_string(),
+                "netmask": a.netmask.map(|n| n.to_string()),
+                "broadcast": a.broadcast_addr.map(|b| b.to_string()),
+                "destination": a.dst_addr.map(|d| d.to_string()),
+            })).collect::<Vec<Value>>(),
+            "flags": {
+                "up": self.flags.up,
+                "running": self.flags.running,
+                "loopback": self.flags.loopback,
+                "wireless": self.flags.wireless,
+            },
+            "datalinks": self.datalinks,
+            "mtu": self.mtu,
+            "mac_address": self.mac_address,
+            "statistics": self.statistics.map(|s| json!({
+                "rx_packets": s.rx_packets,
+                "rx_bytes": s.rx_bytes,
+                "rx_dropped": s.rx_dropped,
+                "tx_packets": s.tx_packets,
+                "tx_bytes": s.tx_bytes,
+                "tx_dropped": s.tx_dropped,
+            })),
+        })
+    }
+}
+
+impl Display for DeviceInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)?;
+        if let Some(description) = &self.description {
+            write!(f, " ({})", description)?;
+        }
+        let flags = [("up", self.flags.up), ("running", self.flags.running), ("loopback", self.flags.loopback), ("wireless", self.flags.wireless)]
+            .iter()
+            .filter(|(_, set)| *set)
+            .map(|(name, _)| *name)
+            .collect::<Vec<&str>>();
+        if !flags.is_empty() {
+            write!(f, " [{}]", flags.join(", "))?;
+        }
+        if let Some(mtu) = self.mtu {
+            write!(f, " mtu {}", mtu)?;
+        }
+        if let Some(mac) = &self.mac_address {
+            write!(f, " mac {}", mac)?;
+        }
+        if !self.datalinks.is_empty() {
+            write!(f, " datalinks {}", self.datalinks.join(","))?;
+        }
+        let addresses = self.addresses.iter().map(|a| a.addr.to_string()).collect::<Vec<String>>();
+        if !addresses.is_empty() {
+            write!(f, " addresses {}", addresses.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Gets the MTU of an interface.
+#[cfg(target_os = "linux")]
+pub fn get_mtu(name: &str) -> Option<u32> {
+    read_sys_value(name, "mtu")
+}
+
+/// Gets the MTU of an interface.
+#[cfg(not(target_os = "linux"))]
+pub fn get_mtu(_name: &str) -> Option<u32> {
+    None
+}
+
+/// Gets the counters of an interface.
+#[cfg(target_os = "linux")]
+pub fn get_statistics(name: &str) -> Option<InterfaceStatistics> {
+    let counter = |c: &str| read_sys_value::<u64>(name, &format!("statistics/{}", c));
+    Some(InterfaceStatistics {
+        rx_packets: counter("rx_packets")?,
+        rx_bytes: counter("rx_bytes")?,
+        rx_dropped: counter("rx_dropped")?,
+        tx_packets: counter("tx_packets")?,
+        tx_bytes: counter("tx_bytes")?,
+        tx_dropped: counter("tx_dropped")?,
+    })
+}
+
+/// Gets the counters of an interface.
+#[cfg(not(target_os = "linux"))]
+pub fn get_statistics(_name: &str) -> Option<InterfaceStatistics> {
+    None
+}
+
+/// Reads a value of the interface from sysfs.
+#[cfg(target_os = "linux")]
+fn read_sys_value<T: std::str::FromStr>(name: &str, file: &str) -> Option<T> {
+    std::fs::read_to_string(format!("/sys/class/net/{}/{}", name, file)).ok()?.trim().parse::<T>().ok()
+}
+
+/// Gets the MAC address of an interface, as lowercase hex bytes separated by colons.
+#[cfg(target_os = "linux")]
+pub fn get_mac_address(name: &str) -> Option<String> {
+    let address = std::fs::read_to_string(format!("/sys/class/net/{}/address", name)).ok()?;
+    let address = address.trim();
+    match address.is_empty() || address == "00:00:00:00:00:00" {
+        true => None,
+        false => Some(address.to_lowercase()),
+    }
+}
+
+/// Gets the MAC address of an interface, as lowercase hex bytes separated by colons.
+#[cfg(not(target_os = "linux"))]
+pub fn get_mac_address(_name: &str) -> Option<String> {
+    None
+}
+
+/// Matches a glob, where `*` is any text and `?` any character, ignoring the case.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.to_lowercase().chars().collect::<Vec<char>>();
+    let text = text.to_lowercase().chars().collect::<Vec<char>>();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last star in the pattern and of the text it was tried at
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((sp, st)) = star {
+            // Let the star take one more character
+            p = sp + 1;
+            t = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Lists the devices with a name close to the pattern, or all the devices when none is close.
+fn suggest(selector: &DeviceSelector, devices: &[Device]) -> String {
+    let pattern = match selector {
+        DeviceSelector::Name(p) | DeviceSelector::Any(p) => p.replace(['*', '?'], "").to_lowercase(),
+        _ => String::new(),
+    };
+    let mut close = devices.iter()
+        .map(|d| (edit_distance(&pattern, &d.name.to_lowercase()), &d.name))
+        .filter(|(distance, _)| !pattern.is_empty() && *distance <= MAX_SUGGESTION_DISTANCE)
+        .collect::<Vec<(usize, &String)>>();
+    close.sort();
+    if !close.is_empty() {
+        return format!(", did you mean {}?", close.iter().map(|(_, n)| n.as_str()).collect::<Vec<&str>>().join(" or "));
+    }
+    match devices.is_empty() {
+        true => String::from(", no devices found"),
+        false => format!(", the devices are: {}", devices.iter().map(|d| d.name.as_str()).collect::<Vec<&str>>().join(", ")),
+    }
+}
+
+/// Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<char>>();
+    let mut previous = (0..=b.len()).collect::<Vec<usize>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+fn invalid_device(message: String) -> ConfigError {
+    ConfigError::InvalidDeviceId(pcap::Error::PcapError(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use super::*;
+
+    fn device(name: &str, description: Option<&str>, addresses: &[&str]) -> Device {
+        Device {
+            name: name.to_string(),
+            desc: description.map(|d| d.to_string()),
+            addresses: addresses.iter().map(|a| Address {
+                addr: a.parse::<IpAddr>().unwrap(),
+                netmask: None,
+                broadcast_addr: None,
+                dst_addr: None,
+            }).collect(),
+            flags: 0.into(),
+        }
+    }
+
+    fn devices() -> Vec<Device> {
+        vec![
+            device("test-eth0", Some("Intel Ethernet"), &["192.168.1.20", "fe80::1"]),
+            device("test-wlan0", Some("Intel Wi-Fi 6 AX201"), &["10.0.0.7"]),
+            device("test-docker0", None, &[]),
+        ]
+    }
+
+    /// Selects a device and returns its name, or the message of the error.
+    fn select(selector: &str) -> Result<String, String> {
+        match DeviceSelector::parse(selector).select(&devices()) {
+            Ok(d) => Ok(d.name),
+            Err(ConfigError::InvalidDeviceId(pcap::Error::PcapError(message))) => Err(message),
+            Err(e) => panic!("unexpected error {}", e),
+        }
+    }
+
+    #[test]
+    fn parses_the_selectors() {
+        assert_eq!(DeviceSelector::parse(" 2 "), DeviceSelector::Index(2));
+        assert_eq!(DeviceSelector::parse("name:eth*"), DeviceSelector::Name(String::from("eth*")));
+        assert_eq!(DeviceSelector::parse("desc:*Wi-Fi*"), DeviceSelector::Description(String::from("*Wi-Fi*")));
+        assert_eq!(DeviceSelector::parse("ip:192.168.1.*"), DeviceSelector::Ip(String::from("192.168.1.*")));
+        assert_eq!(DeviceSelector::parse("mac:00:1a:2b:*"), DeviceSelector::Mac(String::from("00:1a:2b:*")));
+        assert_eq!(DeviceSelector::parse("eth0"), DeviceSelector::Any(String::from("eth0")));
+        assert_eq!(DeviceSelector::parse("fe80::1"), DeviceSelector::Any(String::from("fe80::1")));
+        for selector in ["2", "name:eth*", "desc:*Wi-Fi*", "ip:10.*", "mac:00:1a:*", "eth0"] {
+            assert_eq!(DeviceSelector::parse(selector).to_string(), selector);
+        }
+    }
+
+    #[test]
+    fn selects_by_index_starting_from_one() {
+        assert_eq!(select("1"), Ok(String::from("test-eth0")));
+        assert_eq!(select("3"), Ok(String::from("test-docker0")));
+        assert_eq!(select("0"), Err(String::from("Device 0 does not exist, there are 3 devices")));
+        assert_eq!(select("4"), Err(String::from("Device 4 does not exist, there are 3 devices")));
+        assert_eq!(DeviceSelector::default(), DeviceSelector::Index(1));
+    }
+
+    #[test]
+    fn selects_by_name_description_and_address() {
+        assert_eq!(select("name:TEST-W*"), Ok(String::from("test-wlan0")));
+        assert_eq!(select("name:test-???0"), Ok(String::from("test-eth0")));
+        assert_eq!(select("desc:*wi-fi*"), Ok(String::from("test-wlan0")));
+        assert_eq!(select("ip:10.0.0.*"), Ok(String::from("test-wlan0")));
+        assert_eq!(select("ip:fe80::1"), Ok(String::from("test-eth0")));
+        // A bare glob is matched against everything, the first device matching wins
+        assert_eq!(select("*intel*"), Ok(String::from("test-eth0")));
+        assert_eq!(select("10.0.0.7"), Ok(String::from("test-wlan0")));
+        assert!(select("mac:*").is_err());
+    }
+
+    #[test]
+    fn suggests_the_devices_when_nothing_matches() {
+        assert_eq!(select("name:test-eht0"), Err(String::from("No device matches name:test-eht0, did you mean test-eth0?")));
+        assert_eq!(select("desc:*Realtek*"),
+                   Err(String::from("No device matches desc:*Realtek*, the devices are: test-eth0, test-wlan0, test-docker0")));
+        match DeviceSelector::parse("eth0").select(&[]) {
+            Err(ConfigError::InvalidDeviceId(pcap::Error::PcapError(message))) => assert_eq!(message, "No device matches eth0, no devices found"),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn matches_globs_ignoring_the_case() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("e*0", "ETH0"));
+        assert!(glob_match("*a*b*", "xxaxxbxx"));
+        assert!(glob_match("?th0", "eth0"));
+        assert!(!glob_match("?th0", "th0"));
+        assert!(!glob_match("eth", "eth0"));
+        assert!(!glob_match("*b", "abc"));
+    }
+}
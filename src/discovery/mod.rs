@@ -0,0 +1,443 @@
+use std::collections::HashMap;
+use prettytable::{row, Table};
+use crate::dissector::{Dissector, DissectorContext, TransportProtocol};
+use crate::dns::{DnsMessage, parse_dns, RecordData};
+use crate::packet::{format_timestamp_us, FieldType, FieldValue, Packet};
+
+/// UDP port used by SSDP
+pub const SSDP_PORT: u16 = 1900;
+/// UDP port used by mDNS and DNS-SD
+pub const MDNS_PORT: u16 = 5353;
+/// Number of entries of the inventory, the advertisements of new services are ignored beyond it
+const MAX_SERVICES: usize = 10_000;
+
+#[derive(Debug, Clone, PartialEq)]
+/// Represents a device or a service announced on the local network
+pub struct ServiceAdvertisement {
+    /// The discovery protocol, "SSDP" or "mDNS"
+    pub protocol: String,
+    /// The name of the device or service instance (USN for SSDP)
+    pub name: String,
+    /// The advertised type (NT/ST for SSDP, DNS-SD service type for mDNS)
+    pub service_type: String,
+    /// Where the service can be reached (LOCATION for SSDP, host:port for mDNS)
+    pub location: String,
+    /// Additional details such as the SERVER header or the TXT record
+    pub details: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Represents a message of the Simple Service Discovery Protocol
+pub struct SsdpMessage {
+    /// The request method ("NOTIFY", "M-SEARCH") or "RESPONSE"
+    pub method: String,
+    /// The headers, with upper-case names
+    pub headers: HashMap<String, String>,
+}
+
+#[derive(Default, Debug, Clone)]
+/// Inventory of the devices and services advertised during the capture
+pub struct ServiceInventory {
+    /// Each entry corresponds to a unique protocol, type, name and source host
+    pub entries: HashMap<(String, String, String, String), ServiceEntry>,
+}
+
+#[derive(Default, Debug, Clone)]
+/// Represents an entry in the service inventory
+pub struct ServiceEntry {
+    pub protocol: String,
+    pub name: String,
+    pub service_type: String,
+    pub location: String,
+    pub details: String,
+    /// The host that sent the advertisement
+    pub source: String,
+    /// When the service was first advertised, in microseconds since the epoch
+    pub timestamp_first_us: i64,
+    /// When the service was last advertised, in microseconds since the epoch
+    pub timestamp_last_us: i64,
+    /// The number of advertisements received
+    pub count: u32,
+}
+
+/// Dissector for SSDP (HTTP over UDP on port 1900)
+pub struct SsdpDissector;
+
+impl Dissector for SsdpDissector {
+    fn name(&self) -> &str {
+        "SSDP"
+    }
+
+    fn ports(&self) -> Vec<u16> {
+        vec![SSDP_PORT]
+    }
+
+    fn dissect(&self, context: &mut DissectorContext, packet: &mut Packet) -> bool {
+        if context.transport != TransportProtocol::Udp {
+            return false;
+        }
+        let message = match parse_ssdp(context.payload) {
+            Some(message) => message,
+            None => return false,
+        };
+        packet.set_protocol(String::from("SSDP"));
+        packet.set_info(message.method.clone());
+        packet.add_field("ssdp.method", FieldValue::Str(message.method.clone()));
+        let advertisements = ssdp_advertisements(&message);
+        for advertisement in advertisements.iter() {
+            packet.add_field("ssdp.type", FieldValue::Str(advertisement.service_type.clone()));
+        }
+        packet.set_services(advertisements);
+        true
+    }
+
+    fn fields(&self) -> Vec<(String, FieldType)> {
+        vec![
+            (String::from("ssdp.method"), FieldType::Str),
+            (String::from("ssdp.type"), FieldType::Str),
+        ]
+    }
+}
+
+/// Dissector for mDNS and DNS-SD (port 5353)
+pub struct MdnsDissector;
+
+impl Dissector for MdnsDissector {
+    fn name(&self) -> &str {
+        "mDNS"
+    }
+
+    fn ports(&self) -> Vec<u16> {
+        vec![MDNS_PORT]
+    }
+
+    fn dissect(&self, context: &mut DissectorContext, packet: &mut Packet) -> bool {
+        if context.transport != TransportProtocol::Udp {
+            return false;
+        }
+        let message = match parse_dns(context.payload) {
+            Some(message) => message,
+            None => return false,
+        };
+        packet.set_protocol(String::from("mDNS"));
+        for question in message.questions.iter() {
+            packet.add_field("mdns.qry.name", FieldValue::Str(question.name.clone()));
+        }
+        let advertisements = mdns_advertisements(&message);
+        for advertisement in advertisements.iter() {
+            packet.add_field("mdns.service", FieldValue::Str(advertisement.service_type.clone()));
+        }
+        packet.set_services(advertisements);
+        true
+    }
+
+    fn fields(&self) -> Vec<(String, FieldType)> {
+        vec![
+            (String::from("mdns.qry.name"), FieldType::Str),
+            (String::from("mdns.service"), FieldType::Str),
+        ]
+    }
+}
+
+/// Parses an SSDP message (HTTP over UDP).
+pub fn parse_ssdp(payload: &[u8]) -> Option<SsdpMessage> {
+    let text = std::str::from_utf8(payload).ok()?;
+    let mut lines = text.split("\r\n");
+    let start = lines.next()?;
+    let method = if start.starts_with("HTTP/") {
+        String::from("RESPONSE")
+    } else {
+        let method = start.split(' ').next()?;
+        if !start.ends_with("HTTP/1.1") {
+            return None;
+        }
+        method.to_string()
+    };
+    let mut headers = HashMap::new();
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_uppercase(), value.trim().to_string());
+        }
+    }
+    Some(SsdpMessage { method, headers })
+}
+
+/// Extracts the advertisement carried by an SSDP NOTIFY or M-SEARCH response.
+pub fn ssdp_advertisements(message: &SsdpMessage) -> Vec<ServiceAdvertisement> {
+    let header = |name: &str| message.headers.get(name).cloned().unwrap_or_default();
+    let service_type = match message.method.as_str() {
+        // ssdp:byebye announcements remove a device, they are still recorded to show it existed
+        "NOTIFY" => header("NT"),
+        "RESPONSE" => header("ST"),
+        _ => return Vec::new(),
+    };
+    let mut details = header("SERVER");
+    if let Some(nts) = message.headers.get("NTS") {
+        if details.is_empty() {
+            details = nts.clone();
+        } else {
+            details = format!("{} ({})", details, nts);
+        }
+    }
+    vec![ServiceAdvertisement {
+        protocol: String::from("SSDP"),
+        name: header("USN"),
+        service_type,
+        location: header("LOCATION"),
+        details,
+    }]
+}
+
+/// Extracts the hosts and DNS-SD services announced in an mDNS response.
+pub fn mdns_advertisements(message: &DnsMessage) -> Vec<ServiceAdvertisement> {
+    if !message.is_response {
+        return Vec::new();
+    }
+    let mut addresses: HashMap<&str, Vec<&str>> = HashMap::new();
+    let mut targets: HashMap<&str, String> = HashMap::new();
+    let mut texts: HashMap<&str, String> = HashMap::new();
+    for record in message.records() {
+        match &record.data {
+            RecordData::A(address) | RecordData::Aaaa(address) =>
+                addresses.entry(record.name.as_str()).or_default().push(address.as_str()),
+            RecordData::Srv { port, target, .. } => {
+                targets.insert(record.name.as_str(), format!("{}:{}", target, port));
+            }
+            RecordData::Txt(strings) => {
+                texts.insert(record.name.as_str(), strings.iter().filter(|s| !s.is_empty()).cloned().collect::<Vec<String>>().join(" "));
+            }
+            _ => {}
+        }
+    }
+
+    let mut result = Vec::new();
+    let mut instances = Vec::new();
+    for record in message.records() {
+        if let RecordData::Ptr(instance) = &record.data {
+            // Skip service type enumeration and reverse address lookups
+            if record.name.starts_with("_services._dns-sd.") || record.name.ends_with(".arpa") {
+                continue;
+            }
+            instances.push((instance.clone(), record.name.clone()));
+        }
+    }
+    for name in targets.keys() {
+        if !instances.iter().any(|(i, _)| i == name) {
+            let service_type = name.split_once('.').map(|(_, t)| t.to_string()).unwrap_or_default();
+            instances.push((name.to_string(), service_type));
+        }
+    }
+    for (instance, service_type) in instances {
+        let location = match targets.get(instance.as_str()) {
+            Some(t) => t.clone(),
+            None => String::new(),
+        };
+        result.push(ServiceAdvertisement {
+            protocol: String::from("mDNS"),
+            name: instance.clone(),
+            service_type,
+            location,
+            details: texts.get(instance.as_str()).cloned().unwrap_or_default(),
+        });
+    }
+    for (host, addresses) in addresses {
+        result.push(ServiceAdvertisement {
+            protocol: String::from("mDNS"),
+            name: host.to_string(),
+            service_type: String::from("host"),
+            location: addresses.join(","),
+            details: String::new(),
+        });
+    }
+    result
+}
+
+impl ServiceInventory {
+    /// Adds the advertisements found in a packet sent by the given host at the timestamp, in
+    /// microseconds since the epoch. Once the inventory is full, only the services already in it
+    /// are updated.
+    pub fn add(&mut self, advertisements: &[ServiceAdvertisement], source: &str, timestamp_us: i64) {
+        for advertisement in advertisements {
+            let key = (advertisement.protocol.clone(), advertisement.service_type.clone(), advertisement.name.clone(), source.to_string());
+            if !self.entries.contains_key(&key) && self.entries.len() >= MAX_SERVICES {
+                continue;
+            }
+            let entry = self.entries.entry(key).or_insert_with(|| ServiceEntry {
+                protocol: advertisement.protocol.clone(),
+                name: advertisement.name.clone(),
+                service_type: advertisement.service_type.clone(),
+                source: source.to_string(),
+                timestamp_first_us: timestamp_us,
+                timestamp_last_us: timestamp_us,
+                ..ServiceEntry::default()
+            });
+            // Later advertisements may carry the details missing from the first ones
+            if !advertisement.location.is_empty() {
+                entry.location = advertisement.location.clone();
+            }
+            if !advertisement.details.is_empty() {
+                entry.details = advertisement.details.clone();
+            }
+            // The workers can add the packets slightly out of order
+            entry.timestamp_first_us = entry.timestamp_first_us.min(timestamp_us);
+            entry.timestamp_last_us = entry.timestamp_last_us.max(timestamp_us);
+            entry.count += 1;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn to_formatted_table(&self) -> Table {
+        let mut entries = self.entries.values().collect::<Vec<&ServiceEntry>>();
+        entries.sort_by(|a, b| (&a.protocol, &a.service_type, &a.name).cmp(&(&b.protocol, &b.service_type, &b.name)));
+        let mut table = Table::new();
+        table.add_row(row!["Protocol", "Service Type", "Name", "Location", "Details", "Source", "First Seen", "Last Seen", "Count"]);
+        for e in entries {
+            table.add_row(row![e.protocol, e.service_type, e.name, e.location, e.details, e.source, format_timestamp_us(e.timestamp_first_us), format_timestamp_us(e.timestamp_last_us), e.count]);
+        }
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOTIFY: &str = "NOTIFY * HTTP/1.1\r\n\
+        HOST: 239.255.255.250:1900\r\n\
+        CACHE-CONTROL: max-age=1800\r\n\
+        LOCATION: http://192.168.1.1:49152/rootDesc.xml\r\n\
+        NT: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+        NTS: ssdp:alive\r\n\
+        SERVER: Linux/3.14 UPnP/1.0 MiniUPnPd/2.1\r\n\
+        USN: uuid:f5c1d177-62e5-45d1-a6e7-c0a0bb0fc2ce::urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n";
+
+    const M_SEARCH: &str = "M-SEARCH * HTTP/1.1\r\n\
+        HOST: 239.255.255.250:1900\r\n\
+        MAN: \"ssdp:discover\"\r\n\
+        MX: 1\r\n\
+        ST: ssdp:all\r\n\r\n";
+
+    fn name(name: &str) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for label in name.split('.') {
+            bytes.push(label.len() as u8);
+            bytes.extend_from_slice(label.as_bytes());
+        }
+        bytes.push(0);
+        bytes
+    }
+
+    fn record(owner: &str, record_type: u16, data: &[u8]) -> Vec<u8> {
+        let mut bytes = name(owner);
+        bytes.extend_from_slice(&record_type.to_be_bytes());
+        bytes.extend_from_slice(&[0x80, 0x01, 0, 0, 0x11, 0x94]);
+        bytes.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    /// The announcement of an IPP printer: PTR, SRV, TXT and A records
+    fn printer_announcement() -> Vec<u8> {
+        let mut bytes = vec![0, 0, 0x84, 0, 0, 0, 0, 4, 0, 0, 0, 0];
+        bytes.extend(record("_ipp._tcp.local", 12, &name("Office._ipp._tcp.local")));
+        let mut srv = vec![0, 0, 0, 0, 0x02, 0x77];
+        srv.extend(name("office-printer.local"));
+        bytes.extend(record("Office._ipp._tcp.local", 33, &srv));
+        bytes.extend(record("Office._ipp._tcp.local", 16, b"\x09txtvers=1\x00\x07ty=Jet5"));
+        bytes.extend(record("office-printer.local", 1, &[192, 168, 1, 50]));
+        bytes
+    }
+
+    fn advertisement(name: &str, service_type: &str) -> ServiceAdvertisement {
+        ServiceAdvertisement {
+            protocol: String::from("SSDP"),
+            name: name.to_string(),
+            service_type: service_type.to_string(),
+            location: String::new(),
+            details: String::new(),
+        }
+    }
+
+    #[test]
+    fn parses_ssdp_notify() {
+        let message = parse_ssdp(NOTIFY.as_bytes()).unwrap();
+        assert_eq!(message.method, "NOTIFY");
+        assert_eq!(message.headers.get("CACHE-CONTROL").unwrap(), "max-age=1800");
+        assert_eq!(ssdp_advertisements(&message), vec![ServiceAdvertisement {
+            protocol: String::from("SSDP"),
+            name: String::from("uuid:f5c1d177-62e5-45d1-a6e7-c0a0bb0fc2ce::urn:schemas-upnp-org:device:InternetGatewayDevice:1"),
+            service_type: String::from("urn:schemas-upnp-org:device:InternetGatewayDevice:1"),
+            location: String::from("http://192.168.1.1:49152/rootDesc.xml"),
+            details: String::from("Linux/3.14 UPnP/1.0 MiniUPnPd/2.1 (ssdp:alive)"),
+        }]);
+    }
+
+    #[test]
+    fn parses_ssdp_searches_without_advertising() {
+        let message = parse_ssdp(M_SEARCH.as_bytes()).unwrap();
+        assert_eq!(message.method, "M-SEARCH");
+        assert_eq!(message.headers.get("ST").unwrap(), "ssdp:all");
+        assert!(ssdp_advertisements(&message).is_empty());
+
+        let response = parse_ssdp(b"HTTP/1.1 200 OK\r\nST: upnp:rootdevice\r\nUSN: uuid:1::upnp:rootdevice\r\n\r\n").unwrap();
+        assert_eq!(response.method, "RESPONSE");
+        assert_eq!(ssdp_advertisements(&response)[0].service_type, "upnp:rootdevice");
+    }
+
+    #[test]
+    fn rejects_what_is_not_ssdp() {
+        assert_eq!(parse_ssdp(b"GET / HTTP/1.0\r\n\r\n"), None);
+        assert_eq!(parse_ssdp(&[0xff, 0xfe, 0x00]), None);
+    }
+
+    #[test]
+    fn extracts_the_services_of_mdns_announcements() {
+        let message = parse_dns(&printer_announcement()).unwrap();
+        let advertisements = mdns_advertisements(&message);
+        assert_eq!(advertisements, vec![
+            ServiceAdvertisement {
+                protocol: String::from("mDNS"),
+                name: String::from("Office._ipp._tcp.local"),
+                service_type: String::from("_ipp._tcp.local"),
+                location: String::from("office-printer.local:631"),
+                details: String::from("txtvers=1 ty=Jet5"),
+            },
+            ServiceAdvertisement {
+                protocol: String::from("mDNS"),
+                name: String::from("office-printer.local"),
+                service_type: String::from("host"),
+                location: String::from("192.168.1.50"),
+                details: String::new(),
+            },
+        ]);
+    }
+
+    #[test]
+    fn ignores_mdns_queries_and_enumerations() {
+        let mut query = printer_announcement();
+        query[2] = 0;
+        assert!(mdns_advertisements(&parse_dns(&query).unwrap()).is_empty());
+
+        let mut enumeration = vec![0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 0];
+        enumeration.extend(record("_services._dns-sd._udp.local", 12, &name("_ipp._tcp.local")));
+        assert!(mdns_advertisements(&parse_dns(&enumeration).unwrap()).is_empty());
+    }
+
+    #[test]
+    fn keeps_the_first_and_last_timestamps_across_midnight() {
+        let mut inventory = ServiceInventory::default();
+        // 23:59:59 and 00:00:01 of the next day
+        let before_midnight = 86_399_000_000;
+        let after_midnight = 86_401_000_000;
+        inventory.add(&[advertisement("uuid:1", "upnp:rootdevice")], "192.168.1.1", before_midnight);
+        inventory.add(&[advertisement("uuid:1", "upnp:rootdevice")], "192.168.1.1", after_midnight);
+        // Added late by another worker
+        inventory.add(&[advertisement("uuid:1", "upnp:rootdevice")], "192.168.1.1", before_midnight - 1);
+        let entry = inventory.entries.values().next().unwrap();
+        assert_eq!((entry.timestamp_first_us, entry.timestamp_last_us), (before_midnight - 1, after_midnight));
+        assert_eq!(entry.count, 3);
+    }
+}
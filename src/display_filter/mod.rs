@@ -0,0 +1,623 @@
+//! Display filters
+//!
+//! Unlike the BPF filter, which is applied by pcap to the raw bytes, a display filter is
+//! evaluated on the dissected packet and can use any field decoded by the dissectors.
+//! The syntax is a subset of the Wireshark one:
+//!
+//! tls.sni contains "example.com" and not ip.src == 10.0.0.0/8
+//! dns.rcode != 0 || (icmp.type == 3 && frame.len > 100)
+//!
+//! * comparisons: == eq, != ne, > gt, < lt, >= ge, <= le, contains
+//! * logical operators: and &&, or ||, not !
+//! * a field alone is true when the packet has it, a protocol name alone (tcp, dns, ...) is
+//!   true when the packet belongs to the protocol
+//! * addresses can be compared with a subnet in CIDR notation
+//!
+//! When a field appears more than once in a packet, a comparison is true if any of its values
+//! satisfies it, except for != which is true only if none of them is equal.
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use crate::dissector::{DissectorRegistry, TransportProtocol};
+use crate::packet::{FieldType, FieldValue, Packet};
+
+/// A compiled and type checked display filter
+#[derive(Debug, Clone)]
+pub struct DisplayFilter {
+    expression: String,
+    root: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// An error found while parsing or type checking a display filter
+pub struct FilterError {
+    /// The description of the error
+    pub message: String,
+    /// The position in the expression where the error was found
+    pub position: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Gt,
+    Lt,
+    Ge,
+    Le,
+    Contains,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    LParen,
+    RParen,
+    And,
+    Or,
+    Not,
+    Op(Op),
+    Word(String),
+    Str(String),
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Exists(String),
+    Protocol(String),
+    Compare { field: String, op: Op, value: Literal },
+}
+
+#[derive(Debug, Clone)]
+enum Literal {
+    Int(i64),
+    Str(String),
+    Bool(bool),
+    /// An IP address or subnet
+    Subnet(IpAddr, u8),
+    /// An address that is not an IP address, e.g. a MAC address
+    Address(String),
+}
+
+/// An expression before type checking
+enum RawExpr {
+    Or(Box<RawExpr>, Box<RawExpr>),
+    And(Box<RawExpr>, Box<RawExpr>),
+    Not(Box<RawExpr>),
+    Word(String, usize),
+    Compare { field: String, op: Op, value: Token, position: usize },
+}
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (at position {})", self.message, self.position)
+    }
+}
+
+impl std::error::Error for FilterError {}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Op::Eq => "==",
+            Op::Ne => "!=",
+            Op::Gt => ">",
+            Op::Lt => "<",
+            Op::Ge => ">=",
+            Op::Le => "<=",
+            Op::Contains => "contains",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl fmt::Display for DisplayFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.expression)
+    }
+}
+
+/// Gets the fields every packet can have, regardless of the dissectors.
+pub fn builtin_fields() -> Vec<(String, FieldType)> {
+    vec![
+        (String::from("frame.len"), FieldType::Int),
+        (String::from("frame.interface_name"), FieldType::Str),
+        (String::from("ip.src"), FieldType::Address),
+        (String::from("ip.dst"), FieldType::Address),
+        (String::from("ip.addr"), FieldType::Address),
+        (String::from("port"), FieldType::Int),
+        (String::from("srcport"), FieldType::Int),
+        (String::from("dstport"), FieldType::Int),
+        (String::from("tcp.port"), FieldType::Int),
+        (String::from("tcp.srcport"), FieldType::Int),
+        (String::from("tcp.dstport"), FieldType::Int),
+        (String::from("udp.port"), FieldType::Int),
+        (String::from("udp.srcport"), FieldType::Int),
+        (String::from("udp.dstport"), FieldType::Int),
+        (String::from("protocol"), FieldType::Str),
+        (String::from("info"), FieldType::Str),
+    ]
+}
+
+impl DisplayFilter {
+    /// Parses and type checks a display filter against the fields of the built-in decoders and
+    /// of the given dissectors.
+    pub fn compile(expression: &str, dissectors: &DissectorRegistry) -> Result<DisplayFilter, FilterError> {
+        let tokens = tokenize(expression)?;
+        let mut parser = Parser { tokens, index: 0, end: expression.len() };
+        let raw = parser.parse_or()?;
+        if parser.index < parser.tokens.len() {
+            return Err(FilterError { message: String::from("Unexpected token after the end of the expression"), position: parser.tokens[parser.index].1 });
+        }
+
+        let mut fields: HashMap<String, FieldType> = builtin_fields().into_iter().collect();
+        fields.extend(dissectors.get_fields());
+        let mut protocols = vec![
+            String::from("eth"), String::from("arp"), String::from("ip"), String::from("ipv6"),
+            String::from("tcp"), String::from("udp"), String::from("icmp"), String::from("icmpv6"),
+        ];
+        protocols.extend(dissectors.get_names().iter().map(|n| n.to_lowercase()));
+
+        let root = check(raw, &fields, &protocols)?;
+        Ok(DisplayFilter { expression: expression.to_string(), root })
+    }
+
+    /// Gets the text of the filter.
+    pub fn get_expression(&self) -> &String {
+        &self.expression
+    }
+
+    /// Returns true if the dissected packet satisfies the filter.
+    pub fn matches(&self, packet: &Packet) -> bool {
+        evaluate(&self.root, packet)
+    }
+}
+
+fn tokenize(expression: &str) -> Result<Vec<(Token, usize)>, FilterError> {
+    let chars = expression.char_indices().collect::<Vec<(usize, char)>>();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let (position, c) = chars[i];
+        let next = chars.get(i + 1).map(|(_, c)| *c);
+        let (token, len) = match (c, next) {
+            (c, _) if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            ('(', _) => (Token::LParen, 1),
+            (')', _) => (Token::RParen, 1),
+            ('=', Some('=')) => (Token::Op(Op::Eq), 2),
+            ('!', Some('=')) => (Token::Op(Op::Ne), 2),
+            ('>', Some('=')) => (Token::Op(Op::Ge), 2),
+            ('<', Some('=')) => (Token::Op(Op::Le), 2),
+            ('>', _) => (Token::Op(Op::Gt), 1),
+            ('<', _) => (Token::Op(Op::Lt), 1),
+            ('!', _) => (Token::Not, 1),
+            ('&', Some('&')) => (Token::And, 2),
+            ('|', Some('|')) => (Token::Or, 2),
+            ('"', _) => {
+                let mut value = String::new();
+                let mut j = i + 1;
+                loop {
+                    match chars.get(j) {
+                        None => return Err(FilterError { message: String::from("Unterminated string"), position }),
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => {
+                            match chars.get(j + 1) {
+                                Some((_, e)) => value.push(*e),
+                                None => return Err(FilterError { message: String::from("Unterminated string"), position }),
+                            }
+                            j += 2;
+                        }
+                        Some((_, c)) => {
+                            value.push(*c);
+                            j += 1;
+                        }
+                    }
+                }
+                (Token::Str(value), j + 1 - i)
+            }
+            (c, _) if is_word_char(c) => {
+                let mut j = i;
+                while j < chars.len() && is_word_char(chars[j].1) {
+                    j += 1;
+                }
+                let word = chars[i..j].iter().map(|(_, c)| *c).collect::<String>();
+                let token = match word.as_str() {
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    "not" => Token::Not,
+                    "eq" => Token::Op(Op::Eq),
+                    "ne" => Token::Op(Op::Ne),
+                    "gt" => Token::Op(Op::Gt),
+                    "lt" => Token::Op(Op::Lt),
+                    "ge" => Token::Op(Op::Ge),
+                    "le" => Token::Op(Op::Le),
+                    "contains" => Token::Op(Op::Contains),
+                    _ => Token::Word(word),
+                };
+                (token, j - i)
+            }
+            (c, _) => return Err(FilterError { message: format!("Unexpected character '{}'", c), position }),
+        };
+        tokens.push((token, position));
+        i += len;
+    }
+    Ok(tokens)
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '.' || c == '_' || c == ':' || c == '/' || c == '-'
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    index: usize,
+    /// The position reported for errors at the end of the expression
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.index).map(|(t, _)| t)
+    }
+
+    fn position(&self) -> usize {
+        self.tokens.get(self.index).map(|(_, p)| *p).unwrap_or(self.end)
+    }
+
+    fn parse_or(&mut self) -> Result<RawExpr, FilterError> {
+        let mut left = self.parse_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.index += 1;
+            let right = self.parse_and()?;
+            left = RawExpr::Or(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_and(&mut self) -> Result<RawExpr, FilterError> {
+        let mut left = self.parse_not()?;
+        while self.peek() == Some(&Token::And) {
+            self.index += 1;
+            let right = self.parse_not()?;
+            left = RawExpr::And(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_not(&mut self) -> Result<RawExpr, FilterError> {
+        if self.peek() == Some(&Token::Not) {
+            self.index += 1;
+            return Ok(RawExpr::Not(Box::new(self.parse_not()?)));
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<RawExpr, FilterError> {
+        let position = self.position();
+        match self.tokens.get(self.index).cloned() {
+            Some((Token::LParen, _)) => {
+                self.index += 1;
+                let expr = self.parse_or()?;
+                if self.peek() != Some(&Token::RParen) {
+                    return Err(FilterError { message: String::from("Expected ')'"), position: self.position() });
+                }
+                self.index += 1;
+                Ok(expr)
+            }
+            Some((Token::Word(field), _)) => {
+                self.index += 1;
+                if let Some(Token::Op(op)) = self.peek().cloned() {
+                    self.index += 1;
+                    let value_position = self.position();
+                    match self.tokens.get(self.index).cloned() {
+                        Some((value @ Token::Word(_), _)) | Some((value @ Token::Str(_), _)) => {
+                            self.index += 1;
+                            Ok(RawExpr::Compare { field, op, value, position: value_position })
+                        }
+                        _ => Err(FilterError { message: format!("Expected a value after '{}'", op), position: value_position }),
+                    }
+                } else {
+                    Ok(RawExpr::Word(field, position))
+                }
+            }
+            Some(_) => Err(FilterError { message: String::from("Expected a field, a protocol or '('"), position }),
+            None => Err(FilterError { message: String::from("Unexpected end of the expression"), position }),
+        }
+    }
+}
+
+/// Type checks the expression and converts the literals to the type of the field they are compared with.
+fn check(raw: RawExpr, fields: &HashMap<String, FieldType>, protocols: &[String]) -> Result<Expr, FilterError> {
+    match raw {
+        RawExpr::Or(l, r) => Ok(Expr::Or(Box::new(check(*l, fields, protocols)?), Box::new(check(*r, fields, protocols)?))),
+        RawExpr::And(l, r) => Ok(Expr::And(Box::new(check(*l, fields, protocols)?), Box::new(check(*r, fields, protocols)?))),
+        RawExpr::Not(e) => Ok(Expr::Not(Box::new(check(*e, fields, protocols)?))),
+        RawExpr::Word(word, position) => {
+            if fields.contains_key(&word) {
+                Ok(Expr::Exists(word))
+            } else if protocols.contains(&word.to_lowercase()) {
+                Ok(Expr::Protocol(word.to_lowercase()))
+            } else {
+                Err(FilterError { message: format!("Unknown field or protocol '{}'", word), position })
+            }
+        }
+        RawExpr::Compare { field, op, value, position } => {
+            let field_type = match fields.get(&field) {
+                Some(t) => *t,
+                None => return Err(FilterError { message: format!("Unknown field '{}'", field), position }),
+            };
+            let text = match value {
+                Token::Word(w) | Token::Str(w) => w,
+                _ => String::new(),
+            };
+            let allowed = match field_type {
+                FieldType::Int => op != Op::Contains,
+                FieldType::Str => true,
+                FieldType::Bool | FieldType::Address => op == Op::Eq || op == Op::Ne,
+            };
+            if !allowed {
+                return Err(FilterError { message: format!("Operator '{}' cannot be applied to {} field '{}'", op, field_type, field), position });
+            }
+            let value = match field_type {
+                FieldType::Int => match parse_int(&text) {
+                    Some(v) => Literal::Int(v),
+                    None => return Err(FilterError { message: format!("'{}' is not a valid integer for field '{}'", text, field), position }),
+                },
+                FieldType::Str => Literal::Str(text),
+                FieldType::Bool => match text.as_str() {
+                    "true" | "1" => Literal::Bool(true),
+                    "false" | "0" => Literal::Bool(false),
+                    _ => return Err(FilterError { message: format!("'{}' is not a valid boolean for field '{}'", text, field), position }),
+                },
+                FieldType::Address => parse_subnet(&text).unwrap_or(Literal::Address(text.to_uppercase())),
+            };
+            Ok(Expr::Compare { field, op, value })
+        }
+    }
+}
+
+fn parse_int(text: &str) -> Option<i64> {
+    match text.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16).ok(),
+        None => text.parse::<i64>().ok(),
+    }
+}
+
+fn parse_subnet(text: &str) -> Option<Literal> {
+    let (address, prefix) = match text.split_once('/') {
+        Some((a, p)) => (a, Some(p.parse::<u8>().ok()?)),
+        None => (text, None),
+    };
+    let address = address.parse::<IpAddr>().ok()?;
+    let max = if address.is_ipv4() { 32 } else { 128 };
+    let prefix = prefix.unwrap_or(max);
+    if prefix > max {
+        return None;
+    }
+    Some(Literal::Subnet(address, prefix))
+}
+
+fn evaluate(expr: &Expr, packet: &Packet) -> bool {
+    match expr {
+        Expr::Or(l, r) => evaluate(l, packet) || evaluate(r, packet),
+        Expr::And(l, r) => evaluate(l, packet) && evaluate(r, packet),
+        Expr::Not(e) => !evaluate(e, packet),
+        Expr::Exists(field) => !field_values(packet, field).is_empty(),
+        Expr::Protocol(name) => has_protocol(packet, name),
+        Expr::Compare { field, op, value } => {
+            let values = field_values(packet, field);
+            match op {
+                Op::Ne => !values.is_empty() && !values.iter().any(|v| compare(v, Op::Eq, value)),
+                _ => values.iter().any(|v| compare(v, *op, value)),
+            }
+        }
+    }
+}
+
+fn compare(field_value: &FieldValue, op: Op, value: &Literal) -> bool {
+    match (field_value, value) {
+        (FieldValue::Int(a), Literal::Int(b)) => match op {
+            Op::Eq => a == b,
+            Op::Ne => a != b,
+            Op::Gt => a > b,
+            Op::Lt => a < b,
+            Op::Ge => a >= b,
+            Op::Le => a <= b,
+            Op::Contains => false,
+        },
+        (FieldValue::Str(a), Literal::Str(b)) => match op {
+            Op::Eq => a == b,
+            Op::Ne => a != b,
+            Op::Gt => a > b,
+            Op::Lt => a < b,
+            Op::Ge => a >= b,
+            Op::Le => a <= b,
+            Op::Contains => a.contains(b.as_str()),
+        },
+        (FieldValue::Bool(a), Literal::Bool(b)) => match op {
+            Op::Ne => a != b,
+            _ => a == b,
+        },
+        (FieldValue::Str(a), Literal::Subnet(network, prefix)) => {
+            let result = match a.parse::<IpAddr>() {
+                Ok(address) => in_subnet(&address, network, *prefix),
+                Err(_) => false,
+            };
+            if op == Op::Ne { !result } else { result }
+        }
+        (FieldValue::Str(a), Literal::Address(b)) => {
+            let result = a.to_uppercase() == *b;
+            if op == Op::Ne { !result } else { result }
+        }
+        _ => false,
+    }
+}
+
+pub(crate) fn in_subnet(address: &IpAddr, network: &IpAddr, prefix: u8) -> bool {
+    match (address, network) {
+        (IpAddr::V4(a), IpAddr::V4(n)) => {
+            let mask = if prefix == 0 { 0 } else { u32::MAX << (32 - u32::from(prefix)) };
+            u32::from(*a) & mask == u32::from(*n) & mask
+        }
+        (IpAddr::V6(a), IpAddr::V6(n)) => {
+            let mask = if prefix == 0 { 0 } else { u128::MAX << (128 - u32::from(prefix)) };
+            u128::from(*a) & mask == u128::from(*n) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Gets the values of a built-in or dissected field of the packet.
+pub(crate) fn field_values(packet: &Packet, field: &str) -> Vec<FieldValue> {
+    let port = |p: &Option<String>| p.as_ref().and_then(|p| p.parse::<i64>().ok()).map(FieldValue::Int);
+    let transport = packet.get_transport();
+    match field {
+        "frame.len" => vec![FieldValue::Int(i64::from(*packet.get_length()))],
+        "frame.interface_name" => vec![FieldValue::Str(packet.get_interface().clone())],
+        "ip.src" => vec![FieldValue::Str(packet.get_source().clone())],
+        "ip.dst" => vec![FieldValue::Str(packet.get_destination().clone())],
+        "ip.addr" => vec![FieldValue::Str(packet.get_source().clone()), FieldValue::Str(packet.get_destination().clone())],
+        "protocol" => vec![FieldValue::Str(packet.get_protocol().clone())],
+        "info" => vec![FieldValue::Str(packet.get_info().clone())],
+        "port" => port(packet.get_source_port()).into_iter().chain(port(packet.get_destination_port())).collect(),
+        "srcport" => port(packet.get_source_port()).into_iter().collect(),
+        "dstport" => port(packet.get_destination_port()).into_iter().collect(),
+        "tcp.port" | "tcp.srcport" | "tcp.dstport" if transport != Some(TransportProtocol::Tcp) => Vec::new(),
+        "udp.port" | "udp.srcport" | "udp.dstport" if transport != Some(TransportProtocol::Udp) => Vec::new(),
+        "tcp.port" | "udp.port" => port(packet.get_source_port()).into_iter().chain(port(packet.get_destination_port())).collect(),
+        "tcp.srcport" | "udp.srcport" => port(packet.get_source_port()).into_iter().collect(),
+        "tcp.dstport" | "udp.dstport" => port(packet.get_destination_port()).into_iter().collect(),
+        _ => packet.get_field(field).into_iter().cloned().collect(),
+    }
+}
+
+pub(crate) fn has_protocol(packet: &Packet, name: &str) -> bool {
+    let transport = packet.get_transport();
+    let matched = match name {
+        "tcp" => transport == Some(TransportProtocol::Tcp),
+        "udp" => transport == Some(TransportProtocol::Udp),
+        "icmp" => transport == Some(TransportProtocol::Icmpv4) || transport == Some(TransportProtocol::Icmpv6),
+        "icmpv6" => transport == Some(TransportProtocol::Icmpv6),
+        "ip" => packet.get_source().parse::<IpAddr>().is_ok_and(|a| a.is_ipv4()),
+        "ipv6" => packet.get_source().parse::<IpAddr>().is_ok_and(|a| a.is_ipv6()),
+        "eth" => true,
+        _ => false,
+    };
+    let prefix = name.to_string() + ".";
+    matched ||
+        packet.get_protocol().eq_ignore_ascii_case(name) ||
+        packet.get_fields().iter().any(|(f, _)| f.starts_with(&prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(expression: &str) -> Result<DisplayFilter, FilterError> {
+        DisplayFilter::compile(expression, &DissectorRegistry::default())
+    }
+
+    fn tcp_packet(length: u32) -> Packet {
+        let mut packet = Packet::new(String::new(), String::from("10.1.2.3"), String::from("192.168.1.10"), Some(String::from("40000")), Some(String::from("80")), String::from("TCP"), length, String::new());
+        packet.set_transport(Some(TransportProtocol::Tcp));
+        packet
+    }
+
+    fn dns_packet(names: &[&str], rcode: i64) -> Packet {
+        let mut packet = Packet::new(String::new(), String::from("192.168.1.10"), String::from("9.9.9.9"), Some(String::from("5000")), Some(String::from("53")), String::from("DNS"), 80, String::new());
+        packet.set_transport(Some(TransportProtocol::Udp));
+        packet.add_field("dns.rcode", FieldValue::Int(rcode));
+        packet.add_field("dns.flags.response", FieldValue::Bool(rcode != 0));
+        for name in names {
+            packet.add_field("dns.qry.name", FieldValue::Str(name.to_string()));
+        }
+        packet
+    }
+
+    fn matches(expression: &str, packet: &Packet) -> bool {
+        compile(expression).unwrap().matches(packet)
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let packet = tcp_packet(100);
+        assert!(matches("tcp or udp and frame.len > 1000", &packet));
+        assert!(matches("tcp || udp && frame.len > 1000", &packet));
+        assert!(!matches("(tcp or udp) and frame.len > 1000", &packet));
+    }
+
+    #[test]
+    fn not_binds_tighter_than_and() {
+        let packet = tcp_packet(100);
+        assert!(!matches("not tcp and udp", &packet));
+        assert!(matches("not (tcp and udp)", &packet));
+        assert!(matches("!udp && !!tcp", &packet));
+    }
+
+    #[test]
+    fn compares_integers() {
+        let packet = tcp_packet(100);
+        assert!(matches("frame.len == 100", &packet));
+        assert!(matches("frame.len eq 0x64", &packet));
+        assert!(matches("frame.len >= 100 and frame.len le 100", &packet));
+        assert!(!matches("frame.len gt 100", &packet));
+        assert!(matches("tcp.dstport == 80 and port == 40000", &packet));
+        assert!(!matches("udp.port == 80", &packet));
+    }
+
+    #[test]
+    fn compares_strings() {
+        let packet = dns_packet(&["www.example.com"], 0);
+        assert!(matches("dns.qry.name contains \"example\"", &packet));
+        assert!(matches("dns.qry.name == \"www.example.com\"", &packet));
+        assert!(matches("protocol == DNS", &packet));
+        assert!(matches("dns && dns.rcode == 0 && dns.flags.response == false", &packet));
+    }
+
+    #[test]
+    fn compares_addresses_with_subnets() {
+        let packet = tcp_packet(100);
+        assert!(matches("ip.src == 10.0.0.0/8", &packet));
+        assert!(!matches("ip.src == 10.1.3.0/24", &packet));
+        assert!(matches("ip.dst != 10.0.0.0/8", &packet));
+        assert!(matches("ip.addr == 192.168.1.10", &packet));
+    }
+
+    #[test]
+    fn not_equal_is_true_when_no_value_is_equal() {
+        let packet = dns_packet(&["a.example.com", "b.example.com"], 3);
+        assert!(matches("dns.qry.name == \"b.example.com\"", &packet));
+        assert!(!matches("dns.qry.name != \"b.example.com\"", &packet));
+        assert!(matches("dns.qry.name != \"c.example.com\"", &packet));
+        // A packet without the field has no value different from the literal
+        assert!(!matches("dns.qry.name != \"c.example.com\"", &tcp_packet(100)));
+        assert!(!matches("ip.addr != 192.168.1.10", &packet));
+    }
+
+    #[test]
+    fn checks_the_types_of_the_comparisons() {
+        assert_eq!(compile("frame.len contains 1").unwrap_err().message, "Operator 'contains' cannot be applied to integer field 'frame.len'");
+        assert!(compile("ip.src > 10.0.0.1").is_err());
+        assert!(compile("dns.flags.response == maybe").is_err());
+        let error = compile("tcp and frame.len == abc").unwrap_err();
+        assert_eq!(error.position, 21);
+        assert_eq!(error.message, "'abc' is not a valid integer for field 'frame.len'");
+    }
+
+    #[test]
+    fn reports_syntax_errors_with_their_position() {
+        assert_eq!(compile("nosuchfield").unwrap_err().message, "Unknown field or protocol 'nosuchfield'");
+        assert_eq!(compile("nosuch.field == 1").unwrap_err().message, "Unknown field 'nosuch.field'");
+        assert_eq!(compile("(tcp or udp").unwrap_err(), FilterError { message: String::from("Expected ')'"), position: 11 });
+        assert_eq!(compile("tcp udp").unwrap_err().position, 4);
+        assert_eq!(compile("frame.len ==").unwrap_err().message, "Expected a value after '=='");
+        assert_eq!(compile("info == \"abc").unwrap_err().message, "Unterminated string");
+        assert_eq!(compile("tcp & udp").unwrap_err().message, "Unexpected character '&'");
+        assert_eq!(compile("").unwrap_err().message, "Unexpected end of the expression");
+    }
+}
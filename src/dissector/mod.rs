@@ -0,0 +1,316 @@
+//! Application protocol dissectors
+//!
+//! A dissector decodes the payload of a transport segment and records what it finds in the
+//! [`Packet`]: it can change the protocol name, set the info and add named fields.
+//! Dissectors are chosen by port first and then heuristically, and every call receives the
+//! state of the flow the packet belongs to, so that stateful protocols can be followed.
+//!
+//! # Usage
+//! struct MyProtocol;
+//!
+//! impl Dissector for MyProtocol {
+//!     fn name(&self) -> &str { "MYPROTO" }
+//!     fn ports(&self) -> Vec<u16> { vec![7777] }
+//!     fn dissect(&self, context: &mut DissectorContext, packet: &mut Packet) -> bool {
+//!         packet.set_protocol(String::from("MYPROTO"));
+//!         packet.add_field("myproto.len", FieldValue::Int(context.payload.len() as i64));
+//!         true
+//!     }
+//!     fn fields(&self) -> Vec<(String, FieldType)> {
+//!         vec![(String::from("myproto.len"), FieldType::Int)]
+//!     }
+//! }
+//!
+//! let mut parameters = Parameters::default();
+//! parameters.register_dissector(MyProtocol);
+use std::any::Any;
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use etherparse::SlicedPacket;
+use etherparse::TransportSlice::{Icmpv4, Icmpv6, Tcp, Udp, Unknown};
+use crate::discovery::{MdnsDissector, SsdpDissector};
+use crate::dns::DnsDissector;
+use crate::icmp::IcmpDissector;
+use crate::packet::{FieldType, Packet};
+use crate::tls::TlsDissector;
+
+/// Flows not seen for this long are forgotten first when a shard of the flow table is full
+const FLOW_IDLE_US: i64 = 300_000_000;
+/// Shards of the flow table, so that the workers decoding packets of different flows do not wait for each other
+const FLOW_SHARDS: usize = 64;
+/// Size of a shard of the flow table above which flows are removed
+const MAX_FLOWS_PER_SHARD: usize = 1024;
+/// Size a full shard is brought back to, so that the flows are removed in batches
+const LOW_FLOWS_PER_SHARD: usize = MAX_FLOWS_PER_SHARD * 3 / 4;
+
+type FlowTable = HashMap<(String, String), Arc<Mutex<FlowState>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// The transport protocol of a dissected packet
+pub enum TransportProtocol {
+    Tcp,
+    Udp,
+    Icmpv4,
+    Icmpv6,
+    Unknown,
+}
+
+/// Decodes an application protocol
+pub trait Dissector: Send + Sync {
+    /// The name of the dissector, used to remember which dissector handles a flow
+    fn name(&self) -> &str;
+
+    /// The ports the dissector is registered on. The dissector is tried first when either
+    /// the source or the destination port of a packet is one of them.
+    fn ports(&self) -> Vec<u16> {
+        Vec::new()
+    }
+
+    /// Returns true if the dissector recognises the packet regardless of its ports.
+    /// Called only when no port-matched dissector accepted the packet.
+    fn heuristic(&self, _context: &DissectorContext) -> bool {
+        false
+    }
+
+    /// Decodes the packet. Returns true if the payload belongs to the protocol, in which
+    /// case no other dissector is tried and the flow is bound to this dissector.
+    fn dissect(&self, context: &mut DissectorContext, packet: &mut Packet) -> bool;
+
+    /// The fields the dissector adds to the packets, so that display filters can use them.
+    fn fields(&self) -> Vec<(String, FieldType)> {
+        Vec::new()
+    }
+}
+
+/// What a dissector can see of the packet being decoded
+pub struct DissectorContext<'a> {
+    /// The transport protocol of the packet
+    pub transport: TransportProtocol,
+    pub source_port: Option<u16>,
+    pub destination_port: Option<u16>,
+    /// The transport payload; for ICMP the whole ICMP message
+    pub payload: &'a [u8],
+    /// The state of the flow the packet belongs to
+    pub flow: &'a mut FlowState,
+}
+
+#[derive(Default)]
+/// State kept for each pair of addresses and ports across packets
+pub struct FlowState {
+    /// The number of packets of the flow seen so far, including the current one
+    pub packets: u64,
+    /// The number of bytes of the flow seen so far, including the current one
+    pub bytes: u64,
+    /// The timestamp of the last packet in microseconds since the epoch
+    pub last_seen_us: i64,
+    /// The name of the dissector that accepted the flow
+    pub dissector: Option<String>,
+    /// Data stored by the dissectors, by key
+    data: HashMap<String, Box<dyn Any + Send>>,
+}
+
+impl FlowState {
+    /// Gets the data stored under the given key, if it has the requested type.
+    pub fn get<T: Any + Send>(&self, key: &str) -> Option<&T> {
+        self.data.get(key).and_then(|d| d.downcast_ref::<T>())
+    }
+
+    /// Gets the data stored under the given key mutably, if it has the requested type.
+    pub fn get_mut<T: Any + Send>(&mut self, key: &str) -> Option<&mut T> {
+        self.data.get_mut(key).and_then(|d| d.downcast_mut::<T>())
+    }
+
+    /// Stores data under the given key, replacing the previous value.
+    pub fn insert<T: Any + Send>(&mut self, key: &str, value: T) {
+        self.data.insert(key.to_string(), Box::new(value));
+    }
+
+    /// Removes the data stored under the given key.
+    pub fn remove(&mut self, key: &str) {
+        self.data.remove(key);
+    }
+}
+
+#[derive(Clone)]
+/// The set of dissectors used by a capture.
+///
+/// The default registry contains the built-in dissectors; dissectors registered later are
+/// tried before them, so they can take over a port.
+pub struct DissectorRegistry {
+    dissectors: Vec<Arc<dyn Dissector>>,
+    flows: Arc<Vec<Mutex<FlowTable>>>,
+}
+
+impl Default for DissectorRegistry {
+    fn default() -> Self {
+        let mut registry = DissectorRegistry::empty();
+        registry.dissectors.push(Arc::new(IcmpDissector));
+        registry.dissectors.push(Arc::new(SsdpDissector));
+        registry.dissectors.push(Arc::new(MdnsDissector));
+        registry.dissectors.push(Arc::new(DnsDissector));
+        registry.dissectors.push(Arc::new(TlsDissector));
+        registry
+    }
+}
+
+impl fmt::Debug for DissectorRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.dissectors.iter().map(|d| d.name())).finish()
+    }
+}
+
+impl DissectorRegistry {
+    /// Creates a registry without any dissector, not even the built-in ones.
+    pub fn empty() -> Self {
+        DissectorRegistry {
+            dissectors: Vec::new(),
+            flows: Arc::new((0..FLOW_SHARDS).map(|_| Mutex::new(HashMap::new())).collect()),
+        }
+    }
+
+    /// Registers a dissector, which takes precedence over the ones already registered.
+    pub fn register<D: Dissector + 'static>(&mut self, dissector: D) {
+        self.dissectors.insert(0, Arc::new(dissector));
+    }
+
+    /// Gets the names of the registered dissectors, in the order they are tried.
+    pub fn get_names(&self) -> Vec<String> {
+        self.dissectors.iter().map(|d| d.name().to_string()).collect()
+    }
+
+    /// Gets the fields declared by the registered dissectors.
+    pub fn get_fields(&self) -> Vec<(String, FieldType)> {
+        self.dissectors.iter().flat_map(|d| d.fields()).collect()
+    }
+
+    /// Runs the dissectors on a packet whose addresses, ports and length were already filled.
+    pub(crate) fn dissect(&self, sliced_packet: &SlicedPacket, packet: &mut Packet) {
+        let (transport, source_port, destination_port, payload) = match &sliced_packet.transport {
+            Some(Tcp(header_slice)) =>
+                (TransportProtocol::Tcp, Some(header_slice.source_port()), Some(header_slice.destination_port()), sliced_packet.payload),
+            Some(Udp(header_slice)) =>
+                (TransportProtocol::Udp, Some(header_slice.source_port()), Some(header_slice.destination_port()), sliced_packet.payload),
+            Some(Icmpv4(slice)) => (TransportProtocol::Icmpv4, None, None, slice.slice()),
+            Some(Icmpv6(slice)) => (TransportProtocol::Icmpv6, None, None, slice.slice()),
+            Some(Unknown(..)) => (TransportProtocol::Unknown, None, None, sliced_packet.payload),
+            None => return,
+        };
+
+        let flow = self.get_flow(packet);
+        let mut flow = flow.lock().unwrap();
+        flow.packets += 1;
+        flow.bytes += u64::from(*packet.get_length());
+        flow.last_seen_us = packet.get_timestamp_us();
+        let mut context = DissectorContext {
+            transport,
+            source_port,
+            destination_port,
+            payload,
+            flow: &mut flow,
+        };
+
+        // The dissector that accepted the flow before goes first, then the ones matching the
+        // ports and at last the ones recognising the payload.
+        if let Some(name) = context.flow.dissector.clone() {
+            if let Some(d) = self.dissectors.iter().find(|d| d.name() == name) {
+                if d.dissect(&mut context, packet) {
+                    return;
+                }
+            }
+        }
+        let port_matched = self.dissectors.iter()
+            .filter(|d| {
+                let ports = d.ports();
+                destination_port.is_some_and(|p| ports.contains(&p)) || source_port.is_some_and(|p| ports.contains(&p))
+            })
+            .collect::<Vec<&Arc<dyn Dissector>>>();
+        for d in port_matched.iter() {
+            if d.dissect(&mut context, packet) {
+                context.flow.dissector = Some(d.name().to_string());
+                return;
+            }
+        }
+        for d in self.dissectors.iter() {
+            if port_matched.iter().any(|p| Arc::ptr_eq(p, d)) {
+                continue;
+            }
+            if d.heuristic(&context) && d.dissect(&mut context, packet) {
+                context.flow.dissector = Some(d.name().to_string());
+                return;
+            }
+        }
+    }
+
+    fn get_flow(&self, packet: &Packet) -> Arc<Mutex<FlowState>> {
+        let key = packet.get_flow_key();
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let mut flows = self.flows[(hasher.finish() % FLOW_SHARDS as u64) as usize].lock().unwrap();
+        if let Some(flow) = flows.get(&key) {
+            return flow.clone();
+        }
+        if flows.len() >= MAX_FLOWS_PER_SHARD {
+            evict_flows(&mut flows, packet.get_timestamp_us());
+        }
+        flows.entry(key).or_default().clone()
+    }
+}
+
+/// Brings a full shard of the flow table back to LOW_FLOWS_PER_SHARD: the idle flows are
+/// removed first, then the ones seen least recently.
+fn evict_flows(flows: &mut FlowTable, now_us: i64) {
+    // The flows being dissected are locked, they count as just seen
+    let mut last_seen = flows.iter()
+        .map(|(key, f)| (f.try_lock().map_or(now_us, |f| f.last_seen_us), key.clone()))
+        .collect::<Vec<(i64, (String, String))>>();
+    let mut evicted = last_seen.len() - LOW_FLOWS_PER_SHARD;
+    let idle = last_seen.iter().filter(|(seen, _)| now_us - *seen >= FLOW_IDLE_US).count();
+    evicted = evicted.max(idle);
+    if evicted < last_seen.len() {
+        last_seen.select_nth_unstable(evicted);
+    }
+    for (_, key) in last_seen.iter().take(evicted) {
+        flows.remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flow_table(last_seen: impl Iterator<Item = i64>) -> FlowTable {
+        last_seen.enumerate().map(|(i, seen)| {
+            let flow = FlowState { last_seen_us: seen, ..FlowState::default() };
+            ((i.to_string(), String::new()), Arc::new(Mutex::new(flow)))
+        }).collect()
+    }
+
+    #[test]
+    fn evicts_the_least_recent_flows_down_to_the_low_water_mark() {
+        let mut flows = flow_table((0..MAX_FLOWS_PER_SHARD as i64).map(|i| 1_000_000_000 + i));
+        evict_flows(&mut flows, 1_000_000_000 + MAX_FLOWS_PER_SHARD as i64);
+        assert_eq!(flows.len(), LOW_FLOWS_PER_SHARD);
+        // The most recent flows are kept
+        assert!(flows.contains_key(&((MAX_FLOWS_PER_SHARD - 1).to_string(), String::new())));
+        assert!(!flows.contains_key(&(String::from("0"), String::new())));
+    }
+
+    #[test]
+    fn evicts_all_the_idle_flows() {
+        let mut flows = flow_table((0..MAX_FLOWS_PER_SHARD).map(|i| if i % 2 == 0 { 0 } else { FLOW_IDLE_US }));
+        evict_flows(&mut flows, FLOW_IDLE_US + 1);
+        assert_eq!(flows.len(), MAX_FLOWS_PER_SHARD / 2);
+    }
+
+    #[test]
+    fn keeps_the_flows_being_dissected() {
+        let mut flows = flow_table((0..MAX_FLOWS_PER_SHARD).map(|_| 0));
+        let busy = flows.get(&(String::from("0"), String::new())).unwrap().clone();
+        let _guard = busy.lock().unwrap();
+        evict_flows(&mut flows, FLOW_IDLE_US);
+        assert!(flows.contains_key(&(String::from("0"), String::new())));
+    }
+}
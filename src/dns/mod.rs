@@ -0,0 +1,340 @@
+use crate::dissector::{Dissector, DissectorContext, TransportProtocol};
+use crate::packet::{FieldType, FieldValue, Packet};
+use crate::to_hex_string;
+
+/// Port used by DNS
+pub const DNS_PORT: u16 = 53;
+/// Maximum number of compression pointers followed while reading a name
+const MAX_POINTERS: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+/// Represents a DNS (or mDNS) message
+pub struct DnsMessage {
+    pub id: u16,
+    /// True for responses, false for queries
+    pub is_response: bool,
+    pub opcode: u8,
+    pub rcode: u8,
+    pub questions: Vec<DnsQuestion>,
+    pub answers: Vec<DnsRecord>,
+    pub authorities: Vec<DnsRecord>,
+    pub additionals: Vec<DnsRecord>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Represents an entry of the question section
+pub struct DnsQuestion {
+    pub name: String,
+    pub record_type: u16,
+    pub class: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Represents a resource record
+pub struct DnsRecord {
+    pub name: String,
+    pub record_type: u16,
+    pub class: u16,
+    pub ttl: u32,
+    pub data: RecordData,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// The decoded data of a resource record
+pub enum RecordData {
+    A(String),
+    Aaaa(String),
+    Ptr(String),
+    Cname(String),
+    Srv { priority: u16, weight: u16, port: u16, target: String },
+    Txt(Vec<String>),
+    Other(Vec<u8>),
+}
+
+impl DnsMessage {
+    /// Iterates over the answer, authority and additional records.
+    pub fn records(&self) -> impl Iterator<Item = &DnsRecord> {
+        self.answers.iter().chain(self.authorities.iter()).chain(self.additionals.iter())
+    }
+}
+
+/// Dissector for DNS over UDP and TCP
+pub struct DnsDissector;
+
+impl Dissector for DnsDissector {
+    fn name(&self) -> &str {
+        "DNS"
+    }
+
+    fn ports(&self) -> Vec<u16> {
+        vec![DNS_PORT]
+    }
+
+    fn dissect(&self, context: &mut DissectorContext, packet: &mut Packet) -> bool {
+        let payloads = match context.transport {
+            TransportProtocol::Udp => vec![context.payload],
+            TransportProtocol::Tcp => match split_tcp_messages(context.payload) {
+                Some(p) => p,
+                None => return false,
+            },
+            _ => return false,
+        };
+        let mut messages = Vec::new();
+        for payload in payloads {
+            match parse_dns(payload) {
+                Some(message) => messages.push(message),
+                None => return false,
+            }
+        }
+        packet.set_protocol(String::from("DNS"));
+        for message in messages.iter() {
+            packet.add_field("dns.id", FieldValue::Int(i64::from(message.id)));
+            packet.add_field("dns.flags.response", FieldValue::Bool(message.is_response));
+            packet.add_field("dns.opcode", FieldValue::Int(i64::from(message.opcode)));
+            packet.add_field("dns.rcode", FieldValue::Int(i64::from(message.rcode)));
+            packet.add_field("dns.count.answers", FieldValue::Int(message.answers.len() as i64));
+            for question in message.questions.iter() {
+                packet.add_field("dns.qry.name", FieldValue::Str(question.name.clone()));
+                packet.add_field("dns.qry.type", FieldValue::Int(i64::from(question.record_type)));
+            }
+            for answer in message.answers.iter() {
+                packet.add_field("dns.resp.name", FieldValue::Str(answer.name.clone()));
+            }
+        }
+        let message = &messages[0];
+        let question = match message.questions.first() {
+            Some(q) => format!(" {} {}", record_type_name(q.record_type), q.name),
+            None => String::new(),
+        };
+        let more = match messages.len() {
+            1 => String::new(),
+            n => format!(" (+{} messages)", n - 1),
+        };
+        packet.set_info(format!("{} 0x{:04x}{}{}",
+                                if message.is_response { "Standard query response" } else { "Standard query" },
+                                message.id,
+                                question,
+                                more));
+        true
+    }
+
+    fn fields(&self) -> Vec<(String, FieldType)> {
+        vec![
+            (String::from("dns.id"), FieldType::Int),
+            (String::from("dns.flags.response"), FieldType::Bool),
+            (String::from("dns.opcode"), FieldType::Int),
+            (String::from("dns.rcode"), FieldType::Int),
+            (String::from("dns.count.answers"), FieldType::Int),
+            (String::from("dns.qry.name"), FieldType::Str),
+            (String::from("dns.qry.type"), FieldType::Int),
+            (String::from("dns.resp.name"), FieldType::Str),
+        ]
+    }
+}
+
+/// Splits a TCP segment into the DNS messages it carries, each preceded by its length.
+/// Returns None when the lengths do not cover the segment exactly, e.g. for the continuation
+/// of a message split over several segments.
+fn split_tcp_messages(payload: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut messages = Vec::new();
+    let mut offset = 0;
+    while offset < payload.len() {
+        let len = usize::from(read_u16(payload, offset)?);
+        messages.push(payload.get(offset + 2..offset + 2 + len)?);
+        offset += 2 + len;
+    }
+    match messages.is_empty() {
+        true => None,
+        false => Some(messages),
+    }
+}
+
+/// Parses a DNS message from a UDP payload.
+pub fn parse_dns(bytes: &[u8]) -> Option<DnsMessage> {
+    if bytes.len() < 12 {
+        return None;
+    }
+    let mut message = DnsMessage {
+        id: read_u16(bytes, 0)?,
+        is_response: bytes[2] & 0x80 != 0,
+        opcode: (bytes[2] >> 3) & 0x0F,
+        rcode: bytes[3] & 0x0F,
+        questions: Vec::new(),
+        answers: Vec::new(),
+        authorities: Vec::new(),
+        additionals: Vec::new(),
+    };
+    let counts = [read_u16(bytes, 4)?, read_u16(bytes, 6)?, read_u16(bytes, 8)?, read_u16(bytes, 10)?];
+    let mut offset = 12;
+    for _ in 0..counts[0] {
+        let (name, next) = read_name(bytes, offset)?;
+        message.questions.push(DnsQuestion {
+            name,
+            record_type: read_u16(bytes, next)?,
+            class: read_u16(bytes, next + 2)? & 0x7FFF,
+        });
+        offset = next + 4;
+    }
+    for (i, count) in counts.iter().enumerate().skip(1) {
+        for _ in 0..*count {
+            let (record, next) = match read_record(bytes, offset) {
+                Some(r) => r,
+                // Keep what was decoded so far from truncated packets
+                None => return Some(message),
+            };
+            match i {
+                1 => message.answers.push(record),
+                2 => message.authorities.push(record),
+                _ => message.additionals.push(record),
+            }
+            offset = next;
+        }
+    }
+    Some(message)
+}
+
+/// Gets the mnemonic of a record type, e.g. "AAAA".
+pub fn record_type_name(record_type: u16) -> String {
+    match record_type {
+        1 => String::from("A"),
+        2 => String::from("NS"),
+        5 => String::from("CNAME"),
+        6 => String::from("SOA"),
+        10 => String::from("NULL"),
+        12 => String::from("PTR"),
+        15 => String::from("MX"),
+        16 => String::from("TXT"),
+        28 => String::from("AAAA"),
+        33 => String::from("SRV"),
+        41 => String::from("OPT"),
+        47 => String::from("NSEC"),
+        65 => String::from("HTTPS"),
+        255 => String::from("ANY"),
+        _ => format!("TYPE{}", record_type),
+    }
+}
+
+fn read_record(bytes: &[u8], offset: usize) -> Option<(DnsRecord, usize)> {
+    let (name, next) = read_name(bytes, offset)?;
+    let record_type = read_u16(bytes, next)?;
+    let class = read_u16(bytes, next + 2)? & 0x7FFF;
+    let ttl = u32::from(read_u16(bytes, next + 4)?) << 16 | u32::from(read_u16(bytes, next + 6)?);
+    let len = usize::from(read_u16(bytes, next + 8)?);
+    let start = next + 10;
+    let rdata = bytes.get(start..start + len)?;
+    let data = match record_type {
+        1 if len == 4 => RecordData::A(rdata.iter().map(|it| { it.to_string() }).collect::<Vec<String>>().join(".")),
+        28 if len == 16 => RecordData::Aaaa(to_hex_string(4, rdata.to_vec())),
+        12 => RecordData::Ptr(read_name(bytes, start)?.0),
+        5 => RecordData::Cname(read_name(bytes, start)?.0),
+        33 if len >= 6 => RecordData::Srv {
+            priority: read_u16(bytes, start)?,
+            weight: read_u16(bytes, start + 2)?,
+            port: read_u16(bytes, start + 4)?,
+            target: read_name(bytes, start + 6)?.0,
+        },
+        16 => {
+            let mut strings = Vec::new();
+            let mut i = 0;
+            while i < rdata.len() {
+                let l = usize::from(rdata[i]);
+                match rdata.get(i + 1..i + 1 + l) {
+                    Some(s) => strings.push(String::from_utf8_lossy(s).to_string()),
+                    None => break,
+                }
+                i += 1 + l;
+            }
+            RecordData::Txt(strings)
+        }
+        _ => RecordData::Other(rdata.to_vec()),
+    };
+    Some((DnsRecord { name, record_type, class, ttl, data }, start + len))
+}
+
+/// Reads a possibly compressed name and returns it with the offset right after it.
+fn read_name(bytes: &[u8], offset: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut position = offset;
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let len = *bytes.get(position)?;
+        match len & 0xC0 {
+            0xC0 => {
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return None;
+                }
+                if end.is_none() {
+                    end = Some(position + 2);
+                }
+                position = usize::from(read_u16(bytes, position)? & 0x3FFF);
+            }
+            0x00 => {
+                if len == 0 {
+                    break;
+                }
+                let label = bytes.get(position + 1..position + 1 + usize::from(len))?;
+                labels.push(String::from_utf8_lossy(label).to_string());
+                position += 1 + usize::from(len);
+            }
+            _ => return None,
+        }
+    }
+    Some((labels.join("."), end.unwrap_or(position + 1)))
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    bytes.get(offset..offset + 2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A query for example.com A
+    fn query(id: u16) -> Vec<u8> {
+        let mut bytes = id.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        bytes.extend_from_slice(b"\x07example\x03com\x00");
+        bytes.extend_from_slice(&[0, 1, 0, 1]);
+        bytes
+    }
+
+    fn with_length(message: &[u8]) -> Vec<u8> {
+        let mut bytes = (message.len() as u16).to_be_bytes().to_vec();
+        bytes.extend_from_slice(message);
+        bytes
+    }
+
+    #[test]
+    fn parses_a_query() {
+        let message = parse_dns(&query(0x1234)).unwrap();
+        assert_eq!(message.id, 0x1234);
+        assert!(!message.is_response);
+        assert_eq!(message.questions, vec![DnsQuestion { name: String::from("example.com"), record_type: 1, class: 1 }]);
+    }
+
+    #[test]
+    fn splits_the_messages_of_a_tcp_segment() {
+        let mut segment = with_length(&query(1));
+        segment.extend(with_length(&query(2)));
+        let messages = split_tcp_messages(&segment).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(parse_dns(messages[1]).unwrap().id, 2);
+    }
+
+    #[test]
+    fn rejects_tcp_segments_not_matching_the_length() {
+        let segment = with_length(&query(1));
+        // The first part of a message split over two segments
+        assert_eq!(split_tcp_messages(&segment[..segment.len() - 4]), None);
+        // Its continuation
+        assert_eq!(split_tcp_messages(&segment[6..]), None);
+        // Data after the message
+        let mut longer = segment.clone();
+        longer.push(0);
+        assert_eq!(split_tcp_messages(&longer), None);
+        assert_eq!(split_tcp_messages(&[]), None);
+    }
+}
@@ -0,0 +1,458 @@
+//! DNS tunneling and algorithmically generated domain detection
+//!
+//! The names queried through DNS are grouped by parent domain (the registered domain, e.g.
+//! "example.co.uk" for "a.b.example.co.uk") over tumbling windows.
+//!
+//! A parent domain is suspected of carrying a tunnel when it receives many different subdomains
+//! with long or high-entropy labels, or many queries for record types seldom used by clients
+//! (TXT, NULL). A domain is suspected of being algorithmically generated when its name is
+//! made of unusual letter pairs, has a high entropy or many digits, and often does not exist.
+//!
+//! A domain is reported once while it is followed, it is forgotten along with its alerts after
+//! a window without queries.
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use crate::alert::{Alert, AlertCategory};
+use crate::packet::{FieldValue, Packet};
+
+/// Letter pairs frequent in English words and in legitimate domain names
+const COMMON_BIGRAMS: [&str; 120] = [
+    "th", "he", "in", "er", "an", "re", "on", "at", "en", "nd", "ti", "es", "or", "te", "of",
+    "ed", "is", "it", "al", "ar", "st", "to", "nt", "ng", "se", "ha", "as", "ou", "io", "le",
+    "ve", "co", "me", "de", "hi", "ri", "ro", "ic", "ne", "ea", "ra", "ce", "li", "ch", "ll",
+    "be", "ma", "si", "om", "ur", "ca", "el", "ta", "la", "ns", "di", "fo", "ho", "pe", "ec",
+    "pr", "no", "ct", "us", "ac", "ot", "il", "tr", "ly", "nc", "et", "ut", "ss", "so", "rs",
+    "un", "lo", "wa", "ge", "ie", "wh", "ee", "wi", "em", "ad", "ol", "rt", "po", "we", "na",
+    "ul", "ni", "ts", "mo", "ow", "pa", "im", "mi", "ai", "sh", "ir", "su", "id", "os", "iv",
+    "ia", "am", "fi", "ci", "vi", "pl", "ig", "tu", "go", "oo", "og", "gl", "bo", "ok", "ap",
+];
+/// Second level labels under which the registered domain has three labels, e.g. "co.uk"
+const SECOND_LEVEL_LABELS: [&str; 7] = ["co", "com", "net", "org", "gov", "ac", "edu"];
+/// Record types seldom queried by clients but convenient to carry data
+const UNUSUAL_RECORD_TYPES: [i64; 3] = [10, 16, 99];
+/// Suffixes of the names never analyzed: reverse lookups and local names
+const IGNORED_SUFFIXES: [&str; 3] = ["in-addr.arpa", "ip6.arpa", "local"];
+/// Number of parent domains followed at the same time
+const MAX_DOMAINS: usize = 100_000;
+/// Number of shards of the domains
+const SHARDS: usize = 64;
+/// Number of subdomains and hosts remembered for each parent domain
+const MAX_TRACKED_NAMES: usize = 4096;
+/// Number of hosts printed in an alert
+const MAX_PRINTED_HOSTS: usize = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+/// The thresholds of the DNS detectors
+pub struct DnsThresholds {
+    /// The length of the windows over which the queries are counted, in seconds
+    pub window: u64,
+    /// Labels at least this long are considered long
+    pub label_length: usize,
+    /// Subdomains with at least this entropy, in bits per character, are considered random
+    pub entropy: f64,
+    /// Number of different subdomains of a parent domain in a window that gives the highest volume score
+    pub subdomains: usize,
+    /// Number of queries to a parent domain in a window needed to score it
+    pub min_queries: usize,
+    /// Tunneling score, between 0 and 1, that raises an alert
+    pub tunnel_score: f64,
+    /// Domain generation score, between 0 and 1, that raises an alert
+    pub dga_score: f64,
+    /// Names shorter than this are never considered generated
+    pub dga_min_length: usize,
+}
+
+impl Default for DnsThresholds {
+    fn default() -> Self {
+        DnsThresholds {
+            window: 60,
+            label_length: 40,
+            entropy: 3.8,
+            subdomains: 100,
+            min_queries: 10,
+            tunnel_score: 0.6,
+            dga_score: 0.65,
+            dga_min_length: 8,
+        }
+    }
+}
+
+/// The queries to one parent domain in the current window
+#[derive(Debug, Clone, Default)]
+struct DomainState {
+    window_start_us: i64,
+    last_us: i64,
+    queries: usize,
+    long_labels: usize,
+    high_entropy: usize,
+    unusual_types: usize,
+    responses: usize,
+    nxdomain: usize,
+    subdomains: HashSet<String>,
+    hosts: HashSet<String>,
+    /// Whether the domain was reported as a tunnel, kept across the windows
+    reported_tunnel: bool,
+    /// Whether the domain was reported as generated, kept across the windows
+    reported_dga: bool,
+}
+
+/// Follows the DNS queries and finds tunnels and generated domains
+#[derive(Debug)]
+pub struct DnsAnomalyDetector {
+    thresholds: DnsThresholds,
+    /// The parent domains, sharded so that the workers seldom wait for each other
+    domains: Vec<Mutex<HashMap<String, DomainState>>>,
+}
+
+impl DnsAnomalyDetector {
+    pub fn new(thresholds: DnsThresholds) -> Self {
+        DnsAnomalyDetector {
+            thresholds,
+            domains: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+        }
+    }
+
+    /// Counts the names queried in a DNS packet.
+    pub fn add_packet(&self, packet: &Packet) {
+        let is_response = matches!(packet.get_field("dns.flags.response").first(), Some(FieldValue::Bool(true)));
+        let nxdomain = matches!(packet.get_field("dns.rcode").first(), Some(FieldValue::Int(3)));
+        let names = packet.get_field("dns.qry.name");
+        let types = packet.get_field("dns.qry.type");
+        let now = packet.get_timestamp_us();
+        for (i, name) in names.iter().enumerate() {
+            let name = match name {
+                FieldValue::Str(name) => name.trim_end_matches('.').to_lowercase(),
+                _ => continue,
+            };
+            let (parent, subdomain) = match split_domain(&name) {
+                Some(split) => split,
+                None => continue,
+            };
+            let mut domains = self.get_shard(&parent).lock().unwrap();
+            if !domains.contains_key(&parent) && domains.len() >= MAX_DOMAINS / SHARDS {
+                continue;
+            }
+            let state = domains.entry(parent).or_insert_with(|| DomainState {
+                window_start_us: now,
+                ..Default::default()
+            });
+            state.last_us = state.last_us.max(now);
+            if is_response {
+                state.responses += 1;
+                if nxdomain {
+                    state.nxdomain += 1;
+                }
+                continue;
+            }
+            state.queries += 1;
+            if state.hosts.len() < MAX_TRACKED_NAMES {
+                state.hosts.insert(packet.get_source().clone());
+            }
+            if let Some(FieldValue::Int(record_type)) = types.get(i) {
+                if UNUSUAL_RECORD_TYPES.contains(record_type) {
+                    state.unusual_types += 1;
+                }
+            }
+            if subdomain.is_empty() {
+                continue;
+            }
+            if subdomain.split('.').any(|label| label.len() >= self.thresholds.label_length) {
+                state.long_labels += 1;
+            }
+            if entropy(&subdomain.replace('.', "")) >= self.thresholds.entropy {
+                state.high_entropy += 1;
+            }
+            if state.subdomains.len() < MAX_TRACKED_NAMES {
+                state.subdomains.insert(subdomain);
+            }
+        }
+    }
+
+    /// Scores the domains whose window ended before the given time, returns the alerts for the
+    /// suspicious ones not reported yet and starts a new window for them.
+    pub fn analyze(&self, now_us: i64) -> Vec<Alert> {
+        let window_us = self.thresholds.window as i64 * 1_000_000;
+        let mut alerts = Vec::new();
+        for domains in self.domains.iter() {
+            let mut domains = domains.lock().unwrap();
+            for (domain, state) in domains.iter_mut() {
+                if state.window_start_us + window_us > now_us {
+                    continue;
+                }
+                let tunnel = tunnel_score(&self.thresholds, state);
+                if tunnel.0 >= self.thresholds.tunnel_score && !state.reported_tunnel {
+                    state.reported_tunnel = true;
+                    alerts.push(to_alert("DNS tunneling", domain, state, tunnel));
+                }
+                let dga = dga_score(&self.thresholds, domain, state);
+                if dga.0 >= self.thresholds.dga_score && !state.reported_dga {
+                    state.reported_dga = true;
+                    alerts.push(to_alert("Algorithmically generated domain", domain, state, dga));
+                }
+                *state = DomainState {
+                    window_start_us: now_us,
+                    last_us: state.last_us,
+                    reported_tunnel: state.reported_tunnel,
+                    reported_dga: state.reported_dga,
+                    ..Default::default()
+                };
+            }
+            // Forget the domains that were not queried during the last window, and their alerts
+            domains.retain(|_, state| state.last_us + window_us > now_us);
+        }
+        alerts.sort_by_key(|a| a.start_us);
+        alerts
+    }
+
+    fn get_shard(&self, domain: &str) -> &Mutex<HashMap<String, DomainState>> {
+        let mut hasher = DefaultHasher::new();
+        domain.hash(&mut hasher);
+        &self.domains[(hasher.finish() % SHARDS as u64) as usize]
+    }
+}
+
+/// Splits a name into its registered domain and the subdomain in front of it. Returns None for
+/// the names that are not analyzed.
+fn split_domain(name: &str) -> Option<(String, String)> {
+    if name.is_empty() || IGNORED_SUFFIXES.iter().any(|s| name == *s || name.ends_with(&format!(".{}", s))) {
+        return None;
+    }
+    let labels = name.split('.').collect::<Vec<&str>>();
+    if labels.len() < 2 {
+        return None;
+    }
+    let mut parent_labels = 2;
+    if labels.len() > 2 && labels[labels.len() - 1].len() == 2 && SECOND_LEVEL_LABELS.contains(&labels[labels.len() - 2]) {
+        parent_labels = 3;
+    }
+    let split = labels.len().saturating_sub(parent_labels);
+    Some((labels[split..].join("."), labels[..split].join(".")))
+}
+
+/// Computes the Shannon entropy of a string, in bits per character.
+fn entropy(s: &str) -> f64 {
+    if s.is_empty() {
+        return 0.0;
+    }
+    let mut counts: HashMap<char, usize> = HashMap::new();
+    for c in s.chars() {
+        *counts.entry(c).or_insert(0) += 1;
+    }
+    let len = s.chars().count() as f64;
+    counts.values().map(|c| {
+        let p = *c as f64 / len;
+        -p * p.log2()
+    }).sum()
+}
+
+/// Gets the fraction of the letter pairs of a label that are not common, between 0 and 1.
+fn bigram_score(label: &str) -> f64 {
+    let chars = label.chars().collect::<Vec<char>>();
+    let bigrams = chars.windows(2)
+        .filter(|w| w[0].is_ascii_alphabetic() && w[1].is_ascii_alphabetic())
+        .map(|w| w.iter().collect::<String>())
+        .collect::<Vec<String>>();
+    if bigrams.is_empty() {
+        return 0.0;
+    }
+    let common = bigrams.iter().filter(|b| COMMON_BIGRAMS.contains(&b.as_str())).count();
+    1.0 - common as f64 / bigrams.len() as f64
+}
+
+/// Scores a parent domain as a tunnel, returning the score and the evidence.
+fn tunnel_score(thresholds: &DnsThresholds, state: &DomainState) -> (f64, Vec<String>) {
+    if state.queries < thresholds.min_queries {
+        return (0.0, Vec::new());
+    }
+    let queries = state.queries as f64;
+    let volume = (state.subdomains.len() as f64 / thresholds.subdomains as f64).min(1.0);
+    let long = state.long_labels as f64 / queries;
+    let random = state.high_entropy as f64 / queries;
+    let unusual = state.unusual_types as f64 / queries;
+    let score = 0.3 * volume + 0.25 * long + 0.25 * random + 0.2 * unusual;
+    let evidence = vec![
+        format!("score {:.2}", score),
+        format!("{} queries for {} subdomains in {}s", state.queries, state.subdomains.len(), thresholds.window),
+        format!("{} with long labels, {} with high entropy, {} TXT/NULL", state.long_labels, state.high_entropy, state.unusual_types),
+    ];
+    (score, evidence)
+}
+
+/// Scores a registered domain as generated, returning the score and the evidence.
+fn dga_score(thresholds: &DnsThresholds, domain: &str, state: &DomainState) -> (f64, Vec<String>) {
+    let label = domain.split('.').next().unwrap_or_default();
+    if label.len() < thresholds.dga_min_length || state.queries == 0 {
+        return (0.0, Vec::new());
+    }
+    let ngram = bigram_score(label);
+    let label_entropy = entropy(label);
+    let digits = label.chars().filter(|c| c.is_ascii_digit()).count() as f64 / label.len() as f64;
+    let mut score = 0.6 * ngram + 0.25 * (label_entropy / 4.5).min(1.0) + 0.15 * (digits * 2.0).min(1.0);
+    if state.responses > 0 {
+        // Most generated domains are not registered
+        score = (score + 0.15 * state.nxdomain as f64 / state.responses as f64).min(1.0);
+    }
+    let evidence = vec![
+        format!("score {:.2}", score),
+        format!("n-gram {:.2}, entropy {:.2}, digits {:.0}%", ngram, label_entropy, digits * 100.0),
+        format!("{} of {} responses NXDOMAIN", state.nxdomain, state.responses),
+    ];
+    (score, evidence)
+}
+
+fn to_alert(kind: &str, domain: &str, state: &DomainState, score: (f64, Vec<String>)) -> Alert {
+    let mut hosts = state.hosts.iter().cloned().collect::<Vec<String>>();
+    hosts.sort();
+    let source = if hosts.len() > MAX_PRINTED_HOSTS {
+        format!("{} and {} more hosts", hosts[..MAX_PRINTED_HOSTS].join(", "), hosts.len() - MAX_PRINTED_HOSTS)
+    } else {
+        hosts.join(", ")
+    };
+    Alert {
+        category: AlertCategory::Dns,
+        kind: String::from(kind),
+        source,
+        targets: vec![domain.to_string()],
+        evidence: score.1,
+        start_us: state.window_start_us,
+        end_us: state.last_us,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libc::c_long;
+    use super::*;
+
+    const SECOND: i64 = 1_000_000;
+
+    fn thresholds() -> DnsThresholds {
+        DnsThresholds { window: 10, subdomains: 20, ..DnsThresholds::default() }
+    }
+
+    fn dns_packet(source: &str, name: &str, response: bool, rcode: i64, record_type: i64, second: c_long) -> Packet {
+        let mut packet = Packet::new(String::new(), source.to_string(), String::from("192.168.1.1"), Some(String::from("50000")), Some(String::from("53")), String::from("DNS"), 80, String::new());
+        packet.set_timestamp(&second, &0);
+        packet.add_field("dns.flags.response", FieldValue::Bool(response));
+        packet.add_field("dns.rcode", FieldValue::Int(rcode));
+        packet.add_field("dns.qry.name", FieldValue::Str(name.to_string()));
+        packet.add_field("dns.qry.type", FieldValue::Int(record_type));
+        packet
+    }
+
+    fn query(name: &str, record_type: i64, second: c_long) -> Packet {
+        dns_packet("192.168.1.20", name, false, 0, record_type, second)
+    }
+
+    fn response(name: &str, rcode: i64, second: c_long) -> Packet {
+        dns_packet("192.168.1.1", name, true, rcode, 1, second)
+    }
+
+    /// Labels of 50 random base32 characters, as carried by a tunnel
+    fn random_labels(count: usize) -> Vec<String> {
+        let alphabet = b"abcdefghijklmnopqrstuvwxyz234567";
+        let mut seed: u32 = 12345;
+        (0..count).map(|_| (0..50).map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            alphabet[(seed >> 16) as usize % alphabet.len()] as char
+        }).collect()).collect()
+    }
+
+    fn tunnel(detector: &DnsAnomalyDetector, second: c_long) {
+        for label in random_labels(20) {
+            detector.add_packet(&query(&format!("{}.t.example.net", label), 16, second));
+        }
+    }
+
+    #[test]
+    fn splits_the_registered_domain() {
+        assert_eq!(split_domain("a.b.example.co.uk"), Some((String::from("example.co.uk"), String::from("a.b"))));
+        assert_eq!(split_domain("www.example.com"), Some((String::from("example.com"), String::from("www"))));
+        assert_eq!(split_domain("example.com"), Some((String::from("example.com"), String::new())));
+        assert_eq!(split_domain("4.3.2.1.in-addr.arpa"), None);
+        assert_eq!(split_domain("in-addr.arpa"), None);
+        assert_eq!(split_domain("printer.local"), None);
+        assert_eq!(split_domain("localhost"), None);
+        assert_eq!(split_domain(""), None);
+    }
+
+    #[test]
+    fn computes_the_entropy() {
+        assert_eq!(entropy(""), 0.0);
+        assert_eq!(entropy("aaaa"), 0.0);
+        assert_eq!(entropy("abab"), 1.0);
+        assert_eq!(entropy("abcd"), 2.0);
+    }
+
+    #[test]
+    fn scores_the_letter_pairs() {
+        assert_eq!(bigram_score("there"), 0.0);
+        assert_eq!(bigram_score("xkqzvj"), 1.0);
+        assert!((bigram_score("thxq") - 2.0 / 3.0).abs() < 1e-9);
+        // Pairs with digits are not letter pairs
+        assert_eq!(bigram_score("a1b2"), 0.0);
+    }
+
+    #[test]
+    fn reports_tunnels_once_their_window_ends() {
+        let detector = DnsAnomalyDetector::new(thresholds());
+        tunnel(&detector, 100);
+        assert!(detector.analyze(105 * SECOND).is_empty());
+        let alerts = detector.analyze(110 * SECOND);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].kind, "DNS tunneling");
+        assert_eq!(alerts[0].targets, vec![String::from("example.net")]);
+        assert_eq!(alerts[0].source, "192.168.1.20");
+        assert_eq!(alerts[0].evidence[1], "20 queries for 20 subdomains in 10s");
+    }
+
+    #[test]
+    fn does_not_report_regular_subdomains() {
+        let detector = DnsAnomalyDetector::new(thresholds());
+        for i in 0..20 {
+            detector.add_packet(&query(&format!("host{}.example.net", i), 1, 100));
+        }
+        assert!(detector.analyze(110 * SECOND).is_empty());
+    }
+
+    #[test]
+    fn reports_generated_domains() {
+        let detector = DnsAnomalyDetector::new(thresholds());
+        detector.add_packet(&query("xkqzvjwpyt.com", 1, 100));
+        detector.add_packet(&response("xkqzvjwpyt.com", 3, 100));
+        detector.add_packet(&query("www.google.com", 1, 100));
+        let alerts = detector.analyze(110 * SECOND);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].kind, "Algorithmically generated domain");
+        assert_eq!(alerts[0].targets, vec![String::from("xkqzvjwpyt.com")]);
+        assert_eq!(alerts[0].evidence[2], "1 of 1 responses NXDOMAIN");
+    }
+
+    #[test]
+    fn reports_both_anomalies_of_a_domain() {
+        let detector = DnsAnomalyDetector::new(thresholds());
+        for label in random_labels(20) {
+            detector.add_packet(&query(&format!("{}.xkqzvjwpyt.com", label), 16, 100));
+        }
+        let kinds = detector.analyze(110 * SECOND).into_iter().map(|a| a.kind).collect::<HashSet<String>>();
+        assert_eq!(kinds, HashSet::from([String::from("DNS tunneling"), String::from("Algorithmically generated domain")]));
+    }
+
+    #[test]
+    fn reports_a_domain_again_once_forgotten() {
+        let detector = DnsAnomalyDetector::new(thresholds());
+        tunnel(&detector, 100);
+        tunnel(&detector, 109);
+        assert_eq!(detector.analyze(110 * SECOND).len(), 1);
+        // Still followed: not reported again
+        tunnel(&detector, 115);
+        assert!(detector.analyze(120 * SECOND).is_empty());
+        // A window without queries forgets the domain
+        assert!(detector.analyze(130 * SECOND).is_empty());
+        assert!(detector.domains.iter().all(|d| d.lock().unwrap().is_empty()));
+        tunnel(&detector, 140);
+        assert_eq!(detector.analyze(150 * SECOND).len(), 1);
+    }
+}
@@ -0,0 +1,476 @@
+//! SYN flood and volumetric denial of service detection
+//!
+//! The packets are counted per destination in buckets of one second. When a bucket is complete
+//! its rates are compared with the thresholds: SYNs received versus handshakes completed,
+//! packets and bytes per second, and bytes per second of responses coming from the ports used
+//! for amplification (DNS, NTP, SSDP) or of ICMP echo replies. Consecutive seconds over a
+//! threshold make one attack: an alert is raised as soon as it starts, while it is still going
+//! on, and another one with its start, end and peak rate once it has been quiet for a while.
+//!
+//! The destinations and the handshakes are split in shards behind mutexes of their own, so that
+//! the workers counting the packets of different destinations seldom wait for each other.
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use crate::alert::{Alert, AlertCategory};
+use crate::dissector::TransportProtocol;
+use crate::icmp::IcmpKind;
+use crate::packet::{format_timestamp_us, Packet};
+
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const ACK: u8 = 0x10;
+
+/// Ports of the UDP services commonly abused for amplification
+const AMPLIFICATION_PORTS: [(u16, &str); 3] = [(53, "DNS"), (123, "NTP"), (1900, "SSDP")];
+/// Number of sources remembered for each attack
+const MAX_TRACKED_SOURCES: usize = 1024;
+/// Number of handshakes in progress remembered, a SYN flood must not exhaust the memory
+const MAX_HANDSHAKES: usize = 65536;
+/// Handshakes not completed within this time are forgotten
+const HANDSHAKE_TIMEOUT_US: i64 = 30_000_000;
+/// Number of shards of the destinations and of the handshakes
+const SHARDS: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+/// The thresholds of the denial of service detector
+pub struct DosThresholds {
+    /// SYNs per second to one destination that can make a SYN flood
+    pub syn_rate: u64,
+    /// A SYN flood completes at most this fraction of its handshakes
+    pub max_completed_ratio: f64,
+    /// Packets per second to one destination that make a flood
+    pub packet_rate: u64,
+    /// Bytes per second to one destination that make a flood
+    pub byte_rate: u64,
+    /// Bytes per second of amplified responses to one destination that make an amplification attack
+    pub amplification_rate: u64,
+    /// Responses smaller than this are not counted as amplified
+    pub amplification_min_size: u32,
+    /// Seconds under the thresholds after which an attack is considered over
+    pub quiet: u64,
+}
+
+impl Default for DosThresholds {
+    fn default() -> Self {
+        DosThresholds {
+            syn_rate: 200,
+            max_completed_ratio: 0.2,
+            packet_rate: 20_000,
+            byte_rate: 100_000_000,
+            amplification_rate: 1_000_000,
+            amplification_min_size: 512,
+            quiet: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+/// The kinds of attack recognized by the detector
+pub enum DosKind {
+    SynFlood,
+    PacketFlood,
+    ByteFlood,
+    Amplification,
+}
+
+impl DosKind {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            DosKind::SynFlood => "SYN flood",
+            DosKind::PacketFlood => "Packet flood",
+            DosKind::ByteFlood => "Bandwidth flood",
+            DosKind::Amplification => "Amplification attack",
+        }
+    }
+
+    fn get_unit(&self) -> &'static str {
+        match self {
+            DosKind::SynFlood => "SYN/s",
+            DosKind::PacketFlood => "packets/s",
+            DosKind::ByteFlood | DosKind::Amplification => "bytes/s",
+        }
+    }
+}
+
+/// The packets received by a destination in one second
+#[derive(Debug, Clone, Default)]
+struct Bucket {
+    second: i64,
+    syns: u64,
+    completed: u64,
+    packets: u64,
+    bytes: u64,
+    amplification_bytes: u64,
+    amplification_protocols: BTreeSet<&'static str>,
+    sources: HashSet<String>,
+    reflectors: HashSet<String>,
+}
+
+/// An attack in progress
+#[derive(Debug, Clone)]
+struct Attack {
+    start_us: i64,
+    end_us: i64,
+    seconds: u32,
+    peak: u64,
+    peak_us: i64,
+    /// Additional information about the peak second
+    peak_detail: String,
+    sources: BTreeSet<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct DestinationState {
+    bucket: Bucket,
+    attacks: HashMap<DosKind, Attack>,
+}
+
+/// Handshakes in progress keyed by client, client port, server and server port, with whether
+/// the SYN-ACK was seen and the time of the SYN
+type Handshakes = HashMap<(String, String, String, String), (bool, i64)>;
+
+/// Watches the rates of the packets sent to each destination
+#[derive(Debug)]
+pub struct DosDetector {
+    thresholds: DosThresholds,
+    /// The destinations, sharded by address
+    destinations: Vec<Mutex<HashMap<String, DestinationState>>>,
+    /// The handshakes in progress, sharded by server
+    handshakes: Vec<Mutex<Handshakes>>,
+}
+
+impl DosDetector {
+    pub fn new(thresholds: DosThresholds) -> Self {
+        DosDetector {
+            thresholds,
+            destinations: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            handshakes: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+        }
+    }
+
+    /// Counts a packet and returns the attacks on its destination that just started or ended.
+    pub fn add_packet(&self, packet: &Packet) -> Vec<Alert> {
+        let now = packet.get_timestamp_us();
+        let (syn, completed) = self.track_handshake(packet);
+        let amplification = amplification_protocol(packet, self.thresholds.amplification_min_size);
+
+        let destination = packet.get_destination();
+        let mut destinations = shard(&self.destinations, destination).lock().unwrap();
+        // The address is only copied for a new destination
+        if !destinations.contains_key(destination) {
+            destinations.insert(destination.clone(), DestinationState::default());
+        }
+        let state = destinations.get_mut(destination).unwrap();
+        let mut alerts = Vec::new();
+        let second = now.div_euclid(1_000_000);
+        // Packets handled a little out of order by the workers go to the current bucket
+        if second > state.bucket.second {
+            let finished = std::mem::take(&mut state.bucket);
+            alerts = evaluate(&self.thresholds, destination, state, finished);
+            state.bucket.second = second;
+        }
+
+        let bucket = &mut state.bucket;
+        bucket.packets += 1;
+        bucket.bytes += u64::from(*packet.get_length());
+        if syn {
+            bucket.syns += 1;
+        }
+        if completed {
+            bucket.completed += 1;
+        }
+        if bucket.sources.len() < MAX_TRACKED_SOURCES {
+            bucket.sources.insert(packet.get_source().clone());
+        }
+        if let Some(protocol) = amplification {
+            bucket.amplification_bytes += u64::from(*packet.get_length());
+            bucket.amplification_protocols.insert(protocol);
+            if bucket.reflectors.len() < MAX_TRACKED_SOURCES {
+                bucket.reflectors.insert(packet.get_source().clone());
+            }
+        }
+        alerts
+    }
+
+    /// Evaluates the buckets left behind by destinations that stopped receiving packets and
+    /// returns the attacks that started in them or ended before the given time.
+    pub fn expire(&self, now_us: i64) -> Vec<Alert> {
+        let now_second = now_us.div_euclid(1_000_000);
+        let quiet = self.thresholds.quiet as i64;
+        let mut alerts = Vec::new();
+        for destinations in self.destinations.iter() {
+            let mut destinations = destinations.lock().unwrap();
+            for (destination, state) in destinations.iter_mut() {
+                if state.bucket.second < now_second && state.bucket.packets > 0 {
+                    let finished = std::mem::take(&mut state.bucket);
+                    alerts.extend(evaluate(&self.thresholds, destination, state, finished));
+                }
+                alerts.extend(close_idle(&self.thresholds, destination, state, now_second));
+            }
+            destinations.retain(|_, state| !state.attacks.is_empty() || state.bucket.second + quiet >= now_second);
+        }
+        for handshakes in self.handshakes.iter() {
+            handshakes.lock().unwrap().retain(|_, (_, ts)| now_us - *ts < HANDSHAKE_TIMEOUT_US);
+        }
+        alerts
+    }
+
+    /// Follows the TCP handshakes. Returns whether the packet is a SYN and whether it completes
+    /// a handshake.
+    fn track_handshake(&self, packet: &Packet) -> (bool, bool) {
+        let flags = match (packet.get_transport(), packet.get_tcp_flags()) {
+            (Some(TransportProtocol::Tcp), Some(flags)) => flags,
+            _ => return (false, false),
+        };
+        let source_port = packet.get_source_port().clone().unwrap_or_default();
+        let destination_port = packet.get_destination_port().clone().unwrap_or_default();
+        if flags & (SYN | ACK) == SYN {
+            let mut handshakes = shard(&self.handshakes, packet.get_destination()).lock().unwrap();
+            if handshakes.len() < MAX_HANDSHAKES / SHARDS {
+                let key = (packet.get_source().clone(), source_port, packet.get_destination().clone(), destination_port);
+                handshakes.insert(key, (false, packet.get_timestamp_us()));
+            }
+            (true, false)
+        } else if flags & (SYN | ACK) == SYN | ACK {
+            let mut handshakes = shard(&self.handshakes, packet.get_source()).lock().unwrap();
+            let key = (packet.get_destination().clone(), destination_port, packet.get_source().clone(), source_port);
+            if let Some((synack, _)) = handshakes.get_mut(&key) {
+                *synack = true;
+            }
+            (false, false)
+        } else if flags & (SYN | ACK | RST | FIN) == ACK {
+            let mut handshakes = shard(&self.handshakes, packet.get_destination()).lock().unwrap();
+            let key = (packet.get_source().clone(), source_port, packet.get_destination().clone(), destination_port);
+            match handshakes.get(&key) {
+                Some((true, _)) => {
+                    handshakes.remove(&key);
+                    (false, true)
+                }
+                _ => (false, false),
+            }
+        } else {
+            (false, false)
+        }
+    }
+}
+
+/// Gets the shard of the state of an address.
+fn shard<'a, T>(shards: &'a [Mutex<T>], address: &str) -> &'a Mutex<T> {
+    let mut hasher = DefaultHasher::new();
+    address.hash(&mut hasher);
+    &shards[(hasher.finish() % shards.len() as u64) as usize]
+}
+
+/// Gets the amplification protocol of a packet that looks like an amplified response.
+fn amplification_protocol(packet: &Packet, min_size: u32) -> Option<&'static str> {
+    if *packet.get_length() < min_size {
+        return None;
+    }
+    match packet.get_transport() {
+        Some(TransportProtocol::Udp) => {
+            let port = packet.get_source_port().as_ref()?.parse::<u16>().ok()?;
+            AMPLIFICATION_PORTS.iter().find(|(p, _)| *p == port).map(|(_, name)| *name)
+        }
+        Some(TransportProtocol::Icmpv4) | Some(TransportProtocol::Icmpv6) => match packet.get_icmp() {
+            Some(icmp) if matches!(icmp.kind, IcmpKind::EchoReply { .. }) => Some("ICMP"),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Compares a complete bucket with the thresholds, extends the attacks in progress and
+/// returns the ones that started or ended.
+fn evaluate(thresholds: &DosThresholds, destination: &str, state: &mut DestinationState, bucket: Bucket) -> Vec<Alert> {
+    let mut exceeded: Vec<(DosKind, u64, String, &HashSet<String>)> = Vec::new();
+    if bucket.syns >= thresholds.syn_rate && bucket.completed as f64 <= bucket.syns as f64 * thresholds.max_completed_ratio {
+        exceeded.push((DosKind::SynFlood, bucket.syns, format!("{} handshakes completed", bucket.completed), &bucket.sources));
+    }
+    if bucket.packets >= thresholds.packet_rate {
+        exceeded.push((DosKind::PacketFlood, bucket.packets, format!("{} bytes", bucket.bytes), &bucket.sources));
+    }
+    if bucket.bytes >= thresholds.byte_rate {
+        exceeded.push((DosKind::ByteFlood, bucket.bytes, format!("{} packets", bucket.packets), &bucket.sources));
+    }
+    if bucket.amplification_bytes >= thresholds.amplification_rate {
+        let protocols = bucket.amplification_protocols.iter().cloned().collect::<Vec<&str>>().join(", ");
+        exceeded.push((DosKind::Amplification, bucket.amplification_bytes, format!("{} responses", protocols), &bucket.reflectors));
+    }
+
+    let start_us = bucket.second * 1_000_000;
+    let mut alerts = Vec::new();
+    for (kind, rate, detail, sources) in exceeded {
+        let started = !state.attacks.contains_key(&kind);
+        let attack = state.attacks.entry(kind).or_insert_with(|| Attack {
+            start_us,
+            end_us: start_us,
+            seconds: 0,
+            peak: 0,
+            peak_us: start_us,
+            peak_detail: String::new(),
+            sources: BTreeSet::new(),
+        });
+        attack.end_us = start_us + 1_000_000;
+        attack.seconds += 1;
+        if rate > attack.peak {
+            attack.peak = rate;
+            attack.peak_us = start_us;
+            attack.peak_detail = detail;
+        }
+        for source in sources.iter() {
+            if attack.sources.len() >= MAX_TRACKED_SOURCES {
+                break;
+            }
+            attack.sources.insert(source.clone());
+        }
+        if started {
+            alerts.push(to_start_alert(kind, destination, attack));
+        }
+    }
+    alerts.extend(close_idle(thresholds, destination, state, bucket.second));
+    alerts
+}
+
+/// Ends the attacks that have been under the thresholds for the quiet period.
+fn close_idle(thresholds: &DosThresholds, destination: &str, state: &mut DestinationState, now_second: i64) -> Vec<Alert> {
+    let quiet_us = thresholds.quiet as i64 * 1_000_000;
+    let ended = state.attacks.iter()
+        .filter(|(_, attack)| attack.end_us + quiet_us <= now_second * 1_000_000)
+        .map(|(kind, _)| *kind)
+        .collect::<Vec<DosKind>>();
+    let mut alerts = Vec::new();
+    for kind in ended {
+        if let Some(attack) = state.attacks.remove(&kind) {
+            alerts.push(to_alert(kind, destination, attack));
+        }
+    }
+    alerts
+}
+
+/// The alert raised by the first second of an attack, while it goes on.
+fn to_start_alert(kind: DosKind, destination: &str, attack: &Attack) -> Alert {
+    Alert {
+        category: AlertCategory::Dos,
+        kind: format!("{} started", kind.get_name()),
+        source: sources_name(&attack.sources),
+        targets: vec![destination.to_string()],
+        evidence: vec![format!("{} {} ({})", attack.peak, kind.get_unit(), attack.peak_detail)],
+        start_us: attack.start_us,
+        end_us: attack.end_us,
+    }
+}
+
+/// The alert raised once the attack is over.
+fn to_alert(kind: DosKind, destination: &str, attack: Attack) -> Alert {
+    let source = sources_name(&attack.sources);
+    Alert {
+        category: AlertCategory::Dos,
+        kind: String::from(kind.get_name()),
+        source,
+        targets: vec![destination.to_string()],
+        evidence: vec![
+            format!("peak {} {} at {} ({})", attack.peak, kind.get_unit(), format_timestamp_us(attack.peak_us), attack.peak_detail),
+            format!("{}s over the threshold", attack.seconds),
+        ],
+        start_us: attack.start_us,
+        end_us: attack.end_us,
+    }
+}
+
+fn sources_name(sources: &BTreeSet<String>) -> String {
+    match sources.len() {
+        1 => sources.iter().next().cloned().unwrap_or_default(),
+        n if n >= MAX_TRACKED_SOURCES => format!("{}+ sources", n),
+        n => format!("{} sources", n),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libc::c_long;
+    use super::*;
+
+    fn syn(source: &str, port: u32, second: c_long, microsecond: c_long) -> Packet {
+        let mut packet = Packet::new(String::new(), source.to_string(), String::from("192.168.1.10"), Some(port.to_string()), Some(String::from("80")), String::from("TCP"), 60, String::new());
+        packet.set_transport(Some(TransportProtocol::Tcp));
+        packet.set_tcp_flags(Some(SYN));
+        packet.set_timestamp(&second, &microsecond);
+        packet
+    }
+
+    fn detector() -> DosDetector {
+        DosDetector::new(DosThresholds { syn_rate: 10, quiet: 2, ..DosThresholds::default() })
+    }
+
+    /// Sends 20 SYNs a second from second `from` to second `to` excluded, returns the alerts.
+    fn flood(detector: &DosDetector, from: c_long, to: c_long) -> Vec<Alert> {
+        let mut alerts = Vec::new();
+        for second in from..to {
+            for i in 0..20 {
+                alerts.extend(detector.add_packet(&syn("10.0.0.1", 40000 + i, second, c_long::from(i))));
+            }
+        }
+        alerts
+    }
+
+    #[test]
+    fn raises_an_alert_while_the_flood_goes_on() {
+        let detector = detector();
+        // The first second is evaluated when the second one starts
+        let alerts = flood(&detector, 100, 105);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].kind, "SYN flood started");
+        assert_eq!(alerts[0].targets, vec![String::from("192.168.1.10")]);
+        assert_eq!(alerts[0].start_us, 100_000_000);
+        assert_eq!(alerts[0].evidence, vec![String::from("20 SYN/s (0 handshakes completed)")]);
+    }
+
+    #[test]
+    fn reports_the_attack_once_it_is_quiet() {
+        let detector = detector();
+        flood(&detector, 100, 105);
+        assert!(detector.expire(106_000_000).is_empty());
+        let alerts = detector.expire(107_000_000);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].kind, "SYN flood");
+        assert_eq!(alerts[0].source, "10.0.0.1");
+        assert_eq!((alerts[0].start_us, alerts[0].end_us), (100_000_000, 105_000_000));
+        assert_eq!(alerts[0].evidence[1], "5s over the threshold");
+    }
+
+    #[test]
+    fn counts_the_completed_handshakes() {
+        let detector = detector();
+        let mut alerts = Vec::new();
+        for second in 100..105 {
+            for i in 0..20 {
+                let client_port = 40000 + i + second as u32 * 100;
+                alerts.extend(detector.add_packet(&syn("10.0.0.1", client_port, second, c_long::from(i))));
+                let mut synack = Packet::new(String::new(), String::from("192.168.1.10"), String::from("10.0.0.1"), Some(String::from("80")), Some(client_port.to_string()), String::from("TCP"), 60, String::new());
+                synack.set_transport(Some(TransportProtocol::Tcp));
+                synack.set_tcp_flags(Some(SYN | ACK));
+                synack.set_timestamp(&second, &c_long::from(i));
+                alerts.extend(detector.add_packet(&synack));
+                let mut ack = syn("10.0.0.1", client_port, second, c_long::from(i));
+                ack.set_tcp_flags(Some(ACK));
+                alerts.extend(detector.add_packet(&ack));
+            }
+        }
+        alerts.extend(detector.expire(120_000_000));
+        assert!(alerts.is_empty(), "{:?}", alerts);
+    }
+
+    #[test]
+    fn ignores_traffic_under_the_thresholds() {
+        let detector = detector();
+        let mut alerts = Vec::new();
+        for second in 100..105 {
+            alerts.extend(detector.add_packet(&syn("10.0.0.1", 40000, second, 0)));
+        }
+        alerts.extend(detector.expire(120_000_000));
+        assert!(alerts.is_empty());
+    }
+}
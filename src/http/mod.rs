@@ -0,0 +1,433 @@
+//! Minimal embedded HTTP/1.1 server
+//!
+//! Serves one request per connection, each connection on a thread of its own, which is plenty
+//! for scrapes and control requests coming from a few clients. Beyond MAX_CONNECTIONS clients
+//! at the same time, the new ones are answered 503 right away. The listener is closed when the
+//! Server returned is stopped or dropped.
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use serde_json::Value;
+
+/// Largest request head (request line and headers) accepted
+const MAX_HEAD: usize = 64 * 1024;
+/// Largest request body accepted
+const MAX_BODY: usize = 1024 * 1024;
+/// Time after which a silent client is disconnected
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Number of connections answered at the same time
+const MAX_CONNECTIONS: usize = 64;
+/// Time between two checks for new connections, and for the server being stopped
+const ACCEPT_POLL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Default)]
+/// A request received by the server
+pub struct Request {
+    pub method: String,
+    /// The path without the query string
+    pub path: String,
+    /// The query string, without the question mark
+    pub query: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+/// The response to a request
+pub struct Response {
+    pub status: u16,
+    pub content_type: String,
+    pub body: Vec<u8>,
+}
+
+/// The function answering the requests
+pub type Handler = Arc<dyn Fn(&Request) -> Response + Send + Sync>;
+
+impl Request {
+    /// Gets the value of a header, ignoring the case of its name.
+    pub fn get_header(&self, name: &str) -> Option<&String> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v)
+    }
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &str, body: Vec<u8>) -> Self {
+        Response {
+            status,
+            content_type: content_type.to_string(),
+            body,
+        }
+    }
+
+    /// Creates a plain text response.
+    pub fn text(status: u16, body: &str) -> Self {
+        Response::new(status, "text/plain; charset=utf-8", body.as_bytes().to_vec())
+    }
+
+    /// Creates a JSON response.
+    pub fn json(status: u16, body: &Value) -> Self {
+        Response::new(status, "application/json", body.to_string().into_bytes())
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            201 => "Created",
+            204 => "No Content",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            413 => "Payload Too Large",
+            500 => "Internal Server Error",
+            503 => "Service Unavailable",
+            _ => "Unknown",
+        }
+    }
+}
+
+/// Counts a connection being answered, until it is dropped
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    /// Takes a slot, unless MAX_CONNECTIONS connections are already being answered.
+    fn take(connections: &Arc<AtomicUsize>) -> Option<ConnectionSlot> {
+        connections.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| if n < MAX_CONNECTIONS { Some(n + 1) } else { None })
+            .ok()
+            .map(|_| ConnectionSlot(connections.clone()))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A listener answering requests on a background thread, closed when stopped or dropped
+pub struct Server {
+    address: Option<SocketAddr>,
+    /// The unix socket file, removed when the server stops
+    path: Option<String>,
+    stopping: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Server {
+    /// Gets the TCP address actually bound, useful when the port is 0.
+    pub fn get_address(&self) -> Option<SocketAddr> {
+        self.address
+    }
+
+    /// Closes the listener, waiting for the accept loop to exit. The requests being answered
+    /// are completed.
+    pub fn stop(&mut self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+            if let Some(path) = &self.path {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// A nonblocking listener of the accept loop
+trait Listener: Send + 'static {
+    type Stream: Read + Write + Send + 'static;
+
+    /// Accepts a connection and makes it blocking with the client timeouts.
+    fn accept_stream(&self) -> std::io::Result<Self::Stream>;
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept_stream(&self) -> std::io::Result<TcpStream> {
+        let (stream, _) = self.accept()?;
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+        stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+        Ok(stream)
+    }
+}
+
+#[cfg(unix)]
+impl Listener for std::os::unix::net::UnixListener {
+    type Stream = std::os::unix::net::UnixStream;
+
+    fn accept_stream(&self) -> std::io::Result<Self::Stream> {
+        let (stream, _) = self.accept()?;
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+        stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+        Ok(stream)
+    }
+}
+
+/// Listens on a TCP address and answers the requests with the handler on background threads.
+pub fn serve_tcp(address: &str, handler: Handler) -> std::io::Result<Server> {
+    let listener = TcpListener::bind(address)?;
+    let local_address = listener.local_addr()?;
+    listener.set_nonblocking(true)?;
+    Ok(spawn_server(listener, handler, Some(local_address), None))
+}
+
+/// Listens on a unix socket, replacing a stale socket file, and answers the requests with the
+/// handler on background threads. The socket file is removed when the server stops.
+#[cfg(unix)]
+pub fn serve_unix(path: &str, handler: Handler) -> std::io::Result<Server> {
+    use std::os::unix::net::UnixListener;
+    if std::fs::metadata(path).is_ok() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    listener.set_nonblocking(true)?;
+    Ok(spawn_server(listener, handler, None, Some(path.to_string())))
+}
+
+/// Accepts the connections until the server is stopped, checking every ACCEPT_POLL.
+fn spawn_server<L: Listener>(listener: L, handler: Handler, address: Option<SocketAddr>, path: Option<String>) -> Server {
+    let stopping = Arc::new(AtomicBool::new(false));
+    let stop = stopping.clone();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let thread = std::thread::spawn(move || {
+        while !stop.load(Ordering::SeqCst) {
+            let mut stream = match listener.accept_stream() {
+                Ok(s) => s,
+                // Nothing to accept, or a client that left before it was accepted
+                Err(_) => {
+                    std::thread::sleep(ACCEPT_POLL);
+                    continue;
+                }
+            };
+            let slot = match ConnectionSlot::take(&connections) {
+                Some(slot) => slot,
+                None => {
+                    write_response(&mut stream, &Response::text(503, "Too many connections"));
+                    continue;
+                }
+            };
+            let handler = handler.clone();
+            std::thread::spawn(move || {
+                handle_connection(stream, &handler);
+                drop(slot);
+            });
+        }
+    });
+    Server { address, path, stopping, thread: Some(thread) }
+}
+
+/// Reads a request from the stream, answers it and closes the connection.
+pub(crate) fn handle_connection<S: Read + Write>(stream: S, handler: &Handler) {
+    let mut reader = BufReader::new(stream);
+    let response = match read_request(&mut reader) {
+        Ok(request) => handler(&request),
+        Err(response) => response,
+    };
+    write_response(reader.get_mut(), &response);
+}
+
+fn write_response<S: Write>(stream: &mut S, response: &Response) {
+    let head = format!("HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                       response.status,
+                       response.reason(),
+                       response.content_type,
+                       response.body.len());
+    let _ = stream.write_all(head.as_bytes())
+        .and_then(|_| stream.write_all(&response.body))
+        .and_then(|_| stream.flush());
+}
+
+fn read_request<R: BufRead>(reader: &mut R) -> Result<Request, Response> {
+    let bad_request = |message: &str| Response::text(400, message);
+    // The head is read through a limit, so that a client cannot send an endless line
+    let mut head = reader.take(MAX_HEAD as u64);
+    let read_line = |head: &mut std::io::Take<&mut R>| -> Result<String, Response> {
+        let mut line = String::new();
+        match head.read_line(&mut line) {
+            Ok(_) if line.ends_with('\n') => Ok(line.trim_end_matches(['\r', '\n']).to_string()),
+            Ok(_) if head.limit() == 0 => Err(Response::text(413, "Request head too large")),
+            Ok(_) => Err(bad_request("Connection closed")),
+            Err(_) => Err(bad_request("Invalid request")),
+        }
+    };
+
+    let request_line = read_line(&mut head)?;
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(m), Some(t), Some(v)) if v.starts_with("HTTP/1.") => (m.to_string(), t.to_string()),
+        _ => return Err(bad_request("Invalid request line")),
+    };
+    let (path, query) = match target.split_once('?') {
+        Some((p, q)) => (p.to_string(), q.to_string()),
+        None => (target, String::new()),
+    };
+    let mut request = Request { method, path, query, ..Default::default() };
+    loop {
+        let line = read_line(&mut head)?;
+        if line.is_empty() {
+            break;
+        }
+        match line.split_once(':') {
+            Some((name, value)) => request.headers.push((name.trim().to_string(), value.trim().to_string())),
+            None => return Err(bad_request("Invalid header")),
+        }
+    }
+
+    let length = match request.get_header("Content-Length") {
+        Some(l) => l.parse::<usize>().map_err(|_| bad_request("Invalid Content-Length"))?,
+        None => 0,
+    };
+    if length > MAX_BODY {
+        return Err(Response::text(413, "Request body too large"));
+    }
+    request.body = vec![0; length];
+    let reader = head.into_inner();
+    if reader.read_exact(&mut request.body).is_err() {
+        return Err(bad_request("Incomplete body"));
+    }
+    Ok(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    /// Answers with the method, path, query and body of the request
+    fn echo() -> Handler {
+        Arc::new(|request: &Request| Response::json(200, &json!({
+            "method": request.method,
+            "path": request.path,
+            "query": request.query,
+            "type": request.get_header("content-type"),
+            "body": String::from_utf8_lossy(&request.body),
+        })))
+    }
+
+    /// Sends the bytes, closing the connection for writing, and returns the status and the body of the response.
+    fn send(address: &SocketAddr, request: &[u8]) -> (u16, String) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT)).unwrap();
+        stream.write_all(request).unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        read_response(stream)
+    }
+
+    fn read_response(mut stream: TcpStream) -> (u16, String) {
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse::<u16>().unwrap();
+        (status, body.to_string())
+    }
+
+    #[test]
+    fn answers_requests() {
+        let server = serve_tcp("127.0.0.1:0", echo()).unwrap();
+        let address = server.get_address().unwrap();
+        let (status, body) = send(&address, b"GET /flows?limit=10 HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert_eq!(status, 200);
+        assert_eq!(serde_json::from_str::<Value>(&body).unwrap(), json!({ "method": "GET", "path": "/flows", "query": "limit=10", "type": null, "body": "" }));
+
+        let (status, body) = send(&address, b"PUT /timeout HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: 15\r\n\r\n{\"timeout\": 10}");
+        assert_eq!(status, 200);
+        let body = serde_json::from_str::<Value>(&body).unwrap();
+        assert_eq!((&body["type"], &body["body"]), (&json!("application/json"), &json!("{\"timeout\": 10}")));
+    }
+
+    #[test]
+    fn rejects_invalid_requests() {
+        let server = serve_tcp("127.0.0.1:0", echo()).unwrap();
+        let address = server.get_address().unwrap();
+        assert_eq!(send(&address, b"GET /\r\n\r\n").0, 400);
+        assert_eq!(send(&address, b"GET / HTTP/1.1\r\nno colon\r\n\r\n").0, 400);
+        assert_eq!(send(&address, b"POST / HTTP/1.1\r\nContent-Length: ten\r\n\r\n").0, 400);
+        assert_eq!(send(&address, b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort").0, 400);
+        assert_eq!(send(&address, format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY + 1).as_bytes()).0, 413);
+    }
+
+    #[test]
+    fn stops_reading_an_endless_head() {
+        let server = serve_tcp("127.0.0.1:0", echo()).unwrap();
+        let address = server.get_address().unwrap();
+        // A request line that never ends
+        let (status, body) = send(&address, &vec![b'a'; MAX_HEAD]);
+        assert_eq!((status, body.as_str()), (413, "Request head too large"));
+        // Headers adding up to more than the limit
+        let mut request = b"GET / HTTP/1.1\r\n".to_vec();
+        while request.len() < MAX_HEAD {
+            request.extend_from_slice(b"X-Padding: aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\r\n");
+        }
+        request.truncate(MAX_HEAD);
+        assert_eq!(send(&address, &request).0, 413);
+    }
+
+    #[test]
+    fn refuses_connections_beyond_the_limit() {
+        let server = serve_tcp("127.0.0.1:0", echo()).unwrap();
+        let address = server.get_address().unwrap();
+        // Clients that connect and say nothing hold a connection each
+        let idle = (0..MAX_CONNECTIONS).map(|_| TcpStream::connect(address).unwrap()).collect::<Vec<TcpStream>>();
+        // The next one is answered before it sends its request
+        let refused = TcpStream::connect(address).unwrap();
+        refused.set_read_timeout(Some(CLIENT_TIMEOUT)).unwrap();
+        let (status, body) = read_response(refused);
+        assert_eq!((status, body.as_str()), (503, "Too many connections"));
+
+        // Their connections are released once they leave
+        for stream in idle.iter() {
+            stream.shutdown(std::net::Shutdown::Write).unwrap();
+        }
+        for stream in idle {
+            assert_eq!(read_response(stream).0, 400);
+        }
+        let start = std::time::Instant::now();
+        while send(&address, b"GET / HTTP/1.1\r\n\r\n").0 != 200 {
+            assert!(start.elapsed() < CLIENT_TIMEOUT);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn answers_requests_on_a_unix_socket() {
+        use std::os::unix::net::UnixStream;
+        let path = std::env::temp_dir().join(format!("network_analyzer-{}-http.sock", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        // A stale socket file is replaced
+        std::fs::write(&path, b"").unwrap();
+        let server = serve_unix(&path, echo()).unwrap();
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream.write_all(b"DELETE /alerts HTTP/1.0\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("\"method\":\"DELETE\",\"path\":\"/alerts\",\"query\":\"\",\"type\":null}"), "{}", response);
+        // The socket file is removed with the server
+        drop(server);
+        assert!(std::fs::metadata(&path).is_err());
+    }
+
+    #[test]
+    fn closes_the_listener_when_stopped() {
+        let mut server = serve_tcp("127.0.0.1:0", echo()).unwrap();
+        let address = server.get_address().unwrap();
+        assert_eq!(send(&address, b"GET / HTTP/1.1\r\n\r\n").0, 200);
+        server.stop();
+        assert!(TcpStream::connect(address).is_err());
+        // The address can be bound again
+        let server = serve_tcp(&address.to_string(), echo()).unwrap();
+        assert_eq!(send(&server.get_address().unwrap(), b"GET / HTTP/1.1\r\n\r\n").0, 200);
+    }
+}
@@ -0,0 +1,587 @@
+use std::fmt;
+use crate::dissector::{Dissector, DissectorContext, TransportProtocol};
+use crate::packet::{FieldType, FieldValue, Packet};
+use crate::to_hex_string;
+
+#[derive(Debug, Clone, PartialEq)]
+/// Represents a decoded ICMP or ICMPv6 message
+pub struct IcmpInfo {
+    /// The IP version the message belongs to (4 or 6)
+    pub version: u8,
+    /// The raw ICMP type
+    pub icmp_type: u8,
+    /// The raw ICMP code
+    pub code: u8,
+    /// The decoded body of the message
+    pub kind: IcmpKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// The decoded body of an ICMP message
+pub enum IcmpKind {
+    EchoRequest { id: u16, seq: u16 },
+    EchoReply { id: u16, seq: u16 },
+    DestinationUnreachable { reason: String, original: Option<OriginalPacket> },
+    TimeExceeded { reason: String, original: Option<OriginalPacket> },
+    PacketTooBig { mtu: u32, original: Option<OriginalPacket> },
+    ParameterProblem { pointer: u32, original: Option<OriginalPacket> },
+    Redirect { gateway: String, original: Option<OriginalPacket> },
+    RouterSolicitation,
+    RouterAdvertisement { hop_limit: u8, lifetime: u16, managed: bool, other: bool, prefixes: Vec<String> },
+    NeighborSolicitation { target: String },
+    NeighborAdvertisement { target: String, router: bool, solicited: bool, override_flag: bool },
+    MulticastListenerQuery { group: String },
+    MulticastListenerReport { groups: Vec<String> },
+    MulticastListenerDone { group: String },
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// The 5-tuple of the packet embedded in an ICMP error message
+pub struct OriginalPacket {
+    pub source: String,
+    pub destination: String,
+    pub source_port: Option<String>,
+    pub destination_port: Option<String>,
+    pub protocol: String,
+}
+
+impl IcmpInfo {
+    /// Gets the embedded original packet if the message is an error.
+    pub fn get_original(&self) -> Option<&OriginalPacket> {
+        match &self.kind {
+            IcmpKind::DestinationUnreachable { original, .. } |
+            IcmpKind::TimeExceeded { original, .. } |
+            IcmpKind::PacketTooBig { original, .. } |
+            IcmpKind::ParameterProblem { original, .. } |
+            IcmpKind::Redirect { original, .. } => original.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the message reports an error about another packet.
+    pub fn is_error(&self) -> bool {
+        matches!(self.kind,
+            IcmpKind::DestinationUnreachable { .. } |
+            IcmpKind::TimeExceeded { .. } |
+            IcmpKind::PacketTooBig { .. } |
+            IcmpKind::ParameterProblem { .. })
+    }
+
+    /// Gets the name of the message type, e.g. "Echo request".
+    pub fn get_name(&self) -> String {
+        match &self.kind {
+            IcmpKind::EchoRequest { .. } => String::from("Echo request"),
+            IcmpKind::EchoReply { .. } => String::from("Echo reply"),
+            IcmpKind::DestinationUnreachable { reason, .. } => reason.clone(),
+            IcmpKind::TimeExceeded { reason, .. } => reason.clone(),
+            IcmpKind::PacketTooBig { .. } => String::from("Packet too big"),
+            IcmpKind::ParameterProblem { .. } => String::from("Parameter problem"),
+            IcmpKind::Redirect { .. } => String::from("Redirect"),
+            IcmpKind::RouterSolicitation => String::from("Router solicitation"),
+            IcmpKind::RouterAdvertisement { .. } => String::from("Router advertisement"),
+            IcmpKind::NeighborSolicitation { .. } => String::from("Neighbor solicitation"),
+            IcmpKind::NeighborAdvertisement { .. } => String::from("Neighbor advertisement"),
+            IcmpKind::MulticastListenerQuery { .. } => String::from("Multicast listener query"),
+            IcmpKind::MulticastListenerReport { .. } => String::from("Multicast listener report"),
+            IcmpKind::MulticastListenerDone { .. } => String::from("Multicast listener done"),
+            IcmpKind::Other => format!("Type {} code {}", self.icmp_type, self.code),
+        }
+    }
+}
+
+impl fmt::Display for IcmpInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.get_name())?;
+        match &self.kind {
+            IcmpKind::EchoRequest { id, seq } | IcmpKind::EchoReply { id, seq } =>
+                write!(f, " id={} seq={}", id, seq)?,
+            IcmpKind::PacketTooBig { mtu, .. } =>
+                write!(f, " mtu={}", mtu)?,
+            IcmpKind::ParameterProblem { pointer, .. } =>
+                write!(f, " pointer={}", pointer)?,
+            IcmpKind::Redirect { gateway, .. } =>
+                write!(f, " to {}", gateway)?,
+            IcmpKind::RouterAdvertisement { hop_limit, lifetime, managed, other, prefixes } => {
+                write!(f, " hop_limit={} lifetime={}s", hop_limit, lifetime)?;
+                if *managed {
+                    write!(f, " M")?;
+                }
+                if *other {
+                    write!(f, " O")?;
+                }
+                if !prefixes.is_empty() {
+                    write!(f, " prefixes={}", prefixes.join(","))?;
+                }
+            }
+            IcmpKind::NeighborSolicitation { target } =>
+                write!(f, " who has {}", target)?,
+            IcmpKind::NeighborAdvertisement { target, router, solicited, override_flag } => {
+                write!(f, " {}", target)?;
+                if *router {
+                    write!(f, " R")?;
+                }
+                if *solicited {
+                    write!(f, " S")?;
+                }
+                if *override_flag {
+                    write!(f, " O")?;
+                }
+            }
+            IcmpKind::MulticastListenerQuery { group } | IcmpKind::MulticastListenerDone { group } =>
+                write!(f, " {}", group)?,
+            IcmpKind::MulticastListenerReport { groups } =>
+                write!(f, " {}", groups.join(","))?,
+            _ => {}
+        }
+        if let Some(original) = self.get_original() {
+            write!(f, " for {}", original)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for OriginalPacket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}{} -> {}{}",
+               self.protocol,
+               self.source,
+               match self.source_port {
+                   Some(ref port) => ":".to_owned() + port,
+                   None => "".to_owned(),
+               },
+               self.destination,
+               match self.destination_port {
+                   Some(ref port) => ":".to_owned() + port,
+                   None => "".to_owned(),
+               })
+    }
+}
+
+/// Dissector for ICMP and ICMPv6 messages
+pub struct IcmpDissector;
+
+impl Dissector for IcmpDissector {
+    fn name(&self) -> &str {
+        "ICMP"
+    }
+
+    fn heuristic(&self, context: &DissectorContext) -> bool {
+        context.transport == TransportProtocol::Icmpv4 || context.transport == TransportProtocol::Icmpv6
+    }
+
+    fn dissect(&self, context: &mut DissectorContext, packet: &mut Packet) -> bool {
+        let icmp = match context.transport {
+            TransportProtocol::Icmpv4 => decode_icmpv4(context.payload),
+            TransportProtocol::Icmpv6 => decode_icmpv6(context.payload),
+            _ => None,
+        };
+        let icmp = match icmp {
+            Some(icmp) => icmp,
+            None => return false,
+        };
+        packet.add_field("icmp.type", FieldValue::Int(i64::from(icmp.icmp_type)));
+        packet.add_field("icmp.code", FieldValue::Int(i64::from(icmp.code)));
+        match &icmp.kind {
+            IcmpKind::EchoRequest { id, seq } | IcmpKind::EchoReply { id, seq } => {
+                packet.add_field("icmp.id", FieldValue::Int(i64::from(*id)));
+                packet.add_field("icmp.seq", FieldValue::Int(i64::from(*seq)));
+            }
+            IcmpKind::NeighborSolicitation { target } | IcmpKind::NeighborAdvertisement { target, .. } =>
+                packet.add_field("icmp.nd.target", FieldValue::Str(target.clone())),
+            _ => {}
+        }
+        packet.set_info(icmp.to_string());
+        packet.set_icmp(Some(icmp));
+        true
+    }
+
+    fn fields(&self) -> Vec<(String, FieldType)> {
+        vec![
+            (String::from("icmp.type"), FieldType::Int),
+            (String::from("icmp.code"), FieldType::Int),
+            (String::from("icmp.id"), FieldType::Int),
+            (String::from("icmp.seq"), FieldType::Int),
+            (String::from("icmp.nd.target"), FieldType::Address),
+        ]
+    }
+}
+
+/// Decodes an ICMPv4 message starting from its type byte.
+pub fn decode_icmpv4(bytes: &[u8]) -> Option<IcmpInfo> {
+    if bytes.len() < 8 {
+        return None;
+    }
+    let icmp_type = bytes[0];
+    let code = bytes[1];
+    let rest = &bytes[8..];
+    let kind = match icmp_type {
+        0 => IcmpKind::EchoReply { id: read_u16(bytes, 4), seq: read_u16(bytes, 6) },
+        8 => IcmpKind::EchoRequest { id: read_u16(bytes, 4), seq: read_u16(bytes, 6) },
+        3 => {
+            let reason = match code {
+                0 => "Network unreachable",
+                1 => "Host unreachable",
+                2 => "Protocol unreachable",
+                3 => "Port unreachable",
+                4 => "Fragmentation needed",
+                5 => "Source route failed",
+                6 => "Destination network unknown",
+                7 => "Destination host unknown",
+                9 | 10 => "Communication administratively prohibited",
+                13 => "Communication administratively filtered",
+                _ => "Destination unreachable",
+            };
+            IcmpKind::DestinationUnreachable { reason: String::from(reason), original: decode_original_ipv4(rest) }
+        }
+        11 => {
+            let reason = match code {
+                0 => "TTL exceeded in transit",
+                1 => "Fragment reassembly time exceeded",
+                _ => "Time exceeded",
+            };
+            IcmpKind::TimeExceeded { reason: String::from(reason), original: decode_original_ipv4(rest) }
+        }
+        12 => IcmpKind::ParameterProblem { pointer: u32::from(bytes[4]), original: decode_original_ipv4(rest) },
+        5 => IcmpKind::Redirect { gateway: ipv4_to_string(&bytes[4..8]), original: decode_original_ipv4(rest) },
+        10 => IcmpKind::RouterSolicitation,
+        _ => IcmpKind::Other,
+    };
+    Some(IcmpInfo { version: 4, icmp_type, code, kind })
+}
+
+/// Decodes an ICMPv6 message starting from its type byte.
+pub fn decode_icmpv6(bytes: &[u8]) -> Option<IcmpInfo> {
+    if bytes.len() < 8 {
+        return None;
+    }
+    let icmp_type = bytes[0];
+    let code = bytes[1];
+    let rest = &bytes[8..];
+    let kind = match icmp_type {
+        1 => {
+            let reason = match code {
+                0 => "No route to destination",
+                1 => "Communication administratively prohibited",
+                2 => "Beyond scope of source address",
+                3 => "Address unreachable",
+                4 => "Port unreachable",
+                5 => "Source address failed policy",
+                6 => "Reject route to destination",
+                _ => "Destination unreachable",
+            };
+            IcmpKind::DestinationUnreachable { reason: String::from(reason), original: decode_original_ipv6(rest) }
+        }
+        2 => IcmpKind::PacketTooBig { mtu: read_u32(bytes, 4), original: decode_original_ipv6(rest) },
+        3 => {
+            let reason = match code {
+                0 => "Hop limit exceeded in transit",
+                1 => "Fragment reassembly time exceeded",
+                _ => "Time exceeded",
+            };
+            IcmpKind::TimeExceeded { reason: String::from(reason), original: decode_original_ipv6(rest) }
+        }
+        4 => IcmpKind::ParameterProblem { pointer: read_u32(bytes, 4), original: decode_original_ipv6(rest) },
+        128 => IcmpKind::EchoRequest { id: read_u16(bytes, 4), seq: read_u16(bytes, 6) },
+        129 => IcmpKind::EchoReply { id: read_u16(bytes, 4), seq: read_u16(bytes, 6) },
+        130 => IcmpKind::MulticastListenerQuery { group: ipv6_at(bytes, 8).unwrap_or_default() },
+        131 => IcmpKind::MulticastListenerReport { groups: ipv6_at(bytes, 8).into_iter().collect() },
+        132 => IcmpKind::MulticastListenerDone { group: ipv6_at(bytes, 8).unwrap_or_default() },
+        133 => IcmpKind::RouterSolicitation,
+        134 => {
+            let mut prefixes = Vec::new();
+            // Options start after the 16 byte router advertisement header
+            for (option_type, option) in ndp_options(bytes, 16) {
+                // Prefix information: prefix length at offset 2 and prefix at offset 16
+                if option_type == 3 && option.len() >= 32 {
+                    if let Some(prefix) = ipv6_at(option, 16) {
+                        prefixes.push(format!("{}/{}", prefix, option[2]));
+                    }
+                }
+            }
+            IcmpKind::RouterAdvertisement {
+                hop_limit: bytes[4],
+                lifetime: read_u16(bytes, 6),
+                managed: bytes[5] & 0x80 != 0,
+                other: bytes[5] & 0x40 != 0,
+                prefixes,
+            }
+        }
+        135 => IcmpKind::NeighborSolicitation { target: ipv6_at(bytes, 8).unwrap_or_default() },
+        136 => IcmpKind::NeighborAdvertisement {
+            target: ipv6_at(bytes, 8).unwrap_or_default(),
+            router: bytes[4] & 0x80 != 0,
+            solicited: bytes[4] & 0x40 != 0,
+            override_flag: bytes[4] & 0x20 != 0,
+        },
+        137 => IcmpKind::Redirect { gateway: ipv6_at(bytes, 8).unwrap_or_default(), original: None },
+        143 => {
+            // MLDv2 report: a list of multicast address records after the 8 byte header
+            let mut groups = Vec::new();
+            let mut offset = 8;
+            for _ in 0..read_u16(bytes, 6) {
+                if offset + 20 > bytes.len() {
+                    break;
+                }
+                let aux_len = usize::from(bytes[offset + 1]) * 4;
+                let sources = usize::from(read_u16(bytes, offset + 2));
+                if let Some(group) = ipv6_at(bytes, offset + 4) {
+                    groups.push(group);
+                }
+                offset += 20 + sources * 16 + aux_len;
+            }
+            IcmpKind::MulticastListenerReport { groups }
+        }
+        _ => IcmpKind::Other,
+    };
+    Some(IcmpInfo { version: 6, icmp_type, code, kind })
+}
+
+/// Decodes the IPv4 header and the first 8 bytes of transport carried by an ICMP error.
+fn decode_original_ipv4(bytes: &[u8]) -> Option<OriginalPacket> {
+    if bytes.len() < 20 || bytes[0] >> 4 != 4 {
+        return None;
+    }
+    let header_len = usize::from(bytes[0] & 0x0F) * 4;
+    decode_original_transport(
+        bytes[9],
+        ipv4_to_string(&bytes[12..16]),
+        ipv4_to_string(&bytes[16..20]),
+        bytes.get(header_len..).unwrap_or_default(),
+    )
+}
+
+/// Decodes the IPv6 header and the first bytes of transport carried by an ICMPv6 error.
+fn decode_original_ipv6(bytes: &[u8]) -> Option<OriginalPacket> {
+    if bytes.len() < 40 || bytes[0] >> 4 != 6 {
+        return None;
+    }
+    decode_original_transport(
+        bytes[6],
+        ipv6_at(bytes, 8)?,
+        ipv6_at(bytes, 24)?,
+        &bytes[40..],
+    )
+}
+
+fn decode_original_transport(protocol: u8, source: String, destination: String, transport: &[u8]) -> Option<OriginalPacket> {
+    let protocol_name = match protocol {
+        1 => String::from("ICMPv4"),
+        6 => String::from("TCP"),
+        17 => String::from("UDP"),
+        58 => String::from("ICMPv6"),
+        _ => String::from("Unknown"),
+    };
+    let (source_port, destination_port) = match protocol {
+        6 | 17 if transport.len() >= 4 =>
+            (Some(read_u16(transport, 0).to_string()), Some(read_u16(transport, 2).to_string())),
+        _ => (None, None),
+    };
+    Some(OriginalPacket {
+        source,
+        destination,
+        source_port,
+        destination_port,
+        protocol: protocol_name,
+    })
+}
+
+/// Iterates over the type-length-value options of a Neighbor Discovery message.
+fn ndp_options(bytes: &[u8], start: usize) -> Vec<(u8, &[u8])> {
+    let mut options = Vec::new();
+    let mut offset = start;
+    while offset + 2 <= bytes.len() {
+        let len = usize::from(bytes[offset + 1]) * 8;
+        if len == 0 || offset + len > bytes.len() {
+            break;
+        }
+        options.push((bytes[offset], &bytes[offset..offset + len]));
+        offset += len;
+    }
+    options
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    match bytes.get(offset..offset + 2) {
+        Some(b) => u16::from_be_bytes([b[0], b[1]]),
+        None => 0,
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    match bytes.get(offset..offset + 4) {
+        Some(b) => u32::from_be_bytes([b[0], b[1], b[2], b[3]]),
+        None => 0,
+    }
+}
+
+fn ipv4_to_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|it| { it.to_string() }).collect::<Vec<String>>().join(".")
+}
+
+/// Formats the IPv6 address at the given offset the same way the report does.
+fn ipv6_at(bytes: &[u8], offset: usize) -> Option<String> {
+    bytes.get(offset..offset + 16).map(|b| to_hex_string(4, b.to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use libc::c_long;
+    use crate::report::ShardedReport;
+    use super::*;
+
+    const FE80_1: [u8; 16] = [0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+    const FF02_FB: [u8; 16] = [0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xfb];
+
+    /// The IPv4 header and UDP ports of a datagram from 10.0.0.1:5000 to 10.0.0.2:53
+    fn original_udp() -> Vec<u8> {
+        let mut bytes = vec![0x45, 0, 0, 40, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2];
+        bytes.extend_from_slice(&[0x13, 0x88, 0, 53, 0, 20, 0, 0]);
+        bytes
+    }
+
+    fn port_unreachable() -> Vec<u8> {
+        let mut bytes = vec![3, 3, 0, 0, 0, 0, 0, 0];
+        bytes.extend(original_udp());
+        bytes
+    }
+
+    fn packet(source: &str, destination: &str, ports: Option<(&str, &str)>, protocol: &str, icmp: Option<IcmpInfo>, microsecond: c_long) -> Packet {
+        let (source_port, destination_port) = match ports {
+            Some((s, d)) => (Some(s.to_string()), Some(d.to_string())),
+            None => (None, None),
+        };
+        let mut packet = Packet::new(String::new(), source.to_string(), destination.to_string(), source_port, destination_port, protocol.to_string(), 64, String::new());
+        packet.set_timestamp(&100, &microsecond);
+        packet.set_icmp(icmp);
+        packet
+    }
+
+    #[test]
+    fn decodes_icmpv4_echoes() {
+        let request = decode_icmpv4(&[8, 0, 0, 0, 0x12, 0x34, 0, 7]).unwrap();
+        assert_eq!((request.version, request.icmp_type, request.code), (4, 8, 0));
+        assert_eq!(request.kind, IcmpKind::EchoRequest { id: 0x1234, seq: 7 });
+        assert_eq!(request.to_string(), "Echo request id=4660 seq=7");
+        let reply = decode_icmpv4(&[0, 0, 0, 0, 0x12, 0x34, 0, 7]).unwrap();
+        assert_eq!(reply.kind, IcmpKind::EchoReply { id: 0x1234, seq: 7 });
+    }
+
+    #[test]
+    fn decodes_the_packet_embedded_in_an_icmpv4_error() {
+        let icmp = decode_icmpv4(&port_unreachable()).unwrap();
+        assert!(icmp.is_error());
+        assert_eq!(icmp.get_name(), "Port unreachable");
+        assert_eq!(icmp.get_original(), Some(&OriginalPacket {
+            source: String::from("10.0.0.1"),
+            destination: String::from("10.0.0.2"),
+            source_port: Some(String::from("5000")),
+            destination_port: Some(String::from("53")),
+            protocol: String::from("UDP"),
+        }));
+        assert_eq!(icmp.to_string(), "Port unreachable for UDP 10.0.0.1:5000 -> 10.0.0.2:53");
+    }
+
+    #[test]
+    fn decodes_neighbor_discovery() {
+        let mut solicitation = vec![135, 0, 0, 0, 0, 0, 0, 0];
+        solicitation.extend_from_slice(&FE80_1);
+        let icmp = decode_icmpv6(&solicitation).unwrap();
+        assert_eq!(icmp.kind, IcmpKind::NeighborSolicitation { target: String::from("FE80:0000:0000:0000:0000:0000:0000:0001") });
+
+        let mut advertisement = vec![136, 0, 0, 0, 0xE0, 0, 0, 0];
+        advertisement.extend_from_slice(&FE80_1);
+        let icmp = decode_icmpv6(&advertisement).unwrap();
+        assert_eq!(icmp.to_string(), "Neighbor advertisement FE80:0000:0000:0000:0000:0000:0000:0001 R S O");
+
+        // Router advertisement with a prefix information option for 2001:db8::/64
+        let mut router = vec![134, 0, 0, 0, 64, 0xC0, 0x07, 0x08, 0, 0, 0, 0, 0, 0, 0, 0];
+        router.extend_from_slice(&[3, 4, 64, 0xC0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        router.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let icmp = decode_icmpv6(&router).unwrap();
+        assert_eq!(icmp.kind, IcmpKind::RouterAdvertisement {
+            hop_limit: 64,
+            lifetime: 1800,
+            managed: true,
+            other: true,
+            prefixes: vec![String::from("2001:0DB8:0000:0000:0000:0000:0000:0000/64")],
+        });
+    }
+
+    #[test]
+    fn decodes_mldv2_reports() {
+        // Two records, the first one with a source and 4 bytes of auxiliary data
+        let mut report = vec![143, 0, 0, 0, 0, 0, 0, 2];
+        report.extend_from_slice(&[4, 1, 0, 1]);
+        report.extend_from_slice(&FF02_FB);
+        report.extend_from_slice(&FE80_1);
+        report.extend_from_slice(&[0, 0, 0, 0]);
+        report.extend_from_slice(&[2, 0, 0, 0]);
+        report.extend_from_slice(&[0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x0c]);
+        let icmp = decode_icmpv6(&report).unwrap();
+        assert_eq!(icmp.kind, IcmpKind::MulticastListenerReport { groups: vec![
+            String::from("FF02:0000:0000:0000:0000:0000:0000:00FB"),
+            String::from("FF02:0000:0000:0000:0000:0000:0000:000C"),
+        ] });
+    }
+
+    #[test]
+    fn tolerates_truncated_messages() {
+        assert_eq!(decode_icmpv4(&[8, 0, 0, 0, 0, 1, 0]), None);
+        assert_eq!(decode_icmpv6(&[]), None);
+        // An error whose embedded header is cut
+        let unreachable = port_unreachable();
+        let icmp = decode_icmpv4(&unreachable[..20]).unwrap();
+        assert_eq!(icmp.get_name(), "Port unreachable");
+        assert_eq!(icmp.get_original(), None);
+        // The embedded ports are cut
+        let icmp = decode_icmpv4(&unreachable[..30]).unwrap();
+        assert_eq!(icmp.get_original().unwrap().source_port, None);
+        // A neighbor solicitation without its target
+        assert_eq!(decode_icmpv6(&[135, 0, 0, 0, 0, 0, 0, 0, 0xfe]).unwrap().kind, IcmpKind::NeighborSolicitation { target: String::new() });
+        // A MLDv2 report announcing more records than it carries
+        let mut report = vec![143, 0, 0, 0, 0, 0, 0, 3, 4, 0, 0, 0];
+        report.extend_from_slice(&FF02_FB);
+        report.extend_from_slice(&[4, 0, 0, 0, 0xff]);
+        assert_eq!(decode_icmpv6(&report).unwrap().kind, IcmpKind::MulticastListenerReport { groups: vec![String::from("FF02:0000:0000:0000:0000:0000:0000:00FB")] });
+    }
+
+    #[test]
+    fn matches_echo_replies_with_their_requests() {
+        let report = ShardedReport::new(4, 0);
+        let request = decode_icmpv4(&[8, 0, 0, 0, 0, 1, 0, 1]);
+        let reply = decode_icmpv4(&[0, 0, 0, 0, 0, 1, 0, 1]);
+        let other_reply = decode_icmpv4(&[0, 0, 0, 0, 0, 1, 0, 2]);
+        report.add_packet(&packet("10.0.0.1", "10.0.0.2", None, "ICMPv4", request, 0), |_, _, _| ());
+        report.add_packet(&packet("10.0.0.2", "10.0.0.1", None, "ICMPv4", other_reply, 1_000), |_, _, _| ());
+        report.add_packet(&packet("10.0.0.2", "10.0.0.1", None, "ICMPv4", reply.clone(), 2_500), |_, _, _| ());
+        // A second reply to the same request is not matched
+        report.add_packet(&packet("10.0.0.2", "10.0.0.1", None, "ICMPv4", reply, 9_000), |_, _, _| ());
+        let merged = report.merge();
+        let line = merged.report_lines.values().next().unwrap();
+        let rtt = line.rtt.as_ref().unwrap();
+        assert_eq!((rtt.count, rtt.min, rtt.max), (1, 2.5, 2.5));
+        assert_eq!(line.icmp_summary(), "rtt min/avg/max 2.500/2.500/2.500 ms (1)");
+    }
+
+    #[test]
+    fn links_unreachable_errors_to_their_flow() {
+        let report = ShardedReport::new(4, 0);
+        report.add_packet(&packet("10.0.0.1", "10.0.0.2", Some(("5000", "53")), "UDP", None, 0), |_, _, _| ());
+        report.add_packet(&packet("10.0.0.2", "10.0.0.1", None, "ICMPv4", decode_icmpv4(&port_unreachable()), 1_000), |_, _, _| ());
+        let merged = report.merge();
+        let flow = merged.report_lines.values().find(|rl| rl.protocols == vec![String::from("UDP")]).unwrap();
+        assert_eq!(flow.icmp_errors, vec![String::from("Port unreachable from 10.0.0.2")]);
+        let icmp = merged.report_lines.values().find(|rl| rl.protocols == vec![String::from("ICMPv4")]).unwrap();
+        assert!(icmp.icmp_errors.is_empty());
+    }
+
+    #[test]
+    fn keeps_errors_about_uncaptured_flows_on_their_own_line() {
+        let report = ShardedReport::new(4, 0);
+        report.add_packet(&packet("10.0.0.2", "10.0.0.1", None, "ICMPv4", decode_icmpv4(&port_unreachable()), 0), |_, _, _| ());
+        let merged = report.merge();
+        assert_eq!(merged.report_lines.len(), 1);
+        assert_eq!(merged.report_lines.values().next().unwrap().icmp_errors, vec![String::from("Port unreachable from 10.0.0.2")]);
+    }
+}
@@ -2,48 +2,162 @@
 //!
 //! # Input
 //! The input is a Parameters struct that contains the following information:
-//! * device_id: The id of the network device to capture from
+//! * device: The network device to capture from, by position, name, description, IP or MAC address (see the device module)
+//! * devices: The network devices to capture from at the same time, instead of device
+//! * group_by_interface: Whether the report has a line for each device a communication is seen on
 //! * timeout: The time after which the capture stops
 //! * file_path: The path of the file where the captured packets will be saved
 //! * filter: An optional filter to be applied to the captured packets (in BPF format https://biot.com/capstats/bpf.html)
+//! * dissectors: The dissectors used to decode the application protocols (see the dissector module)
+//! * display_filter: An optional display filter evaluated on the dissected packets (see the display_filter module)
+//! * export_file: An optional pcap file where the packets that pass the display filter are saved
+//! * scan_thresholds: The thresholds of the port scan and host sweep detector (see the scan module)
+//! * dos_thresholds: The thresholds of the SYN flood and volumetric attack detector (see the dos module)
+//! * dns_thresholds: The thresholds of the DNS tunneling and generated domain detectors (see the dns_anomaly module)
+//! * rules_file: An optional file of signatures matched against every packet (see the rules module)
+//! * sinks: The destinations of the alerts and errors: syslog, NDJSON file or webhook (see the sink module)
+//! * metrics_address: An optional address where /metrics is served in the Prometheus format (see the metrics module)
+//! * api_address: An optional TCP address or unix socket where the control API is served (see the api module)
+//! * packet_count: An optional number of packets after which the capture stops
+//! * capture_options: The snaplen, promiscuous mode, buffer size, immediate mode and read timeout of the capture
+//! * output_formats: The formats of the report: text, json or both
+//!
+//! The parameters can also be loaded from a named profile of a configuration file (see the config module).
+//! Several captures with their own parameters can run in the same process as named sessions
+//! (see the session module).
 //!
 //! # Output
 //! The output is written to a file in the following format:
-//! Timestamp first | Timestamp last | Address 1 | Address 2 | Protocols | Bytes Total
+//! Timestamp first | Timestamp last | Address 1 | Address 2 | Protocols | Bytes Total | ICMP
+//!
+//! The ICMP column contains the echo round trip times of the pair and the ICMP errors
+//! (unreachable, time exceeded, ...) that were sent back because of its packets.
+//!
+//! When capturing from several devices, an Interfaces column lists the devices each
+//! communication was seen on.
+//!
+//! When SSDP or mDNS traffic is captured, the table is followed by an inventory of the
+//! advertised devices and services:
+//! Protocol | Service Type | Name | Location | Details | Source | First Seen | Last Seen | Count
+//!
+//! # Alerts
+//! Every time the report is written, the communications are analyzed to find port scans and
+//! host sweeps. The rates of the packets sent to each destination are watched to find SYN
+//! floods, floods and amplification attacks, which are reported as soon as they start and
+//! again with their start, end and peak rate once they are over. The names queried through DNS are scored to find tunnels and
+//! algorithmically generated domains, reported with the hosts that queried them. Every packet
+//! is matched against the signatures of the rules file, each hit raising an alert with the rule
+//! id, its message and the frame number of the packet. The alerts raised are kept in the control
+//! block (see `ControlBlock::get_alerts`), the last 10 000 only, and, like the errors, delivered
+//! to the sinks. The workers queue their alerts without waiting, a full queue dropping them.
+//!
+//! # Events
+//! The decoded packets, the start, update and end of the flows, the state transitions and the
+//! errors can be received in-process by subscribing to the control block (see the subscription module).
+//! The report kept in memory can be copied at any time, or copied and reset for interval
+//! reporting (see the snapshot module).
+//! With the `async` feature, analyze_network_async gives them as streams to tokio users (see the async_api module).
 //!
 //! # Usage
 //! let control_block = analyze_network(Parameters {
-//!                 device_id: 1,
+//!                 device: DeviceSelector::parse("name:eth*"),
+//!                 devices: vec![],
+//!                 group_by_interface: false,
 //!                 timeout: 1,
 //!                 file_path: output.txt,
 //!                 filter: None,
+//!                 dissectors: DissectorRegistry::default(),
+//!                 display_filter: Some("dns.rcode != 0".to_string()),
+//!                 export_file: None,
+//!                 scan_thresholds: ScanThresholds::default(),
+//!                 dos_thresholds: DosThresholds::default(),
+//!                 dns_thresholds: DnsThresholds::default(),
+//!                 rules_file: Some("local.rules".to_string()),
+//!                 sinks: vec![SinkConfig::Ndjson("alerts.ndjson".to_string())],
+//!                 metrics_address: Some("127.0.0.1:9100".to_string()),
+//!                 api_address: Some("unix:///run/network_analyzer.sock".to_string()),
+//!                 packet_count: None,
+//!                 capture_options: CaptureOptions::default(),
+//!                 output_formats: vec![OutputFormat::Text],
 //!             });
-mod packet;
+//!
+//! analyze_network starts the capture right away. To control its lifecycle, build the control
+//! block and start it, it can be stopped and restarted:
+//!
+//! let control_block = Arc::new(ControlBlockBuilder::new().parameters(parameters).build()?);
+//! let handle = control_block.start()?;
+//! control_block.stop();
+//! handle.join();
+//! let handle = control_block.start()?;
+pub mod alert;
+pub mod api;
+#[cfg(feature = "async")]
+pub mod async_api;
+pub mod bpf;
+pub mod config;
+pub mod device;
+pub mod discovery;
+pub mod display_filter;
+pub mod dissector;
+pub mod dns;
+pub mod dns_anomaly;
+pub mod dos;
+pub mod http;
+pub mod icmp;
+pub mod metrics;
+pub mod packet;
 pub mod parameters;
 mod report;
+pub mod rules;
+pub mod scan;
+pub mod session;
+pub mod sink;
+pub mod snapshot;
+pub mod subscription;
+pub mod tls;
 
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
 use std::fmt::{Display, Formatter};
 use std::fs;
 use std::fs::{File, metadata};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::thread::JoinHandle;
 use etherparse::InternetSlice::{Ipv4, Ipv6};
 use etherparse::{SlicedPacket};
 use etherparse::LinkSlice::Ethernet2;
 use etherparse::TransportSlice::{Icmpv4, Icmpv6, Tcp, Udp, Unknown};
-use pcap::{Device, Capture, PacketHeader, Address, Active};
+use pcap::{Device, Capture, PacketHeader, Active, Savefile};
 use threadpool::ThreadPool;
-use crate::ConfigError::{InvalidDeviceId, InvalidFilter};
+use crate::alert::Alert;
+use crate::ConfigError::{InvalidDeviceId, InvalidDisplayFilter, InvalidFilter};
+use crate::device::{DeviceInfo, DeviceSelector};
+use crate::display_filter::{DisplayFilter, FilterError};
+use crate::dissector::{DissectorRegistry, TransportProtocol};
+use crate::dns_anomaly::{DnsAnomalyDetector, DnsThresholds};
+use crate::dos::{DosDetector, DosThresholds};
+use crate::http::{Handler, Request, Response, Server};
+use crate::metrics::Metrics;
 use crate::packet::Packet as MyPacket;
-use crate::parameters::Parameters;
+use crate::parameters::{CaptureOptions, OutputFormat, Parameters};
 use crate::report::Report;
+pub use crate::report::ShardedReport;
+use crate::rules::{RuleError, RuleSet};
+use crate::scan::{ScanDetector, ScanThresholds};
+use crate::sink::{AlertSink, Event, SinkDispatcher};
+use crate::snapshot::ReportSnapshot;
+use crate::subscription::{CaptureEvent, Subscriptions, Topic};
 
-#[derive(Eq, PartialEq, Clone)]
-/// There are 3 possible states:
-/// 1. The capture is running
-/// 2. The capture is paused
-/// 3. The capture is stopped
+#[derive(Debug, Eq, PartialEq, Clone)]
+/// There are 4 possible states:
+/// 1. The capture was built but not started yet
+/// 2. The capture is running
+/// 3. The capture is paused
+/// 4. The capture is stopped, it can be restarted
 pub enum CaptureState {
+    Idle(),
     Capturing(),
     Paused(),
     Stopped(),
@@ -53,6 +167,7 @@ pub enum CaptureState {
 pub enum SnifferError {
     ConfigError(ConfigError),
     CaptureError(CaptureError),
+    SinkError(sink::SinkError),
 }
 
 #[derive(Debug)]
@@ -61,6 +176,11 @@ pub enum ConfigError {
     InvalidTimeout(pcap::Error),
     InvalidFilePath(String),
     InvalidFilter(pcap::Error),
+    InvalidDisplayFilter(FilterError),
+    InvalidLinktype(pcap::Error),
+    InvalidRules(RuleError),
+    InvalidAddress(String),
+    InvalidProfile(String),
 }
 
 #[derive(Debug)]
@@ -68,6 +188,11 @@ pub enum CaptureError {
     DeviceError(pcap::Error),
     CaptureError(pcap::Error),
     FilterError(pcap::Error),
+    ReportError(std::io::Error),
+    /// The capture cannot go from its current state to the one requested
+    InvalidTransition(String),
+    /// A device is no longer read after failing too many times in a row
+    InterfaceStopped(String),
 }
 
 impl Display for SnifferError {
@@ -77,6 +202,8 @@ impl Display for SnifferError {
                 write!(f, "Error in configuration: {}", e),
             SnifferError::CaptureError(e) =>
                 write!(f, "Error in capture: {}", e),
+            SnifferError::SinkError(e) =>
+                write!(f, "Error in alert sink: {}", e),
         }
     }
 }
@@ -94,6 +221,16 @@ impl Display for ConfigError {
                 write!(f, "Invalid file path: {}", e),
             InvalidFilter(e) =>
                 write!(f, "Invalid filter: {}", e),
+            InvalidDisplayFilter(e) =>
+                write!(f, "Invalid display filter: {}", e),
+            ConfigError::InvalidLinktype(e) =>
+                write!(f, "Invalid datalink type: {}", e),
+            ConfigError::InvalidRules(e) =>
+                write!(f, "Invalid rules: {}", e),
+            ConfigError::InvalidAddress(e) =>
+                write!(f, "Invalid listen address: {}", e),
+            ConfigError::InvalidProfile(e) =>
+                write!(f, "Invalid profile: {}", e),
         }
     }
 }
@@ -109,69 +246,404 @@ impl Display for CaptureError {
                 write!(f, "Error capturing packets: {}", e),
             CaptureError::FilterError(e) =>
                 write!(f, "Error setting filter: {}", e),
+            CaptureError::ReportError(e) =>
+                write!(f, "Error writing the report: {}", e),
+            CaptureError::InvalidTransition(e) =>
+                write!(f, "Invalid state transition: {}", e),
+            CaptureError::InterfaceStopped(e) =>
+                write!(f, "Stopped capturing from {} after {} errors in a row", e, MAX_READ_ERRORS),
         }
     }
 }
 
 impl std::error::Error for CaptureError {}
 
+/// Shards of the report for each CPU, so that the workers rarely add packets to the same one
+const REPORT_SHARDS_PER_CPU: usize = 4;
+/// Errors in a row after which a device is no longer read
+const MAX_READ_ERRORS: u32 = 10;
+/// Longest pause between two reads of a device failing
+const MAX_READ_BACKOFF: std::time::Duration = std::time::Duration::from_secs(1);
+/// Alerts of the workers waiting to be recorded before the new ones are dropped
+const ALERT_QUEUE: usize = 1024;
+/// Alerts kept until they are cleared, the oldest being dropped beyond
+const MAX_ALERTS: usize = 10_000;
+
+/// A message to the thread recording the alerts of the workers
+enum AlertMessage {
+    Alert(Alert),
+    /// Answered once the alerts queued before are recorded
+    Flush(SyncSender<()>),
+}
+
+/// A device captured by the control block
+struct Interface {
+    name: String,
+    capture: Mutex<Capture<Active>>,
+}
+
 /// Controls the capture process
 pub struct ControlBlock {
     m: Mutex<CaptureState>,
     cv: Condvar,
     timeout: Mutex<u32>,
     output_file: Mutex<String>,
-    capture: Mutex<Capture<Active>>,
-    error_list: Mutex<VecDeque<SnifferError>>,
+    captures: Mutex<Vec<Arc<Interface>>>,
+    error_list: Arc<Mutex<VecDeque<SnifferError>>>,
+    dissectors: Mutex<DissectorRegistry>,
+    display_filter: Mutex<Option<Arc<DisplayFilter>>>,
+    export: Mutex<Option<Savefile>>,
+    /// Whether the packets are exported, read by the workers without locking export
+    exporting: AtomicBool,
+    alert_list: Arc<Mutex<VecDeque<Alert>>>,
+    /// The alerts raised by the workers, recorded by a thread of its own
+    alert_queue: SyncSender<AlertMessage>,
+    rules: Mutex<Option<Arc<RuleSet>>>,
+    sinks: Arc<SinkDispatcher>,
+    metrics: Metrics,
+    report: Arc<ShardedReport>,
+    finished: Mutex<bool>,
+    finished_cv: Condvar,
+    capture_options: Mutex<CaptureOptions>,
+    output_formats: Mutex<Vec<OutputFormat>>,
+    scan_thresholds: ScanThresholds,
+    dos_thresholds: DosThresholds,
+    dns_thresholds: DnsThresholds,
+    packet_count: Option<u64>,
+    metrics_address: Option<String>,
+    api_address: Option<String>,
+    /// The servers of the metrics and of the API, started by start and closed by stop
+    servers: Mutex<Option<Vec<Server>>>,
+    /// The number of times the capture was started, so that the writer of a previous run exits
+    run: AtomicU64,
+    subscriptions: Arc<Subscriptions>,
+}
+
+/// Waits for the threads of a capture started by `ControlBlock::start`
+pub struct CaptureHandle {
+    reader: JoinHandle<()>,
+    writer: JoinHandle<()>,
+}
+
+impl CaptureHandle {
+    /// Blocks until the capture is stopped, its last report is written and the report writer exited.
+    pub fn join(self) {
+        let _ = self.reader.join();
+        let _ = self.writer.join();
+    }
+
+    /// Whether both the capture thread and the report writer exited.
+    pub fn is_finished(&self) -> bool {
+        self.reader.is_finished() && self.writer.is_finished()
+    }
+}
+
+#[derive(Default)]
+/// Builds a control block from the parameters of the capture, opening its devices.
+///
+/// ## Example
+///
+/// let control_block = Arc::new(ControlBlockBuilder::new().device(DeviceSelector::parse("name:eth0")).output_file("report.txt").build()?);
+/// let handle = control_block.start()?;
+/// ...
+/// control_block.stop();
+/// handle.join();
+pub struct ControlBlockBuilder {
+    parameters: Parameters,
+}
+
+impl ControlBlockBuilder {
+    pub fn new() -> Self {
+        ControlBlockBuilder::default()
+    }
+
+    /// Replaces all the parameters of the capture.
+    pub fn parameters(mut self, parameters: Parameters) -> Self {
+        self.parameters = parameters;
+        self
+    }
+
+    pub fn device(mut self, device: DeviceSelector) -> Self {
+        self.parameters.set_device(device);
+        self
+    }
+
+    pub fn add_device(mut self, device: DeviceSelector) -> Self {
+        self.parameters.add_device(device);
+        self
+    }
+
+    pub fn timeout(mut self, timeout: u32) -> Self {
+        self.parameters.set_timeout(timeout);
+        self
+    }
+
+    pub fn output_file(mut self, file_path: &str) -> Self {
+        self.parameters.set_file_path(file_path.to_string());
+        self
+    }
+
+    pub fn filter(mut self, filter: &str) -> Self {
+        self.parameters.set_protocol(filter.to_string());
+        self
+    }
+
+    pub fn display_filter(mut self, display_filter: &str) -> Self {
+        self.parameters.set_display_filter(display_filter.to_string());
+        self
+    }
+
+    pub fn packet_count(mut self, packet_count: u64) -> Self {
+        self.parameters.set_packet_count(packet_count);
+        self
+    }
+
+    pub fn capture_options(mut self, capture_options: CaptureOptions) -> Self {
+        self.parameters.set_capture_options(capture_options);
+        self
+    }
+
+    /// Opens the devices and checks the parameters. The control block is idle until it is started.
+    pub fn build(self) -> Result<ControlBlock, SnifferError> {
+        let parameters = self.parameters;
+        let mut captures: Vec<(String, Capture<Active>)> = Vec::new();
+        for selector in parameters.get_devices().iter() {
+            let device = match selector.select_device() {
+                Ok(d) => d,
+                Err(e) => return Err(SnifferError::ConfigError(e))
+            };
+            if captures.iter().any(|(name, _)| *name == device.name) {
+                return Err(SnifferError::ConfigError(InvalidDeviceId(pcap::Error::PcapError(format!("{} selected more than once", device.name)))));
+            }
+            let name = device.name.clone();
+            let mut cap = open_capture(device, &parameters.capture_options)?;
+
+            if let Some(filter) = &parameters.filter {
+                if let Err(e) = cap.filter(filter, true) {
+                    return Err(SnifferError::ConfigError(InvalidFilter(e)));
+                };
+            }
+            captures.push((name, cap));
+        }
+        build_control_block(parameters, captures)
+    }
+
+    /// Builds a control block without opening any device. It cannot be started: its report is
+    /// fed by a PacketProcessor with packets read from elsewhere, e.g. a pcap file.
+    pub fn build_offline(self) -> Result<ControlBlock, SnifferError> {
+        build_control_block(self.parameters, Vec::new())
+    }
+}
+
+/// Creates a control block with the parameters, capturing from the devices already opened.
+fn build_control_block(parameters: Parameters, captures: Vec<(String, Capture<Active>)>) -> Result<ControlBlock, SnifferError> {
+    let control_block = ControlBlock::new(&parameters);
+    if !parameters.file_path.is_empty() {
+        control_block.set_output_file(parameters.file_path)?;
+    }
+    if parameters.timeout != 0 {
+        control_block.set_timeout(parameters.timeout);
+    }
+    control_block.set_dissectors(parameters.dissectors);
+    control_block.set_capture_options(parameters.capture_options);
+    control_block.set_output_formats(parameters.output_formats);
+    control_block.report.set_group_by_interface(parameters.group_by_interface);
+    control_block.set_display_filter(parameters.display_filter)?;
+    control_block.set_rules_file(parameters.rules_file)?;
+    for sink in parameters.sinks.iter() {
+        match sink.open() {
+            Ok(s) => control_block.add_sink(s),
+            Err(e) => return Err(SnifferError::SinkError(e))
+        }
+    }
+    for (name, cap) in captures {
+        control_block.add_capture(name, cap);
+    }
+    control_block.set_export_file(parameters.export_file)?;
+    Ok(control_block)
 }
 
 impl ControlBlock {
-    fn new() -> Arc<ControlBlock> {
-        Arc::new(ControlBlock {
-            m: Mutex::new(CaptureState::Capturing()),
+    fn new(parameters: &Parameters) -> ControlBlock {
+        let error_list = Arc::new(Mutex::new(VecDeque::new()));
+        let subscriptions = Arc::new(Subscriptions::default());
+        let sink_errors = error_list.clone();
+        let sink_subscriptions = subscriptions.clone();
+        // The failure of a sink is recorded without delivering it to the sinks, which could fail again.
+        // The delivery threads stop when the control block is dropped.
+        let dispatcher = Arc::new(SinkDispatcher::start(move |e| {
+            sink_subscriptions.send(CaptureEvent::Error { timestamp_us: chrono::Utc::now().timestamp_micros(), message: e.to_string() });
+            let mut e_list = sink_errors.lock().unwrap();
+            e_list.push_back(SnifferError::SinkError(e));
+        }));
+        let alert_list = Arc::new(Mutex::new(VecDeque::new()));
+        let (alert_queue, alerts) = sync_channel(ALERT_QUEUE);
+        let (queue_list, queue_sinks) = (alert_list.clone(), dispatcher.clone());
+        // The thread stops when the control block is dropped.
+        std::thread::spawn(move || {
+            for message in alerts {
+                match message {
+                    AlertMessage::Alert(alert) => record_alert(&queue_list, &queue_sinks, alert),
+                    AlertMessage::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        });
+        ControlBlock {
+            m: Mutex::new(CaptureState::Idle()),
             cv: Condvar::new(),
             timeout: Mutex::new(5),
             output_file: Mutex::new(String::new()),
-            capture: Mutex::new(Capture::from_device(Device::lookup().unwrap().unwrap())
-                .unwrap()
-                .promisc(true)
-                .snaplen(5000)
-                .timeout(1000)
-                .open()
-                .unwrap()),
-            error_list: Mutex::new(VecDeque::new()),
-        })
+            captures: Mutex::new(Vec::new()),
+            error_list,
+            dissectors: Mutex::new(DissectorRegistry::default()),
+            display_filter: Mutex::new(None),
+            export: Mutex::new(None),
+            exporting: AtomicBool::new(false),
+            alert_list,
+            alert_queue,
+            rules: Mutex::new(None),
+            sinks: dispatcher,
+            metrics: Metrics::default(),
+            report: Arc::new(ShardedReport::new(num_cpus::get() * REPORT_SHARDS_PER_CPU, chrono::Utc::now().timestamp_micros())),
+            finished: Mutex::new(true),
+            finished_cv: Condvar::new(),
+            capture_options: Mutex::new(CaptureOptions::default()),
+            output_formats: Mutex::new(vec![OutputFormat::Text]),
+            scan_thresholds: parameters.scan_thresholds.clone(),
+            dos_thresholds: parameters.dos_thresholds.clone(),
+            dns_thresholds: parameters.dns_thresholds.clone(),
+            packet_count: parameters.packet_count,
+            metrics_address: parameters.metrics_address.clone(),
+            api_address: parameters.api_address.clone(),
+            servers: Mutex::new(None),
+            run: AtomicU64::new(0),
+            subscriptions,
+        }
     }
 
     /// Gets the current state of the capture.
     ///
-    /// There are 2 states:
+    /// There are 4 states:
+    /// - Idle
     /// - Capturing
     /// - Paused
+    /// - Stopped
     pub fn get_state(&self) -> CaptureState {
         let state = self.m.lock().unwrap();
         state.clone()
     }
 
-    /// Pauses the capture.
+    /// Starts the capture of an idle or stopped control block: a thread reads the devices and
+    /// another one writes the report every timeout seconds. After a stop, the capture goes on
+    /// adding to the same report. The metrics and the API are served until the capture is stopped.
+    pub fn start(self: &Arc<Self>) -> Result<CaptureHandle, SnifferError> {
+        let mut state = self.m.lock().unwrap();
+        match *state {
+            CaptureState::Idle() => (),
+            CaptureState::Stopped() if *self.finished.lock().unwrap() => (),
+            CaptureState::Stopped() => return Err(invalid_transition("the previous capture is still finishing")),
+            _ => return Err(invalid_transition("the capture is already started")),
+        }
+        if self.get_interface_count() == 0 {
+            return Err(invalid_transition("there is no device to capture from"));
+        }
+        let mut servers = self.servers.lock().unwrap();
+        if servers.is_none() {
+            // The servers already started are closed when another one fails
+            let mut started = Vec::new();
+            if let Some(address) = &self.metrics_address {
+                started.push(serve_metrics(self, address)?);
+            }
+            if let Some(address) = &self.api_address {
+                started.push(api::serve(self, address)?);
+            }
+            *servers = Some(started);
+        }
+        drop(servers);
+
+        let run = self.run.fetch_add(1, Ordering::SeqCst) + 1;
+        *self.finished.lock().unwrap() = false;
+        self.change_state(&mut state, CaptureState::Capturing());
+        drop(state);
+
+        let dos_detector = Arc::new(DosDetector::new(self.dos_thresholds.clone()));
+        let dns_detector = Arc::new(DnsAnomalyDetector::new(self.dns_thresholds.clone()));
+        let control_block = self.clone();
+        let scan_thresholds = self.scan_thresholds.clone();
+        let dos_detector_clone = dos_detector.clone();
+        let dns_detector_clone = dns_detector.clone();
+        let writer = std::thread::spawn(move || {
+            write_reports(control_block, run, scan_thresholds, dos_detector_clone, dns_detector_clone);
+        });
+        let control_block = self.clone();
+        let reader = std::thread::spawn(move || {
+            read_packets(control_block, dos_detector, dns_detector);
+        });
+        Ok(CaptureHandle { reader, writer })
+    }
+
+    /// Stops the capture, waits for its last report and starts it again.
+    pub fn restart(self: &Arc<Self>) -> Result<CaptureHandle, SnifferError> {
+        if self.get_state() == CaptureState::Idle() {
+            return Err(invalid_transition("the capture was never started"));
+        }
+        self.stop();
+        self.wait_finished();
+        self.start()
+    }
+
+    /// Pauses the capture, if it is capturing.
     pub fn pause(&self) {
         let mut state = self.m.lock().unwrap();
-        *state = CaptureState::Paused();
-        self.cv.notify_all();
+        if *state == CaptureState::Capturing() {
+            self.change_state(&mut state, CaptureState::Paused());
+        }
     }
 
-    /// Resumes the capture.
+    /// Resumes the capture, if it is paused.
     pub fn resume(&self) {
         let mut state = self.m.lock().unwrap();
-        *state = CaptureState::Capturing();
-        self.cv.notify_all();
+        if *state == CaptureState::Paused() {
+            self.change_state(&mut state, CaptureState::Capturing());
+        }
     }
 
-    /// Stops the capture.
+    /// Stops the capture and closes the servers of the metrics and of the API. The threads end
+    /// once the packets already captured are in the report, see `wait_finished`. An idle control
+    /// block is stopped too, e.g. an offline one once its packets are all processed.
     pub fn stop(&self) {
         let mut state = self.m.lock().unwrap();
-        *state = CaptureState::Stopped();
+        if *state != CaptureState::Stopped() {
+            self.change_state(&mut state, CaptureState::Stopped());
+        }
+        drop(state);
+        // Dropping the servers waits for their accept loops to exit
+        let servers = self.servers.lock().unwrap().take();
+        drop(servers);
+    }
+
+    /// Moves to a new state, waking up the threads waiting for it and notifying the subscribers.
+    fn change_state(&self, state: &mut CaptureState, to: CaptureState) {
+        let from = std::mem::replace(state, to.clone());
         self.cv.notify_all();
+        self.subscriptions.send(CaptureEvent::StateChanged { from, to });
+    }
+
+    /// Subscribes to the events of the topics, see the subscription module.
+    pub fn subscribe(&self, topics: &[Topic]) -> Receiver<CaptureEvent> {
+        self.subscriptions.subscribe(topics)
+    }
+
+    /// Calls the callback with every event of the topics, from a thread that stops with the control block.
+    pub fn subscribe_callback<F: Fn(CaptureEvent) + Send + 'static>(&self, topics: &[Topic], callback: F) {
+        let events = self.subscribe(topics);
+        std::thread::spawn(move || {
+            for event in events {
+                callback(event);
+            }
+        });
     }
 
     fn wait(&self) {
@@ -181,6 +653,38 @@ impl ControlBlock {
         }
     }
 
+    /// Blocks until the capture is stopped.
+    pub fn wait_stopped(&self) {
+        let mut state = self.m.lock().unwrap();
+        while *state != CaptureState::Stopped() {
+            state = self.cv.wait(state).unwrap();
+        }
+    }
+
+    /// Blocks until the run of the capture is stopped or the time is over, returns whether it is stopped.
+    fn wait_stopped_timeout(&self, run: u64, timeout: std::time::Duration) -> bool {
+        let state = self.m.lock().unwrap();
+        let (state, _) = self.cv.wait_timeout_while(state, timeout, |s| {
+            *s != CaptureState::Stopped() && self.run.load(Ordering::SeqCst) == run
+        }).unwrap();
+        *state == CaptureState::Stopped() || self.run.load(Ordering::SeqCst) != run
+    }
+
+    /// Blocks until the capture is stopped and the packets already captured are in the report.
+    /// Returns immediately if the capture was never started.
+    pub fn wait_finished(&self) {
+        let mut finished = self.finished.lock().unwrap();
+        while !*finished {
+            finished = self.finished_cv.wait(finished).unwrap();
+        }
+    }
+
+    fn finish(&self) {
+        let mut finished = self.finished.lock().unwrap();
+        *finished = true;
+        self.finished_cv.notify_all();
+    }
+
     /// Gets the timeout of the capture.
     pub fn get_timeout(&self) -> u32 {
         let t = self.timeout.lock().unwrap();
@@ -221,56 +725,198 @@ impl ControlBlock {
         }
     }
 
-    /// Gets the capture.
-    fn get_capture(&self) -> MutexGuard<'_, Capture<Active>> {
-        let c = self.capture.lock().unwrap();
-        c
+    /// Gets the interface captured at the position, starting from 0.
+    fn get_interface(&self, index: usize) -> Option<Arc<Interface>> {
+        let c = self.captures.lock().unwrap();
+        c.get(index).cloned()
+    }
+
+    /// Gets the number of interfaces captured.
+    fn get_interface_count(&self) -> usize {
+        let c = self.captures.lock().unwrap();
+        c.len()
+    }
+
+    /// Gets the names of the interfaces captured.
+    pub fn get_interfaces(&self) -> Vec<String> {
+        let c = self.captures.lock().unwrap();
+        c.iter().map(|i| i.name.clone()).collect()
     }
 
-    /// Sets the capture.
-    fn set_capture(&self, capture: Capture<Active>) {
-        let mut c = self.capture.lock().unwrap();
-        *c = capture;
+    /// Adds the capture of an interface.
+    fn add_capture(&self, name: String, capture: Capture<Active>) {
+        let mut c = self.captures.lock().unwrap();
+        c.push(Arc::new(Interface { name, capture: Mutex::new(capture) }));
     }
 
     /// Sets the device for the capture. Index starts from 1.
     pub fn set_device(&self, device_id: usize) -> Result<(), SnifferError>{
-        let device = match Device::list(){
-            Ok(d) => {
-                match d.get(device_id - 1) {
-                    Some(d) => d.clone(),
-                    None => return Err(SnifferError::ConfigError(InvalidDeviceId(pcap::Error::PcapError("Invalid device id".to_string()))))
-                }
-            },
-            Err(e) => return Err(SnifferError::ConfigError(InvalidDeviceId(e)))
+        self.select_device(&DeviceSelector::Index(device_id))
+    }
+
+    /// Sets the device for the capture, selected by position, name, description, IP or MAC address.
+    /// When capturing from several devices, the first one is replaced.
+    pub fn select_device(&self, selector: &DeviceSelector) -> Result<(), SnifferError> {
+        let device = match selector.select_device() {
+            Ok(d) => d,
+            Err(e) => return Err(SnifferError::ConfigError(e))
         };
 
-        let cap =
-            match match Capture::from_device(device){
-                Ok(c) => c.promisc(true)
-                    .snaplen(5000)
-                    .timeout(1000)
-                    .open(),
-                Err(e) => return Err(SnifferError::ConfigError(InvalidDeviceId(e)))
-            } {
-                Ok(c) => c,
-                Err(e) => return Err(SnifferError::CaptureError(CaptureError::CaptureError(e)))
-            };
-        self.set_capture(cap);
+        let name = device.name.clone();
+        let cap = open_capture(device, &self.get_capture_options())?;
+        let interface = Arc::new(Interface { name, capture: Mutex::new(cap) });
+        let mut c = self.captures.lock().unwrap();
+        match c.first_mut() {
+            Some(first) => *first = interface,
+            None => c.push(interface),
+        }
         Ok(())
     }
 
+    /// Gets the options used to open the capture.
+    pub fn get_capture_options(&self) -> CaptureOptions {
+        let o = self.capture_options.lock().unwrap();
+        o.clone()
+    }
+
+    /// Sets the options used to open the capture, applied by the next `set_device`.
+    pub fn set_capture_options(&self, capture_options: CaptureOptions) {
+        let mut o = self.capture_options.lock().unwrap();
+        *o = capture_options;
+    }
+
+    /// Gets the formats of the report.
+    pub fn get_output_formats(&self) -> Vec<OutputFormat> {
+        let f = self.output_formats.lock().unwrap();
+        f.clone()
+    }
+
+    /// Sets the formats of the report, text when empty.
+    pub fn set_output_formats(&self, output_formats: Vec<OutputFormat>) {
+        let mut f = self.output_formats.lock().unwrap();
+        *f = if output_formats.is_empty() { vec![OutputFormat::Text] } else { output_formats };
+    }
+
+    /// Sets the BPF filter of the capture.
+    ///
+    /// The filter can be built with the bpf module: `set_filter(BpfFilter::port(53).into())`
+    /// and is applied to all the devices captured.
     pub fn set_filter(&self, filter: String) -> Result<(), CaptureError> {
-        let mut capture = self.get_capture();
-        match capture.filter(&filter, true) {
-            Ok(_) => {
-                self.resume();
-                Ok(())
-            },
-            Err(e) => {
+        let captures = self.captures.lock().unwrap().clone();
+        for interface in captures.iter() {
+            let mut capture = interface.capture.lock().unwrap();
+            if let Err(e) = capture.filter(&filter, true) {
                 self.wait();
-                Err(CaptureError::FilterError(e))
+                return Err(CaptureError::FilterError(e));
+            }
+        }
+        self.resume();
+        Ok(())
+    }
+
+    /// Applies a BPF filter to all the devices captured, leaving the state of the capture as it is.
+    ///
+    /// Unlike set_filter it never waits nor resumes the capture, so it can be called while the
+    /// capture runs or is paused; an invalid filter is only returned as an error.
+    pub fn apply_filter(&self, filter: &str) -> Result<(), CaptureError> {
+        let captures = self.captures.lock().unwrap().clone();
+        for interface in captures.iter() {
+            let mut capture = interface.capture.lock().unwrap();
+            capture.filter(filter, true).map_err(CaptureError::FilterError)?;
+        }
+        Ok(())
+    }
+
+    /// Gets the dissectors used by the capture.
+    fn get_dissectors(&self) -> DissectorRegistry {
+        let d = self.dissectors.lock().unwrap();
+        d.clone()
+    }
+
+    /// Sets the dissectors used by the capture.
+    fn set_dissectors(&self, dissectors: DissectorRegistry) {
+        let mut d = self.dissectors.lock().unwrap();
+        *d = dissectors;
+    }
+
+    /// Gets the display filter of the capture, if any.
+    pub fn get_display_filter(&self) -> Option<String> {
+        let f = self.display_filter.lock().unwrap();
+        f.as_ref().map(|f| f.get_expression().clone())
+    }
+
+    /// Sets the display filter applied to the dissected packets before they are added to the
+    /// report and exported. None removes the filter.
+    pub fn set_display_filter(&self, filter: Option<String>) -> Result<(), SnifferError> {
+        let compiled = match filter {
+            Some(filter) => match DisplayFilter::compile(&filter, &self.get_dissectors()) {
+                Ok(f) => Some(Arc::new(f)),
+                Err(e) => return Err(SnifferError::ConfigError(InvalidDisplayFilter(e)))
             },
+            None => None,
+        };
+        let mut f = self.display_filter.lock().unwrap();
+        *f = compiled;
+        Ok(())
+    }
+
+    fn get_compiled_display_filter(&self) -> Option<Arc<DisplayFilter>> {
+        let f = self.display_filter.lock().unwrap();
+        f.clone()
+    }
+
+    /// Loads the signatures matched against every packet from a rules file. None removes them.
+    pub fn set_rules_file(&self, rules_file: Option<String>) -> Result<(), SnifferError> {
+        let rules = match rules_file {
+            Some(path) => {
+                let text = match fs::read_to_string(&path) {
+                    Ok(t) => t,
+                    Err(e) => return Err(SnifferError::ConfigError(ConfigError::InvalidFilePath(format!("{}: {}", path, e))))
+                };
+                match RuleSet::parse(&text, &self.get_dissectors()) {
+                    Ok(r) => Some(Arc::new(r)),
+                    Err(e) => return Err(SnifferError::ConfigError(ConfigError::InvalidRules(e)))
+                }
+            },
+            None => None,
+        };
+        let mut r = self.rules.lock().unwrap();
+        *r = rules;
+        Ok(())
+    }
+
+    fn get_rules(&self) -> Option<Arc<RuleSet>> {
+        let r = self.rules.lock().unwrap();
+        r.clone()
+    }
+
+    /// Sets the pcap file where the packets that pass the display filter are saved.
+    /// None stops the export.
+    pub fn set_export_file(&self, export_file: Option<String>) -> Result<(), SnifferError> {
+        // The file takes the datalink type of the first device
+        let savefile = match export_file {
+            Some(path) => match self.get_interface(0) {
+                Some(interface) => match interface.capture.lock().unwrap().savefile(&path) {
+                    Ok(s) => Some(s),
+                    Err(e) => return Err(SnifferError::ConfigError(ConfigError::InvalidFilePath(format!("{}: {}", path, e))))
+                },
+                None => return Err(SnifferError::ConfigError(ConfigError::InvalidFilePath(format!("{}: no device captured", path))))
+            },
+            None => None,
+        };
+        let mut s = self.export.lock().unwrap();
+        self.exporting.store(savefile.is_some(), Ordering::Relaxed);
+        *s = savefile;
+        Ok(())
+    }
+
+    fn export_packet(&self, header: &PacketHeader, data: &[u8]) {
+        if !self.exporting.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut s = self.export.lock().unwrap();
+        if let Some(savefile) = s.as_mut() {
+            savefile.write(&pcap::Packet::new(header, data));
         }
     }
 
@@ -287,159 +933,473 @@ impl ControlBlock {
     }
 
     pub fn push_error(&self, error: SnifferError) {
+        let event = Event::Error { timestamp_us: chrono::Utc::now().timestamp_micros(), message: error.to_string() };
+        self.subscriptions.send(CaptureEvent::Error { timestamp_us: chrono::Utc::now().timestamp_micros(), message: error.to_string() });
         let mut e = self.error_list.lock().unwrap();
         e.push_back(error);
+        drop(e);
+        self.sinks.send(event);
+    }
+
+    /// Gets the metrics of the capture in the Prometheus text format.
+    pub fn get_metrics(&self) -> String {
+        let errors = self.get_errors().len();
+        let alerts = self.get_alerts().len();
+        self.metrics.to_prometheus(&self.get_state(), errors, alerts, self.sinks.get_dropped())
+    }
+
+    /// Gets the packets and bytes captured by protocol.
+    pub fn get_protocols(&self) -> BTreeMap<String, (u64, u64)> {
+        self.metrics.get_protocols()
+    }
+
+    /// Writes the current report to the output file.
+    pub fn write_report(&self) -> Result<(), SnifferError> {
+        self.write_output(&self.report.merge())
+    }
+
+    /// Writes the report in every output format.
+    fn write_output(&self, report: &Report) -> Result<(), SnifferError> {
+        let output_file = self.get_output_file();
+        for (i, format) in self.get_output_formats().iter().enumerate() {
+            let path = match i {
+                0 => PathBuf::from(&output_file),
+                _ => Path::new(&output_file).with_extension(format.get_extension()),
+            };
+            let content = match format {
+                OutputFormat::Text => report.to_output(),
+                OutputFormat::Json => report.to_json().to_string(),
+            };
+            if let Err(e) = fs::write(path, content) {
+                return Err(SnifferError::CaptureError(CaptureError::ReportError(e)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Gets the current report as JSON.
+    pub fn get_report_json(&self) -> serde_json::Value {
+        self.report.merge().to_json()
+    }
+
+    /// Gets a copy of the communications and totals of the report, see the snapshot module.
+    pub fn snapshot(&self) -> ReportSnapshot {
+        ReportSnapshot::from_report(&self.report.merge(), chrono::Utc::now().timestamp_micros())
+    }
+
+    /// Gets a copy of the report and empties it, so that the next snapshot covers the next interval.
+    pub fn snapshot_and_reset(&self) -> ReportSnapshot {
+        let now = chrono::Utc::now().timestamp_micros();
+        let subscriptions = &self.subscriptions;
+        // The flows drained leave the report, so their subscribers see them end. They are ended
+        // before the shard is unlocked, so that a packet of the same flow starts it again after.
+        let drained = self.report.reset(now, |shard| subscriptions.end_drained_flows(shard));
+        ReportSnapshot::from_report(&drained, now)
+    }
+
+    /// Adds a destination for the alerts and errors.
+    pub fn add_sink(&self, sink: Box<dyn AlertSink>) {
+        self.sinks.add(sink);
+    }
+
+    /// Gets the alerts raised by the detectors that were not cleared yet.
+    pub fn get_alerts(&self) -> MutexGuard<'_, VecDeque<Alert>> {
+        let a = self.alert_list.lock().unwrap();
+        a
+    }
+
+    pub fn clear_alerts(&self, size: usize) {
+        let mut a = self.alert_list.lock().unwrap();
+        for _ in 0..size {
+            a.pop_front();
+        }
+    }
+
+    /// Records an alert and delivers it to the sinks. Beyond MAX_ALERTS alerts not cleared, the
+    /// oldest one is dropped.
+    pub fn push_alert(&self, alert: Alert) {
+        record_alert(&self.alert_list, &self.sinks, alert);
+    }
+
+    /// Queues an alert raised by a worker, so that the workers do not wait for the readers of the
+    /// alerts or for the sinks. The alert is dropped and counted when the queue is full.
+    pub(crate) fn queue_alert(&self, alert: Alert) {
+        if let Err(TrySendError::Full(_)) = self.alert_queue.try_send(AlertMessage::Alert(alert)) {
+            self.metrics.add_dropped_alert();
+        }
+    }
+
+    /// Waits until the alerts queued by the workers are recorded.
+    fn flush_alerts(&self) {
+        let (done, flushed) = sync_channel(1);
+        if self.alert_queue.send(AlertMessage::Flush(done)).is_ok() {
+            let _ = flushed.recv();
+        }
     }
 }
 
-/// Gets the list of network interfaces with their addresses.
+fn record_alert(alert_list: &Mutex<VecDeque<Alert>>, sinks: &SinkDispatcher, alert: Alert) {
+    sinks.send(Event::Alert(alert.clone()));
+    let mut a = alert_list.lock().unwrap();
+    if a.len() >= MAX_ALERTS {
+        a.pop_front();
+    }
+    a.push_back(alert);
+}
+
+/// Gets the list of network interfaces with their addresses, flags, MTU, MAC address and counters.
+/// The devices are not opened, so the datalink types are empty.
 ///
 /// ## Example
 ///
-/// Microsoft Interface: [Address { addr: 192.168.56.1, netmask: Some(255.255.255.0), broadcast_addr: Some(255.255.255.255), dst_addr: None }]
-pub fn get_devices() -> Result<Vec<(String, Vec<Address>)>, pcap::Error> {
-    let devices_list = Device::list();
-    if devices_list.is_err() {
-        return Err(devices_list.err().unwrap());
-    }
-    let devices = devices_list.unwrap();
-    let mut device_names: Vec<(String, Vec<Address>)> = Vec::new();
-    for device in devices {
-        if device.desc.is_some() {
-            device_names.push((device.desc.unwrap().to_string(), device.addresses));
-        } else {
-            device_names.push((device.name.to_string(), device.addresses));
-        }
-    }
-    Ok(device_names)
+/// eth0 [up, running] mtu 1500 mac 00:1a:2b:3c:4d:5e addresses 192.168.1.20, fe80::21a:2bff:fe3c:4d5e
+pub fn get_devices() -> Result<Vec<DeviceInfo>, pcap::Error> {
+    let devices = Device::list()?;
+    Ok(devices.iter().map(DeviceInfo::from_device).collect())
+}
+
+/// Gets the list of network interfaces like get_devices, with the datalink types too.
+/// Every device is opened, which usually requires the capture privileges.
+///
+/// ## Example
+///
+/// eth0 [up, running] mtu 1500 mac 00:1a:2b:3c:4d:5e datalinks EN10MB,DOCSIS addresses 192.168.1.20, fe80::21a:2bff:fe3c:4d5e
+pub fn get_devices_with_datalinks() -> Result<Vec<DeviceInfo>, pcap::Error> {
+    let mut devices = get_devices()?;
+    devices.iter_mut().for_each(DeviceInfo::read_datalinks);
+    Ok(devices)
 }
 
 /// Begins the capture process and returns a control block for the capture.
 /// The input is a Parameters struct that contains the following information:
-/// * device_id: The id of the network device to capture from
+/// * device: The network device to capture from, by position (starting from 1), name, description, IP or MAC address
+/// * devices: The network devices to capture from at the same time, each one read by its own thread, instead of device
+/// * group_by_interface: Whether the report has a line for each device a communication is seen on
 /// * timeout: The time after which the capture stops
 /// * file_path: The path of the file where the captured packets will be saved
 /// * filter: An optional filter to be applied to the captured packets (in BPF format - https://biot.com/capstats/bpf.html)
+/// * dissectors: The dissectors used to decode the application protocols
+/// * display_filter: An optional display filter evaluated on the dissected packets
+/// * export_file: An optional pcap file where the packets that pass the display filter are saved
+/// * scan_thresholds: The thresholds of the port scan and host sweep detector
+/// * dos_thresholds: The thresholds of the SYN flood and volumetric attack detector
+/// * dns_thresholds: The thresholds of the DNS tunneling and generated domain detectors
+/// * rules_file: An optional file of signatures matched against every packet
+/// * sinks: The destinations of the alerts and errors
+/// * metrics_address: An optional address where /metrics is served in the Prometheus format
+/// * api_address: An optional TCP address or unix socket (unix:///path) where the control API is served
+/// * packet_count: An optional number of packets after which the capture stops
+/// * capture_options: The options used to open the capture
+/// * output_formats: The formats of the report
 pub fn analyze_network(parameters: Parameters) -> Result<Arc<ControlBlock>, SnifferError> {
-    let device_id = parameters.device_id;
-    let main_device = Device::list().unwrap();
-    let device = main_device.get(device_id).unwrap().clone();
-    let mut cap =
-        match match Capture::from_device(device){
-            Ok(c) => c.promisc(true)
-                .snaplen(5000)
-                .timeout(1000)
-                .open(),
-            Err(e) => return Err(SnifferError::ConfigError(InvalidDeviceId(e)))
-        } {
-            Ok(c) => c,
-            Err(e) => return Err(SnifferError::CaptureError(CaptureError::CaptureError(e)))
-        };
+    let control_block = Arc::new(ControlBlockBuilder::new().parameters(parameters).build()?);
+    // The threads are detached, wait_finished tells when the capture is over
+    control_block.start()?;
+    Ok(control_block)
+}
 
-    if let Some(filter) = &parameters.filter {
-        if let Err(e) = cap.filter(filter, true) {
-            return Err(SnifferError::ConfigError(InvalidFilter(e)));
+/// Serves /metrics on the address, until the server returned is stopped or dropped.
+fn serve_metrics(control_block: &Arc<ControlBlock>, address: &str) -> Result<Server, SnifferError> {
+    let weak = Arc::downgrade(control_block);
+    let handler: Handler = Arc::new(move |request: &Request| {
+        let control_block = match weak.upgrade() {
+            Some(cb) => cb,
+            None => return Response::text(404, "Capture ended"),
         };
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/metrics") => Response::new(200, "text/plain; version=0.0.4", control_block.get_metrics().into_bytes()),
+            (_, "/metrics") => Response::text(405, "Method not allowed"),
+            _ => Response::text(404, "Not found"),
+        }
+    });
+    match http::serve_tcp(address, handler) {
+        Ok(server) => Ok(server),
+        Err(e) => Err(SnifferError::ConfigError(ConfigError::InvalidAddress(format!("{}: {}", address, e))))
     }
+}
 
-    let control_block = ControlBlock::new();
-    if !parameters.file_path.is_empty() {
-        match control_block.set_output_file(parameters.file_path){
-            Ok(_) => {},
-            Err(e) => return Err(e)
-        };
+fn invalid_transition(message: &str) -> SnifferError {
+    SnifferError::CaptureError(CaptureError::InvalidTransition(String::from(message)))
+}
+
+/// Opens a capture on the device with the options.
+fn open_capture(device: Device, options: &CaptureOptions) -> Result<Capture<Active>, SnifferError> {
+    let mut inactive = match Capture::from_device(device) {
+        Ok(c) => c.promisc(options.promisc)
+            .snaplen(options.snaplen)
+            .timeout(options.read_timeout)
+            .immediate_mode(options.immediate_mode),
+        Err(e) => return Err(SnifferError::ConfigError(InvalidDeviceId(e)))
+    };
+    if let Some(buffer_size) = options.buffer_size {
+        inactive = inactive.buffer_size(buffer_size);
     }
-    if parameters.timeout != 0 {
-        control_block.set_timeout(parameters.timeout);
+    match inactive.open() {
+        Ok(c) => Ok(c),
+        Err(e) => Err(SnifferError::CaptureError(CaptureError::CaptureError(e)))
     }
-    let control_block_clone = control_block.clone();
+}
 
-    control_block_clone.set_capture(cap);
+#[derive(Clone)]
+/// Decodes the packets and adds them to the report of a control block, the job of the workers
+/// of a capture. It can be fed packets read from elsewhere, e.g. by a benchmark.
+///
+/// The workers share the processor without waiting for each other: the detectors, the flows
+/// of the dissectors, the report and the metrics are sharded, and the export and the
+/// subscriptions are only locked when they are used.
+pub struct PacketProcessor {
+    control_block: Arc<ControlBlock>,
+    dissectors: DissectorRegistry,
+    display_filter: Option<Arc<DisplayFilter>>,
+    rules: Option<Arc<RuleSet>>,
+    dos_detector: Arc<DosDetector>,
+    dns_detector: Arc<DnsAnomalyDetector>,
+}
 
-    std::thread::spawn(move || {
-        read_packets(control_block_clone);
-    });
-    Ok(control_block)
+impl PacketProcessor {
+    /// Creates a processor with the dissectors, the display filter and the rules of the control
+    /// block, and detectors of its own.
+    pub fn new(control_block: Arc<ControlBlock>) -> PacketProcessor {
+        let dos_detector = Arc::new(DosDetector::new(control_block.dos_thresholds.clone()));
+        let dns_detector = Arc::new(DnsAnomalyDetector::new(control_block.dns_thresholds.clone()));
+        PacketProcessor::with_detectors(control_block, dos_detector, dns_detector)
+    }
+
+    pub fn get_control_block(&self) -> &Arc<ControlBlock> {
+        &self.control_block
+    }
+
+    fn with_detectors(control_block: Arc<ControlBlock>, dos_detector: Arc<DosDetector>, dns_detector: Arc<DnsAnomalyDetector>) -> PacketProcessor {
+        PacketProcessor {
+            dissectors: control_block.get_dissectors(),
+            display_filter: control_block.get_compiled_display_filter(),
+            rules: control_block.get_rules(),
+            control_block,
+            dos_detector,
+            dns_detector,
+        }
+    }
+
+    /// Gets a processor with the display filter and the rules of the control block, which can
+    /// change during the capture. The processor itself is returned while they are the same.
+    fn refresh(self: &Arc<Self>) -> Arc<PacketProcessor> {
+        let display_filter = self.control_block.get_compiled_display_filter();
+        let rules = self.control_block.get_rules();
+        let same_filter = match (&display_filter, &self.display_filter) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (a, b) => a.is_none() && b.is_none(),
+        };
+        let same_rules = match (&rules, &self.rules) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (a, b) => a.is_none() && b.is_none(),
+        };
+        if same_filter && same_rules {
+            return self.clone();
+        }
+        Arc::new(PacketProcessor { display_filter, rules, ..PacketProcessor::clone(self) })
+    }
+
+    /// Decodes a packet captured on the interface and adds it to the report. The frame is the
+    /// number of the packet in the capture, starting from 1.
+    pub fn process(&self, interface: &str, header: &PacketHeader, data: &[u8], frame: u64) {
+        let sliced_packet = match SlicedPacket::from_ethernet(data) {
+            Ok(p) => p,
+            Err(..) => return,
+        };
+        let control_block = &self.control_block;
+        let mut result = MyPacket::new(Default::default(), Default::default(), Default::default(), None, None, Default::default(), Default::default(), Default::default());
+        result.set_interface(interface.to_string());
+        fill_timestamp_and_lenght(header, &mut result);
+        fill_ip_address(&sliced_packet, &mut result);
+        fill_protocol_and_ports(&sliced_packet, &mut result);
+        self.dissectors.dissect(&sliced_packet, &mut result);
+        control_block.metrics.add_packet(result.get_protocol(), *result.get_length());
+        // The rates are computed on all the traffic, regardless of the display filter
+        for alert in self.dos_detector.add_packet(&result) {
+            control_block.queue_alert(alert);
+        }
+        if result.get_protocol() == "DNS" {
+            self.dns_detector.add_packet(&result);
+        }
+        if let Some(rules) = &self.rules {
+            for alert in rules.matches(&result, sliced_packet.payload, frame) {
+                control_block.queue_alert(alert);
+            }
+        }
+        if let Some(filter) = &self.display_filter {
+            if !filter.matches(&result) {
+                return;
+            }
+        }
+        control_block.export_packet(header, data);
+        let subscriptions = &control_block.subscriptions;
+        control_block.report.add_packet(&result, |report, key, new| {
+            if subscriptions.wants(Topic::Flows) {
+                subscriptions.flow_packet(report, key, new);
+            }
+        });
+        control_block.metrics.set_flows(control_block.report.get_flow_count());
+        if subscriptions.wants(Topic::Packets) {
+            subscriptions.send(CaptureEvent::Packet(Box::new(result)));
+        }
+    }
 }
 
-fn read_packets(control_block: Arc<ControlBlock>) {
-    let report = Arc::new(Mutex::new(Report::default()));
+#[derive(Clone)]
+/// The state shared by the threads reading the devices
+struct PacketContext {
+    control_block: Arc<ControlBlock>,
+    pool: ThreadPool,
+    processor: Arc<PacketProcessor>,
+    //number of the packets in the capture, on all the devices, starting from 1
+    frame: Arc<AtomicU64>,
+    packet_count: Option<u64>,
+}
 
+/// Writes the report every timeout seconds and looks for the alerts of the detectors, until the
+/// run of the capture is stopped. The last report is written by read_packets.
+fn write_reports(control_block: Arc<ControlBlock>, run: u64, scan_thresholds: ScanThresholds, dos_detector: Arc<DosDetector>, dns_detector: Arc<DnsAnomalyDetector>) {
+    let mut scan_detector = ScanDetector::new(scan_thresholds);
+    loop {
+        if control_block.wait_stopped_timeout(run, std::time::Duration::from_secs(u64::from(control_block.get_timeout()))) {
+            break;
+        }
+        match control_block.get_state() {
+            CaptureState::Paused() => {
+                control_block.wait();
+                continue;
+            }
+            CaptureState::Capturing() => {
+                let now = chrono::Utc::now().timestamp_micros();
+                for alert in dos_detector.expire(now) {
+                    control_block.push_alert(alert);
+                }
+                for alert in dns_detector.analyze(now) {
+                    control_block.push_alert(alert);
+                }
+                // The shards are merged into a copy, so that the workers go on while it is written
+                let report = control_block.report.merge();
+                for alert in scan_detector.analyze(&report) {
+                    control_block.push_alert(alert);
+                }
+                let subscriptions = &control_block.subscriptions;
+                control_block.report.for_each_shard(|shard| subscriptions.expire_flows(shard, now));
+                match control_block.write_output(&report){
+                    Ok(_) => (),
+                    Err(_) => continue
+                }
+            }
+            _ => break,
+        };
+    }
+}
+
+fn read_packets(control_block: Arc<ControlBlock>, dos_detector: Arc<DosDetector>, dns_detector: Arc<DnsAnomalyDetector>) {
     //create a thread pool to handle the packets
     let pool = ThreadPool::new(num_cpus::get());
+    let context = PacketContext {
+        control_block: control_block.clone(),
+        pool: pool.clone(),
+        processor: Arc::new(PacketProcessor::with_detectors(control_block.clone(), dos_detector, dns_detector)),
+        frame: Arc::new(AtomicU64::new(0)),
+        packet_count: control_block.packet_count,
+    };
 
-    let report_clone_out = report.clone();
-
-    let control_block_clone = control_block.clone();
-    std::thread::spawn(move || {
-        loop {
-            std::thread::sleep(std::time::Duration::from_secs(u64::from(control_block_clone.get_timeout())));
-            match control_block_clone.get_state() {
-                CaptureState::Stopped() => (),
-                CaptureState::Paused() => {
-                    control_block_clone.wait();
-                    continue;
-                }
-                CaptureState::Capturing() => {
-                    match fs::write(control_block_clone.get_output_file(), report_clone_out.lock().unwrap().to_formatted_table().to_string()){
-                        Ok(_) => (),
-                        Err(_) => continue
-                    }
-                }
-            };
+    //one thread for each device, all feeding the same pool and report
+    let readers = (0..control_block.get_interface_count()).map(|index| {
+        let context = context.clone();
+        std::thread::spawn(move || read_interface(index, context))
+    }).collect::<Vec<_>>();
+    for reader in readers {
+        let _ = reader.join();
+    }
+
+    pool.join();
+    control_block.flush_alerts();
+    let subscriptions = &control_block.subscriptions;
+    control_block.report.for_each_shard(|shard| subscriptions.end_all_flows(shard));
+    // The last report contains the packets captured after the previous write
+    if !control_block.get_output_file().is_empty() {
+        if let Err(e) = control_block.write_report() {
+            control_block.push_error(e);
         }
-    });
+    }
+    control_block.finish();
+}
+
+/// Reads the packets of the device at the position until the capture is stopped.
+fn read_interface(index: usize, context: PacketContext) {
+    let control_block = &context.control_block;
+    let pool = &context.pool;
+    let mut processor = context.processor.clone();
+    let mut last_stats = std::time::Instant::now();
+    let mut errors = 0;
 
     loop {
         match control_block.get_state() {
-            CaptureState::Stopped() => break,
+            CaptureState::Stopped() | CaptureState::Idle() => break,
             CaptureState::Paused() => {
                 control_block.wait();
                 continue;
             }
             CaptureState::Capturing() => {
-                match control_block.get_capture().next_packet() {
+                // The device is looked up every time, as set_device can replace it
+                let interface = match control_block.get_interface(index) {
+                    Some(i) => i,
+                    None => break,
+                };
+                let mut capture = interface.capture.lock().unwrap();
+                if last_stats.elapsed() >= std::time::Duration::from_secs(1) {
+                    if let Ok(stats) = capture.stats() {
+                        control_block.metrics.set_pcap_stats(&interface.name, stats.received, stats.dropped, stats.if_dropped);
+                    }
+                    control_block.metrics.set_workers(pool.queued_count(), pool.active_count());
+                    last_stats = std::time::Instant::now();
+                }
+                match capture.next_packet() {
                     Ok(packet) => {
+                        errors = 0;
+                        let frame = context.frame.fetch_add(1, Ordering::Relaxed) + 1;
                         //recheck the state of the capture and discard data if it has come after it was paused or stopped
                         match control_block.get_state() {
-                            CaptureState::Stopped() => (),
+                            CaptureState::Stopped() | CaptureState::Idle() => (),
                             CaptureState::Paused() => (),
                             CaptureState::Capturing() => {
                                 let packet_data = packet.data.to_owned();
                                 let packet_header = packet.header.to_owned();
-                                let report_copy = report.clone();
-                                pool.execute(move || {
-                                    match SlicedPacket::from_ethernet(&*packet_data) {
-                                        Err(..) => {}
-                                        Ok(sliced_packet) => {
-                                            let mut result = MyPacket::new(Default::default(), Default::default(), Default::default(), None, None, Default::default(), Default::default(), Default::default());
-                                            fill_timestamp_and_lenght(&packet_header, &mut result);
-                                            fill_ip_address(&sliced_packet, &mut result);
-                                            fill_protocol_and_ports(&sliced_packet, &mut result);
-                                            report_copy.lock().unwrap().add_packet(result);
-                                        }
-                                    }
-                                });
+                                let interface = interface.clone();
+                                processor = processor.refresh();
+                                let processor = processor.clone();
+                                pool.execute(move || processor.process(&interface.name, &packet_header, &packet_data, frame));
+                                control_block.metrics.set_workers(pool.queued_count(), pool.active_count());
                             }
                         }
+                        if context.packet_count.is_some_and(|c| frame >= c) {
+                            control_block.stop();
+                        }
                     }
+                    // The read timeout only gives the loop a chance to check the state of the capture
+                    Err(pcap::Error::TimeoutExpired) => errors = 0,
                     Err(e) => {
-                        // match e {
-                        //     //ignore only the timeout error, I wanted to use a timeout to be able to check the state of the capture
-                        //     pcap::Error::TimeoutExpired => (),
-                        //     _ => {
-                        //         control_block.push_error(SnifferError::CaptureError(CaptureError::CaptureError(e)));
-                        //         break;
-                        //     }
-                        // }
                         control_block.push_error(SnifferError::CaptureError(CaptureError::CaptureError(e)));
+                        errors += 1;
+                        if errors >= MAX_READ_ERRORS {
+                            control_block.push_error(SnifferError::CaptureError(CaptureError::InterfaceStopped(interface.name.clone())));
+                            break;
+                        }
+                        // Back off without holding the device, so that set_device and set_filter can use it
+                        drop(capture);
+                        std::thread::sleep(read_backoff(errors));
                     }
                 }
             }
         }
     };
+}
 
-    pool.join();
+/// Gets the pause after a number of read errors in a row, doubling from 10 ms.
+fn read_backoff(errors: u32) -> std::time::Duration {
+    std::time::Duration::from_millis(10u64 << (errors - 1).min(16)).min(MAX_READ_BACKOFF)
 }
 
 fn fill_ip_address(packet: &SlicedPacket, dest_packet: &mut MyPacket) {
@@ -495,24 +1455,28 @@ fn fill_protocol_and_ports(packet: &SlicedPacket, dest_packet: &mut MyPacket) {
             match val {
                 Udp(header_slice) => {
                     dest_packet.set_protocol(String::from("UDP"));
+                    dest_packet.set_transport(Some(TransportProtocol::Udp));
                     dest_packet.set_source_port(Some(header_slice.to_header().source_port.to_string()));
                     dest_packet.set_destination_port(Some(header_slice.to_header().destination_port.to_string()));
                 }
                 Tcp(header_slice) => {
                     dest_packet.set_protocol(String::from("TCP"));
+                    dest_packet.set_transport(Some(TransportProtocol::Tcp));
+                    dest_packet.set_tcp_flags(Some(header_slice.slice()[13]));
                     dest_packet.set_source_port(Some(header_slice.to_header().source_port.to_string()));
                     dest_packet.set_destination_port(Some(header_slice.to_header().destination_port.to_string()));
                 }
                 Icmpv4(..) => {
                     dest_packet.set_protocol(String::from("ICMPv4"));
-                    // dest_packet.set_info(slice.header().icmp_type.to_string());
+                    dest_packet.set_transport(Some(TransportProtocol::Icmpv4));
                 }
                 Icmpv6(..) => {
                     dest_packet.set_protocol(String::from("ICMPv6"));
-                    // dest_packet.set_info(slice.header().icmp_type.to_string());
+                    dest_packet.set_transport(Some(TransportProtocol::Icmpv6));
                 }
                 Unknown(..) => {
                     dest_packet.set_protocol(String::from("Unknown"));
+                    dest_packet.set_transport(Some(TransportProtocol::Unknown));
                     dest_packet.set_info(String::from("UNKNOWN"));
                 }
             }
@@ -539,3 +1503,45 @@ fn to_hex_string(group_size: usize, address: Vec<u8>) -> String {
         .collect::<Vec<String>>()
         .join(":")
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::alert::AlertCategory;
+    use super::*;
+
+    fn alert(source: &str) -> Alert {
+        Alert {
+            category: AlertCategory::Signature,
+            kind: String::from("Admin page"),
+            source: source.to_string(),
+            targets: vec![String::from("10.0.0.2:80")],
+            evidence: Vec::new(),
+            start_us: 0,
+            end_us: 0,
+        }
+    }
+
+    #[test]
+    fn keeps_the_last_alerts() {
+        let control_block = ControlBlockBuilder::new().build_offline().unwrap();
+        for i in 0..MAX_ALERTS + 5 {
+            control_block.push_alert(alert(&i.to_string()));
+        }
+        let alerts = control_block.get_alerts();
+        assert_eq!(alerts.len(), MAX_ALERTS);
+        assert_eq!(alerts[0].source, "5");
+        assert_eq!(alerts[MAX_ALERTS - 1].source, (MAX_ALERTS + 4).to_string());
+    }
+
+    #[test]
+    fn records_the_alerts_queued_by_the_workers() {
+        let control_block = ControlBlockBuilder::new().build_offline().unwrap();
+        let sources = ["10.0.0.1", "10.0.0.3", "10.0.0.4"];
+        for source in sources {
+            control_block.queue_alert(alert(source));
+        }
+        control_block.flush_alerts();
+        assert_eq!(control_block.get_alerts().iter().map(|a| a.source.as_str()).collect::<Vec<&str>>(), sources);
+        assert!(control_block.get_metrics().contains("network_analyzer_alerts_dropped_total 0\n"));
+    }
+}
@@ -1,10 +1,23 @@
 use std::io;
-use network_analyzer::{analyze_network, ControlBlock, get_devices, SnifferError};
+use std::sync::Arc;
+use std::time::Duration;
+use network_analyzer::{analyze_network, ControlBlock, get_devices, get_devices_with_datalinks, SnifferError};
+use network_analyzer::bpf::{compile_filter, parse_linktype};
+use network_analyzer::config::load_profile;
+use network_analyzer::device::DeviceSelector;
 use network_analyzer::parameters::Parameters;
+use network_analyzer::sink::{SinkConfig, SyslogTransport};
 
 use clap::{Args, Parser, Subcommand};
 use libc::exit;
 
+/// Seconds between two reports when neither the command line nor the profile set them
+const DEFAULT_TIMEOUT: u32 = 5;
+
+#[cfg(unix)]
+mod daemon;
+mod tui;
+
 /// Network analyzer
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
@@ -19,57 +32,319 @@ pub enum Options {
     Devices(Devices),
 
     /// Begin analyzing the network
-    Parse(ParseCommand),
+    Parse(Box<ParseCommand>),
+
+    /// Compile a BPF filter offline and print the resulting program
+    CheckFilter(CheckFilterCommand),
+}
+
+#[derive(Debug, Args)]
+pub struct Devices {
+    /// Print the devices as a JSON array
+    #[clap(long, value_parser)]
+    json: bool,
+
+    /// List the datalink types too, opening every device (usually requires the capture privileges)
+    #[clap(short, long, value_parser)]
+    verbose: bool,
 }
 
 #[derive(Debug, Args)]
-pub struct Devices {}
+pub struct CheckFilterCommand {
+    /// Filter in standardized BPF language
+    #[clap(value_parser)]
+    filter: String,
+
+    /// Datalink type the filter is compiled for, by name (e.g. EN10MB) or number
+    #[clap(short, long, value_parser, default_value = "EN10MB")]
+    linktype: String,
+}
 
 #[derive(Debug, Args)]
 pub struct ParseCommand {
-    /// Network adapter id
-    #[clap(short, long, value_parser, default_value = "0")]
-    device_id: usize,
+    /// Network adapter: its number in the devices list, or a glob matched against its name,
+    /// description, IP or MAC address, optionally prefixed by name:, desc:, ip: or mac: (default 1).
+    /// Repeat it to capture from several devices at the same time
+    #[clap(short, long, value_parser)]
+    device: Vec<String>,
 
-    /// Timeout after which it stops sniffing
-    #[clap(short, long, value_parser, default_value_t = 5)]
-    timeout: u32,
+    /// Give a line of the report to each device a communication is seen on
+    #[clap(long, value_parser)]
+    group_by_interface: bool,
 
-    /// Output file path
+    /// Timeout after which it stops sniffing (default 5, or the interval of the profile)
     #[clap(short, long, value_parser)]
-    output: String,
+    timeout: Option<u32>,
+
+    /// Output file path
+    #[clap(short, long, value_parser, required_unless_present = "profile")]
+    output: Option<String>,
+
+    /// Configuration file with the capture profiles
+    #[clap(long, value_parser, requires = "profile")]
+    profile_file: Option<String>,
+
+    /// Profile of the configuration file to capture with
+    #[clap(long, value_parser, requires = "profile_file")]
+    profile: Option<String>,
 
     /// Filter in standardized BPF language to be applied to the sniffed packets
     #[clap(short, long, value_parser)]
     filter: Option<String>,
+
+    /// Display filter evaluated on the dissected packets, e.g. "dns.rcode != 0"
+    #[clap(long, value_parser)]
+    display_filter: Option<String>,
+
+    /// Pcap file where the packets that pass the display filter are saved
+    #[clap(short, long, value_parser)]
+    export: Option<String>,
+
+    /// File of signatures, in Suricata syntax, matched against every packet
+    #[clap(short, long, value_parser)]
+    rules: Option<String>,
+
+    /// Syslog server receiving the alerts and errors: udp://host:port, tcp://host:port or unix:///path
+    #[clap(long, value_parser)]
+    syslog: Option<String>,
+
+    /// File where the alerts and errors are appended as JSON lines
+    #[clap(long, value_parser)]
+    json_log: Option<String>,
+
+    /// http:// url the alerts and errors are posted to
+    #[clap(long, value_parser)]
+    webhook: Option<String>,
+
+    /// Attempts of the webhook after a failed delivery
+    #[clap(long, value_parser, default_value_t = 3)]
+    webhook_retries: u32,
+
+    /// Address where /metrics is served in the Prometheus format, e.g. 127.0.0.1:9100
+    #[clap(long, value_parser)]
+    metrics: Option<String>,
+
+    /// Address of the HTTP/JSON control API, host:port or unix:///path, replacing the interactive commands
+    #[clap(long, value_parser)]
+    api: Option<String>,
+
+    /// Show a full screen dashboard instead of the interactive commands
+    #[clap(long, value_parser)]
+    tui: bool,
+
+    /// Run without the interactive commands until SIGINT/SIGTERM, --duration or --count;
+    /// the exit code is 1 if capture errors occurred
+    #[clap(long, value_parser)]
+    batch: bool,
+
+    /// Seconds after which the capture stops, implies --batch
+    #[clap(long, value_parser)]
+    duration: Option<u64>,
+
+    /// Number of packets after which the capture stops, implies --batch
+    #[clap(long, value_parser)]
+    count: Option<u64>,
+
+    /// Detach from the terminal and run until SIGTERM; SIGHUP reloads the settings file, SIGUSR1 writes the report
+    #[cfg(unix)]
+    #[clap(long, value_parser)]
+    daemon: bool,
+
+    /// Settings file applied at start and on SIGHUP in daemon mode (filter, display_filter, output, interval)
+    #[cfg(unix)]
+    #[clap(long, value_parser)]
+    config: Option<String>,
+
+    /// File where the pid of the daemon is written
+    #[cfg(unix)]
+    #[clap(long, value_parser)]
+    pid_file: Option<String>,
+
+    /// File where the daemon appends its log
+    #[cfg(unix)]
+    #[clap(long, value_parser)]
+    log_file: Option<String>,
+
+    /// Sliding window of the scan detector, in seconds (default 60)
+    #[clap(long, value_parser)]
+    scan_window: Option<u64>,
+
+    /// Number of hosts probed on the same port that makes a horizontal scan (default 20)
+    #[clap(long, value_parser)]
+    scan_hosts: Option<usize>,
+
+    /// Number of ports probed on the same host that makes a vertical scan (default 20)
+    #[clap(long, value_parser)]
+    scan_ports: Option<usize>,
+
+    /// SYNs per second to one destination that can make a SYN flood (default 200)
+    #[clap(long, value_parser)]
+    syn_rate: Option<u64>,
+
+    /// Packets per second to one destination that make a flood (default 20000)
+    #[clap(long, value_parser)]
+    packet_rate: Option<u64>,
+
+    /// Bytes per second to one destination that make a flood (default 100000000)
+    #[clap(long, value_parser)]
+    byte_rate: Option<u64>,
 }
 
 fn main() {
     let args = NetworkAnalyzer::parse();
     match args.subcommand {
-        Options::Devices(_devices) => {
-            let devices = get_devices();
+        Options::Devices(devices_command) => {
+            let devices = match devices_command.verbose {
+                true => get_devices_with_datalinks(),
+                false => get_devices(),
+            };
             if devices.is_err() {
                 println!("Error: {}", devices.err().unwrap());
+                std::process::exit(1);
+            }
+            let devices = devices.unwrap();
+            if devices_command.json {
+                let json = devices.iter().map(|d| d.to_json()).collect::<Vec<serde_json::Value>>();
+                println!("{}", serde_json::to_string_pretty(&json).unwrap());
                 return;
             }
-            for d in devices.unwrap().iter().enumerate() {
-                println!("{}) {} {:?}", d.0 + 1, d.1.0, d.1.1);
+            for d in devices.iter().enumerate() {
+                println!("{}) {}", d.0 + 1, d.1);
+            }
+        }
+        Options::CheckFilter(check_command) => {
+            let linktype = match parse_linktype(&check_command.linktype) {
+                Ok(l) => l,
+                Err(e) => {
+                    println!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            match compile_filter(&check_command.filter, linktype) {
+                Ok(program) => {
+                    for (i, instruction) in program.get_instructions().iter().enumerate() {
+                        println!("({:03}) {}", i, instruction);
+                    }
+                }
+                Err(e) => {
+                    println!("Error: {}", e);
+                    std::process::exit(1);
+                }
             }
         }
         Options::Parse(parse_command) => {
-            let parameters = Parameters {
-                device_id: parse_command.device_id - 1,
-                timeout: parse_command.timeout,
-                file_path: parse_command.output,
-                filter: parse_command.filter,
+            let mut sinks = Vec::new();
+            if let Some(syslog) = &parse_command.syslog {
+                match SyslogTransport::parse(syslog) {
+                    Ok(t) => sinks.push(SinkConfig::Syslog(t)),
+                    Err(e) => {
+                        println!("Error: {}", e);
+                        return;
+                    }
+                }
+            }
+            if let Some(path) = parse_command.json_log {
+                sinks.push(SinkConfig::Ndjson(path));
+            }
+            if let Some(url) = parse_command.webhook {
+                sinks.push(SinkConfig::Webhook { url, retries: parse_command.webhook_retries, backoff: Duration::from_secs(1) });
+            }
+            // The options given on the command line take precedence over the profile
+            let mut parameters = match (&parse_command.profile_file, &parse_command.profile) {
+                (Some(path), Some(name)) => match load_profile(path, name) {
+                    Ok(p) => p,
+                    Err(e) => {
+                        println!("Error: {}", e);
+                        return;
+                    }
+                },
+                _ => Parameters::default(),
             };
+            match parse_command.device.as_slice() {
+                [] => (),
+                [device] => {
+                    parameters.device = DeviceSelector::parse(device);
+                    parameters.devices.clear();
+                }
+                devices => parameters.devices = devices.iter().map(|d| DeviceSelector::parse(d)).collect(),
+            }
+            if parse_command.group_by_interface {
+                parameters.set_group_by_interface(true);
+            }
+            if let Some(timeout) = parse_command.timeout {
+                parameters.timeout = timeout;
+            }
+            // The built-in defaults only fill what neither the command line nor the profile set
+            if parameters.timeout == 0 {
+                parameters.timeout = DEFAULT_TIMEOUT;
+            }
+            if let Some(output) = parse_command.output {
+                parameters.file_path = output;
+            }
+            parameters.filter = parse_command.filter.or(parameters.filter);
+            parameters.display_filter = parse_command.display_filter.or(parameters.display_filter);
+            parameters.export_file = parse_command.export.or(parameters.export_file);
+            let scan = &mut parameters.scan_thresholds;
+            scan.window = parse_command.scan_window.unwrap_or(scan.window);
+            scan.horizontal_hosts = parse_command.scan_hosts.unwrap_or(scan.horizontal_hosts);
+            scan.vertical_ports = parse_command.scan_ports.unwrap_or(scan.vertical_ports);
+            let dos = &mut parameters.dos_thresholds;
+            dos.syn_rate = parse_command.syn_rate.unwrap_or(dos.syn_rate);
+            dos.packet_rate = parse_command.packet_rate.unwrap_or(dos.packet_rate);
+            dos.byte_rate = parse_command.byte_rate.unwrap_or(dos.byte_rate);
+            parameters.rules_file = parse_command.rules.or(parameters.rules_file);
+            parameters.sinks.extend(sinks);
+            parameters.metrics_address = parse_command.metrics.or(parameters.metrics_address);
+            parameters.api_address = parse_command.api.clone().or(parameters.api_address);
+            parameters.packet_count = parse_command.count.or(parameters.packet_count);
+            let api = parameters.api_address.clone();
+            let packet_count = parameters.packet_count;
+            #[cfg(unix)]
+            let mut pid_file = None;
+            #[cfg(unix)]
+            if parse_command.daemon {
+                if let Err(e) = daemon::detach(parse_command.log_file.as_deref()) {
+                    println!("Error in starting the daemon: {}", e);
+                    std::process::exit(1);
+                }
+                if let Some(path) = &parse_command.pid_file {
+                    match daemon::PidFile::create(path) {
+                        Ok(p) => pid_file = Some(p),
+                        Err(e) => {
+                            println!("Error in writing the pidfile {}: {}", path, e);
+                            std::process::exit(1);
+                        }
+                    }
+                }
+            }
             let cb_result = analyze_network(parameters);
             if cb_result.is_err() {
                 println!("Error: {}", cb_result.err().unwrap());
                 return;
             }
             let cb = cb_result.unwrap();
+            #[cfg(unix)]
+            if parse_command.daemon {
+                std::process::exit(daemon::run(cb, parse_command.config.clone(), pid_file));
+            }
+            if parse_command.batch || parse_command.duration.is_some() || packet_count.is_some() {
+                std::process::exit(run_batch(cb, parse_command.duration));
+            }
+            if parse_command.tui {
+                if let Err(e) = tui::run(&cb) {
+                    println!("Error: {}", e);
+                }
+                // The last report is written after the capture is stopped
+                cb.wait_finished();
+                return;
+            }
+            if let Some(api) = &api {
+                println!("Control API listening on {}, POST /stop to end the capture", api);
+                cb.wait_finished();
+                // Leaves the time to answer the request that stopped the capture
+                std::thread::sleep(Duration::from_millis(200));
+                return;
+            }
             clear_screen();
             loop {
                 println!("Write: \n \
@@ -79,7 +354,12 @@ fn main() {
                 - \"device\" to list all devices and choose one \n \
                 - \"timeout\" to change the report generation interval\n \
                 - \"output\" to change the output file path\n \
-                - \"errors\" to see the errors occurred during the capture\n");
+                - \"filter\" to change the BPF filter\n \
+                - \"display\" to change the display filter (empty to remove it)\n \
+                - \"export\" to change the pcap export file (empty to stop exporting)\n \
+                - \"rules\" to reload the rules or load another rules file (empty to remove them)\n \
+                - \"errors\" to see the errors occurred during the capture\n \
+                - \"alerts\" to see the scans, attacks and signature hits detected during the capture\n");
                 println!("Command: ");
                 let input = read_input();
                 clear_screen();
@@ -94,6 +374,8 @@ fn main() {
                     }
                     "exit" => {
                         cb.stop();
+                        println!("Writing the last report");
+                        cb.wait_finished();
                         break;
                     }
                     "timeout" => {
@@ -145,15 +427,15 @@ fn main() {
                             }
                             let devices = devices.unwrap();
                             for d in devices.iter().enumerate() {
-                                println!("{}) {} {:?}", d.0 + 1, d.1.0, d.1.1);
+                                println!("{}) {}", d.0 + 1, d.1);
                             }
-                            println!("Insert the new device id: ");
+                            println!("Insert the new device id or name: ");
                             let input = read_input();
-                            let device_id = input.trim().parse::<usize>().unwrap();
-                            match cb.set_device(device_id) {
+                            let device = DeviceSelector::parse(&input);
+                            match cb.select_device(&device) {
                                 Ok(_) => {
                                     clear_screen();
-                                    println!("Device id set to {}", device_id);
+                                    println!("Device set to {}", device);
                                     break;
                                 }
                                 Err(e) => {
@@ -176,9 +458,60 @@ fn main() {
                         clear_screen();
                         println!("Filter set");
                     }
+                    "display" => {
+                        clear_screen();
+                        println!("Insert the new display filter: ");
+                        let input = read_input();
+                        let filter = if input.is_empty() { None } else { Some(input) };
+                        match cb.set_display_filter(filter) {
+                            Ok(_) => {
+                                clear_screen();
+                                println!("Display filter set");
+                            }
+                            Err(e) => {
+                                clear_screen();
+                                println!("{}", e);
+                            }
+                        }
+                    }
+                    "export" => {
+                        clear_screen();
+                        println!("Insert the new export file path: ");
+                        let input = read_input();
+                        let export = if input.is_empty() { None } else { Some(input) };
+                        match cb.set_export_file(export) {
+                            Ok(_) => {
+                                clear_screen();
+                                println!("Export file set");
+                            }
+                            Err(e) => {
+                                clear_screen();
+                                println!("Error in setting the export file: {}", e);
+                            }
+                        }
+                    }
+                    "rules" => {
+                        clear_screen();
+                        println!("Insert the rules file path: ");
+                        let input = read_input();
+                        let rules = if input.is_empty() { None } else { Some(input) };
+                        match cb.set_rules_file(rules) {
+                            Ok(_) => {
+                                clear_screen();
+                                println!("Rules loaded");
+                            }
+                            Err(e) => {
+                                clear_screen();
+                                println!("{}", e);
+                            }
+                        }
+                    }
                     "errors" => {
                         error_handler(&cb);
                     }
+                    "alerts" => {
+                        alert_handler(&cb);
+                    }
                     _ => {
                         println!("Command not valid");
                     }
@@ -188,6 +521,33 @@ fn main() {
     }
 }
 
+/// Waits for the end of the capture, then prints the errors and returns the exit code.
+fn run_batch(cb: Arc<ControlBlock>, duration: Option<u64>) -> i32 {
+    let cb_signal = cb.clone();
+    if let Err(e) = ctrlc::set_handler(move || cb_signal.stop()) {
+        eprintln!("Error: {}", e);
+        cb.stop();
+        cb.wait_finished();
+        return 1;
+    }
+    if let Some(seconds) = duration {
+        let cb_timer = cb.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_secs(seconds));
+            cb_timer.stop();
+        });
+    }
+    cb.wait_finished();
+    let errors = cb.get_errors();
+    for e in errors.iter() {
+        eprintln!("{}", e);
+    }
+    match errors.iter().any(|e| matches!(e, SnifferError::CaptureError(_))) {
+        true => 1,
+        false => 0,
+    }
+}
+
 fn clear_screen() {
     match clearscreen::clear() {
         Ok(_) => {}
@@ -239,4 +599,22 @@ fn error_handler(cb: &ControlBlock) {
         clear_screen();
         println!("No errors occurred");
     }
-}
\ No newline at end of file
+}
+
+fn alert_handler(cb: &ControlBlock) {
+    let a = cb.get_alerts();
+    clear_screen();
+    if !a.is_empty() {
+        println!("Some alerts were raised:\n");
+        let i = a.len();
+        for alert in a.iter() {
+            println!("{}", alert);
+        }
+        drop(a);
+        cb.clear_alerts(i);
+        println!();
+    }
+    else {
+        println!("No alerts raised");
+    }
+}
@@ -0,0 +1,172 @@
+//! Counters of a live capture, exposed in the Prometheus text format
+//!
+//! The counters are atomics, or maps behind mutexes held only for an increment, updated by the
+//! capture loop and the workers, so that a scrape never waits for the report. The counters by
+//! protocol are split in shards, each worker thread updating its own, so that the workers do not
+//! contend for a lock on every packet; a scrape adds the shards up.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use crate::CaptureState;
+
+/// Prefix of the names of the metrics
+const PREFIX: &str = "network_analyzer";
+/// Number of shards of the counters by protocol
+const PROTOCOL_SHARDS: usize = 32;
+
+/// Shard assigned to the next thread counting a packet
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// Shard of the counters by protocol updated by the current thread
+    static SHARD: usize = NEXT_SHARD.fetch_add(1, Ordering::Relaxed) % PROTOCOL_SHARDS;
+}
+
+#[derive(Debug, Default)]
+/// The counters of a capture
+pub struct Metrics {
+    /// Packets and bytes by protocol, one map per shard
+    protocols: [Mutex<BTreeMap<String, (u64, u64)>>; PROTOCOL_SHARDS],
+    flows: AtomicU64,
+    /// Packets received, dropped by pcap and by the interface, by interface
+    pcap_stats: Mutex<BTreeMap<String, (u64, u64, u64)>>,
+    queued: AtomicU64,
+    active_workers: AtomicU64,
+    /// Alerts of the workers dropped because the alert queue was full
+    alerts_dropped: AtomicU64,
+}
+
+impl Metrics {
+    /// Counts a packet of the given protocol.
+    pub fn add_packet(&self, protocol: &str, bytes: u32) {
+        let mut p = self.protocols[SHARD.with(|shard| *shard)].lock().unwrap();
+        match p.get_mut(protocol) {
+            Some((packets, total)) => {
+                *packets += 1;
+                *total += u64::from(bytes);
+            }
+            None => {
+                p.insert(protocol.to_string(), (1, u64::from(bytes)));
+            }
+        }
+    }
+
+    /// Gets the packets and bytes counted by protocol.
+    pub fn get_protocols(&self) -> BTreeMap<String, (u64, u64)> {
+        let mut protocols = BTreeMap::new();
+        for shard in self.protocols.iter() {
+            for (protocol, (packets, bytes)) in shard.lock().unwrap().iter() {
+                let (p, b) = protocols.entry(protocol.clone()).or_insert((0, 0));
+                *p += packets;
+                *b += bytes;
+            }
+        }
+        protocols
+    }
+
+    pub fn set_flows(&self, flows: usize) {
+        self.flows.store(flows as u64, Ordering::Relaxed);
+    }
+
+    /// Stores the statistics of pcap on an interface: packets received, dropped by pcap and by the interface.
+    pub fn set_pcap_stats(&self, interface: &str, received: u32, dropped: u32, if_dropped: u32) {
+        let mut s = self.pcap_stats.lock().unwrap();
+        s.insert(interface.to_string(), (u64::from(received), u64::from(dropped), u64::from(if_dropped)));
+    }
+
+    /// Stores the packets waiting for a worker and the workers busy.
+    pub fn set_workers(&self, queued: usize, active: usize) {
+        self.queued.store(queued as u64, Ordering::Relaxed);
+        self.active_workers.store(active as u64, Ordering::Relaxed);
+    }
+
+    /// Counts an alert dropped because the alert queue was full.
+    pub fn add_dropped_alert(&self) {
+        self.alerts_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Formats the metrics in the Prometheus text format, with the values that are not kept here.
+    pub fn to_prometheus(&self, state: &CaptureState, errors: usize, alerts: usize, sink_dropped: u64) -> String {
+        let mut out = String::new();
+        let protocols = self.get_protocols();
+        header(&mut out, "packets_total", "counter", "Packets captured by protocol");
+        for (protocol, (packets, _)) in protocols.iter() {
+            let _ = writeln!(out, "{}_packets_total{{protocol=\"{}\"}} {}", PREFIX, escape_label(protocol), packets);
+        }
+        header(&mut out, "bytes_total", "counter", "Bytes captured by protocol");
+        for (protocol, (_, bytes)) in protocols.iter() {
+            let _ = writeln!(out, "{}_bytes_total{{protocol=\"{}\"}} {}", PREFIX, escape_label(protocol), bytes);
+        }
+        gauge(&mut out, "flows", "Communications in the report", self.flows.load(Ordering::Relaxed));
+        let pcap_stats = self.pcap_stats.lock().unwrap().clone();
+        header(&mut out, "pcap_received_total", "counter", "Packets received by pcap");
+        for (interface, (received, _, _)) in pcap_stats.iter() {
+            let _ = writeln!(out, "{}_pcap_received_total{{interface=\"{}\"}} {}", PREFIX, escape_label(interface), received);
+        }
+        header(&mut out, "pcap_dropped_total", "counter", "Packets dropped by pcap because of a full buffer");
+        for (interface, (_, dropped, _)) in pcap_stats.iter() {
+            let _ = writeln!(out, "{}_pcap_dropped_total{{interface=\"{}\"}} {}", PREFIX, escape_label(interface), dropped);
+        }
+        header(&mut out, "pcap_if_dropped_total", "counter", "Packets dropped by the network interface");
+        for (interface, (_, _, if_dropped)) in pcap_stats.iter() {
+            let _ = writeln!(out, "{}_pcap_if_dropped_total{{interface=\"{}\"}} {}", PREFIX, escape_label(interface), if_dropped);
+        }
+        gauge(&mut out, "worker_queue_depth", "Packets waiting for a worker", self.queued.load(Ordering::Relaxed));
+        gauge(&mut out, "workers_active", "Workers decoding a packet", self.active_workers.load(Ordering::Relaxed));
+        gauge(&mut out, "errors", "Errors waiting to be read", errors as u64);
+        gauge(&mut out, "alerts", "Alerts waiting to be read", alerts as u64);
+        header(&mut out, "alerts_dropped_total", "counter", "Alerts of the workers dropped because the alert queue was full");
+        let _ = writeln!(out, "{}_alerts_dropped_total {}", PREFIX, self.alerts_dropped.load(Ordering::Relaxed));
+        header(&mut out, "sink_events_dropped_total", "counter", "Events not delivered because the queue of a sink was full");
+        let _ = writeln!(out, "{}_sink_events_dropped_total {}", PREFIX, sink_dropped);
+        header(&mut out, "capture_state", "gauge", "Current state of the capture");
+        for (name, s) in [("idle", CaptureState::Idle()), ("capturing", CaptureState::Capturing()), ("paused", CaptureState::Paused()), ("stopped", CaptureState::Stopped())] {
+            let _ = writeln!(out, "{}_capture_state{{state=\"{}\"}} {}", PREFIX, name, u8::from(*state == s));
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {}_{} {}", PREFIX, name, help);
+    let _ = writeln!(out, "# TYPE {}_{} {}", PREFIX, name, kind);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, "gauge", help);
+    let _ = writeln!(out, "{}_{} {}", PREFIX, name, value);
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adds_up_the_counters_of_every_thread() {
+        let metrics = Metrics::default();
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..1000 {
+                        metrics.add_packet("TCP", 60);
+                    }
+                    metrics.add_packet("DNS", 100);
+                });
+            }
+        });
+        let protocols = metrics.get_protocols();
+        assert_eq!(protocols.get("TCP"), Some(&(8000, 480_000)));
+        assert_eq!(protocols.get("DNS"), Some(&(8, 800)));
+        metrics.add_dropped_alert();
+        let text = metrics.to_prometheus(&CaptureState::Capturing(), 0, 0, 0);
+        assert!(text.contains("network_analyzer_alerts_dropped_total 1\n"));
+        assert!(text.contains("network_analyzer_packets_total{protocol=\"TCP\"} 8000\n"));
+        assert!(text.contains("network_analyzer_bytes_total{protocol=\"DNS\"} 800\n"));
+        assert!(text.contains("network_analyzer_capture_state{state=\"capturing\"} 1\n"));
+    }
+}
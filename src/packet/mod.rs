@@ -1,7 +1,10 @@
-use std::fmt;
-use chrono::{DateTime, NaiveDateTime, Utc};
+use std::{fmt, mem};
+use chrono::{DateTime, Utc};
 use libc::{c_long};
-use num_traits::FromPrimitive;
+use num_traits::ToPrimitive;
+use crate::discovery::ServiceAdvertisement;
+use crate::dissector::TransportProtocol;
+use crate::icmp::IcmpInfo;
 
 #[derive(Debug, Clone, PartialEq)]
 /// Represents a packet captured by the library
@@ -22,6 +25,38 @@ pub struct Packet {
     length: u32,
     /// Some additional info that can be registered
     info: String,
+    /// The capture time in microseconds since the epoch
+    timestamp_us: i64,
+    /// The transport protocol, if the packet has a transport layer
+    transport: Option<TransportProtocol>,
+    /// The TCP flags (FIN = 0x01 ... CWR = 0x80), if the packet is a TCP segment
+    tcp_flags: Option<u8>,
+    /// The decoded ICMP message, if the packet carries one
+    icmp: Option<IcmpInfo>,
+    /// The devices and services announced by the packet (SSDP, mDNS)
+    services: Vec<ServiceAdvertisement>,
+    /// The fields decoded by the dissectors, e.g. ("icmp.type", Int(8))
+    fields: Vec<(String, FieldValue)>,
+    /// The name of the device the packet was captured on
+    interface: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// The value of a field decoded by a dissector
+pub enum FieldValue {
+    Int(i64),
+    Str(String),
+    Bool(bool),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The type of a field, used to type check the display filters
+pub enum FieldType {
+    Int,
+    Str,
+    Bool,
+    /// A string holding an IP or MAC address
+    Address,
 }
 
 impl Packet {
@@ -35,29 +70,28 @@ impl Packet {
             protocol,
             length,
             info,
+            timestamp_us: 0,
+            transport: None,
+            tcp_flags: None,
+            icmp: None,
+            services: Vec::new(),
+            fields: Vec::new(),
+            interface: String::new(),
         }
     }
 
     pub fn set_timestamp(&mut self, timestamp: &c_long, timestamp_ns: &c_long) {
-        let ts;
-        let ts_ns;
-        if cfg!(target_os = "linux") {
-            ts = i64::from_i64(*timestamp as i64).unwrap();
-            ts_ns = u32::from_i64(*timestamp_ns as i64).unwrap();
-        } else {
-            ts = i64::from_i32(*timestamp).unwrap();
-            ts_ns = u32::from_i32(*timestamp_ns).unwrap();
-        }
-        // Create a NaiveDateTime from the timestamp
-        let naive = NaiveDateTime::from_timestamp_opt(ts, ts_ns*1000).unwrap();
-
-        // Create a normal DateTime from the NaiveDateTime and then turn it into a Local DateTime
-        let datetime: DateTime<Utc> = DateTime::from_utc(naive, Utc);
+        // c_long is 32 or 64 bits depending on the target
+        let ts = timestamp.to_i64().unwrap();
+        let ts_ns = timestamp_ns.to_u32().unwrap();
+        // Create a UTC DateTime from the timestamp and then turn it into a Local DateTime
+        let datetime: DateTime<Utc> = DateTime::from_timestamp(ts, ts_ns*1000).unwrap();
         let datetime_local = datetime.with_timezone(&chrono::Local);
 
         // Format the datetime how you want
         let newdate = datetime_local.format("%H:%M:%S%.3f");
         self.timestamp = newdate.to_string();
+        self.timestamp_us = ts * 1_000_000 + i64::from(ts_ns);
     }
 
     //Setters
@@ -82,6 +116,25 @@ impl Packet {
     pub fn set_info(&mut self, info: String) {
         self.info = info;
     }
+    pub fn set_transport(&mut self, transport: Option<TransportProtocol>) {
+        self.transport = transport;
+    }
+    pub fn set_tcp_flags(&mut self, tcp_flags: Option<u8>) {
+        self.tcp_flags = tcp_flags;
+    }
+    pub fn set_icmp(&mut self, icmp: Option<IcmpInfo>) {
+        self.icmp = icmp;
+    }
+    pub fn set_services(&mut self, services: Vec<ServiceAdvertisement>) {
+        self.services = services;
+    }
+    pub fn set_interface(&mut self, interface: String) {
+        self.interface = interface;
+    }
+    /// Adds a decoded field. A field can appear more than once in a packet.
+    pub fn add_field(&mut self, name: &str, value: FieldValue) {
+        self.fields.push((name.to_string(), value));
+    }
 
     //Getters
     pub fn get_timestamp(&self) -> &String {
@@ -105,6 +158,38 @@ impl Packet {
     pub fn get_length(&self) -> &u32 {
         &self.length
     }
+    pub fn get_info(&self) -> &String {
+        &self.info
+    }
+    pub fn get_transport(&self) -> Option<TransportProtocol> {
+        self.transport
+    }
+    pub fn get_tcp_flags(&self) -> Option<u8> {
+        self.tcp_flags
+    }
+    pub fn get_timestamp_us(&self) -> i64 {
+        self.timestamp_us
+    }
+    pub fn get_icmp(&self) -> &Option<IcmpInfo> {
+        &self.icmp
+    }
+    pub fn get_services(&self) -> &Vec<ServiceAdvertisement> {
+        &self.services
+    }
+    pub fn get_interface(&self) -> &String {
+        &self.interface
+    }
+    pub fn get_fields(&self) -> &Vec<(String, FieldValue)> {
+        &self.fields
+    }
+    /// Gets all the values of the field with the given name.
+    pub fn get_field(&self, name: &str) -> Vec<&FieldValue> {
+        self.fields.iter().filter(|(n, _)| n == name).map(|(_, v)| v).collect()
+    }
+    /// Gets the key identifying the pair of addresses and ports, regardless of the direction.
+    pub fn get_flow_key(&self) -> (String, String) {
+        flow_key(&self.source, &self.source_port, &self.destination, &self.destination_port)
+    }
 }
 
 impl fmt::Display for Packet {
@@ -125,4 +210,50 @@ impl fmt::Display for Packet {
                self.length,
                self.info)
     }
-}
\ No newline at end of file
+}
+impl fmt::Display for FieldValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FieldValue::Int(v) => write!(f, "{}", v),
+            FieldValue::Str(v) => write!(f, "{}", v),
+            FieldValue::Bool(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+impl fmt::Display for FieldType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FieldType::Int => write!(f, "integer"),
+            FieldType::Str => write!(f, "string"),
+            FieldType::Bool => write!(f, "boolean"),
+            FieldType::Address => write!(f, "address"),
+        }
+    }
+}
+
+/// Formats a timestamp in microseconds since the epoch like the timestamps of the packets.
+pub fn format_timestamp_us(timestamp_us: i64) -> String {
+    match DateTime::from_timestamp(timestamp_us.div_euclid(1_000_000), (timestamp_us.rem_euclid(1_000_000) * 1000) as u32) {
+        Some(datetime) => datetime.with_timezone(&chrono::Local).format("%H:%M:%S%.3f").to_string(),
+        None => String::new(),
+    }
+}
+
+/// Builds the key of a pair of addresses and ports, which does not depend on the direction of the packet.
+pub(crate) fn flow_key(source: &str, source_port: &Option<String>, destination: &str, destination_port: &Option<String>) -> (String, String) {
+    let mut addr1 = address_optional_port(source, source_port);
+    let mut addr2 = address_optional_port(destination, destination_port);
+    if addr1 > addr2 {
+        mem::swap(&mut addr1, &mut addr2);
+    }
+    (addr1, addr2)
+}
+
+pub(crate) fn address_optional_port(address: &str, port: &Option<String>) -> String {
+    address.to_owned() +
+        &*match port {
+            Some(port) => ":".to_owned() + port,
+            None => "".to_owned(),
+        }
+}
@@ -1,21 +1,130 @@
 
-#[derive(Debug,Clone)]
+use crate::device::DeviceSelector;
+use crate::dissector::{Dissector, DissectorRegistry};
+use crate::dns_anomaly::DnsThresholds;
+use crate::dos::DosThresholds;
+use crate::scan::ScanThresholds;
+use crate::sink::SinkConfig;
+
+#[derive(Debug,Clone,Default)]
 /// Represents the input parameters for the library
 pub struct Parameters {
-    /// The device id to capture from
-    pub device_id: usize,
+    /// The device to capture from
+    pub device: DeviceSelector,
+    /// The devices to capture from at the same time, used instead of device when not empty
+    pub devices: Vec<DeviceSelector>,
+    /// Whether a communication seen on several devices has a line of the report for each of them
+    pub group_by_interface: bool,
     /// The timeout for the capture
     pub timeout: u32,
     /// The path to the output file
     pub file_path: String,
     /// The protocol filter in BPF format
     pub filter: Option<String>,
+    /// The dissectors used to decode the application protocols
+    pub dissectors: DissectorRegistry,
+    /// The display filter evaluated on the dissected packets
+    pub display_filter: Option<String>,
+    /// The pcap file where the packets that pass the display filter are saved
+    pub export_file: Option<String>,
+    /// The thresholds of the port scan and host sweep detector
+    pub scan_thresholds: ScanThresholds,
+    /// The thresholds of the SYN flood and volumetric attack detector
+    pub dos_thresholds: DosThresholds,
+    /// The thresholds of the DNS tunneling and generated domain detectors
+    pub dns_thresholds: DnsThresholds,
+    /// The file of signatures matched against every packet
+    pub rules_file: Option<String>,
+    /// The destinations of the alerts and errors
+    pub sinks: Vec<SinkConfig>,
+    /// The address where /metrics is served in the Prometheus format, e.g. 127.0.0.1:9100
+    pub metrics_address: Option<String>,
+    /// The address where the control API is served: host:port, tcp://host:port or unix:///path
+    pub api_address: Option<String>,
+    /// The number of packets after which the capture stops
+    pub packet_count: Option<u64>,
+    /// The options used to open the capture
+    pub capture_options: CaptureOptions,
+    /// The formats of the report, text when empty
+    pub output_formats: Vec<OutputFormat>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// The options used to open a capture on a device
+pub struct CaptureOptions {
+    /// The largest number of bytes kept of each packet
+    pub snaplen: i32,
+    /// Whether the device captures the packets that are not addressed to it
+    pub promisc: bool,
+    /// The size of the kernel buffer in bytes, the default of pcap when None
+    pub buffer_size: Option<i32>,
+    /// Whether the packets are delivered as soon as they arrive instead of in batches
+    pub immediate_mode: bool,
+    /// The read timeout in milliseconds
+    pub read_timeout: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// A format of the report
+///
+/// The first format is written to the output file, the others to the output file with the
+/// extension of the format, e.g. report.txt and report.json.
+pub enum OutputFormat {
+    /// The table of the communications
+    Text,
+    /// The communications and the services as a JSON object
+    Json,
+}
+
+impl Default for CaptureOptions {
+    fn default() -> Self {
+        CaptureOptions {
+            snaplen: 5000,
+            promisc: true,
+            buffer_size: None,
+            immediate_mode: false,
+            read_timeout: 1000,
+        }
+    }
+}
+
+impl OutputFormat {
+    pub fn parse(name: &str) -> Option<OutputFormat> {
+        match name {
+            "text" => Some(OutputFormat::Text),
+            "json" => Some(OutputFormat::Json),
+            _ => None,
+        }
+    }
+
+    pub fn get_extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Text => "txt",
+            OutputFormat::Json => "json",
+        }
+    }
 }
 
 impl Parameters {
 
-    pub fn set_device_id(&mut self, device_id: usize) {
-        self.device_id = device_id;
+    pub fn set_device(&mut self, device: DeviceSelector) {
+        self.device = device;
+    }
+
+    pub fn add_device(&mut self, device: DeviceSelector) {
+        self.devices.push(device);
+    }
+
+    pub fn set_group_by_interface(&mut self, group_by_interface: bool) {
+        self.group_by_interface = group_by_interface;
+    }
+
+    /// Gets the devices to capture from: devices, or device when there are none.
+    pub fn get_devices(&self) -> Vec<DeviceSelector> {
+        match self.devices.is_empty() {
+            true => vec![self.device.clone()],
+            false => self.devices.clone(),
+        }
     }
 
     pub fn set_timeout(&mut self, timeout: u32) {
@@ -29,4 +138,57 @@ impl Parameters {
     pub fn set_protocol(&mut self, filter: String) {
         self.filter = Some(filter);
     }
+
+    pub fn set_display_filter(&mut self, display_filter: String) {
+        self.display_filter = Some(display_filter);
+    }
+
+    pub fn set_export_file(&mut self, export_file: String) {
+        self.export_file = Some(export_file);
+    }
+
+    pub fn set_scan_thresholds(&mut self, scan_thresholds: ScanThresholds) {
+        self.scan_thresholds = scan_thresholds;
+    }
+
+    pub fn set_dos_thresholds(&mut self, dos_thresholds: DosThresholds) {
+        self.dos_thresholds = dos_thresholds;
+    }
+
+    pub fn set_dns_thresholds(&mut self, dns_thresholds: DnsThresholds) {
+        self.dns_thresholds = dns_thresholds;
+    }
+
+    pub fn set_rules_file(&mut self, rules_file: String) {
+        self.rules_file = Some(rules_file);
+    }
+
+    pub fn add_sink(&mut self, sink: SinkConfig) {
+        self.sinks.push(sink);
+    }
+
+    pub fn set_metrics_address(&mut self, metrics_address: String) {
+        self.metrics_address = Some(metrics_address);
+    }
+
+    pub fn set_api_address(&mut self, api_address: String) {
+        self.api_address = Some(api_address);
+    }
+
+    pub fn set_packet_count(&mut self, packet_count: u64) {
+        self.packet_count = Some(packet_count);
+    }
+
+    pub fn set_capture_options(&mut self, capture_options: CaptureOptions) {
+        self.capture_options = capture_options;
+    }
+
+    pub fn add_output_format(&mut self, output_format: OutputFormat) {
+        self.output_formats.push(output_format);
+    }
+
+    /// Registers a dissector, which takes precedence over the built-in ones.
+    pub fn register_dissector<D: Dissector + 'static>(&mut self, dissector: D) {
+        self.dissectors.register(dissector);
+    }
 }
\ No newline at end of file
@@ -1,14 +1,49 @@
-use std::collections::HashMap;
-use std::{fmt, mem};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
 use std::fmt::{Display};
-use prettytable::{row, Table};
-use crate::packet::Packet;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use prettytable::{row, Cell, Table};
+use serde_json::{json, Value};
+use crate::discovery::ServiceInventory;
+use crate::dissector::TransportProtocol;
+use crate::icmp::IcmpKind;
+use crate::packet::{address_optional_port, flow_key, format_timestamp_us, Packet};
+
+/// Echo requests older than this are dropped when no reply has been seen
+const ECHO_TIMEOUT_US: i64 = 30_000_000;
 
 #[derive(Default, Debug, Clone)]
 /// Represents the report generated by the library
 pub struct Report {
-    /// Each line corresponds to a communication between a unique pair of addresses
-    pub report_lines: HashMap<(String, String), ReportLine>,
+    /// Each line corresponds to a communication between a unique pair of addresses, keyed by the
+    /// pair and the interface when the lines are grouped by interface, an empty string otherwise
+    pub report_lines: HashMap<(String, String, String), ReportLine>,
+    /// Echo requests waiting for a reply, keyed by source, destination, id and sequence number
+    pub pending_echoes: HashMap<(String, String, u16, u16), i64>,
+    /// The devices and services advertised through SSDP and mDNS
+    pub services: ServiceInventory,
+    /// Whether a communication seen on several interfaces has a line for each of them
+    pub group_by_interface: bool,
+    /// Packets and bytes by protocol since the report was created or reset
+    pub protocols: BTreeMap<String, (u64, u64)>,
+    /// When the report was created or reset, in microseconds since the epoch
+    pub since_us: i64,
+}
+
+/// The report split in shards by the hash of the key of the lines, so that the workers adding
+/// packets of different communications do not wait for each other. The shards are merged into a
+/// Report when it is written, without stopping the workers for longer than the copy of a shard.
+/// The services, rarely advertised, have a lock of their own.
+pub struct ShardedReport {
+    shards: Vec<Mutex<Report>>,
+    services: Mutex<ServiceInventory>,
+    group_by_interface: AtomicBool,
+    since_us: AtomicI64,
+    /// The number of lines in all the shards
+    flows: AtomicUsize,
 }
 
 #[derive(Default, Debug, Clone)]
@@ -26,6 +61,43 @@ pub struct ReportLine {
     pub protocols: Vec<String>,
     /// The number of packets exchanged
     pub bytes_total: u32,
+    /// Round trip times of the matched ICMP echo requests
+    pub rtt: Option<RttStats>,
+    /// ICMP errors that reported a problem with this communication
+    pub icmp_errors: Vec<String>,
+    /// The first timestamp of the line, in microseconds since the epoch
+    pub timestamp_first_us: i64,
+    /// The last timestamp of the line, in microseconds since the epoch
+    pub timestamp_last_us: i64,
+    /// The address that sent the first packet
+    pub source: String,
+    /// The address that received the first packet
+    pub destination: String,
+    /// The destination port of the first packet
+    pub destination_port: Option<String>,
+    /// The transport protocol of the first packet
+    pub transport: Option<TransportProtocol>,
+    /// The TCP flags of the first packet
+    pub first_tcp_flags: Option<u8>,
+    /// The number of packets sent by the source
+    pub packets_forward: u32,
+    /// The number of packets sent back to the source
+    pub packets_backward: u32,
+    /// The interfaces the communication was captured on
+    pub interfaces: Vec<String>,
+    /// The last timestamp of the line when a subscriber was last told about it
+    pub(crate) notified_us: i64,
+    /// Whether the subscribers were told that the flow ended
+    pub(crate) ended: bool,
+}
+
+#[derive(Default, Debug, Clone)]
+/// Round trip time statistics, in milliseconds
+pub struct RttStats {
+    pub count: u32,
+    pub min: f64,
+    pub max: f64,
+    pub total: f64,
 }
 
 impl Report {
@@ -34,56 +106,279 @@ impl Report {
     //         report_lines: HashMap::new(),
     //     }
     // }
-    pub fn get_report_lines(&mut self) -> &mut HashMap<(String, String), ReportLine> {
+    pub fn get_report_lines(&mut self) -> &mut HashMap<(String, String, String), ReportLine> {
         &mut self.report_lines
     }
-    pub fn add_packet(&mut self, packet: Packet) {
-        let mut addr1 = packet.get_source().clone() +
-            &(match packet.get_source_port() {
-                Some(port) => (":".to_owned() + port).to_owned(),
-                None => "".to_owned(),
-            });
-        let mut addr2 = packet.get_destination().clone() +
-            &(match packet.get_destination_port() {
-                Some(port) => (":".to_owned() + port).to_owned(),
-                None => "".to_owned(),
-            });
-        if addr1 > addr2 {
-            mem::swap(&mut addr1, &mut addr2);
-        }
-        let key = (addr1, addr2).clone();
+
+    /// Whether the interfaces are shown: when the lines are grouped by interface or more than one was captured.
+    fn show_interfaces(&self) -> bool {
+        self.group_by_interface ||
+            self.report_lines.values().flat_map(|rl| rl.interfaces.iter()).collect::<HashSet<&String>>().len() > 1
+    }
+    /// Adds a packet to its line, returns whether the line is new. The ICMP errors and the
+    /// services are added by the sharded report, as they can belong to other shards.
+    fn add_packet(&mut self, packet: &Packet, key: &(String, String, String)) -> bool {
+        let totals = self.protocols.entry(packet.get_protocol().clone()).or_insert((0, 0));
+        totals.0 += 1;
+        totals.1 += u64::from(*packet.get_length());
         let report_lines = self.get_report_lines();
+        let new = report_lines.get_mut(key).is_none();
 
-        if report_lines.get_mut(&key).is_none() {
+        if new {
             let mut rl = ReportLine::default();
             rl.set_timestamp_first(packet.get_timestamp().clone());
             rl.set_timestamp_last(packet.get_timestamp().clone());
-            rl.set_source_optional_port(packet.get_source().clone() +
-                &*match packet.get_source_port() {
-                    Some(port) => ":".to_owned() + port,
-                    None => "".to_owned(),
-                });
-            rl.set_destination_optional_port(packet.get_destination().clone() +
-                &*match packet.get_destination_port() {
-                    Some(port) => ":".to_owned() + port,
-                    None => "".to_owned(),
-                });
+            rl.set_source_optional_port(address_optional_port(packet.get_source(), packet.get_source_port()));
+            rl.set_destination_optional_port(address_optional_port(packet.get_destination(), packet.get_destination_port()));
             rl.add_protocol(packet.get_protocol().clone());
-            rl.set_bytes_total(packet.get_length().clone());
-            report_lines.insert(key, rl);
+            rl.set_bytes_total(*packet.get_length());
+            rl.timestamp_first_us = packet.get_timestamp_us();
+            rl.timestamp_last_us = packet.get_timestamp_us();
+            rl.source = packet.get_source().clone();
+            rl.destination = packet.get_destination().clone();
+            rl.destination_port = packet.get_destination_port().clone();
+            rl.transport = packet.get_transport();
+            rl.first_tcp_flags = packet.get_tcp_flags();
+            rl.packets_forward = 1;
+            rl.add_interface(packet.get_interface());
+            report_lines.insert(key.clone(), rl);
         } else {
-            report_lines.get_mut(&key).unwrap().add_packet(packet.clone());
+            report_lines.get_mut(key).unwrap().add_packet(packet);
+        }
+        self.add_echo(packet, key);
+        new
+    }
+
+    /// Matches echo requests with their replies, which belong to the same line.
+    fn add_echo(&mut self, packet: &Packet, key: &(String, String, String)) {
+        let icmp = match packet.get_icmp() {
+            Some(icmp) => icmp,
+            None => return,
+        };
+        match &icmp.kind {
+            IcmpKind::EchoRequest { id, seq } => {
+                let now = packet.get_timestamp_us();
+                if self.pending_echoes.len() > 1024 {
+                    self.pending_echoes.retain(|_, ts| now - *ts < ECHO_TIMEOUT_US);
+                }
+                self.pending_echoes.insert((packet.get_source().clone(), packet.get_destination().clone(), *id, *seq), now);
+            }
+            IcmpKind::EchoReply { id, seq } => {
+                let request = (packet.get_destination().clone(), packet.get_source().clone(), *id, *seq);
+                if let Some(ts) = self.pending_echoes.remove(&request) {
+                    let rtt = (packet.get_timestamp_us() - ts) as f64 / 1000.0;
+                    if let Some(rl) = self.report_lines.get_mut(key) {
+                        rl.add_rtt(rtt);
+                    }
+                }
+            }
+            _ => (),
         }
     }
 
+    /// Adds an ICMP error to a line, returns false if the line does not exist.
+    fn add_icmp_error(&mut self, key: &(String, String, String), message: &str) -> bool {
+        match self.report_lines.get_mut(key) {
+            Some(rl) => {
+                rl.add_icmp_error(message.to_string());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Empties the communications and the totals, for interval reporting. The echo requests waiting
+    /// for a reply and the services are kept.
+    pub fn reset(&mut self, now_us: i64) {
+        self.report_lines.clear();
+        self.protocols.clear();
+        self.since_us = now_us;
+    }
+
     pub fn to_formatted_table(&self) -> Table {
         let mut table = Table::new();
-        table.add_row(row!["First Timestamp", "Last Timestamp", "Address 1", "Address 2", "Protocols", "Bytes Total"]);
+        let show_interfaces = self.show_interfaces();
+        let mut header = row!["First Timestamp", "Last Timestamp", "Address 1", "Address 2", "Protocols", "Bytes Total", "ICMP"];
+        if show_interfaces {
+            header.add_cell(Cell::new("Interfaces"));
+        }
+        table.add_row(header);
         for (_, rls) in self.report_lines.iter() {
-            table.add_row(row![rls.timestamp_first, rls.timestamp_last, rls.source_optional_port, rls.destination_optional_port, rls.protocols.join(","), rls.bytes_total]);
+            let mut line = row![rls.timestamp_first, rls.timestamp_last, rls.source_optional_port, rls.destination_optional_port, rls.protocols.join(","), rls.bytes_total, rls.icmp_summary()];
+            if show_interfaces {
+                line.add_cell(Cell::new(&rls.interfaces.join(",")));
+            }
+            table.add_row(line);
         }
         table
     }
+
+    /// Formats the whole report: the table of the communications followed by the service discovery inventory.
+    pub fn to_output(&self) -> String {
+        let mut output = self.to_formatted_table().to_string();
+        if !self.services.is_empty() {
+            output.push_str("\nAdvertised devices and services\n");
+            output.push_str(&self.services.to_formatted_table().to_string());
+        }
+        output
+    }
+
+    /// Converts the report to JSON: the communications and the advertised services.
+    pub fn to_json(&self) -> Value {
+        let lines = self.report_lines.values().map(|rl| json!({
+            "timestamp_first": rl.timestamp_first,
+            "timestamp_last": rl.timestamp_last,
+            "address_1": rl.source_optional_port,
+            "address_2": rl.destination_optional_port,
+            "protocols": rl.protocols,
+            "bytes_total": rl.bytes_total,
+            "packets_forward": rl.packets_forward,
+            "packets_backward": rl.packets_backward,
+            "icmp": rl.icmp_summary(),
+            "interfaces": rl.interfaces,
+        })).collect::<Vec<Value>>();
+        let services = self.services.entries.values().map(|s| json!({
+            "protocol": s.protocol,
+            "service_type": s.service_type,
+            "name": s.name,
+            "location": s.location,
+            "details": s.details,
+            "source": s.source,
+            "timestamp_first": format_timestamp_us(s.timestamp_first_us),
+            "timestamp_last": format_timestamp_us(s.timestamp_last_us),
+            "count": s.count,
+        })).collect::<Vec<Value>>();
+        json!({ "communications": lines, "services": services })
+    }
+}
+
+impl ShardedReport {
+    pub fn new(shard_count: usize, since_us: i64) -> Self {
+        ShardedReport {
+            shards: (0..shard_count.max(1)).map(|_| Mutex::new(Report::default())).collect(),
+            services: Mutex::new(ServiceInventory::default()),
+            group_by_interface: AtomicBool::new(false),
+            since_us: AtomicI64::new(since_us),
+            flows: AtomicUsize::new(0),
+        }
+    }
+
+    /// Sets whether a communication seen on several interfaces has a line for each of them.
+    pub fn set_group_by_interface(&self, group_by_interface: bool) {
+        self.group_by_interface.store(group_by_interface, Ordering::Relaxed);
+        for shard in self.shards.iter() {
+            shard.lock().unwrap().group_by_interface = group_by_interface;
+        }
+    }
+
+    /// Gets the number of lines of the report.
+    pub fn get_flow_count(&self) -> usize {
+        self.flows.load(Ordering::Relaxed)
+    }
+
+    fn get_shard(&self, key: &(String, String, String)) -> &Mutex<Report> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[(hasher.finish() % self.shards.len() as u64) as usize]
+    }
+
+    /// Adds a packet to its line. on_added is called with the shard, the key of the line and
+    /// whether the line is new, while the shard is locked.
+    pub fn add_packet<F: FnOnce(&mut Report, &(String, String, String), bool)>(&self, packet: &Packet, on_added: F) {
+        let group_by_interface = self.group_by_interface.load(Ordering::Relaxed);
+        let key = line_key(packet.get_flow_key(), packet.get_interface(), group_by_interface);
+        let shard = self.get_shard(&key);
+        let mut report = shard.lock().unwrap();
+        let new = report.add_packet(packet, &key);
+        if new {
+            self.flows.fetch_add(1, Ordering::Relaxed);
+        }
+        on_added(&mut report, &key, new);
+        drop(report);
+
+        if let Some((original_key, message)) = get_icmp_error(packet, group_by_interface) {
+            // Fall back to the line of the ICMP message itself when the flow was not captured
+            let added = self.get_shard(&original_key).lock().unwrap().add_icmp_error(&original_key, &message);
+            if !added {
+                shard.lock().unwrap().add_icmp_error(&key, &message);
+            }
+        }
+        if !packet.get_services().is_empty() {
+            self.services.lock().unwrap().add(packet.get_services(), packet.get_source(), packet.get_timestamp_us());
+        }
+    }
+
+    /// Merges the shards into a report, locking one shard at a time.
+    pub fn merge(&self) -> Report {
+        let mut report = self.new_report(self.since_us.load(Ordering::Relaxed));
+        for shard in self.shards.iter() {
+            let shard = shard.lock().unwrap();
+            report.report_lines.extend(shard.report_lines.iter().map(|(k, rl)| (k.clone(), rl.clone())));
+            add_protocols(&mut report, &shard.protocols);
+        }
+        report
+    }
+
+    /// Calls f with every shard, locking one at a time.
+    pub(crate) fn for_each_shard<F: FnMut(&mut Report)>(&self, mut f: F) {
+        for shard in self.shards.iter() {
+            f(&mut shard.lock().unwrap());
+        }
+    }
+
+    /// Merges the shards into a report and empties them, for interval reporting. on_drained is
+    /// called with every shard about to be emptied, while it is locked.
+    pub fn reset<F: FnMut(&Report)>(&self, now_us: i64, mut on_drained: F) -> Report {
+        let mut report = self.new_report(self.since_us.swap(now_us, Ordering::Relaxed));
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock().unwrap();
+            on_drained(&shard);
+            self.flows.fetch_sub(shard.report_lines.len(), Ordering::Relaxed);
+            report.report_lines.extend(shard.report_lines.drain());
+            add_protocols(&mut report, &shard.protocols);
+            shard.reset(now_us);
+        }
+        report
+    }
+
+    fn new_report(&self, since_us: i64) -> Report {
+        Report {
+            services: self.services.lock().unwrap().clone(),
+            group_by_interface: self.group_by_interface.load(Ordering::Relaxed),
+            since_us,
+            ..Report::default()
+        }
+    }
+}
+
+/// Builds the key of the line of a pair of addresses captured on the interface.
+fn line_key(flow_key: (String, String), interface: &str, group_by_interface: bool) -> (String, String, String) {
+    match group_by_interface {
+        true => (flow_key.0, flow_key.1, interface.to_string()),
+        false => (flow_key.0, flow_key.1, String::new()),
+    }
+}
+
+/// Gets the key of the line of the flow that triggered an ICMP error, and the description of the error.
+fn get_icmp_error(packet: &Packet, group_by_interface: bool) -> Option<((String, String, String), String)> {
+    let icmp = packet.get_icmp().as_ref()?;
+    match &icmp.kind {
+        IcmpKind::EchoRequest { .. } | IcmpKind::EchoReply { .. } => None,
+        _ => {
+            let original = icmp.get_original()?;
+            let message = format!("{} from {}", icmp.get_name(), packet.get_source());
+            let key = line_key(flow_key(&original.source, &original.source_port, &original.destination, &original.destination_port), packet.get_interface(), group_by_interface);
+            Some((key, message))
+        }
+    }
+}
+
+fn add_protocols(report: &mut Report, protocols: &BTreeMap<String, (u64, u64)>) {
+    for (protocol, (packets, bytes)) in protocols.iter() {
+        let totals = report.protocols.entry(protocol.clone()).or_insert((0, 0));
+        totals.0 += packets;
+        totals.1 += bytes;
+    }
 }
 
 impl Display for Report {
@@ -123,11 +418,55 @@ impl ReportLine {
     pub fn set_bytes_total(&mut self, bytes_total: u32) {
         self.bytes_total = bytes_total;
     }
-    pub fn add_packet(&mut self, packet: Packet) {
+    pub fn add_rtt(&mut self, rtt: f64) {
+        match &mut self.rtt {
+            Some(stats) => {
+                stats.count += 1;
+                stats.total += rtt;
+                if rtt < stats.min {
+                    stats.min = rtt;
+                }
+                if rtt > stats.max {
+                    stats.max = rtt;
+                }
+            }
+            None => {
+                self.rtt = Some(RttStats { count: 1, min: rtt, max: rtt, total: rtt });
+            }
+        }
+    }
+    pub fn add_interface(&mut self, interface: &str) {
+        if !interface.is_empty() && !self.interfaces.iter().any(|i| i == interface) {
+            self.interfaces.push(interface.to_string());
+        }
+    }
+    pub fn add_icmp_error(&mut self, error: String) {
+        if !self.icmp_errors.contains(&error) {
+            self.icmp_errors.push(error);
+        }
+    }
+    /// Summarizes the echo round trip times and the ICMP errors of the line.
+    pub fn icmp_summary(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(stats) = &self.rtt {
+            parts.push(format!("rtt min/avg/max {:.3}/{:.3}/{:.3} ms ({})", stats.min, stats.total / f64::from(stats.count), stats.max, stats.count));
+        }
+        parts.extend(self.icmp_errors.iter().cloned());
+        parts.join("\n")
+    }
+    pub fn add_packet(&mut self, packet: &Packet) {
         if !self.protocols.contains(packet.get_protocol()) {
             self.protocols.push(packet.get_protocol().clone());
         }
         self.bytes_total += packet.get_length();
+        self.add_interface(packet.get_interface());
+        if *packet.get_source() == self.source && *packet.get_destination_port() == self.destination_port {
+            self.packets_forward += 1;
+        } else {
+            self.packets_backward += 1;
+        }
+        self.timestamp_first_us = self.timestamp_first_us.min(packet.get_timestamp_us());
+        self.timestamp_last_us = self.timestamp_last_us.max(packet.get_timestamp_us());
         if self.timestamp_last < *packet.get_timestamp() {
             self.timestamp_last = packet.get_timestamp().clone();
         } else if self.timestamp_first > *packet.get_timestamp() {
@@ -157,4 +496,150 @@ impl Display for ReportLine {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{} {} {} {} {} {}", self.timestamp_first, self.timestamp_last, self.source_optional_port, self.destination_optional_port, self.protocols.join(","), self.bytes_total)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use etherparse::PacketBuilder;
+    use libc::{c_long, suseconds_t, time_t, timeval};
+    use pcap::PacketHeader;
+    use crate::parameters::Parameters;
+    use crate::{ControlBlockBuilder, PacketProcessor};
+    use super::*;
+
+    /// Processes a UDP packet captured on the interface, one second after the epoch plus the microseconds.
+    fn capture(processor: &PacketProcessor, interface: &str, source: [u8; 4], destination: [u8; 4], microsecond: i64) {
+        let mut data = Vec::new();
+        PacketBuilder::ethernet2([2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 2])
+            .ipv4(source, destination, 64)
+            .udp(40000, 40000)
+            .write(&mut data, &[0; 16])
+            .unwrap();
+        let header = PacketHeader {
+            ts: timeval { tv_sec: 1 as time_t, tv_usec: microsecond as suseconds_t },
+            caplen: data.len() as u32,
+            len: data.len() as u32,
+        };
+        processor.process(interface, &header, &data, 1);
+    }
+
+    /// A packet of 64 bytes from 10.0.0.1 to the host, captured one second after the epoch.
+    fn packet(host: u8, protocol: &str) -> Packet {
+        let mut packet = Packet::new(String::new(), String::from("10.0.0.1"), format!("10.0.1.{}", host), None, None, protocol.to_string(), 64, String::new());
+        packet.set_timestamp(&(1 as c_long), &(0 as c_long));
+        packet
+    }
+
+    /// The lines in every shard, in the order of the shards.
+    fn shard_lines(report: &ShardedReport) -> Vec<usize> {
+        let mut lines = Vec::new();
+        report.for_each_shard(|shard| lines.push(shard.report_lines.len()));
+        lines
+    }
+
+    fn new_processor(group_by_interface: bool) -> PacketProcessor {
+        let parameters = Parameters { group_by_interface, ..Parameters::default() };
+        PacketProcessor::new(Arc::new(ControlBlockBuilder::new().parameters(parameters).build_offline().unwrap()))
+    }
+
+    #[test]
+    fn merges_the_packets_of_several_interfaces() {
+        let processor = new_processor(false);
+        capture(&processor, "eth0", [10, 0, 0, 1], [10, 0, 0, 2], 0);
+        capture(&processor, "wlan0", [10, 0, 0, 2], [10, 0, 0, 1], 10);
+        capture(&processor, "eth0", [10, 0, 0, 1], [10, 0, 0, 3], 20);
+        let report = processor.get_control_block().report.merge();
+        assert_eq!(report.report_lines.len(), 2);
+        let line = &report.report_lines[&(String::from("10.0.0.1:40000"), String::from("10.0.0.2:40000"), String::new())];
+        assert_eq!(line.interfaces, vec![String::from("eth0"), String::from("wlan0")]);
+        assert_eq!((line.packets_forward, line.packets_backward), (1, 1));
+        assert!(report.to_output().contains("eth0,wlan0"));
+    }
+
+    #[test]
+    fn groups_the_lines_by_interface() {
+        let processor = new_processor(true);
+        capture(&processor, "eth0", [10, 0, 0, 1], [10, 0, 0, 2], 0);
+        capture(&processor, "wlan0", [10, 0, 0, 2], [10, 0, 0, 1], 10);
+        capture(&processor, "wlan0", [10, 0, 0, 1], [10, 0, 0, 2], 20);
+        let report = processor.get_control_block().report.merge();
+        assert_eq!(report.report_lines.len(), 2);
+        for (interface, packets) in [("eth0", (1, 0)), ("wlan0", (1, 1))] {
+            let line = &report.report_lines[&(String::from("10.0.0.1:40000"), String::from("10.0.0.2:40000"), interface.to_string())];
+            assert_eq!(line.interfaces, vec![interface.to_string()]);
+            assert_eq!((line.packets_forward, line.packets_backward), packets);
+        }
+        // A single interface is shown when grouping
+        let processor = new_processor(true);
+        capture(&processor, "eth0", [10, 0, 0, 1], [10, 0, 0, 2], 0);
+        assert!(processor.get_control_block().report.merge().to_output().contains("Interfaces"));
+    }
+
+    #[test]
+    fn merges_the_lines_and_protocols_of_every_shard() {
+        let report = ShardedReport::new(8, 5);
+        for host in 0..40 {
+            report.add_packet(&packet(host, if host % 4 == 0 { "DNS" } else { "UDP" }), |_, _, _| ());
+        }
+        report.add_packet(&packet(3, "UDP"), |_, _, _| ());
+        // Every line is in a single shard, and the lines are spread over several of them
+        let lines = shard_lines(&report);
+        assert_eq!(lines.iter().sum::<usize>(), 40);
+        assert!(lines.iter().filter(|l| **l > 0).count() > 1);
+        assert_eq!(report.get_flow_count(), 40);
+
+        let merged = report.merge();
+        assert_eq!(merged.since_us, 5);
+        assert_eq!(merged.report_lines.len(), 40);
+        assert_eq!(merged.report_lines[&(String::from("10.0.0.1"), String::from("10.0.1.3"), String::new())].bytes_total, 128);
+        assert_eq!(merged.protocols.get("DNS"), Some(&(10, 640)));
+        assert_eq!(merged.protocols.get("UDP"), Some(&(31, 1984)));
+        // Merging copies the shards
+        assert_eq!(report.merge().report_lines.len(), 40);
+    }
+
+    #[test]
+    fn counts_the_flows_added_by_several_threads() {
+        let report = ShardedReport::new(4, 0);
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for i in 0..1000 {
+                        report.add_packet(&packet((i % 10) as u8, "UDP"), |_, _, _| ());
+                    }
+                });
+            }
+        });
+        assert_eq!(report.get_flow_count(), 10);
+        let merged = report.merge();
+        assert_eq!(merged.protocols.get("UDP"), Some(&(8000, 512_000)));
+        assert!(merged.report_lines.values().all(|rl| rl.packets_forward == 800));
+    }
+
+    #[test]
+    fn drains_every_shard_on_reset() {
+        let report = ShardedReport::new(4, 5);
+        let mut new_lines = 0;
+        for host in 0..20 {
+            report.add_packet(&packet(host, "UDP"), |_, _, new| new_lines += usize::from(new));
+        }
+        assert_eq!(new_lines, 20);
+        let lines = shard_lines(&report);
+
+        let mut drained = Vec::new();
+        let reset = report.reset(100, |shard| drained.push(shard.report_lines.len()));
+        assert_eq!(drained, lines);
+        assert_eq!(reset.since_us, 5);
+        assert_eq!(reset.report_lines.len(), 20);
+        assert_eq!(reset.protocols.get("UDP"), Some(&(20, 1280)));
+
+        let merged = report.merge();
+        assert_eq!(merged.since_us, 100);
+        assert!(merged.report_lines.is_empty() && merged.protocols.is_empty());
+        assert_eq!(report.get_flow_count(), 0);
+        // The lines start again with their next packet
+        report.add_packet(&packet(0, "UDP"), |_, _, new| assert!(new));
+        assert_eq!(report.get_flow_count(), 1);
+    }
+}
@@ -0,0 +1,798 @@
+//! Signature based detection
+//!
+//! The rules are written in a subset of the Suricata/Snort syntax, one per line (a line ending
+//! with a backslash continues on the next one, lines starting with # are comments):
+//!
+//! alert tcp any any -> 192.168.0.0/16 [80,8080] (msg:"Admin page"; content:"GET /admin"; depth:10; sid:1000001; rev:1;)
+//! alert udp any any -> any 53 (msg:"Suspicious TLD"; dns.query; content:".xyz"; nocase; sid:1000002;)
+//! alert tcp any any <> any any (msg:"NULL scan probe"; flags:0; sid:1000003;)
+//!
+//! * actions: alert
+//! * protocols: ip (any packet), tcp, udp, icmp or the name of a dissector (dns, tls, ...)
+//! * addresses: any, an address, a subnet in CIDR notation or a list between brackets, each
+//!   entry optionally negated with !
+//! * ports: any, a port, a range (1024:, :1023, 6000:6100) or a list between brackets
+//! * directions: -> and <> (both directions)
+//! * options: msg, sid, rev, content (with |hex bytes| and ! negation), nocase, offset, depth,
+//!   distance, within, dsize, flags, and sticky buffers naming a dissected field
+//!   (e.g. "tls.sni;" or "dns.query;") to match the following contents against the field
+//!   instead of the payload, "pkt_data;" going back to the payload
+//!
+//! The metadata options classtype, reference, metadata, priority and gid are accepted and
+//! ignored, any other option is an error.
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use crate::alert::{Alert, AlertCategory};
+use crate::display_filter::{builtin_fields, field_values, has_protocol, in_subnet};
+use crate::dissector::{DissectorRegistry, TransportProtocol};
+use crate::packet::{address_optional_port, format_timestamp_us, FieldType, Packet};
+
+/// Options accepted for compatibility but without effect
+const IGNORED_OPTIONS: [&str; 5] = ["classtype", "reference", "metadata", "priority", "gid"];
+/// Suricata names of the sticky buffers and the fields they correspond to
+const BUFFER_ALIASES: [(&str, &str); 2] = [("dns.query", "dns.qry.name"), ("dns_query", "dns.qry.name")];
+
+#[derive(Debug, Clone, PartialEq)]
+/// An error found while parsing a rules file
+pub struct RuleError {
+    /// The line of the rule, starting from 1
+    pub line: usize,
+    /// The description of the error
+    pub message: String,
+}
+
+impl fmt::Display for RuleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for RuleError {}
+
+/// The rules loaded from a file
+#[derive(Debug, Clone, Default)]
+pub struct RuleSet {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone)]
+/// A signature
+pub struct Rule {
+    pub sid: u32,
+    pub rev: u32,
+    pub msg: String,
+    protocol: String,
+    source: AddressSpec,
+    source_ports: PortSpec,
+    bidirectional: bool,
+    destination: AddressSpec,
+    destination_ports: PortSpec,
+    flags: Option<FlagsSpec>,
+    dsize: Option<(SizeOp, usize, usize)>,
+    groups: Vec<ContentGroup>,
+}
+
+/// A list of networks, matching when the address is in one of the positive entries (or there
+/// are none) and in none of the negated ones
+#[derive(Debug, Clone, Default)]
+struct AddressSpec {
+    negated: bool,
+    entries: Vec<(bool, IpAddr, u8)>,
+}
+
+/// A list of port ranges, with the same semantics as AddressSpec
+#[derive(Debug, Clone, Default)]
+struct PortSpec {
+    negated: bool,
+    entries: Vec<(bool, u16, u16)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FlagsModifier {
+    /// Exactly the flags given
+    Exact,
+    /// The flags given and possibly others
+    All,
+    /// Any of the flags given
+    Any,
+    /// None of the flags given
+    Not,
+}
+
+#[derive(Debug, Clone)]
+struct FlagsSpec {
+    mask: u8,
+    modifier: FlagsModifier,
+    ignored: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SizeOp {
+    Eq,
+    Lt,
+    Gt,
+    Between,
+}
+
+/// The buffer the contents are searched in
+#[derive(Debug, Clone, PartialEq)]
+enum Buffer {
+    Payload,
+    Field(String),
+}
+
+/// Consecutive contents searched in the same buffer
+#[derive(Debug, Clone)]
+struct ContentGroup {
+    buffer: Buffer,
+    contents: Vec<Content>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Content {
+    pattern: Vec<u8>,
+    negated: bool,
+    nocase: bool,
+    offset: Option<usize>,
+    depth: Option<usize>,
+    distance: Option<i64>,
+    within: Option<usize>,
+}
+
+impl RuleSet {
+    /// Parses the text of a rules file. The sticky buffers are checked against the fields of the
+    /// built-in decoders and of the given dissectors.
+    pub fn parse(text: &str, dissectors: &DissectorRegistry) -> Result<RuleSet, RuleError> {
+        let mut fields: HashMap<String, FieldType> = builtin_fields().into_iter().collect();
+        fields.extend(dissectors.get_fields());
+        let mut rules = Vec::new();
+        let mut pending = String::new();
+        let mut first_line = 0;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if pending.is_empty() {
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                first_line = i + 1;
+            }
+            match line.strip_suffix('\\') {
+                Some(part) => {
+                    pending.push_str(part);
+                    continue;
+                }
+                None => pending.push_str(line),
+            }
+            let rule = parse_rule(&pending, &fields).map_err(|message| RuleError { line: first_line, message })?;
+            if rules.iter().any(|r: &Rule| r.sid == rule.sid) {
+                return Err(RuleError { line: first_line, message: format!("Duplicate sid {}", rule.sid) });
+            }
+            rules.push(rule);
+            pending.clear();
+        }
+        if !pending.is_empty() {
+            return Err(RuleError { line: first_line, message: String::from("Rule continues past the end of the file") });
+        }
+        Ok(RuleSet { rules })
+    }
+
+    pub fn get_rules(&self) -> &Vec<Rule> {
+        &self.rules
+    }
+
+    /// Matches a dissected packet and its transport payload against the rules and returns an
+    /// alert for each hit. The frame is the number of the packet in the capture.
+    pub fn matches(&self, packet: &Packet, payload: &[u8], frame: u64) -> Vec<Alert> {
+        self.rules.iter()
+            .filter(|rule| rule.matches(packet, payload))
+            .map(|rule| Alert {
+                category: AlertCategory::Signature,
+                kind: rule.msg.clone(),
+                source: address_optional_port(packet.get_source(), packet.get_source_port()),
+                targets: vec![address_optional_port(packet.get_destination(), packet.get_destination_port())],
+                evidence: vec![
+                    format!("sid:{} rev:{}", rule.sid, rule.rev),
+                    format!("frame {} at {}", frame, format_timestamp_us(packet.get_timestamp_us())),
+                ],
+                start_us: packet.get_timestamp_us(),
+                end_us: packet.get_timestamp_us(),
+            })
+            .collect()
+    }
+}
+
+impl Rule {
+    fn matches(&self, packet: &Packet, payload: &[u8]) -> bool {
+        if self.protocol != "ip" && !has_protocol(packet, &self.protocol) {
+            return false;
+        }
+        let forward = self.matches_endpoints(packet.get_source(), packet.get_source_port(), packet.get_destination(), packet.get_destination_port());
+        let backward = self.bidirectional &&
+            self.matches_endpoints(packet.get_destination(), packet.get_destination_port(), packet.get_source(), packet.get_source_port());
+        if !forward && !backward {
+            return false;
+        }
+        if let Some(flags) = &self.flags {
+            match (packet.get_transport(), packet.get_tcp_flags()) {
+                (Some(TransportProtocol::Tcp), Some(f)) if flags.matches(f) => {}
+                _ => return false,
+            }
+        }
+        if let Some((op, a, b)) = self.dsize {
+            let size = payload.len();
+            let ok = match op {
+                SizeOp::Eq => size == a,
+                SizeOp::Lt => size < a,
+                SizeOp::Gt => size > a,
+                SizeOp::Between => size > a && size < b,
+            };
+            if !ok {
+                return false;
+            }
+        }
+        self.groups.iter().all(|group| match &group.buffer {
+            Buffer::Payload => match_contents(payload, &group.contents, 0, 0),
+            Buffer::Field(field) => field_values(packet, field).iter()
+                .any(|value| match_contents(value.to_string().as_bytes(), &group.contents, 0, 0)),
+        })
+    }
+
+    fn matches_endpoints(&self, source: &str, source_port: &Option<String>, destination: &str, destination_port: &Option<String>) -> bool {
+        self.source.matches(source) &&
+            self.source_ports.matches(source_port) &&
+            self.destination.matches(destination) &&
+            self.destination_ports.matches(destination_port)
+    }
+}
+
+impl AddressSpec {
+    fn matches(&self, address: &str) -> bool {
+        if self.entries.is_empty() {
+            return !self.negated;
+        }
+        let address = match address.parse::<IpAddr>() {
+            Ok(a) => a,
+            Err(_) => return self.negated,
+        };
+        let positive = self.entries.iter().filter(|e| !e.0).collect::<Vec<_>>();
+        let result = (positive.is_empty() || positive.iter().any(|(_, n, p)| in_subnet(&address, n, *p))) &&
+            !self.entries.iter().any(|(negated, n, p)| *negated && in_subnet(&address, n, *p));
+        result != self.negated
+    }
+}
+
+impl PortSpec {
+    fn matches(&self, port: &Option<String>) -> bool {
+        if self.entries.is_empty() {
+            return !self.negated;
+        }
+        let port = match port.as_ref().and_then(|p| p.parse::<u16>().ok()) {
+            Some(p) => p,
+            None => return self.negated,
+        };
+        let positive = self.entries.iter().filter(|e| !e.0).collect::<Vec<_>>();
+        let result = (positive.is_empty() || positive.iter().any(|(_, first, last)| (*first..=*last).contains(&port))) &&
+            !self.entries.iter().any(|(negated, first, last)| *negated && (*first..=*last).contains(&port));
+        result != self.negated
+    }
+}
+
+impl FlagsSpec {
+    fn matches(&self, flags: u8) -> bool {
+        let flags = flags & !self.ignored;
+        match self.modifier {
+            FlagsModifier::Exact => flags == self.mask,
+            FlagsModifier::All => flags & self.mask == self.mask,
+            FlagsModifier::Any => flags & self.mask != 0,
+            FlagsModifier::Not => flags & self.mask == 0,
+        }
+    }
+}
+
+/// Searches the contents from the given one on, backtracking over the positions of each
+/// content so that the relative ones can be satisfied.
+fn match_contents(buffer: &[u8], contents: &[Content], index: usize, previous_end: usize) -> bool {
+    let content = match contents.get(index) {
+        Some(c) => c,
+        None => return true,
+    };
+    let relative = content.distance.is_some() || content.within.is_some();
+    let start = if relative {
+        (previous_end as i64 + content.distance.unwrap_or(0)).max(0) as usize
+    } else {
+        content.offset.unwrap_or(0)
+    };
+    let limit = if relative { content.within } else { content.depth };
+    let end = match limit {
+        Some(l) => buffer.len().min(start + l),
+        None => buffer.len(),
+    };
+    let len = content.pattern.len();
+    let positions = if start + len <= end { start..end - len + 1 } else { 0..0 };
+    let mut found = positions.filter(|p| {
+        let window = &buffer[*p..*p + len];
+        if content.nocase { window.eq_ignore_ascii_case(&content.pattern) } else { window == content.pattern.as_slice() }
+    });
+    if content.negated {
+        return found.next().is_none() && match_contents(buffer, contents, index + 1, previous_end);
+    }
+    found.any(|p| match_contents(buffer, contents, index + 1, p + len))
+}
+
+fn parse_rule(text: &str, fields: &HashMap<String, FieldType>) -> Result<Rule, String> {
+    let open = text.find('(').ok_or("Missing options")?;
+    let close = text.rfind(')').ok_or("Missing closing parenthesis")?;
+    if close < open || !text[close + 1..].trim().is_empty() {
+        return Err(String::from("Unexpected text after the options"));
+    }
+    let header = split_header(&text[..open]);
+    if header.len() != 7 {
+        return Err(format!("Expected 7 header fields, found {}", header.len()));
+    }
+    if header[0] != "alert" {
+        return Err(format!("Unsupported action \"{}\"", header[0]));
+    }
+    let bidirectional = match header[4].as_str() {
+        "->" => false,
+        "<>" => true,
+        d => return Err(format!("Invalid direction \"{}\"", d)),
+    };
+    let mut rule = Rule {
+        sid: 0,
+        rev: 1,
+        msg: String::new(),
+        protocol: header[1].to_lowercase(),
+        source: parse_addresses(&header[2])?,
+        source_ports: parse_ports(&header[3])?,
+        bidirectional,
+        destination: parse_addresses(&header[5])?,
+        destination_ports: parse_ports(&header[6])?,
+        flags: None,
+        dsize: None,
+        groups: Vec::new(),
+    };
+
+    let mut buffer = Buffer::Payload;
+    let mut has_sid = false;
+    for option in split_options(&text[open + 1..close])? {
+        let (key, value) = match option.split_once(':') {
+            Some((k, v)) => (k.trim(), Some(v.trim())),
+            None => (option.trim(), None),
+        };
+        let value_of = |key: &str| value.ok_or(format!("Option \"{}\" needs a value", key));
+        match key {
+            "msg" => rule.msg = unquote(value_of(key)?)?,
+            "sid" => {
+                rule.sid = value_of(key)?.parse::<u32>().map_err(|_| String::from("Invalid sid"))?;
+                has_sid = true;
+            }
+            "rev" => rule.rev = value_of(key)?.parse::<u32>().map_err(|_| String::from("Invalid rev"))?,
+            "content" => {
+                let value = value_of(key)?;
+                let (negated, value) = match value.strip_prefix('!') {
+                    Some(v) => (true, v.trim()),
+                    None => (false, value),
+                };
+                let content = Content { pattern: parse_pattern(&unquote(value)?)?, negated, ..Default::default() };
+                match rule.groups.last_mut() {
+                    Some(group) if group.buffer == buffer => group.contents.push(content),
+                    _ => rule.groups.push(ContentGroup { buffer: buffer.clone(), contents: vec![content] }),
+                }
+            }
+            "nocase" | "offset" | "depth" | "distance" | "within" => {
+                let content = rule.groups.last_mut()
+                    .filter(|g| g.buffer == buffer)
+                    .and_then(|g| g.contents.last_mut())
+                    .ok_or(format!("\"{}\" must follow a content", key))?;
+                let number = |v: Option<&str>| v.ok_or(format!("Option \"{}\" needs a value", key))?
+                    .parse::<i64>().map_err(|_| format!("Invalid value for \"{}\"", key));
+                match key {
+                    "nocase" => content.nocase = true,
+                    "offset" => content.offset = Some(number(value)?.max(0) as usize),
+                    "depth" => content.depth = Some(number(value)?.max(0) as usize),
+                    "distance" => content.distance = Some(number(value)?),
+                    _ => content.within = Some(number(value)?.max(0) as usize),
+                }
+            }
+            "dsize" => rule.dsize = Some(parse_dsize(value_of(key)?)?),
+            "flags" => rule.flags = Some(parse_flags(value_of(key)?)?),
+            "pkt_data" => buffer = Buffer::Payload,
+            _ if IGNORED_OPTIONS.contains(&key) => {}
+            _ if value.is_none() => {
+                let field = BUFFER_ALIASES.iter().find(|(alias, _)| *alias == key).map_or(key, |(_, f)| *f);
+                if !fields.contains_key(field) {
+                    return Err(format!("Unknown option or field \"{}\"", key));
+                }
+                buffer = Buffer::Field(field.to_string());
+            }
+            _ => return Err(format!("Unsupported option \"{}\"", key)),
+        }
+    }
+    if !has_sid {
+        return Err(String::from("Missing sid"));
+    }
+    Ok(rule)
+}
+
+/// Splits the header on whitespace, keeping lists between brackets together.
+fn split_header(header: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0;
+    for c in header.chars() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            _ => {}
+        }
+        if c.is_whitespace() && depth == 0 {
+            if !current.is_empty() {
+                parts.push(current.clone());
+                current.clear();
+            }
+        } else if !c.is_whitespace() {
+            current.push(c);
+        }
+    }
+    if !current.is_empty() {
+        parts.push(current);
+    }
+    parts
+}
+
+/// Splits the options on the semicolons that are not quoted or escaped.
+fn split_options(options: &str) -> Result<Vec<String>, String> {
+    let mut result = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    for c in options.chars() {
+        if escaped {
+            current.push(c);
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' => {
+                current.push(c);
+                escaped = true;
+            }
+            '"' => {
+                current.push(c);
+                quoted = !quoted;
+            }
+            ';' if !quoted => {
+                if !current.trim().is_empty() {
+                    result.push(current.trim().to_string());
+                }
+                current.clear();
+            }
+            _ => current.push(c),
+        }
+    }
+    if quoted {
+        return Err(String::from("Unterminated string"));
+    }
+    if !current.trim().is_empty() {
+        return Err(format!("Missing semicolon after \"{}\"", current.trim()));
+    }
+    Ok(result)
+}
+
+/// Removes the quotes around a value and resolves the escapes.
+fn unquote(value: &str) -> Result<String, String> {
+    let inner = value.strip_prefix('"').and_then(|v| v.strip_suffix('"'))
+        .ok_or(format!("Expected a quoted string, found {}", value))?;
+    let mut result = String::new();
+    let mut escaped = false;
+    for c in inner.chars() {
+        if escaped {
+            result.push(c);
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else {
+            result.push(c);
+        }
+    }
+    Ok(result)
+}
+
+/// Converts a content into bytes, decoding the parts between pipes as hexadecimal.
+fn parse_pattern(text: &str) -> Result<Vec<u8>, String> {
+    if !text.matches('|').count().is_multiple_of(2) {
+        return Err(String::from("Unterminated hexadecimal bytes"));
+    }
+    let mut pattern = Vec::new();
+    for (i, part) in text.split('|').enumerate() {
+        if i % 2 == 0 {
+            pattern.extend_from_slice(part.as_bytes());
+        } else {
+            let digits = part.split_whitespace().collect::<String>();
+            let bytes = hex::decode(&digits).map_err(|_| format!("Invalid hexadecimal bytes \"{}\"", part))?;
+            pattern.extend(bytes);
+        }
+    }
+    if pattern.is_empty() {
+        return Err(String::from("Empty content"));
+    }
+    Ok(pattern)
+}
+
+fn parse_addresses(text: &str) -> Result<AddressSpec, String> {
+    let (negated, text) = match text.strip_prefix('!') {
+        Some(t) => (true, t),
+        None => (false, text),
+    };
+    let mut spec = AddressSpec { negated, entries: Vec::new() };
+    for entry in list_entries(text) {
+        let (negated, entry) = match entry.strip_prefix('!') {
+            Some(e) => (true, e),
+            None => (false, entry),
+        };
+        if entry == "any" {
+            if negated {
+                return Err(String::from("\"!any\" matches nothing"));
+            }
+            continue;
+        }
+        let (address, prefix) = match entry.split_once('/') {
+            Some((a, p)) => (a, Some(p.parse::<u8>().map_err(|_| format!("Invalid prefix in \"{}\"", entry))?)),
+            None => (entry, None),
+        };
+        let address = address.parse::<IpAddr>().map_err(|_| format!("Invalid address \"{}\"", entry))?;
+        let max = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        if prefix > max {
+            return Err(format!("Invalid prefix in \"{}\"", entry));
+        }
+        spec.entries.push((negated, address, prefix));
+    }
+    Ok(spec)
+}
+
+fn parse_ports(text: &str) -> Result<PortSpec, String> {
+    let (negated, text) = match text.strip_prefix('!') {
+        Some(t) => (true, t),
+        None => (false, text),
+    };
+    let mut spec = PortSpec { negated, entries: Vec::new() };
+    for entry in list_entries(text) {
+        let (negated, entry) = match entry.strip_prefix('!') {
+            Some(e) => (true, e),
+            None => (false, entry),
+        };
+        if entry == "any" {
+            continue;
+        }
+        let port = |p: &str, default: u16| if p.is_empty() {
+            Ok(default)
+        } else {
+            p.parse::<u16>().map_err(|_| format!("Invalid port \"{}\"", entry))
+        };
+        let (first, last) = match entry.split_once(':') {
+            Some((first, last)) => (port(first, 0)?, port(last, u16::MAX)?),
+            None => {
+                let p = port(entry, 0)?;
+                (p, p)
+            }
+        };
+        spec.entries.push((negated, first, last));
+    }
+    Ok(spec)
+}
+
+/// Gets the entries of a list between brackets, or the value itself when it is not a list.
+fn list_entries(text: &str) -> Vec<&str> {
+    match text.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+        Some(list) => list.split(',').map(|e| e.trim()).filter(|e| !e.is_empty()).collect(),
+        None => vec![text],
+    }
+}
+
+fn parse_dsize(text: &str) -> Result<(SizeOp, usize, usize), String> {
+    let number = |n: &str| n.trim().parse::<usize>().map_err(|_| format!("Invalid dsize \"{}\"", text));
+    if let Some((a, b)) = text.split_once("<>") {
+        return Ok((SizeOp::Between, number(a)?, number(b)?));
+    }
+    match text.chars().next() {
+        Some('<') => Ok((SizeOp::Lt, number(&text[1..])?, 0)),
+        Some('>') => Ok((SizeOp::Gt, number(&text[1..])?, 0)),
+        _ => Ok((SizeOp::Eq, number(text)?, 0)),
+    }
+}
+
+fn parse_flags(text: &str) -> Result<FlagsSpec, String> {
+    let (flags, ignored) = match text.split_once(',') {
+        Some((f, i)) => (f.trim(), i.trim()),
+        None => (text.trim(), ""),
+    };
+    let mut modifier = FlagsModifier::Exact;
+    let mut mask = 0;
+    for c in flags.chars() {
+        match c {
+            '+' => modifier = FlagsModifier::All,
+            '*' => modifier = FlagsModifier::Any,
+            '!' => modifier = FlagsModifier::Not,
+            _ => mask |= flag_bit(c).ok_or(format!("Invalid TCP flag '{}'", c))?,
+        }
+    }
+    let mut ignored_mask = 0;
+    for c in ignored.chars() {
+        ignored_mask |= flag_bit(c).ok_or(format!("Invalid TCP flag '{}'", c))?;
+    }
+    Ok(FlagsSpec { mask, modifier, ignored: ignored_mask })
+}
+
+fn flag_bit(flag: char) -> Option<u8> {
+    match flag.to_ascii_uppercase() {
+        'F' => Some(0x01),
+        'S' => Some(0x02),
+        'R' => Some(0x04),
+        'P' => Some(0x08),
+        'A' => Some(0x10),
+        'U' => Some(0x20),
+        'E' | '2' => Some(0x40),
+        'C' | '1' => Some(0x80),
+        '0' => Some(0),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::packet::FieldValue;
+    use super::*;
+
+    fn parse(text: &str) -> Result<RuleSet, RuleError> {
+        RuleSet::parse(text, &DissectorRegistry::default())
+    }
+
+    fn tcp_packet(source: &str, source_port: u16, destination: &str, destination_port: u16) -> Packet {
+        let mut packet = Packet::new(String::new(), source.to_string(), destination.to_string(), Some(source_port.to_string()), Some(destination_port.to_string()), String::from("TCP"), 100, String::new());
+        packet.set_transport(Some(TransportProtocol::Tcp));
+        packet.set_tcp_flags(Some(0x18));
+        packet
+    }
+
+    /// Whether the single rule of the text matches the packet and the payload.
+    fn hit(rule: &str, packet: &Packet, payload: &[u8]) -> bool {
+        !parse(rule).unwrap().matches(packet, payload, 1).is_empty()
+    }
+
+    fn web(source: &str, destination_port: u16) -> Packet {
+        tcp_packet(source, 40000, "192.168.1.10", destination_port)
+    }
+
+    #[test]
+    fn parses_continued_lines_and_comments() {
+        let rules = parse("# comment\n\nalert tcp any any -> any 80 \\\n  (msg:\"Web\"; sid:1; rev:2;)\nalert udp any any -> any 53 (msg:\"DNS\"; sid:2;)\n").unwrap();
+        assert_eq!(rules.get_rules().len(), 2);
+        assert_eq!((rules.get_rules()[0].sid, rules.get_rules()[0].rev, rules.get_rules()[0].msg.as_str()), (1, 2, "Web"));
+        assert_eq!(rules.get_rules()[1].rev, 1);
+    }
+
+    #[test]
+    fn reports_errors_with_their_line() {
+        let error = |text: &str| parse(text).unwrap_err();
+        assert_eq!(error("\nalert tcp any any -> any any (msg:\"x\";)"), RuleError { line: 2, message: String::from("Missing sid") });
+        assert_eq!(error("alert tcp any any -> any any (sid:1;)\nalert tcp any any -> any any (sid:1;)").line, 2);
+        assert_eq!(error("drop tcp any any -> any any (sid:1;)").message, "Unsupported action \"drop\"");
+        assert_eq!(error("alert tcp any any => any any (sid:1;)").message, "Invalid direction \"=>\"");
+        assert_eq!(error("alert tcp any any -> any (sid:1;)").message, "Expected 7 header fields, found 6");
+        assert_eq!(error("alert tcp 10.0.0.0/33 any -> any any (sid:1;)").message, "Invalid prefix in \"10.0.0.0/33\"");
+        assert_eq!(error("alert tcp [!any] any -> any any (sid:1;)").message, "\"!any\" matches nothing");
+        assert_eq!(error("alert tcp any any -> any 70000 (sid:1;)").message, "Invalid port \"70000\"");
+        assert_eq!(error("alert tcp any any -> any any (sid:1; depth:4;)").message, "\"depth\" must follow a content");
+        assert_eq!(error("alert tcp any any -> any any (content:\"|4G|\"; sid:1;)").message, "Invalid hexadecimal bytes \"4G\"");
+        assert_eq!(error("alert tcp any any -> any any (content:\"|41\"; sid:1;)").message, "Unterminated hexadecimal bytes");
+        assert_eq!(error("alert tcp any any -> any any (sid:1; nosuch.field;)").message, "Unknown option or field \"nosuch.field\"");
+        assert_eq!(error("alert tcp any any -> any any (sid:1; threshold:type limit;)").message, "Unsupported option \"threshold\"");
+        assert_eq!(error("alert tcp any any -> any any (msg:\"x; sid:1;)").message, "Unterminated string");
+        assert_eq!(error("alert tcp any any -> any any (sid:1)").message, "Missing semicolon after \"sid:1\"");
+        assert_eq!(error("alert tcp any any -> any any (sid:1;) \\").message, "Rule continues past the end of the file");
+    }
+
+    #[test]
+    fn matches_negated_address_lists() {
+        let rule = "alert tcp [10.0.0.0/8,!10.1.0.0/16,!10.2.3.4] any -> any any (sid:1;)";
+        assert!(hit(rule, &web("10.9.9.9", 80), b""));
+        assert!(!hit(rule, &web("10.1.2.3", 80), b""));
+        assert!(!hit(rule, &web("10.2.3.4", 80), b""));
+        assert!(!hit(rule, &web("172.16.0.1", 80), b""));
+        // A list of negated entries only matches everything else
+        let rule = "alert tcp [!10.0.0.0/8] any -> any any (sid:1;)";
+        assert!(hit(rule, &web("172.16.0.1", 80), b""));
+        assert!(!hit(rule, &web("10.0.0.1", 80), b""));
+        // Negating the whole list
+        let rule = "alert tcp ![10.0.0.0/8,172.16.0.0/12] any -> any any (sid:1;)";
+        assert!(hit(rule, &web("192.168.0.1", 80), b""));
+        assert!(!hit(rule, &web("172.20.0.1", 80), b""));
+    }
+
+    #[test]
+    fn matches_port_ranges() {
+        let rule = "alert tcp any any -> any [80,8000:8100,!8080] (sid:1;)";
+        assert!(hit(rule, &web("10.0.0.1", 80), b""));
+        assert!(hit(rule, &web("10.0.0.1", 8000), b""));
+        assert!(hit(rule, &web("10.0.0.1", 8100), b""));
+        assert!(!hit(rule, &web("10.0.0.1", 8080), b""));
+        assert!(!hit(rule, &web("10.0.0.1", 8101), b""));
+        assert!(hit("alert tcp any any -> any :1023 (sid:1;)", &web("10.0.0.1", 443), b""));
+        assert!(!hit("alert tcp any any -> any 1024: (sid:1;)", &web("10.0.0.1", 443), b""));
+        assert!(hit("alert tcp any any -> any !443 (sid:1;)", &web("10.0.0.1", 80), b""));
+    }
+
+    #[test]
+    fn matches_both_directions() {
+        let reply = tcp_packet("192.168.1.10", 80, "10.0.0.1", 40000);
+        assert!(!hit("alert tcp 10.0.0.0/8 any -> any 80 (sid:1;)", &reply, b""));
+        assert!(hit("alert tcp 10.0.0.0/8 any <> any 80 (sid:1;)", &reply, b""));
+        assert!(!hit("alert udp any any <> any any (sid:1;)", &reply, b""));
+    }
+
+    #[test]
+    fn matches_contents_with_hex_bytes() {
+        let packet = web("10.0.0.1", 80);
+        assert!(hit("alert tcp any any -> any any (content:\"GET|20|/\"; sid:1;)", &packet, b"GET /admin"));
+        assert!(hit("alert tcp any any -> any any (content:\"|47 45 54|\"; sid:1;)", &packet, b"GET /admin"));
+        assert!(!hit("alert tcp any any -> any any (content:\"get\"; sid:1;)", &packet, b"GET /admin"));
+        assert!(hit("alert tcp any any -> any any (content:\"get\"; nocase; sid:1;)", &packet, b"GET /admin"));
+        assert!(hit("alert tcp any any -> any any (content:!\"POST\"; sid:1;)", &packet, b"GET /admin"));
+        assert!(!hit("alert tcp any any -> any any (content:!\"GET\"; sid:1;)", &packet, b"GET /admin"));
+    }
+
+    #[test]
+    fn matches_contents_with_offset_and_depth() {
+        let packet = web("10.0.0.1", 80);
+        let payload = b"GET /admin HTTP/1.1";
+        assert!(hit("alert tcp any any -> any any (content:\"/admin\"; offset:4; depth:6; sid:1;)", &packet, payload));
+        assert!(!hit("alert tcp any any -> any any (content:\"/admin\"; offset:5; sid:1;)", &packet, payload));
+        assert!(!hit("alert tcp any any -> any any (content:\"/admin\"; depth:9; sid:1;)", &packet, payload));
+        assert!(hit("alert tcp any any -> any any (content:\"/admin\"; depth:10; sid:1;)", &packet, payload));
+    }
+
+    #[test]
+    fn matches_contents_with_distance_and_within() {
+        let packet = web("10.0.0.1", 80);
+        let payload = b"GET /a HTTP/1.1 GET /admin HTTP/1.1";
+        // The second content must follow the first one: the search goes back to the second GET
+        assert!(hit("alert tcp any any -> any any (content:\"GET\"; content:\"admin\"; distance:1; within:6; sid:1;)", &packet, payload));
+        assert!(!hit("alert tcp any any -> any any (content:\"GET\"; content:\"admin\"; distance:3; within:5; sid:1;)", &packet, payload));
+        assert!(!hit("alert tcp any any -> any any (content:\"admin\"; content:\"GET\"; distance:0; sid:1;)", &packet, payload));
+        // A negative distance looks back before the end of the previous content
+        assert!(hit("alert tcp any any -> any any (content:\"HTTP\"; content:\"admin \"; distance:-10; within:6; sid:1;)", &packet, payload));
+    }
+
+    #[test]
+    fn matches_sticky_buffers_flags_and_dsize() {
+        let mut packet = Packet::new(String::new(), String::from("192.168.1.10"), String::from("9.9.9.9"), Some(String::from("5000")), Some(String::from("53")), String::from("DNS"), 80, String::new());
+        packet.set_transport(Some(TransportProtocol::Udp));
+        packet.add_field("dns.qry.name", FieldValue::Str(String::from("evil.example.xyz")));
+        assert!(hit("alert udp any any -> any 53 (dns.query; content:\".xyz\"; sid:1;)", &packet, b""));
+        assert!(hit("alert dns any any -> any any (dns_query; content:\"EVIL\"; nocase; sid:1;)", &packet, b""));
+        assert!(!hit("alert udp any any -> any 53 (dns.query; content:\".xyz\"; pkt_data; content:\"x\"; sid:1;)", &packet, b""));
+
+        let packet = web("10.0.0.1", 80);
+        assert!(hit("alert tcp any any -> any any (flags:PA; sid:1;)", &packet, b""));
+        assert!(!hit("alert tcp any any -> any any (flags:S; sid:1;)", &packet, b""));
+        assert!(hit("alert tcp any any -> any any (flags:+A; sid:1;)", &packet, b""));
+        assert!(hit("alert tcp any any -> any any (flags:!SR; sid:1;)", &packet, b""));
+        assert!(hit("alert tcp any any -> any any (dsize:>3; sid:1;)", &packet, b"abcd"));
+        assert!(hit("alert tcp any any -> any any (dsize:2<>5; sid:1;)", &packet, b"abcd"));
+        assert!(!hit("alert tcp any any -> any any (dsize:4<>5; sid:1;)", &packet, b"abcd"));
+    }
+
+    #[test]
+    fn describes_the_hits_in_the_alerts() {
+        let rules = parse("alert tcp any any -> any 80 (msg:\"Admin page\"; content:\"/admin\"; sid:1000001; rev:3;)").unwrap();
+        let alerts = rules.matches(&web("10.0.0.1", 80), b"GET /admin", 42);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].kind, "Admin page");
+        assert_eq!(alerts[0].source, "10.0.0.1:40000");
+        assert_eq!(alerts[0].targets, vec![String::from("192.168.1.10:80")]);
+        assert_eq!(alerts[0].evidence[0], "sid:1000001 rev:3");
+        assert!(alerts[0].evidence[1].starts_with("frame 42 at "));
+    }
+}
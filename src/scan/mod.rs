@@ -0,0 +1,392 @@
+//! Port scan and host sweep detection
+//!
+//! Every communication of the report that looks like a probe (a TCP connection attempt that
+//! never carried data, or a UDP exchange without an answer) is attributed to the host that
+//! started it. A host that probes too many hosts on the same port (horizontal scan, or host
+//! sweep) or too many ports of the same host (vertical scan) within the time window raises an
+//! alert. The scan type comes from the TCP flags of the first probe: SYN, FIN, NULL or Xmas.
+//!
+//! Only the windows ending less than a window before the latest communication of the report are
+//! looked at, the older ones were by the previous calls. The scans reported are forgotten at the
+//! same time, so that the detector does not grow with the sources seen.
+use std::collections::HashMap;
+use std::net::IpAddr;
+use crate::alert::{Alert, AlertCategory};
+use crate::dissector::TransportProtocol;
+use crate::report::{Report, ReportLine};
+
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const PSH: u8 = 0x08;
+const URG: u8 = 0x20;
+
+#[derive(Debug, Clone, PartialEq)]
+/// The thresholds of the scan detector
+pub struct ScanThresholds {
+    /// The length of the sliding window, in seconds
+    pub window: u64,
+    /// The number of different hosts probed on one port that makes a horizontal scan
+    pub horizontal_hosts: usize,
+    /// The number of different ports probed on one host that makes a vertical scan
+    pub vertical_ports: usize,
+    /// A communication with more packets than this from the source is not a probe
+    pub max_probe_packets: u32,
+}
+
+impl Default for ScanThresholds {
+    fn default() -> Self {
+        ScanThresholds {
+            window: 60,
+            horizontal_hosts: 20,
+            vertical_ports: 20,
+            max_probe_packets: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+/// The technique used by a scan
+pub enum ScanType {
+    Syn,
+    Fin,
+    Null,
+    Xmas,
+    Udp,
+}
+
+impl ScanType {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            ScanType::Syn => "SYN",
+            ScanType::Fin => "FIN",
+            ScanType::Null => "NULL",
+            ScanType::Xmas => "Xmas",
+            ScanType::Udp => "UDP",
+        }
+    }
+}
+
+/// A communication classified as a probe
+struct Probe {
+    time_us: i64,
+    end_us: i64,
+    target: String,
+    port: String,
+    answered: bool,
+}
+
+/// Finds scans in the report, remembering what was already reported
+#[derive(Debug, Clone, Default)]
+pub struct ScanDetector {
+    thresholds: ScanThresholds,
+    /// The end of the last alert raised for each source, scan type and direction, kept for a window
+    reported: HashMap<(String, ScanType, bool), i64>,
+}
+
+impl ScanDetector {
+    pub fn new(thresholds: ScanThresholds) -> Self {
+        ScanDetector {
+            thresholds,
+            reported: HashMap::new(),
+        }
+    }
+
+    /// Looks for scans in the communications of the report. Returns only the scans that were
+    /// not reported by a previous call.
+    pub fn analyze(&mut self, report: &Report) -> Vec<Alert> {
+        let window_us = self.thresholds.window as i64 * 1_000_000;
+        let now_us = match report.report_lines.values().map(|rl| rl.timestamp_last_us).max() {
+            Some(t) => t,
+            None => return Vec::new(),
+        };
+        self.reported.retain(|_, end_us| *end_us + window_us >= now_us);
+
+        let mut probes: HashMap<(String, ScanType), Vec<Probe>> = HashMap::new();
+        for rl in report.report_lines.values() {
+            if let Some((scan_type, probe)) = self.classify(rl) {
+                probes.entry((rl.source.clone(), scan_type)).or_default().push(probe);
+            }
+        }
+
+        let mut alerts = Vec::new();
+        for ((source, scan_type), mut probes) in probes {
+            probes.sort_by_key(|p| p.time_us);
+            for horizontal in [true, false] {
+                if let Some(alert) = self.find_scan(&source, scan_type, &probes, horizontal, now_us) {
+                    alerts.push(alert);
+                }
+            }
+        }
+        alerts.sort_by_key(|a| a.start_us);
+        alerts
+    }
+
+    /// Decides whether a communication is a probe and of which kind.
+    fn classify(&self, rl: &ReportLine) -> Option<(ScanType, Probe)> {
+        if rl.packets_forward > self.thresholds.max_probe_packets || is_group_address(&rl.destination) {
+            return None;
+        }
+        let port = rl.destination_port.clone()?;
+        let (scan_type, answered) = match rl.transport {
+            Some(TransportProtocol::Tcp) => {
+                let scan_type = match rl.first_tcp_flags? & 0x3F {
+                    SYN => ScanType::Syn,
+                    FIN => ScanType::Fin,
+                    0 => ScanType::Null,
+                    f if f == FIN | PSH | URG => ScanType::Xmas,
+                    _ => return None,
+                };
+                // An open port answers a SYN, but a connection that carried data is not a probe
+                if rl.packets_backward > self.thresholds.max_probe_packets {
+                    return None;
+                }
+                (scan_type, rl.packets_backward > 0)
+            }
+            Some(TransportProtocol::Udp) => {
+                // Closed UDP ports answer with an ICMP port unreachable, open ones usually stay silent
+                if rl.packets_backward > 0 {
+                    return None;
+                }
+                (ScanType::Udp, !rl.icmp_errors.is_empty())
+            }
+            _ => return None,
+        };
+        Some((scan_type, Probe {
+            time_us: rl.timestamp_first_us,
+            end_us: rl.timestamp_last_us,
+            target: rl.destination.clone(),
+            port,
+            answered,
+        }))
+    }
+
+    /// Slides the window over the probes of a source and reports the window with the most
+    /// hosts (horizontal) or ports (vertical) if it reaches the threshold. The windows ending more
+    /// than a window before now are skipped.
+    fn find_scan<'a>(&mut self, source: &str, scan_type: ScanType, probes: &'a [Probe], horizontal: bool, now_us: i64) -> Option<Alert> {
+        let window_us = self.thresholds.window as i64 * 1_000_000;
+        let threshold = if horizontal { self.thresholds.horizontal_hosts } else { self.thresholds.vertical_ports };
+        // (count, start index, end index, group)
+        let mut best: Option<(usize, usize, usize, String)> = None;
+        // Horizontal scans are grouped by port and count hosts, vertical ones the opposite
+        let key_of = |p: &'a Probe| if horizontal { (p.port.as_str(), p.target.as_str()) } else { (p.target.as_str(), p.port.as_str()) };
+        let mut groups: HashMap<&str, HashMap<&str, usize>> = HashMap::new();
+        let mut start = 0;
+        for end in 0..probes.len() {
+            let (group, member) = key_of(&probes[end]);
+            *groups.entry(group).or_default().entry(member).or_insert(0) += 1;
+            while probes[end].time_us - probes[start].time_us > window_us {
+                let (g, m) = key_of(&probes[start]);
+                if let Some(members) = groups.get_mut(g) {
+                    if let Some(c) = members.get_mut(m) {
+                        *c -= 1;
+                        if *c == 0 {
+                            members.remove(m);
+                        }
+                    }
+                }
+                start += 1;
+            }
+            // Only the group of the probe just added can have grown
+            let count = groups.get(group).map_or(0, |m| m.len());
+            let recent = probes[end].time_us + window_us >= now_us;
+            if recent && count >= threshold && best.as_ref().is_none_or(|b| count > b.0) {
+                best = Some((count, start, end, group.to_string()));
+            }
+        }
+
+        let (count, start, end, group) = best?;
+        let window = &probes[start..=end];
+        let start_us = window.first()?.time_us;
+        let end_us = window.iter().map(|p| p.end_us).max()?;
+        let key = (source.to_string(), scan_type, horizontal);
+        if let Some(last_end) = self.reported.get(&key) {
+            if start_us <= *last_end {
+                return None;
+            }
+        }
+        self.reported.insert(key, end_us);
+
+        let in_group = |p: &&Probe| if horizontal { p.port == group } else { p.target == group };
+        let mut targets = window.iter().filter(in_group)
+            .map(|p| if horizontal { p.target.clone() } else { p.port.clone() })
+            .collect::<Vec<String>>();
+        targets.sort();
+        targets.dedup();
+        let answered = window.iter().filter(in_group).filter(|p| p.answered).count();
+        let kind = if horizontal {
+            format!("Horizontal {} scan of port {}", scan_type.get_name(), group)
+        } else {
+            format!("Vertical {} scan of {}", scan_type.get_name(), group)
+        };
+        let evidence = vec![
+            format!("{} {} probed in {:.1}s (threshold {} in {}s)",
+                    count,
+                    if horizontal { "hosts" } else { "ports" },
+                    (end_us - start_us) as f64 / 1_000_000.0,
+                    threshold,
+                    self.thresholds.window),
+            format!("{} probes answered", answered),
+        ];
+        Some(Alert {
+            category: AlertCategory::Scan,
+            kind,
+            source: source.to_string(),
+            targets,
+            evidence,
+            start_us,
+            end_us,
+        })
+    }
+}
+
+/// Returns true for multicast and broadcast addresses, which are never scan targets.
+fn is_group_address(address: &str) -> bool {
+    match address.parse::<IpAddr>() {
+        Ok(IpAddr::V4(a)) => a.is_multicast() || a.is_broadcast(),
+        Ok(IpAddr::V6(a)) => a.is_multicast(),
+        Err(_) => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: i64 = 1_000_000;
+
+    fn thresholds() -> ScanThresholds {
+        ScanThresholds { window: 10, horizontal_hosts: 5, vertical_ports: 5, max_probe_packets: 3 }
+    }
+
+    /// Adds a probe from the source with the transport and TCP flags, sent at the time and
+    /// answered by the number of packets.
+    fn add_probe(report: &mut Report, source: &str, target: &str, port: u16, probe: (TransportProtocol, Option<u8>), time_us: i64, answers: u32) {
+        let (transport, flags) = probe;
+        let rl = ReportLine {
+            timestamp_first_us: time_us,
+            timestamp_last_us: time_us,
+            source: source.to_string(),
+            destination: target.to_string(),
+            destination_port: Some(port.to_string()),
+            transport: Some(transport),
+            first_tcp_flags: flags,
+            packets_forward: 1,
+            packets_backward: answers,
+            ..ReportLine::default()
+        };
+        report.report_lines.insert((format!("{}:40000", source), format!("{}:{}", target, port), String::new()), rl);
+    }
+
+    /// A report of SYN probes from 10.0.0.66 to the hosts 192.168.1.x on port 22, one a second
+    fn horizontal(hosts: u8) -> Report {
+        let mut report = Report::default();
+        for i in 0..hosts {
+            add_probe(&mut report, "10.0.0.66", &format!("192.168.1.{}", i + 1), 22, (TransportProtocol::Tcp, Some(SYN)), i64::from(i) * SECOND, 0);
+        }
+        report
+    }
+
+    #[test]
+    fn finds_horizontal_scans() {
+        let alerts = ScanDetector::new(thresholds()).analyze(&horizontal(6));
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].kind, "Horizontal SYN scan of port 22");
+        assert_eq!(alerts[0].source, "10.0.0.66");
+        assert_eq!(alerts[0].targets.len(), 6);
+        assert_eq!((alerts[0].start_us, alerts[0].end_us), (0, 5 * SECOND));
+        assert_eq!(alerts[0].evidence[0], "6 hosts probed in 5.0s (threshold 5 in 10s)");
+    }
+
+    #[test]
+    fn finds_vertical_scans() {
+        let mut report = Report::default();
+        for port in 20..26 {
+            // The open ports answer with a SYN-ACK
+            let answers = if port == 22 { 1 } else { 0 };
+            add_probe(&mut report, "10.0.0.66", "192.168.1.1", port, (TransportProtocol::Tcp, Some(SYN)), i64::from(port) * SECOND, answers);
+        }
+        let alerts = ScanDetector::new(thresholds()).analyze(&report);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].kind, "Vertical SYN scan of 192.168.1.1");
+        assert_eq!(alerts[0].targets, vec!["20", "21", "22", "23", "24", "25"]);
+        assert_eq!(alerts[0].evidence[1], "1 probes answered");
+    }
+
+    #[test]
+    fn names_the_scan_types() {
+        let types = [
+            (TransportProtocol::Tcp, Some(SYN), "SYN"),
+            (TransportProtocol::Tcp, Some(FIN), "FIN"),
+            (TransportProtocol::Tcp, Some(0), "NULL"),
+            (TransportProtocol::Tcp, Some(FIN | PSH | URG), "Xmas"),
+            (TransportProtocol::Udp, None, "UDP"),
+        ];
+        for (transport, flags, name) in types {
+            let mut report = Report::default();
+            for port in 1..=5 {
+                add_probe(&mut report, "10.0.0.66", "192.168.1.1", port, (transport, flags), i64::from(port) * SECOND, 0);
+            }
+            let alerts = ScanDetector::new(thresholds()).analyze(&report);
+            assert_eq!(alerts.len(), 1, "{} scan", name);
+            assert_eq!(alerts[0].kind, format!("Vertical {} scan of 192.168.1.1", name));
+        }
+    }
+
+    #[test]
+    fn ignores_what_is_not_a_probe() {
+        let mut report = Report::default();
+        for port in 1..=5 {
+            // A SYN-ACK as first packet, and UDP exchanges that were answered
+            add_probe(&mut report, "10.0.0.66", "192.168.1.1", port, (TransportProtocol::Tcp, Some(SYN | 0x10)), i64::from(port) * SECOND, 0);
+            add_probe(&mut report, "10.0.0.66", "192.168.1.2", port, (TransportProtocol::Udp, None), i64::from(port) * SECOND, 1);
+            // Probes sent to a multicast group
+            add_probe(&mut report, "10.0.0.66", "224.0.0.251", port, (TransportProtocol::Udp, None), i64::from(port) * SECOND, 0);
+        }
+        assert!(ScanDetector::new(thresholds()).analyze(&report).is_empty());
+    }
+
+    #[test]
+    fn raises_the_alert_exactly_at_the_threshold() {
+        assert!(ScanDetector::new(thresholds()).analyze(&horizontal(4)).is_empty());
+        assert_eq!(ScanDetector::new(thresholds()).analyze(&horizontal(5)).len(), 1);
+    }
+
+    #[test]
+    fn counts_the_probes_exactly_one_window_apart() {
+        // The first and the fifth probe are 10 seconds apart: the same window
+        let mut report = Report::default();
+        for (i, time) in [0, 1, 2, 3, 10].iter().enumerate() {
+            add_probe(&mut report, "10.0.0.66", &format!("192.168.1.{}", i + 1), 22, (TransportProtocol::Tcp, Some(SYN)), time * SECOND, 0);
+        }
+        assert_eq!(ScanDetector::new(thresholds()).analyze(&report).len(), 1);
+        // A microsecond later they are not
+        let mut report = Report::default();
+        for (i, time) in [0, 1, 2, 3, 10].iter().enumerate() {
+            add_probe(&mut report, "10.0.0.66", &format!("192.168.1.{}", i + 1), 22, (TransportProtocol::Tcp, Some(SYN)), time * SECOND + i64::from(i == 4), 0);
+        }
+        assert!(ScanDetector::new(thresholds()).analyze(&report).is_empty());
+    }
+
+    #[test]
+    fn reports_a_scan_once_and_forgets_it_after_a_window() {
+        let mut detector = ScanDetector::new(thresholds());
+        let mut report = horizontal(6);
+        assert_eq!(detector.analyze(&report).len(), 1);
+        assert!(detector.analyze(&report).is_empty());
+        assert_eq!(detector.reported.len(), 1);
+
+        // Unrelated traffic a window later: the scan is forgotten and not reported again
+        add_probe(&mut report, "10.0.0.7", "192.168.1.1", 80, (TransportProtocol::Tcp, Some(SYN)), 16 * SECOND, 0);
+        assert!(detector.analyze(&report).is_empty());
+        assert!(detector.reported.is_empty());
+
+        // A new scan from the same source is
+        for i in 0..5 {
+            add_probe(&mut report, "10.0.0.66", &format!("192.168.2.{}", i + 1), 22, (TransportProtocol::Tcp, Some(SYN)), (20 + i) * SECOND, 0);
+        }
+        let alerts = detector.analyze(&report);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].start_us, 20 * SECOND);
+    }
+}
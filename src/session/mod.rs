@@ -0,0 +1,190 @@
+//! Named capture sessions
+//!
+//! A SessionManager runs several captures in the same process. Every session is started by
+//! analyze_network with its own parameters, so it has its own devices, filters, output file,
+//! report, threads and errors: a session that fails, e.g. because its device went down, only
+//! records the error in its own control block and the other sessions keep capturing.
+//!
+//! # Usage
+//! let sessions = SessionManager::default();
+//! sessions.start("dns", dns_parameters)?;
+//! sessions.start("web", web_parameters)?;
+//! for session in sessions.list() {
+//!     println!("{} {}", session.name, api::state_name(&session.state));
+//! }
+//! sessions.stop("dns")?;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use crate::parameters::Parameters;
+use crate::{analyze_network, CaptureState, ControlBlock, SnifferError};
+
+#[derive(Debug)]
+pub enum SessionError {
+    /// The name is empty or used by another session
+    InvalidName(String),
+    /// There is no session with the name
+    NotFound(String),
+    /// The capture of the session could not be started
+    StartError(String, SnifferError),
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SessionError::InvalidName(name) => write!(f, "Invalid session name: \"{}\" is empty or already used", name),
+            SessionError::NotFound(name) => write!(f, "No session named {}", name),
+            SessionError::StartError(name, e) => write!(f, "Error starting session {}: {}", name, e),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+#[derive(Clone)]
+/// The state of a session
+pub struct SessionInfo {
+    pub name: String,
+    pub state: CaptureState,
+    /// The devices captured
+    pub interfaces: Vec<String>,
+    pub output_file: String,
+    /// The errors waiting to be read
+    pub errors: usize,
+}
+
+#[derive(Default)]
+/// Starts, lists and stops the capture sessions of the process
+pub struct SessionManager {
+    sessions: Mutex<BTreeMap<String, Arc<ControlBlock>>>,
+}
+
+impl SessionManager {
+    /// Starts a session with the parameters and returns its control block.
+    pub fn start(&self, name: &str, parameters: Parameters) -> Result<Arc<ControlBlock>, SessionError> {
+        if name.is_empty() || self.get(name).is_some() {
+            return Err(SessionError::InvalidName(name.to_string()));
+        }
+        // The devices are opened without holding the lock, so that the other sessions can be used
+        let control_block = match analyze_network(parameters) {
+            Ok(cb) => cb,
+            Err(e) => return Err(SessionError::StartError(name.to_string(), e)),
+        };
+        if let Err(e) = self.add(name, control_block.clone()) {
+            // Another session with the same name was started in the meantime
+            control_block.stop();
+            return Err(e);
+        }
+        Ok(control_block)
+    }
+
+    /// Adds a session with a control block built by the caller, e.g. to read a pcap file.
+    pub fn add(&self, name: &str, control_block: Arc<ControlBlock>) -> Result<(), SessionError> {
+        let mut s = self.sessions.lock().unwrap();
+        if name.is_empty() || s.contains_key(name) {
+            return Err(SessionError::InvalidName(name.to_string()));
+        }
+        s.insert(name.to_string(), control_block);
+        Ok(())
+    }
+
+    /// Gets the control block of a session.
+    pub fn get(&self, name: &str) -> Option<Arc<ControlBlock>> {
+        let s = self.sessions.lock().unwrap();
+        s.get(name).cloned()
+    }
+
+    /// Gets the names of the sessions.
+    pub fn get_names(&self) -> Vec<String> {
+        let s = self.sessions.lock().unwrap();
+        s.keys().cloned().collect()
+    }
+
+    /// Gets the state of every session, including the ones that stopped by themselves
+    /// (timeout, packet count) and were not removed yet.
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let sessions = self.sessions.lock().unwrap().clone();
+        sessions.iter().map(|(name, cb)| SessionInfo {
+            name: name.clone(),
+            state: cb.get_state(),
+            interfaces: cb.get_interfaces(),
+            output_file: cb.get_output_file(),
+            errors: cb.get_errors().len(),
+        }).collect()
+    }
+
+    /// Stops a session, waits for its final report and removes it.
+    pub fn stop(&self, name: &str) -> Result<(), SessionError> {
+        let control_block = match self.sessions.lock().unwrap().remove(name) {
+            Some(cb) => cb,
+            None => return Err(SessionError::NotFound(name.to_string())),
+        };
+        control_block.stop();
+        control_block.wait_finished();
+        Ok(())
+    }
+
+    /// Stops all the sessions, waiting for their final reports.
+    pub fn stop_all(&self) {
+        let sessions = std::mem::take(&mut *self.sessions.lock().unwrap());
+        for cb in sessions.values() {
+            cb.stop();
+        }
+        for cb in sessions.values() {
+            cb.wait_finished();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::device::DeviceSelector;
+    use crate::ControlBlockBuilder;
+    use super::*;
+
+    fn offline() -> Arc<ControlBlock> {
+        Arc::new(ControlBlockBuilder::new().build_offline().unwrap())
+    }
+
+    #[test]
+    fn rejects_duplicate_names() {
+        let sessions = SessionManager::default();
+        sessions.add("dns", offline()).unwrap();
+        assert!(matches!(sessions.add("dns", offline()), Err(SessionError::InvalidName(_))));
+        assert!(matches!(sessions.add("", offline()), Err(SessionError::InvalidName(_))));
+        // The name is checked before opening the devices
+        assert!(matches!(sessions.start("dns", Parameters::default()), Err(SessionError::InvalidName(_))));
+        assert_eq!(sessions.get_names(), vec![String::from("dns")]);
+    }
+
+    #[test]
+    fn stops_only_known_sessions() {
+        let sessions = SessionManager::default();
+        sessions.add("dns", offline()).unwrap();
+        assert!(matches!(sessions.stop("web"), Err(SessionError::NotFound(name)) if name == "web"));
+        sessions.stop("dns").unwrap();
+        assert!(sessions.get("dns").is_none());
+        assert!(matches!(sessions.stop("dns"), Err(SessionError::NotFound(_))));
+    }
+
+    #[test]
+    fn keeps_the_other_sessions_when_one_fails_to_start() {
+        let sessions = SessionManager::default();
+        let dns = offline();
+        sessions.add("dns", dns.clone()).unwrap();
+        let mut parameters = Parameters::default();
+        parameters.set_device(DeviceSelector::Name(String::from("network-analyzer-no-such-device")));
+        match sessions.start("web", parameters) {
+            Err(SessionError::StartError(name, _)) => assert_eq!(name, "web"),
+            _ => panic!("session started without a device"),
+        }
+        let list = sessions.list();
+        assert_eq!(list.len(), 1);
+        assert_eq!((list[0].name.as_str(), &list[0].state), ("dns", &CaptureState::Idle()));
+        assert!(Arc::ptr_eq(&sessions.get("dns").unwrap(), &dns));
+        // The name of the failed session can be used again
+        sessions.add("web", offline()).unwrap();
+        sessions.stop_all();
+        assert!(sessions.get_names().is_empty());
+    }
+}
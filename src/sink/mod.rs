@@ -0,0 +1,721 @@
+//! Alert and event sinks
+//!
+//! Every alert raised and every error occurred during the capture is an event delivered to the
+//! sinks of the control block. Each sink has a bounded queue and a thread of its own, so that a
+//! slow sink, e.g. a webhook waiting to retry, never blocks the capture nor the other sinks. The
+//! events that do not fit in the queue of a sink are dropped and counted. The built-in sinks are:
+//! * syslog: RFC 5424 messages over UDP, TCP (with octet counting framing) or a unix socket,
+//!   e.g. "udp://127.0.0.1:514", "tcp://logs.local:601" or "unix:///dev/log"
+//! * ndjson: one JSON object per line appended to a file
+//! * webhook: an HTTP POST of the JSON object, retried with exponential backoff
+//!
+//! Other sinks can be plugged in by implementing the AlertSink trait.
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use chrono::{DateTime, SecondsFormat};
+use serde_json::{json, Value};
+use crate::alert::Alert;
+
+/// Private enterprise number reserved for documentation, used for the structured data id
+const SD_ENTERPRISE: u32 = 32473;
+/// Facility of the syslog messages (local0)
+const SYSLOG_FACILITY: u8 = 16;
+/// Timeout of the network operations of the sinks
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest wait between two attempts of the webhook
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Number of events waiting for each sink, the next ones are dropped
+const SINK_QUEUE: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+/// Something worth reporting that happened during the capture
+pub enum Event {
+    Alert(Alert),
+    Error { timestamp_us: i64, message: String },
+}
+
+#[derive(Debug)]
+pub enum SinkError {
+    /// The configuration of the sink is not valid
+    InvalidConfig(String),
+    /// The sink could not write the event
+    Io(String, std::io::Error),
+    /// The webhook did not accept the event
+    Http(String),
+}
+
+impl fmt::Display for SinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SinkError::InvalidConfig(e) => write!(f, "Invalid sink configuration: {}", e),
+            SinkError::Io(sink, e) => write!(f, "Error writing to {}: {}", sink, e),
+            SinkError::Http(e) => write!(f, "Webhook error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SinkError {}
+
+/// A destination for the events
+pub trait AlertSink: Send {
+    /// The name of the sink, used in the error messages
+    fn name(&self) -> String;
+
+    /// Delivers an event. The sink is called from a dedicated thread and can block.
+    fn send(&mut self, event: &Event) -> Result<(), SinkError>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// The transports of the syslog sink
+pub enum SyslogTransport {
+    Udp(String),
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// The configuration of a built-in sink
+pub enum SinkConfig {
+    Syslog(SyslogTransport),
+    Ndjson(String),
+    Webhook { url: String, retries: u32, backoff: Duration },
+}
+
+impl Event {
+    pub fn get_timestamp_us(&self) -> i64 {
+        match self {
+            Event::Alert(alert) => alert.start_us,
+            Event::Error { timestamp_us, .. } => *timestamp_us,
+        }
+    }
+
+    /// Converts the event to a JSON object.
+    pub fn to_json(&self) -> Value {
+        match self {
+            Event::Alert(alert) => json!({
+                "type": "alert",
+                "timestamp": format_rfc3339(alert.start_us),
+                "category": alert.category.to_string(),
+                "kind": alert.kind,
+                "source": alert.source,
+                "targets": alert.targets,
+                "evidence": alert.evidence,
+                "start": format_rfc3339(alert.start_us),
+                "end": format_rfc3339(alert.end_us),
+            }),
+            Event::Error { timestamp_us, message } => json!({
+                "type": "error",
+                "timestamp": format_rfc3339(*timestamp_us),
+                "message": message,
+            }),
+        }
+    }
+
+    /// Formats the event as an RFC 5424 syslog message.
+    pub fn to_syslog(&self, hostname: &str, app_name: &str) -> String {
+        let (severity, msgid, data, message) = match self {
+            Event::Alert(alert) => (
+                4,
+                "ALERT",
+                format!("[alert@{} category=\"{}\" source=\"{}\" start=\"{}\" end=\"{}\"]",
+                        SD_ENTERPRISE,
+                        escape_param(&alert.category.to_string()),
+                        escape_param(&alert.source),
+                        format_rfc3339(alert.start_us),
+                        format_rfc3339(alert.end_us)),
+                alert.to_string(),
+            ),
+            Event::Error { message, .. } => (3, "ERROR", String::from("-"), message.clone()),
+        };
+        format!("<{}>1 {} {} {} {} {} {} {}",
+                SYSLOG_FACILITY * 8 + severity,
+                format_rfc3339(self.get_timestamp_us()),
+                hostname,
+                app_name,
+                std::process::id(),
+                msgid,
+                data,
+                message)
+    }
+}
+
+impl SyslogTransport {
+    /// Parses a syslog destination: udp://host:port, tcp://host:port or unix:///path.
+    pub fn parse(uri: &str) -> Result<SyslogTransport, SinkError> {
+        let (scheme, address) = uri.split_once("://").ok_or(SinkError::InvalidConfig(format!("Invalid syslog destination {}", uri)))?;
+        match scheme {
+            "udp" => Ok(SyslogTransport::Udp(address.to_string())),
+            "tcp" => Ok(SyslogTransport::Tcp(address.to_string())),
+            #[cfg(unix)]
+            "unix" => Ok(SyslogTransport::Unix(address.to_string())),
+            _ => Err(SinkError::InvalidConfig(format!("Unsupported syslog transport {}", scheme))),
+        }
+    }
+}
+
+impl SinkConfig {
+    /// Creates the sink described by the configuration.
+    pub fn open(&self) -> Result<Box<dyn AlertSink>, SinkError> {
+        match self {
+            SinkConfig::Syslog(transport) => Ok(Box::new(SyslogSink::new(transport.clone())?)),
+            SinkConfig::Ndjson(path) => Ok(Box::new(NdjsonSink::new(path)?)),
+            SinkConfig::Webhook { url, retries, backoff } => Ok(Box::new(WebhookSink::new(url, *retries, *backoff)?)),
+        }
+    }
+}
+
+/// Sends RFC 5424 messages to a syslog server
+pub struct SyslogSink {
+    transport: SyslogTransport,
+    hostname: String,
+    udp: Option<UdpSocket>,
+    tcp: Option<TcpStream>,
+    #[cfg(unix)]
+    unix: Option<std::os::unix::net::UnixDatagram>,
+}
+
+impl SyslogSink {
+    pub fn new(transport: SyslogTransport) -> Result<SyslogSink, SinkError> {
+        let hostname = std::env::var("HOSTNAME")
+            .or_else(|_| std::env::var("COMPUTERNAME"))
+            .unwrap_or_else(|_| String::from("-"));
+        let mut sink = SyslogSink {
+            transport,
+            hostname,
+            udp: None,
+            tcp: None,
+            #[cfg(unix)]
+            unix: None,
+        };
+        sink.connect()?;
+        Ok(sink)
+    }
+
+    fn connect(&mut self) -> Result<(), SinkError> {
+        let name = self.name();
+        let io = |e| SinkError::Io(name.clone(), e);
+        match &self.transport {
+            SyslogTransport::Udp(address) => {
+                let socket = UdpSocket::bind("0.0.0.0:0").map_err(io)?;
+                socket.connect(address.as_str()).map_err(io)?;
+                self.udp = Some(socket);
+            }
+            SyslogTransport::Tcp(address) => {
+                let address = resolve(address).map_err(io)?;
+                let stream = TcpStream::connect_timeout(&address, IO_TIMEOUT).map_err(io)?;
+                stream.set_write_timeout(Some(IO_TIMEOUT)).map_err(io)?;
+                self.tcp = Some(stream);
+            }
+            #[cfg(unix)]
+            SyslogTransport::Unix(path) => {
+                let socket = std::os::unix::net::UnixDatagram::unbound().map_err(io)?;
+                socket.connect(path).map_err(io)?;
+                self.unix = Some(socket);
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, message: &str) -> std::io::Result<()> {
+        match &self.transport {
+            SyslogTransport::Udp(_) => match &self.udp {
+                Some(socket) => socket.send(message.as_bytes()).map(|_| ()),
+                None => Err(std::io::Error::from(std::io::ErrorKind::NotConnected)),
+            },
+            // Octet counting framing (RFC 6587)
+            SyslogTransport::Tcp(_) => match &mut self.tcp {
+                Some(stream) => stream.write_all(format!("{} {}", message.len(), message).as_bytes()),
+                None => Err(std::io::Error::from(std::io::ErrorKind::NotConnected)),
+            },
+            #[cfg(unix)]
+            SyslogTransport::Unix(_) => match &self.unix {
+                Some(socket) => socket.send(message.as_bytes()).map(|_| ()),
+                None => Err(std::io::Error::from(std::io::ErrorKind::NotConnected)),
+            },
+        }
+    }
+}
+
+impl AlertSink for SyslogSink {
+    fn name(&self) -> String {
+        match &self.transport {
+            SyslogTransport::Udp(a) => format!("syslog udp://{}", a),
+            SyslogTransport::Tcp(a) => format!("syslog tcp://{}", a),
+            #[cfg(unix)]
+            SyslogTransport::Unix(a) => format!("syslog unix://{}", a),
+        }
+    }
+
+    fn send(&mut self, event: &Event) -> Result<(), SinkError> {
+        let message = event.to_syslog(&self.hostname, env!("CARGO_PKG_NAME"));
+        if self.write(&message).is_ok() {
+            return Ok(());
+        }
+        // The server may have closed the connection: reconnect once
+        self.connect()?;
+        self.write(&message).map_err(|e| SinkError::Io(self.name(), e))
+    }
+}
+
+/// Appends the events to a file, one JSON object per line
+pub struct NdjsonSink {
+    path: String,
+    file: File,
+}
+
+impl NdjsonSink {
+    pub fn new(path: &str) -> Result<NdjsonSink, SinkError> {
+        match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => Ok(NdjsonSink { path: path.to_string(), file }),
+            Err(e) => Err(SinkError::Io(path.to_string(), e)),
+        }
+    }
+}
+
+impl AlertSink for NdjsonSink {
+    fn name(&self) -> String {
+        self.path.clone()
+    }
+
+    fn send(&mut self, event: &Event) -> Result<(), SinkError> {
+        let line = event.to_json().to_string() + "\n";
+        self.file.write_all(line.as_bytes())
+            .and_then(|_| self.file.flush())
+            .map_err(|e| SinkError::Io(self.path.clone(), e))
+    }
+}
+
+/// Posts the events as JSON to an HTTP endpoint
+pub struct WebhookSink {
+    url: String,
+    host: String,
+    address: String,
+    path: String,
+    retries: u32,
+    backoff: Duration,
+}
+
+impl WebhookSink {
+    /// Creates a webhook for an http:// url. A failed delivery is attempted again up to
+    /// `retries` times, waiting `backoff` the first time and twice as long every time after.
+    pub fn new(url: &str, retries: u32, backoff: Duration) -> Result<WebhookSink, SinkError> {
+        let rest = url.strip_prefix("http://").ok_or(SinkError::InvalidConfig(format!("Only http:// webhooks are supported: {}", url)))?;
+        let (host, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        if host.is_empty() {
+            return Err(SinkError::InvalidConfig(format!("Missing host in {}", url)));
+        }
+        let address = if host.rsplit_once(':').is_some_and(|(_, port)| port.parse::<u16>().is_ok()) {
+            host.to_string()
+        } else {
+            format!("{}:80", host)
+        };
+        Ok(WebhookSink {
+            url: url.to_string(),
+            host: host.to_string(),
+            address,
+            path: path.to_string(),
+            retries,
+            backoff,
+        })
+    }
+
+    fn post(&self, body: &str) -> Result<(), SinkError> {
+        let io = |e| SinkError::Io(self.url.clone(), e);
+        let address = resolve(&self.address).map_err(io)?;
+        let mut stream = TcpStream::connect_timeout(&address, IO_TIMEOUT).map_err(io)?;
+        stream.set_read_timeout(Some(IO_TIMEOUT)).map_err(io)?;
+        stream.set_write_timeout(Some(IO_TIMEOUT)).map_err(io)?;
+        let request = format!("POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                              self.path, self.host, body.len(), body);
+        stream.write_all(request.as_bytes()).map_err(io)?;
+        let mut response = Vec::new();
+        let mut buffer = [0; 512];
+        // The status line is enough
+        while !response.contains(&b'\n') {
+            match stream.read(&mut buffer).map_err(io)? {
+                0 => break,
+                n => response.extend_from_slice(&buffer[..n]),
+            }
+        }
+        let status_line = String::from_utf8_lossy(&response).lines().next().unwrap_or_default().to_string();
+        match status_line.split_whitespace().nth(1).and_then(|s| s.parse::<u16>().ok()) {
+            Some(status) if (200..300).contains(&status) => Ok(()),
+            Some(_) => Err(SinkError::Http(format!("{} answered {}", self.url, status_line))),
+            None => Err(SinkError::Http(format!("{} sent an invalid response", self.url))),
+        }
+    }
+}
+
+impl AlertSink for WebhookSink {
+    fn name(&self) -> String {
+        self.url.clone()
+    }
+
+    fn send(&mut self, event: &Event) -> Result<(), SinkError> {
+        let body = event.to_json().to_string();
+        let mut backoff = self.backoff;
+        let mut attempt = 0;
+        loop {
+            match self.post(&body) {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt >= self.retries => return Err(e),
+                Err(_) => {
+                    std::thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Delivers the events to the sinks, each one from a thread of its own
+pub(crate) struct SinkDispatcher {
+    /// Read for every event, written only when a sink is added
+    queues: RwLock<Vec<SyncSender<Event>>>,
+    on_error: Arc<dyn Fn(SinkError) + Send + Sync>,
+    /// The number of events dropped because the queue of a sink was full
+    dropped: AtomicU64,
+}
+
+impl SinkDispatcher {
+    /// Creates a dispatcher without sinks, which calls on_error for each failed delivery. The
+    /// threads of the sinks stop when the dispatcher is dropped.
+    pub(crate) fn start<F: Fn(SinkError) + Send + Sync + 'static>(on_error: F) -> SinkDispatcher {
+        SinkDispatcher {
+            queues: RwLock::new(Vec::new()),
+            on_error: Arc::new(on_error),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Starts the delivery thread of a sink.
+    pub(crate) fn add(&self, mut sink: Box<dyn AlertSink>) {
+        let (sender, receiver) = sync_channel::<Event>(SINK_QUEUE);
+        let on_error = self.on_error.clone();
+        std::thread::spawn(move || {
+            for event in receiver {
+                if let Err(e) = sink.send(&event) {
+                    on_error(e);
+                }
+            }
+        });
+        let mut q = self.queues.write().unwrap();
+        q.push(sender);
+    }
+
+    /// Queues the event for every sink, without waiting: it is dropped for the sinks whose queue is full.
+    pub(crate) fn send(&self, event: Event) {
+        let q = self.queues.read().unwrap();
+        for sender in q.iter() {
+            if let Err(TrySendError::Full(_)) = sender.try_send(event.clone()) {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Gets the number of events dropped because the queue of a sink was full.
+    pub(crate) fn get_dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+fn resolve(address: &str) -> std::io::Result<std::net::SocketAddr> {
+    address.to_socket_addrs()?
+        .next()
+        .ok_or(std::io::Error::from(std::io::ErrorKind::AddrNotAvailable))
+}
+
+fn format_rfc3339(timestamp_us: i64) -> String {
+    match DateTime::from_timestamp_micros(timestamp_us) {
+        Some(t) => t.to_rfc3339_opts(SecondsFormat::Micros, true),
+        None => String::from("-"),
+    }
+}
+
+/// Escapes the characters not allowed in the value of a structured data parameter.
+fn escape_param(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace(']', "\\]")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufRead;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::time::Instant;
+    use crate::alert::AlertCategory;
+    use super::*;
+
+    fn alert() -> Event {
+        Event::Alert(Alert {
+            category: AlertCategory::Scan,
+            kind: String::from("Vertical SYN scan"),
+            source: String::from("10.0.0.1 \"x]"),
+            targets: vec![String::from("192.168.1.10")],
+            evidence: vec![String::from("120 ports")],
+            start_us: 1_700_000_000_000_000,
+            end_us: 1_700_000_010_000_000,
+        })
+    }
+
+    fn error() -> Event {
+        Event::Error { timestamp_us: 1_700_000_000_000_000, message: String::from("Error in capture: device down") }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("network_analyzer-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    /// Checks the header of an RFC 5424 message and returns its structured data and message.
+    fn check_syslog(message: &str, priority: u8, msgid: &str) -> String {
+        let parts = message.splitn(7, ' ').collect::<Vec<&str>>();
+        assert_eq!(parts[0], format!("<{}>1", priority));
+        assert_eq!(parts[1], "2023-11-14T22:13:20.000000Z");
+        assert_eq!(parts[3], env!("CARGO_PKG_NAME"));
+        assert_eq!(parts[4], std::process::id().to_string());
+        assert_eq!(parts[5], msgid);
+        parts[6].to_string()
+    }
+
+    #[test]
+    fn sends_syslog_messages_over_udp() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(IO_TIMEOUT)).unwrap();
+        let address = server.local_addr().unwrap().to_string();
+        let mut sink = SinkConfig::Syslog(SyslogTransport::parse(&format!("udp://{}", address)).unwrap()).open().unwrap();
+        assert_eq!(sink.name(), format!("syslog udp://{}", address));
+        sink.send(&alert()).unwrap();
+
+        let mut buffer = [0; 2048];
+        let n = server.recv(&mut buffer).unwrap();
+        let rest = check_syslog(&String::from_utf8_lossy(&buffer[..n]), 132, "ALERT");
+        let data = "[alert@32473 category=\"Scan\" source=\"10.0.0.1 \\\"x\\]\" start=\"2023-11-14T22:13:20.000000Z\" end=\"2023-11-14T22:13:30.000000Z\"]";
+        assert_eq!(rest, format!("{} {}", data, match alert() { Event::Alert(a) => a.to_string(), _ => unreachable!() }));
+    }
+
+    #[test]
+    fn frames_syslog_messages_over_tcp_with_octet_counting() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let mut sink = SyslogSink::new(SyslogTransport::Tcp(address)).unwrap();
+        sink.send(&error()).unwrap();
+        sink.send(&alert()).unwrap();
+        drop(sink);
+
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(IO_TIMEOUT)).unwrap();
+        let mut received = Vec::new();
+        std::io::BufReader::new(stream).read_to_end(&mut received).unwrap();
+        let mut frames = Vec::new();
+        let mut rest = received.as_slice();
+        while !rest.is_empty() {
+            let space = rest.iter().position(|b| *b == b' ').unwrap();
+            let len = String::from_utf8_lossy(&rest[..space]).parse::<usize>().unwrap();
+            frames.push(String::from_utf8_lossy(&rest[space + 1..space + 1 + len]).to_string());
+            rest = &rest[space + 1 + len..];
+        }
+        assert_eq!(frames.len(), 2);
+        assert_eq!(check_syslog(&frames[0], 131, "ERROR"), "- Error in capture: device down");
+        check_syslog(&frames[1], 132, "ALERT");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sends_syslog_messages_to_a_unix_socket() {
+        let path = temp_path("syslog.sock");
+        let server = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+        server.set_read_timeout(Some(IO_TIMEOUT)).unwrap();
+        let transport = SyslogTransport::parse(&format!("unix://{}", path.display())).unwrap();
+        let mut sink = SyslogSink::new(transport).unwrap();
+        sink.send(&error()).unwrap();
+
+        let mut buffer = [0; 2048];
+        let n = server.recv(&mut buffer).unwrap();
+        assert_eq!(check_syslog(&String::from_utf8_lossy(&buffer[..n]), 131, "ERROR"), "- Error in capture: device down");
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn rejects_unknown_syslog_transports() {
+        assert!(matches!(SyslogTransport::parse("tls://logs:6514"), Err(SinkError::InvalidConfig(_))));
+        assert!(matches!(SyslogTransport::parse("logs:514"), Err(SinkError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn appends_json_lines_to_a_file() {
+        let path = temp_path("alerts.ndjson");
+        let path_name = path.to_string_lossy().to_string();
+        let mut sink = NdjsonSink::new(&path_name).unwrap();
+        sink.send(&alert()).unwrap();
+        sink.send(&error()).unwrap();
+        drop(sink);
+        // A new sink appends to the same file
+        NdjsonSink::new(&path_name).unwrap().send(&error()).unwrap();
+
+        let lines = std::io::BufReader::new(File::open(&path).unwrap()).lines().map(|l| l.unwrap()).collect::<Vec<String>>();
+        assert_eq!(lines.len(), 3);
+        let first: Value = serde_json::from_str(&lines[0]).unwrap();
+        assert_eq!(first["type"], "alert");
+        assert_eq!(first["kind"], "Vertical SYN scan");
+        assert_eq!(first["end"], "2023-11-14T22:13:30.000000Z");
+        let second: Value = serde_json::from_str(&lines[1]).unwrap();
+        assert_eq!(second, json!({ "type": "error", "timestamp": "2023-11-14T22:13:20.000000Z", "message": "Error in capture: device down" }));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    /// Answers a request with each status in turn and returns when each one arrived, with its body.
+    fn serve_webhook(listener: TcpListener, statuses: Vec<u16>) -> std::thread::JoinHandle<Vec<(Instant, String)>> {
+        std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = std::io::BufReader::new(stream);
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                        length = value.trim().parse::<usize>().unwrap();
+                    }
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                requests.push((Instant::now(), String::from_utf8(body).unwrap()));
+                let response = format!("HTTP/1.1 {} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+            }
+            requests
+        })
+    }
+
+    #[test]
+    fn retries_the_webhook_with_backoff() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hooks/alerts", listener.local_addr().unwrap());
+        let server = serve_webhook(listener, vec![500, 503, 200]);
+        let mut sink = WebhookSink::new(&url, 3, Duration::from_millis(50)).unwrap();
+        sink.send(&alert()).unwrap();
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 3);
+        let body = alert().to_json().to_string();
+        assert!(requests.iter().all(|(_, b)| *b == body));
+        // The wait doubles after every failure
+        assert!(requests[1].0 - requests[0].0 >= Duration::from_millis(50));
+        assert!(requests[2].0 - requests[1].0 >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn gives_up_on_the_webhook_after_the_retries() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = serve_webhook(listener, vec![500, 500]);
+        let mut sink = WebhookSink::new(&url, 1, Duration::from_millis(10)).unwrap();
+        match sink.send(&error()) {
+            Err(SinkError::Http(message)) => assert!(message.ends_with("answered HTTP/1.1 500 Status"), "{}", message),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(server.join().unwrap().len(), 2);
+    }
+
+    /// Forwards the events to a channel
+    struct ChannelSink(std::sync::mpsc::Sender<Event>);
+
+    impl AlertSink for ChannelSink {
+        fn name(&self) -> String {
+            String::from("channel")
+        }
+
+        fn send(&mut self, event: &Event) -> Result<(), SinkError> {
+            let _ = self.0.send(event.clone());
+            Ok(())
+        }
+    }
+
+    /// Tells when it got its first event and blocks until released, like a webhook that is down
+    struct BlockedSink {
+        started: std::sync::mpsc::Sender<()>,
+        release: std::sync::mpsc::Receiver<()>,
+    }
+
+    impl AlertSink for BlockedSink {
+        fn name(&self) -> String {
+            String::from("blocked")
+        }
+
+        fn send(&mut self, _: &Event) -> Result<(), SinkError> {
+            let _ = self.started.send(());
+            let _ = self.release.recv();
+            Err(SinkError::Http(String::from("down")))
+        }
+    }
+
+    /// Adds a sink to the dispatcher that blocks on the first event sent, returns the sender
+    /// releasing it.
+    fn add_blocked_sink(dispatcher: &SinkDispatcher) -> std::sync::mpsc::Sender<()> {
+        let (started, started_receiver) = std::sync::mpsc::channel();
+        let (release, release_receiver) = std::sync::mpsc::channel();
+        dispatcher.add(Box::new(BlockedSink { started, release: release_receiver }));
+        dispatcher.send(error());
+        started_receiver.recv_timeout(IO_TIMEOUT).unwrap();
+        release
+    }
+
+    #[test]
+    fn delivers_to_the_other_sinks_while_one_is_blocked() {
+        let errors = Arc::new(AtomicU64::new(0));
+        let counted = errors.clone();
+        let dispatcher = SinkDispatcher::start(move |_| {
+            counted.fetch_add(1, Ordering::Relaxed);
+        });
+        let (sender, receiver) = std::sync::mpsc::channel();
+        dispatcher.add(Box::new(ChannelSink(sender)));
+        let release = add_blocked_sink(&dispatcher);
+        dispatcher.send(alert());
+        assert_eq!(receiver.recv_timeout(IO_TIMEOUT).unwrap(), error());
+        assert_eq!(receiver.recv_timeout(IO_TIMEOUT).unwrap(), alert());
+        assert_eq!(dispatcher.get_dropped(), 0);
+        // The failures of the blocked sink are reported once it is released
+        release.send(()).unwrap();
+        release.send(()).unwrap();
+        let start = Instant::now();
+        while errors.load(Ordering::Relaxed) < 2 && start.elapsed() < IO_TIMEOUT {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(errors.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn drops_and_counts_the_events_of_a_full_queue() {
+        let dispatcher = SinkDispatcher::start(|_| ());
+        let release = add_blocked_sink(&dispatcher);
+        // The blocked sink holds the first event, its queue takes SINK_QUEUE more
+        for _ in 0..SINK_QUEUE + 10 {
+            dispatcher.send(alert());
+        }
+        assert_eq!(dispatcher.get_dropped(), 10);
+        drop(release);
+    }
+
+    #[test]
+    fn rejects_invalid_webhook_urls() {
+        assert!(matches!(WebhookSink::new("https://example.com/hook", 0, Duration::ZERO), Err(SinkError::InvalidConfig(_))));
+        assert!(matches!(WebhookSink::new("http:///hook", 0, Duration::ZERO), Err(SinkError::InvalidConfig(_))));
+    }
+}
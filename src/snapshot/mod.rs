@@ -0,0 +1,188 @@
+//! Snapshots of the report kept in memory
+//!
+//! A snapshot is a copy of the communications and of the totals of the report at a point in
+//! time, shared behind an Arc so that it can be cloned cheaply and handed to other threads.
+//! `ControlBlock::snapshot` leaves the report untouched, `ControlBlock::snapshot_and_reset`
+//! also empties it, so that every snapshot covers the interval since the previous one. The
+//! report written to the output file and analyzed by the scan detector is the same, so after a
+//! reset they only cover the current interval too.
+//!
+//! # Usage
+//! loop {
+//!     std::thread::sleep(Duration::from_secs(60));
+//!     let snapshot = control_block.snapshot_and_reset();
+//!     let totals = snapshot.get_totals();
+//!     println!("{} flows, {} packets, {} bytes", totals.flows, totals.packets, totals.bytes);
+//! }
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use serde_json::{json, Value};
+use crate::report::Report;
+use crate::subscription::Flow;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// The totals of a snapshot
+pub struct Totals {
+    pub flows: usize,
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+#[derive(Debug)]
+struct SnapshotData {
+    since_us: i64,
+    taken_us: i64,
+    flows: Vec<Flow>,
+    protocols: BTreeMap<String, (u64, u64)>,
+    totals: Totals,
+}
+
+#[derive(Debug, Clone)]
+/// An immutable copy of the report
+pub struct ReportSnapshot {
+    data: Arc<SnapshotData>,
+}
+
+impl ReportSnapshot {
+    pub(crate) fn from_report(report: &Report, taken_us: i64) -> ReportSnapshot {
+        let mut flows = report.report_lines.iter().map(|(key, line)| Flow::from_line(key, line)).collect::<Vec<Flow>>();
+        flows.sort_by_key(|f| f.timestamp_first_us);
+        let totals = Totals {
+            flows: flows.len(),
+            packets: report.protocols.values().map(|(packets, _)| packets).sum(),
+            bytes: report.protocols.values().map(|(_, bytes)| bytes).sum(),
+        };
+        ReportSnapshot {
+            data: Arc::new(SnapshotData {
+                since_us: report.since_us,
+                taken_us,
+                flows,
+                protocols: report.protocols.clone(),
+                totals,
+            }),
+        }
+    }
+
+    /// Gets the time the report was created or last reset, in microseconds since the epoch.
+    pub fn get_since_us(&self) -> i64 {
+        self.data.since_us
+    }
+
+    /// Gets the time of the snapshot, in microseconds since the epoch.
+    pub fn get_taken_us(&self) -> i64 {
+        self.data.taken_us
+    }
+
+    /// Gets the communications, ordered by their first packet.
+    pub fn get_flows(&self) -> &[Flow] {
+        &self.data.flows
+    }
+
+    /// Gets the packets and bytes by protocol.
+    pub fn get_protocols(&self) -> &BTreeMap<String, (u64, u64)> {
+        &self.data.protocols
+    }
+
+    pub fn get_totals(&self) -> Totals {
+        self.data.totals
+    }
+
+    pub fn to_json(&self) -> Value {
+        let flows = self.data.flows.iter().map(|f| json!({
+            "address_1": f.address_1,
+            "address_2": f.address_2,
+            "interfaces": f.interfaces,
+            "protocols": f.protocols,
+            "bytes_total": f.bytes_total,
+            "packets_forward": f.packets_forward,
+            "packets_backward": f.packets_backward,
+            "timestamp_first_us": f.timestamp_first_us,
+            "timestamp_last_us": f.timestamp_last_us,
+        })).collect::<Vec<Value>>();
+        let protocols = self.data.protocols.iter().map(|(name, (packets, bytes))| {
+            (name.clone(), json!({ "packets": packets, "bytes": bytes }))
+        }).collect::<serde_json::Map<String, Value>>();
+        json!({
+            "since_us": self.data.since_us,
+            "taken_us": self.data.taken_us,
+            "totals": {
+                "flows": self.data.totals.flows,
+                "packets": self.data.totals.packets,
+                "bytes": self.data.totals.bytes,
+            },
+            "protocols": protocols,
+            "flows": flows,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libc::c_long;
+    use crate::packet::Packet;
+    use crate::subscription::{CaptureEvent, Topic};
+    use crate::{ControlBlock, ControlBlockBuilder};
+    use super::*;
+
+    /// Adds a packet to the report of the control block, like the workers do.
+    fn add_packet(control_block: &ControlBlock, source: &str, destination: &str, protocol: &str, length: u32, second: c_long) {
+        let mut packet = Packet::new(String::new(), source.to_string(), destination.to_string(), None, None, protocol.to_string(), length, String::new());
+        packet.set_timestamp(&second, &0);
+        let subscriptions = &control_block.subscriptions;
+        control_block.report.add_packet(&packet, |report, key, new| subscriptions.flow_packet(report, key, new));
+    }
+
+    #[test]
+    fn copies_the_report() {
+        let control_block = ControlBlockBuilder::new().build_offline().unwrap();
+        add_packet(&control_block, "10.0.0.3", "10.0.0.4", "DNS", 80, 2);
+        add_packet(&control_block, "10.0.0.1", "10.0.0.2", "TCP", 60, 1);
+        add_packet(&control_block, "10.0.0.2", "10.0.0.1", "TCP", 1500, 3);
+        let snapshot = control_block.snapshot();
+        assert_eq!(snapshot.get_totals(), Totals { flows: 2, packets: 3, bytes: 1640 });
+        // Ordered by their first packet
+        let flows = snapshot.get_flows().iter().map(|f| (f.address_1.as_str(), f.packets_forward, f.packets_backward)).collect::<Vec<_>>();
+        assert_eq!(flows, vec![("10.0.0.1", 1, 1), ("10.0.0.3", 1, 0)]);
+        assert_eq!(snapshot.get_protocols().get("TCP"), Some(&(2, 1560)));
+        assert_eq!(snapshot.to_json()["protocols"]["DNS"], json!({ "packets": 1, "bytes": 80 }));
+
+        // The report is left as it is
+        let later = control_block.snapshot();
+        assert_eq!(later.get_totals(), snapshot.get_totals());
+        assert_eq!(later.get_since_us(), snapshot.get_since_us());
+    }
+
+    #[test]
+    fn empties_the_report_and_ends_its_flows() {
+        let control_block = ControlBlockBuilder::new().build_offline().unwrap();
+        let events = control_block.subscribe(&[Topic::Flows]);
+        add_packet(&control_block, "10.0.0.1", "10.0.0.2", "TCP", 60, 100);
+        add_packet(&control_block, "10.0.0.3", "10.0.0.4", "DNS", 80, 2);
+        // A flow already ended does not end twice
+        control_block.report.for_each_shard(|shard| control_block.subscriptions.expire_flows(shard, 62_000_000));
+        let snapshot = control_block.snapshot_and_reset();
+        assert_eq!(snapshot.get_totals(), Totals { flows: 2, packets: 2, bytes: 140 });
+        let after = control_block.snapshot();
+        assert_eq!(after.get_totals(), Totals::default());
+        assert_eq!(after.get_since_us(), snapshot.get_taken_us());
+        assert_eq!(control_block.report.get_flow_count(), 0);
+
+        // Both flows start again with their next packet
+        add_packet(&control_block, "10.0.0.1", "10.0.0.2", "TCP", 60, 101);
+        add_packet(&control_block, "10.0.0.3", "10.0.0.4", "DNS", 80, 102);
+        let received = events.try_iter().map(|event| match event {
+            CaptureEvent::FlowStart(f) => ("start", f.address_1),
+            CaptureEvent::FlowUpdate(f) => ("update", f.address_1),
+            CaptureEvent::FlowEnd(f) => ("end", f.address_1),
+            e => panic!("unexpected event {:?}", e),
+        }).collect::<Vec<(&str, String)>>();
+        assert_eq!(received, vec![
+            ("start", String::from("10.0.0.1")),
+            ("start", String::from("10.0.0.3")),
+            ("end", String::from("10.0.0.3")),
+            ("end", String::from("10.0.0.1")),
+            ("start", String::from("10.0.0.1")),
+            ("start", String::from("10.0.0.3")),
+        ]);
+    }
+}
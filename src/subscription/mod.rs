@@ -0,0 +1,303 @@
+//! Subscriptions to the events of a capture
+//!
+//! Instead of reading back the report file, an application can subscribe to the events of a
+//! control block, choosing the topics it is interested in:
+//! * Packets: every decoded packet that passes the display filter
+//! * Flows: a communication of the report started, received packets or ended. A flow is updated
+//!   at most once every FLOW_UPDATE_US of capture time. A flow ends when no packet was seen for
+//!   FLOW_IDLE_US, checked every time the report is written, or when the capture stops; a packet
+//!   of an ended flow starts it again.
+//! * States: the transitions of the capture between idle, capturing, paused and stopped
+//! * Errors: the errors pushed to the control block
+//!
+//! The events are delivered through a bounded channel: when a subscriber falls more than
+//! SUBSCRIPTION_CAPACITY events behind, the new events are dropped for it, so that a slow
+//! subscriber never slows down the capture. A subscriber is removed when its receiver is dropped.
+//!
+//! # Usage
+//! let events = control_block.subscribe(&[Topic::Flows, Topic::Errors]);
+//! for event in events {
+//!     match event {
+//!         CaptureEvent::FlowEnd(flow) => println!("{} <-> {} {} bytes", flow.address_1, flow.address_2, flow.bytes_total),
+//!         CaptureEvent::Error { message, .. } => println!("{}", message),
+//!         _ => (),
+//!     }
+//! }
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::RwLock;
+use crate::packet::Packet;
+use crate::report::{Report, ReportLine};
+use crate::CaptureState;
+
+/// Events a subscriber can be behind before the new ones are dropped
+pub const SUBSCRIPTION_CAPACITY: usize = 10_000;
+
+/// Time without packets after which a flow ends, in microseconds
+pub const FLOW_IDLE_US: i64 = 60_000_000;
+
+/// Shortest time between two updates of a flow, in microseconds
+pub const FLOW_UPDATE_US: i64 = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The kinds of events
+pub enum Topic {
+    Packets,
+    Flows,
+    States,
+    Errors,
+}
+
+#[derive(Debug, Clone)]
+/// An event of a capture
+pub enum CaptureEvent {
+    Packet(Box<Packet>),
+    FlowStart(Flow),
+    FlowUpdate(Flow),
+    FlowEnd(Flow),
+    StateChanged { from: CaptureState, to: CaptureState },
+    Error { timestamp_us: i64, message: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A communication of the report, at the time of the event
+pub struct Flow {
+    /// The first address plus an optional port
+    pub address_1: String,
+    /// The second address plus an optional port
+    pub address_2: String,
+    /// The interface of the line, empty when the report is not grouped by interface
+    pub interface: String,
+    /// The interfaces the communication was captured on
+    pub interfaces: Vec<String>,
+    pub protocols: Vec<String>,
+    pub bytes_total: u32,
+    pub packets_forward: u32,
+    pub packets_backward: u32,
+    /// In microseconds since the epoch
+    pub timestamp_first_us: i64,
+    /// In microseconds since the epoch
+    pub timestamp_last_us: i64,
+}
+
+impl CaptureEvent {
+    pub fn get_topic(&self) -> Topic {
+        match self {
+            CaptureEvent::Packet(_) => Topic::Packets,
+            CaptureEvent::FlowStart(_) | CaptureEvent::FlowUpdate(_) | CaptureEvent::FlowEnd(_) => Topic::Flows,
+            CaptureEvent::StateChanged { .. } => Topic::States,
+            CaptureEvent::Error { .. } => Topic::Errors,
+        }
+    }
+}
+
+impl Flow {
+    pub(crate) fn from_line(key: &(String, String, String), line: &ReportLine) -> Flow {
+        Flow {
+            address_1: key.0.clone(),
+            address_2: key.1.clone(),
+            interface: key.2.clone(),
+            interfaces: line.interfaces.clone(),
+            protocols: line.protocols.clone(),
+            bytes_total: line.bytes_total,
+            packets_forward: line.packets_forward,
+            packets_backward: line.packets_backward,
+            timestamp_first_us: line.timestamp_first_us,
+            timestamp_last_us: line.timestamp_last_us,
+        }
+    }
+}
+
+fn topic_bit(topic: Topic) -> u8 {
+    match topic {
+        Topic::Packets => 1,
+        Topic::Flows => 2,
+        Topic::States => 4,
+        Topic::Errors => 8,
+    }
+}
+
+struct Subscriber {
+    topics: Vec<Topic>,
+    sender: SyncSender<CaptureEvent>,
+    /// Whether the receiver was dropped, the subscriber being removed by the next event
+    closed: AtomicBool,
+}
+
+#[derive(Default)]
+/// The subscribers of a control block
+pub(crate) struct Subscriptions {
+    /// Read by the workers sending the events, written only when the subscribers change
+    subscribers: RwLock<Vec<Subscriber>>,
+    /// The topics of the subscribers, one bit each, read by the workers without locking
+    topics: AtomicU8,
+}
+
+impl Subscriptions {
+    pub(crate) fn subscribe(&self, topics: &[Topic]) -> Receiver<CaptureEvent> {
+        let (sender, receiver) = sync_channel(SUBSCRIPTION_CAPACITY);
+        let mut s = self.subscribers.write().unwrap();
+        s.push(Subscriber { topics: topics.to_vec(), sender, closed: AtomicBool::new(false) });
+        self.update_topics(&s);
+        receiver
+    }
+
+    /// Whether someone subscribed to the topic, so that the events are built only when needed.
+    pub(crate) fn wants(&self, topic: Topic) -> bool {
+        self.topics.load(Ordering::Relaxed) & topic_bit(topic) != 0
+    }
+
+    fn update_topics(&self, subscribers: &[Subscriber]) {
+        let topics = subscribers.iter().flat_map(|s| s.topics.iter()).fold(0, |bits, topic| bits | topic_bit(*topic));
+        self.topics.store(topics, Ordering::Relaxed);
+    }
+
+    /// Delivers the event to the subscribers of its topic.
+    pub(crate) fn send(&self, event: CaptureEvent) {
+        let topic = event.get_topic();
+        let mut closed = false;
+        let s = self.subscribers.read().unwrap();
+        for subscriber in s.iter().filter(|subscriber| subscriber.topics.contains(&topic)) {
+            if let Err(TrySendError::Disconnected(_)) = subscriber.sender.try_send(event.clone()) {
+                subscriber.closed.store(true, Ordering::Relaxed);
+                closed = true;
+            }
+        }
+        drop(s);
+        if closed {
+            let mut s = self.subscribers.write().unwrap();
+            s.retain(|subscriber| !subscriber.closed.load(Ordering::Relaxed));
+            self.update_topics(&s);
+        }
+    }
+
+    /// Sends the start or update of the flow of a line the report just added a packet to. The
+    /// update is skipped when the previous event of the flow is less than FLOW_UPDATE_US old.
+    pub(crate) fn flow_packet(&self, report: &mut Report, key: &(String, String, String), new: bool) {
+        let line = match report.report_lines.get_mut(key) {
+            Some(l) => l,
+            None => return,
+        };
+        let restarted = std::mem::take(&mut line.ended);
+        if !new && !restarted && line.timestamp_last_us - line.notified_us < FLOW_UPDATE_US {
+            return;
+        }
+        line.notified_us = line.timestamp_last_us;
+        let flow = Flow::from_line(key, line);
+        match new || restarted {
+            true => self.send(CaptureEvent::FlowStart(flow)),
+            false => self.send(CaptureEvent::FlowUpdate(flow)),
+        }
+    }
+
+    /// Ends the flows of a shard without packets since FLOW_IDLE_US before now.
+    pub(crate) fn expire_flows(&self, shard: &mut Report, now_us: i64) {
+        self.end_flows(shard, |line| now_us - line.timestamp_last_us >= FLOW_IDLE_US);
+    }
+
+    /// Ends all the flows of a shard, when the capture stops.
+    pub(crate) fn end_all_flows(&self, shard: &mut Report) {
+        self.end_flows(shard, |_| true);
+    }
+
+    /// Ends the flows of a shard of the report being reset that were not ended yet.
+    pub(crate) fn end_drained_flows(&self, shard: &Report) {
+        for (key, line) in shard.report_lines.iter().filter(|(_, line)| !line.ended) {
+            self.send(CaptureEvent::FlowEnd(Flow::from_line(key, line)));
+        }
+    }
+
+    fn end_flows<F: Fn(&ReportLine) -> bool>(&self, shard: &mut Report, ended: F) {
+        for (key, line) in shard.report_lines.iter_mut() {
+            if !line.ended && ended(line) {
+                line.ended = true;
+                self.send(CaptureEvent::FlowEnd(Flow::from_line(key, line)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libc::c_long;
+    use crate::report::ShardedReport;
+    use super::*;
+
+    fn error(message: &str) -> CaptureEvent {
+        CaptureEvent::Error { timestamp_us: 0, message: message.to_string() }
+    }
+
+    /// Adds a packet from 10.0.0.1 to 10.0.0.2, captured at the microsecond, and sends its flow event.
+    fn add_packet(subscriptions: &Subscriptions, report: &ShardedReport, microsecond: i64) {
+        let mut packet = Packet::new(String::new(), String::from("10.0.0.1"), String::from("10.0.0.2"), None, None, String::from("UDP"), 64, String::new());
+        packet.set_timestamp(&((microsecond / 1_000_000) as c_long), &((microsecond % 1_000_000) as c_long));
+        report.add_packet(&packet, |report, key, new| subscriptions.flow_packet(report, key, new));
+    }
+
+    /// Gets the kind and the bytes of the flow events received so far.
+    fn flow_events(events: &Receiver<CaptureEvent>) -> Vec<(&'static str, u32)> {
+        events.try_iter().map(|event| match event {
+            CaptureEvent::FlowStart(f) => ("start", f.bytes_total),
+            CaptureEvent::FlowUpdate(f) => ("update", f.bytes_total),
+            CaptureEvent::FlowEnd(f) => ("end", f.bytes_total),
+            e => panic!("unexpected event {:?}", e),
+        }).collect()
+    }
+
+    #[test]
+    fn delivers_only_the_topics_subscribed() {
+        let subscriptions = Subscriptions::default();
+        let states = subscriptions.subscribe(&[Topic::States]);
+        let errors = subscriptions.subscribe(&[Topic::Errors, Topic::States]);
+        assert!(subscriptions.wants(Topic::Errors) && !subscriptions.wants(Topic::Packets));
+        subscriptions.send(CaptureEvent::StateChanged { from: CaptureState::Idle(), to: CaptureState::Capturing() });
+        subscriptions.send(error("device down"));
+        assert_eq!(states.try_iter().map(|e| e.get_topic()).collect::<Vec<Topic>>(), vec![Topic::States]);
+        assert_eq!(errors.try_iter().map(|e| e.get_topic()).collect::<Vec<Topic>>(), vec![Topic::States, Topic::Errors]);
+
+        // A subscriber leaves with its receiver, noticed by the next event of its topics
+        drop(errors);
+        subscriptions.send(error("device down"));
+        assert!(!subscriptions.wants(Topic::Errors) && subscriptions.wants(Topic::States));
+    }
+
+    #[test]
+    fn drops_the_events_of_a_full_channel() {
+        let subscriptions = Subscriptions::default();
+        let events = subscriptions.subscribe(&[Topic::Errors]);
+        for i in 0..SUBSCRIPTION_CAPACITY + 10 {
+            subscriptions.send(error(&i.to_string()));
+        }
+        let received = events.try_iter().collect::<Vec<CaptureEvent>>();
+        assert_eq!(received.len(), SUBSCRIPTION_CAPACITY);
+        assert!(matches!(&received[SUBSCRIPTION_CAPACITY - 1], CaptureEvent::Error { message, .. } if *message == (SUBSCRIPTION_CAPACITY - 1).to_string()));
+        // The subscriber is kept and receives the events once it caught up
+        subscriptions.send(error("last"));
+        assert!(matches!(events.try_recv(), Ok(CaptureEvent::Error { message, .. }) if message == "last"));
+    }
+
+    #[test]
+    fn starts_updates_and_ends_the_flows_in_order() {
+        let subscriptions = Subscriptions::default();
+        let report = ShardedReport::new(4, 0);
+        let events = subscriptions.subscribe(&[Topic::Flows]);
+        add_packet(&subscriptions, &report, 1_000_000);
+        // Updated at most once every FLOW_UPDATE_US
+        add_packet(&subscriptions, &report, 1_100_000);
+        add_packet(&subscriptions, &report, 1_000_000 + FLOW_UPDATE_US);
+        add_packet(&subscriptions, &report, 1_200_000 + FLOW_UPDATE_US);
+        assert_eq!(flow_events(&events), vec![("start", 64), ("update", 192)]);
+
+        report.for_each_shard(|shard| subscriptions.expire_flows(shard, 1_200_000 + FLOW_UPDATE_US + FLOW_IDLE_US - 1));
+        assert!(flow_events(&events).is_empty());
+        report.for_each_shard(|shard| subscriptions.expire_flows(shard, 1_200_000 + FLOW_UPDATE_US + FLOW_IDLE_US));
+        assert_eq!(flow_events(&events), vec![("end", 256)]);
+        // An ended flow ends once, a packet starts it again
+        report.for_each_shard(|shard| subscriptions.end_all_flows(shard));
+        assert!(flow_events(&events).is_empty());
+        add_packet(&subscriptions, &report, 100_000_000);
+        assert_eq!(flow_events(&events), vec![("start", 320)]);
+        report.for_each_shard(|shard| subscriptions.end_all_flows(shard));
+        assert_eq!(flow_events(&events), vec![("end", 320)]);
+    }
+}
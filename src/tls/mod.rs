@@ -0,0 +1,100 @@
+use crate::dissector::{Dissector, DissectorContext, TransportProtocol};
+use crate::packet::{FieldType, FieldValue, Packet};
+
+/// Port used by HTTPS
+pub const HTTPS_PORT: u16 = 443;
+
+/// Dissector for the TLS record layer, decoding the server name of the Client Hello
+pub struct TlsDissector;
+
+impl Dissector for TlsDissector {
+    fn name(&self) -> &str {
+        "TLS"
+    }
+
+    fn ports(&self) -> Vec<u16> {
+        vec![HTTPS_PORT]
+    }
+
+    fn heuristic(&self, context: &DissectorContext) -> bool {
+        // A handshake record with a Client Hello inside
+        context.payload.len() > 5 && context.payload[0] == 22 && context.payload[1] == 3 && context.payload[5] == 1
+    }
+
+    fn dissect(&self, context: &mut DissectorContext, packet: &mut Packet) -> bool {
+        let payload = context.payload;
+        if context.transport != TransportProtocol::Tcp || payload.len() < 5 {
+            return false;
+        }
+        let content_type = payload[0];
+        if !(20..=23).contains(&content_type) || payload[1] != 3 {
+            return false;
+        }
+        packet.set_protocol(String::from("TLS"));
+        packet.add_field("tls.record.content_type", FieldValue::Int(i64::from(content_type)));
+        if content_type == 22 && payload.len() > 5 {
+            let handshake_type = payload[5];
+            packet.add_field("tls.handshake.type", FieldValue::Int(i64::from(handshake_type)));
+            match handshake_type {
+                1 => {
+                    let sni = server_name(&payload[5..]);
+                    match &sni {
+                        Some(name) => {
+                            packet.add_field("tls.sni", FieldValue::Str(name.clone()));
+                            packet.set_info(format!("Client Hello ({})", name));
+                        }
+                        None => packet.set_info(String::from("Client Hello")),
+                    }
+                }
+                2 => packet.set_info(String::from("Server Hello")),
+                _ => packet.set_info(String::from("Handshake")),
+            }
+        } else {
+            let info = match content_type {
+                20 => "Change Cipher Spec",
+                21 => "Alert",
+                _ => "Application Data",
+            };
+            packet.set_info(String::from(info));
+        }
+        true
+    }
+
+    fn fields(&self) -> Vec<(String, FieldType)> {
+        vec![
+            (String::from("tls.record.content_type"), FieldType::Int),
+            (String::from("tls.handshake.type"), FieldType::Int),
+            (String::from("tls.sni"), FieldType::Str),
+        ]
+    }
+}
+
+/// Gets the host name of the server_name extension of a Client Hello handshake message.
+fn server_name(handshake: &[u8]) -> Option<String> {
+    // type (1), length (3), version (2), random (32)
+    let mut offset = 38;
+    let session_id_len = usize::from(*handshake.get(offset)?);
+    offset += 1 + session_id_len;
+    let cipher_suites_len = usize::from(read_u16(handshake, offset)?);
+    offset += 2 + cipher_suites_len;
+    let compression_len = usize::from(*handshake.get(offset)?);
+    offset += 1 + compression_len;
+    let extensions_end = offset + 2 + usize::from(read_u16(handshake, offset)?);
+    offset += 2;
+    while offset + 4 <= extensions_end {
+        let extension_type = read_u16(handshake, offset)?;
+        let len = usize::from(read_u16(handshake, offset + 2)?);
+        if extension_type == 0 {
+            // list length (2), name type (1), name length (2), name
+            let name_len = usize::from(read_u16(handshake, offset + 7)?);
+            let name = handshake.get(offset + 9..offset + 9 + name_len)?;
+            return Some(String::from_utf8_lossy(name).to_string());
+        }
+        offset += 4 + len;
+    }
+    None
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    bytes.get(offset..offset + 2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+}
@@ -0,0 +1,369 @@
+//! Full screen terminal dashboard
+//!
+//! Shows the communications of the report in a live table, the throughput of the capture, the
+//! traffic by protocol and the errors, and maps keys onto the control block:
+//! * p: pause or resume the capture
+//! * f: change the BPF filter
+//! * d: choose another device
+//! * s / r: change the sort column / reverse the order
+//! * c: clear the errors
+//! * q: stop the capture and quit
+use std::collections::VecDeque;
+use std::io;
+use std::time::{Duration, Instant};
+use network_analyzer::{api, get_devices, CaptureState, ControlBlock};
+use network_analyzer::device::DeviceSelector;
+use network_analyzer::packet::format_timestamp_us;
+use network_analyzer::snapshot::ReportSnapshot;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Direction, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::widgets::{BarChart, Block, Borders, Clear, List, ListItem, ListState, Paragraph, Row, Sparkline, Table, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+
+/// Time between two redraws
+const TICK: Duration = Duration::from_millis(250);
+/// Seconds of throughput kept for the sparklines
+const HISTORY: usize = 120;
+
+#[derive(Clone, Copy, PartialEq)]
+enum SortColumn {
+    Bytes,
+    Packets,
+    First,
+    Last,
+    Address,
+}
+
+impl SortColumn {
+    fn next(self) -> Self {
+        match self {
+            SortColumn::Bytes => SortColumn::Packets,
+            SortColumn::Packets => SortColumn::First,
+            SortColumn::First => SortColumn::Last,
+            SortColumn::Last => SortColumn::Address,
+            SortColumn::Address => SortColumn::Bytes,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            SortColumn::Bytes => "bytes",
+            SortColumn::Packets => "packets",
+            SortColumn::First => "first seen",
+            SortColumn::Last => "last seen",
+            SortColumn::Address => "address",
+        }
+    }
+}
+
+enum Mode {
+    Normal,
+    /// Typing a new BPF filter
+    Filter(String),
+    /// Choosing a device among the listed ones
+    Device(Vec<String>, ListState),
+}
+
+struct Dashboard<'a> {
+    control_block: &'a ControlBlock,
+    mode: Mode,
+    sort: SortColumn,
+    descending: bool,
+    /// The report at the last sample
+    snapshot: ReportSnapshot,
+    /// Positions of the flows of the snapshot in the order of the table
+    order: Vec<usize>,
+    /// Packets and bytes per second, the most recent last
+    packet_rates: VecDeque<u64>,
+    byte_rates: VecDeque<u64>,
+    last_totals: (u64, u64),
+    last_sample: Instant,
+    /// Outcome of the last command
+    message: String,
+}
+
+/// Runs the dashboard until the user quits, then stops the capture.
+pub fn run(control_block: &ControlBlock) -> io::Result<()> {
+    let mut terminal = ratatui::try_init()?;
+    let result = Dashboard::new(control_block).run(&mut terminal);
+    ratatui::restore();
+    control_block.stop();
+    result
+}
+
+impl<'a> Dashboard<'a> {
+    fn new(control_block: &'a ControlBlock) -> Self {
+        let mut dashboard = Dashboard {
+            control_block,
+            mode: Mode::Normal,
+            sort: SortColumn::Bytes,
+            descending: true,
+            snapshot: control_block.snapshot(),
+            order: Vec::new(),
+            packet_rates: VecDeque::new(),
+            byte_rates: VecDeque::new(),
+            last_totals: totals(control_block),
+            last_sample: Instant::now(),
+            message: String::new(),
+        };
+        dashboard.sort_flows();
+        dashboard
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
+        loop {
+            if self.last_sample.elapsed() >= Duration::from_secs(1) {
+                self.sample();
+            }
+            terminal.draw(|frame| self.draw(frame))?;
+            if event::poll(TICK)? {
+                if let Event::Key(key) = event::read()? {
+                    if key.kind == KeyEventKind::Press && !self.handle_key(key.code) {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Records the throughput since the previous sample.
+    fn sample(&mut self) {
+        let (packets, bytes) = totals(self.control_block);
+        let seconds = self.last_sample.elapsed().as_secs_f64();
+        let packet_rate = (packets.saturating_sub(self.last_totals.0) as f64 / seconds) as u64;
+        let byte_rate = (bytes.saturating_sub(self.last_totals.1) as f64 / seconds) as u64;
+        for (history, rate) in [(&mut self.packet_rates, packet_rate), (&mut self.byte_rates, byte_rate)] {
+            history.push_back(rate);
+            if history.len() > HISTORY {
+                history.pop_front();
+            }
+        }
+        self.last_totals = (packets, bytes);
+        self.last_sample = Instant::now();
+        self.snapshot = self.control_block.snapshot();
+        self.sort_flows();
+    }
+
+    /// Orders the flows of the snapshot by the sort column.
+    fn sort_flows(&mut self) {
+        let flows = self.snapshot.get_flows();
+        self.order = (0..flows.len()).collect();
+        match self.sort {
+            SortColumn::Bytes => self.order.sort_by_key(|i| flows[*i].bytes_total),
+            SortColumn::Packets => self.order.sort_by_key(|i| u64::from(flows[*i].packets_forward) + u64::from(flows[*i].packets_backward)),
+            SortColumn::First => self.order.sort_by_key(|i| flows[*i].timestamp_first_us),
+            SortColumn::Last => self.order.sort_by_key(|i| flows[*i].timestamp_last_us),
+            SortColumn::Address => self.order.sort_by(|a, b| (&flows[*a].address_1, &flows[*a].address_2).cmp(&(&flows[*b].address_1, &flows[*b].address_2))),
+        }
+        if self.descending {
+            self.order.reverse();
+        }
+    }
+
+    /// Handles a key, returns false when the dashboard must be closed.
+    fn handle_key(&mut self, code: KeyCode) -> bool {
+        match &mut self.mode {
+            Mode::Normal => match code {
+                KeyCode::Char('q') | KeyCode::Esc => return false,
+                KeyCode::Char('p') => match self.control_block.get_state() {
+                    CaptureState::Paused() => {
+                        self.control_block.resume();
+                        self.message = String::from("Analysis resumed");
+                    }
+                    _ => {
+                        self.control_block.pause();
+                        self.message = String::from("Analysis paused");
+                    }
+                },
+                KeyCode::Char('f') => self.mode = Mode::Filter(String::new()),
+                KeyCode::Char('d') => match get_devices() {
+                    Ok(devices) => {
+                        let mut state = ListState::default();
+                        state.select(Some(0));
+                        self.mode = Mode::Device(devices.into_iter().map(|d| d.name).collect(), state);
+                    }
+                    Err(e) => self.message = format!("Error in loading devices: {}", e),
+                },
+                KeyCode::Char('s') => {
+                    self.sort = self.sort.next();
+                    self.sort_flows();
+                }
+                KeyCode::Char('r') => {
+                    self.descending = !self.descending;
+                    self.sort_flows();
+                }
+                KeyCode::Char('c') => {
+                    let count = self.control_block.get_errors().len();
+                    self.control_block.clear_errors(count);
+                }
+                _ => {}
+            },
+            Mode::Filter(filter) => match code {
+                KeyCode::Esc => self.mode = Mode::Normal,
+                KeyCode::Backspace => {
+                    filter.pop();
+                }
+                KeyCode::Char(c) => filter.push(c),
+                KeyCode::Enter => {
+                    self.message = match self.control_block.apply_filter(filter) {
+                        Ok(_) => format!("Filter set to \"{}\"", filter),
+                        Err(e) => format!("Filter not valid: {}", e),
+                    };
+                    self.mode = Mode::Normal;
+                }
+                _ => {}
+            },
+            Mode::Device(devices, state) => match code {
+                KeyCode::Esc => self.mode = Mode::Normal,
+                KeyCode::Up => state.select_previous(),
+                KeyCode::Down if state.selected().is_some_and(|s| s + 1 < devices.len()) => state.select_next(),
+                KeyCode::Enter => {
+                    if let Some(i) = state.selected() {
+                        // The list may have changed since it was loaded, select the device by its name
+                        self.message = match self.control_block.select_device(&DeviceSelector::Name(devices[i].clone())) {
+                            Ok(_) => format!("Device set to {}", devices[i]),
+                            Err(e) => format!("Error in setting the new device: {}", e),
+                        };
+                    }
+                    self.mode = Mode::Normal;
+                }
+                _ => {}
+            },
+        }
+        true
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Length(7), Constraint::Min(6), Constraint::Length(8), Constraint::Length(1)])
+            .split(frame.area());
+        let top = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(35), Constraint::Percentage(35), Constraint::Percentage(30)])
+            .split(rows[0]);
+        let bottom = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(35), Constraint::Percentage(65)])
+            .split(rows[2]);
+
+        self.draw_rate(frame, top[0], "Packets/s", &self.packet_rates, Color::Cyan);
+        self.draw_rate(frame, top[1], "Bytes/s", &self.byte_rates, Color::Green);
+        self.draw_status(frame, top[2]);
+        self.draw_flows(frame, rows[1]);
+        self.draw_protocols(frame, bottom[0]);
+        self.draw_errors(frame, bottom[1]);
+        let help = "p pause/resume  f filter  d device  s sort  r reverse  c clear errors  q quit";
+        frame.render_widget(Paragraph::new(help).style(Style::default().add_modifier(Modifier::REVERSED)), rows[3]);
+
+        match &mut self.mode {
+            Mode::Normal => {}
+            Mode::Filter(filter) => {
+                let area = popup(frame.area(), 60, 3);
+                frame.render_widget(Clear, area);
+                frame.render_widget(Paragraph::new(filter.as_str())
+                    .block(Block::default().borders(Borders::ALL).title("New BPF filter (Enter to apply, Esc to cancel)")), area);
+            }
+            Mode::Device(devices, state) => {
+                let area = popup(frame.area(), 60, u16::try_from(devices.len()).unwrap_or(u16::MAX).saturating_add(2));
+                let items = devices.iter().enumerate().map(|(i, d)| ListItem::new(format!("{}) {}", i + 1, d))).collect::<Vec<ListItem>>();
+                frame.render_widget(Clear, area);
+                frame.render_stateful_widget(List::new(items)
+                    .block(Block::default().borders(Borders::ALL).title("Device (Enter to select, Esc to cancel)"))
+                    .highlight_style(Style::default().add_modifier(Modifier::REVERSED)), area, state);
+            }
+        }
+    }
+
+    fn draw_rate(&self, frame: &mut Frame, area: Rect, title: &str, history: &VecDeque<u64>, color: Color) {
+        // Only the most recent values that fit in the block are shown
+        let width = area.width.saturating_sub(2) as usize;
+        let data = history.iter().skip(history.len().saturating_sub(width)).copied().collect::<Vec<u64>>();
+        let current = data.last().copied().unwrap_or(0);
+        frame.render_widget(Sparkline::default()
+            .block(Block::default().borders(Borders::ALL).title(format!("{} {}", title, current)))
+            .style(Style::default().fg(color))
+            .data(&data), area);
+    }
+
+    fn draw_status(&self, frame: &mut Frame, area: Rect) {
+        let state = api::state_name(&self.control_block.get_state());
+        let text = format!("State: {}\nOutput: {}\nInterval: {} s\nAlerts: {}\n{}",
+                           state,
+                           self.control_block.get_output_file(),
+                           self.control_block.get_timeout(),
+                           self.control_block.get_alerts().len(),
+                           self.message);
+        frame.render_widget(Paragraph::new(text)
+            .block(Block::default().borders(Borders::ALL).title("Capture"))
+            .wrap(Wrap { trim: true }), area);
+    }
+
+    fn draw_flows(&self, frame: &mut Frame, area: Rect) {
+        let flows = self.snapshot.get_flows();
+        let title = format!("Communications: {} (sorted by {}, {})", flows.len(), self.sort.name(), if self.descending { "descending" } else { "ascending" });
+        // Only the lines that fit in the block, below the header, are built
+        let visible = area.height.saturating_sub(3) as usize;
+        let rows = self.order.iter().take(visible).map(|i| {
+            let f = &flows[*i];
+            Row::new(vec![
+                f.address_1.clone(),
+                f.address_2.clone(),
+                f.protocols.join(","),
+                (u64::from(f.packets_forward) + u64::from(f.packets_backward)).to_string(),
+                f.bytes_total.to_string(),
+                format_timestamp_us(f.timestamp_first_us),
+                format_timestamp_us(f.timestamp_last_us),
+            ])
+        });
+        let table = Table::new(rows, [
+            Constraint::Percentage(18),
+            Constraint::Percentage(18),
+            Constraint::Percentage(14),
+            Constraint::Percentage(8),
+            Constraint::Percentage(10),
+            Constraint::Percentage(16),
+            Constraint::Percentage(16),
+        ])
+            .header(Row::new(vec!["Address 1", "Address 2", "Protocols", "Packets", "Bytes", "First", "Last"])
+                .style(Style::default().add_modifier(Modifier::BOLD)))
+            .block(Block::default().borders(Borders::ALL).title(title));
+        frame.render_widget(table, area);
+    }
+
+    fn draw_protocols(&self, frame: &mut Frame, area: Rect) {
+        let mut protocols = self.control_block.get_protocols().into_iter().map(|(p, (_, bytes))| (p, bytes)).collect::<Vec<(String, u64)>>();
+        protocols.sort_by_key(|p| std::cmp::Reverse(p.1));
+        let data = protocols.iter().map(|(p, bytes)| (p.as_str(), *bytes)).collect::<Vec<(&str, u64)>>();
+        frame.render_widget(BarChart::default()
+            .block(Block::default().borders(Borders::ALL).title("Bytes by protocol"))
+            .direction(Direction::Horizontal)
+            .bar_width(1)
+            .bar_gap(0)
+            .data(data.as_slice()), area);
+    }
+
+    fn draw_errors(&self, frame: &mut Frame, area: Rect) {
+        let errors = self.control_block.get_errors();
+        // The most recent errors that fit in the block
+        let visible = area.height.saturating_sub(2) as usize;
+        let items = errors.iter().skip(errors.len().saturating_sub(visible)).map(|e| ListItem::new(e.to_string())).collect::<Vec<ListItem>>();
+        let title = format!("Errors: {}", errors.len());
+        frame.render_widget(List::new(items)
+            .block(Block::default().borders(Borders::ALL).title(title))
+            .style(Style::default().fg(Color::Red)), area);
+    }
+}
+
+/// Sums the packets and bytes of all the protocols.
+fn totals(control_block: &ControlBlock) -> (u64, u64) {
+    control_block.get_protocols().values().fold((0, 0), |(p, b), (packets, bytes)| (p + packets, b + bytes))
+}
+
+/// Centers a box of the given width percentage and height in the area.
+fn popup(area: Rect, percent_x: u16, height: u16) -> Rect {
+    let width = (u32::from(area.width) * u32::from(percent_x.min(100)) / 100) as u16;
+    let height = height.min(area.height);
+    Rect::new(area.x + (area.width - width) / 2, area.y + (area.height - height) / 2, width, height)
+}